@@ -0,0 +1,61 @@
+//! Measures how many instructions per second the CPU executes with and without the instruction
+//! cache.
+//!
+//! Run with `cargo bench -p firn-arch-x86 --bench instr_cache`.
+
+use firn_arch_x86::{Cpu, InstrCache, SegmentReg, System};
+use firn_core::mem::{BasicMem, MemMap};
+use std::time::{Duration, Instant};
+
+const INSTRS: u64 = 5_000_000;
+
+// A loop that does some arithmetic and writes to a data segment, like most real code does:
+//
+// 0x100: mov cx, 0x1000
+// 0x103: add ax, bx
+// 0x105: mov [si], al
+// 0x107: inc si
+// 0x108: xor dx, ax
+// 0x10a: loop 0x103
+// 0x10c: jmp 0x100
+const PROGRAM: &[u8] = &[
+    0xb9, 0x00, 0x10, 0x01, 0xd8, 0x88, 0x04, 0x46, 0x31, 0xc2, 0xe2, 0xf7, 0xeb, 0xf2,
+];
+
+fn create_sys(cache: bool) -> System {
+    let mut mem = BasicMem::new(0x100000);
+    for (index, byte) in PROGRAM.iter().enumerate() {
+        mem[0x100 + index] = *byte;
+    }
+
+    let mut map = MemMap::new(0x100000);
+    map.map_full(mem);
+
+    let mut sys = System::new(Cpu::new(), map);
+    sys.cpu.set_reg_16(SegmentReg::Ds.into(), 0x2000);
+    sys.cpu.ip = 0x100;
+    sys.cpu.instr_cache = if cache { Some(InstrCache::new()) } else { None };
+
+    sys
+}
+
+fn run(cache: bool) -> Duration {
+    let mut sys = create_sys(cache);
+
+    let start = Instant::now();
+    for _ in 0..INSTRS {
+        let instr = Cpu::fetch(&mut sys);
+        instr.execute(&mut sys);
+    }
+
+    start.elapsed()
+}
+
+fn main() {
+    for (name, cache) in [("without cache", false), ("with cache", true)] {
+        let elapsed = run(cache);
+        let ips = INSTRS as f64 / elapsed.as_secs_f64();
+
+        println!("{:>13}: {:>12.0} instructions/s", name, ips);
+    }
+}
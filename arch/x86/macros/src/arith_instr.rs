@@ -16,7 +16,7 @@ pub fn arith_instr_impl(input: TokenStream) -> TokenStream {
     let rm16_imm16_attr = format!("{} r/m16, imm16", instr);
     let rm16_imm8_attr = format!("{} r/m16, imm8", instr);
     let rm8_r8_attr = format!("{} r/m8, r8", instr);
-    let rm16_r16_attr = format!("{} r/m8, r16", instr);
+    let rm16_r16_attr = format!("{} r/m16, r16", instr);
     let r8_rm8_attr = format!("{} r8, r/m8", instr);
     let r16_rm16_attr = format!("{} r16, r/m16", instr);
 
@@ -31,42 +31,42 @@ pub fn arith_instr_impl(input: TokenStream) -> TokenStream {
     let r16_rm16 = format_ident!("{}_r16_rm16", instr_lower);
 
     let expanded = quote! {
-        #[firn_arch_x86_macros::instr(#al_imm8_attr)]
+        #[firn_arch_x86_macros::instr(#al_imm8_attr, flags = "OSZAPC")]
         pub fn #al_imm8(sys: &mut crate::System, imm: u8) {
             let old = sys.cpu.reg_8(crate::GeneralByteReg::Al);
             let value = crate::arith::#operation_8(sys, old, imm);
             sys.cpu.set_reg_8(crate::GeneralByteReg::Al, value);
         }
 
-        #[firn_arch_x86_macros::instr(#ax_imm16_attr)]
+        #[firn_arch_x86_macros::instr(#ax_imm16_attr, flags = "OSZAPC")]
         pub fn #ax_imm16(sys: &mut crate::System, imm: u16) {
             let old = sys.cpu.reg_16(crate::GeneralWordReg::Ax.into());
             let value = crate::arith::#operation_16(sys, old, imm);
             sys.cpu.set_reg_16(crate::GeneralWordReg::Ax.into(), value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm8_imm8_attr)]
+        #[firn_arch_x86_macros::instr(#rm8_imm8_attr, flags = "OSZAPC")]
         pub fn #rm8_imm8(sys: &mut crate::System, rm: crate::RegMem, imm: u8) {
             let old = rm.get_8(sys);
             let value = crate::arith::#operation_8(sys, old, imm);
             rm.set_8(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm16_imm16_attr)]
+        #[firn_arch_x86_macros::instr(#rm16_imm16_attr, flags = "OSZAPC")]
         pub fn #rm16_imm16(sys: &mut crate::System, rm: crate::RegMem, imm: u16) {
             let old = rm.get_16(sys);
             let value = crate::arith::#operation_16(sys, old, imm);
             rm.set_16(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm16_imm8_attr)]
+        #[firn_arch_x86_macros::instr(#rm16_imm8_attr, flags = "OSZAPC")]
         pub fn #rm16_imm8(sys: &mut crate::System, rm: crate::RegMem, imm: u8) {
             let old = rm.get_16(sys);
             let value = crate::arith::#operation_16(sys, old, imm as u16);
             rm.set_16(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm8_r8_attr)]
+        #[firn_arch_x86_macros::instr(#rm8_r8_attr, flags = "OSZAPC")]
         pub fn #rm8_r8(sys: &mut crate::System, rm: crate::RegMem, reg: crate::GeneralByteReg) {
             let old = rm.get_8(sys);
             let reg = sys.cpu.reg_8(reg);
@@ -74,7 +74,7 @@ pub fn arith_instr_impl(input: TokenStream) -> TokenStream {
             rm.set_8(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm16_r16_attr)]
+        #[firn_arch_x86_macros::instr(#rm16_r16_attr, flags = "OSZAPC")]
         pub fn #rm16_r16(sys: &mut crate::System, rm: crate::RegMem, reg: crate::GeneralWordReg) {
             let old = rm.get_16(sys);
             let reg = sys.cpu.reg_16(reg.into());
@@ -82,7 +82,7 @@ pub fn arith_instr_impl(input: TokenStream) -> TokenStream {
             rm.set_16(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#r8_rm8_attr)]
+        #[firn_arch_x86_macros::instr(#r8_rm8_attr, flags = "OSZAPC")]
         pub fn #r8_rm8(sys: &mut crate::System, reg: crate::GeneralByteReg, rm: crate::RegMem) {
             let old = sys.cpu.reg_8(reg);
             let rm = rm.get_8(sys);
@@ -90,7 +90,7 @@ pub fn arith_instr_impl(input: TokenStream) -> TokenStream {
             sys.cpu.set_reg_8(reg, value);
         }
 
-        #[firn_arch_x86_macros::instr(#r16_rm16_attr)]
+        #[firn_arch_x86_macros::instr(#r16_rm16_attr, flags = "OSZAPC")]
         pub fn #r16_rm16(sys: &mut crate::System, reg: crate::GeneralWordReg, rm: crate::RegMem) {
             let old = sys.cpu.reg_16(reg.into());
             let rm = rm.get_16(sys);
@@ -4,23 +4,24 @@ use quote::{format_ident, quote};
 use std::str::FromStr;
 use strum_macros::EnumString;
 use syn::parse::{Parse, ParseStream};
-use syn::punctuated::Punctuated;
 use syn::{parse_macro_input, Error, ItemFn, LitStr, Token};
 
-#[derive(Eq, PartialEq, EnumString)]
+#[derive(EnumString)]
 #[strum(ascii_case_insensitive)]
 enum Operand {
-    Ignored,
+    /// A register that's implied by the opcode, like `AL` (stored in lowercase).
+    #[strum(disabled)]
+    Fixed(String),
+    /// A constant that's implied by the opcode, like the `1` in `SHL r/m8, 1`.
+    #[strum(disabled)]
+    Const(u8),
 
-    #[strum(serialize = "imm8", serialize = "rel8")]
     Imm8,
-    #[strum(
-        serialize = "imm16",
-        serialize = "rel16",
-        serialize = "moffs8",
-        serialize = "moffs16"
-    )]
     Imm16,
+    Rel8,
+    Rel16,
+    Moffs8,
+    Moffs16,
 
     R8,
     R16,
@@ -36,26 +37,75 @@ enum Operand {
     M16_16,
 }
 
-struct Mnemonic {
-    operands: Vec<Operand>,
-}
+impl Operand {
+    fn parse_implied(operand: &str) -> Option<Self> {
+        if let Ok(value) = operand.parse() {
+            return Some(Operand::Const(value));
+        }
 
-impl Mnemonic {
-    fn is_ignored(operand: &str) -> bool {
-        let last = operand.to_lowercase().chars().last().unwrap();
+        let operand = operand.to_lowercase();
+        let is_reg = BYTE_REGS.contains(&operand.as_str())
+            || WORD_REGS.contains(&operand.as_str())
+            || SEGMENT_REGS.contains(&operand.as_str());
 
-        let single_reg = operand.len() == 2
-            && operand.chars().all(char::is_alphabetic)
-            && ['l', 'x', 's'].contains(&last);
-        let number = operand.chars().all(char::is_numeric);
+        is_reg.then_some(Operand::Fixed(operand))
+    }
 
-        single_reg || number
+    fn kind(&self) -> TokenStream2 {
+        let kind = match self {
+            Operand::Fixed(reg) => {
+                let variant = format_ident!("{}{}", reg[..1].to_uppercase(), &reg[1..]);
+                let reg = if BYTE_REGS.contains(&reg.as_str()) {
+                    quote! { crate::Reg::Byte(crate::GeneralByteReg::#variant) }
+                } else if WORD_REGS.contains(&reg.as_str()) {
+                    quote! { crate::Reg::Word(crate::WordReg::General(crate::GeneralWordReg::#variant)) }
+                } else {
+                    quote! { crate::Reg::Word(crate::WordReg::Segment(crate::SegmentReg::#variant)) }
+                };
+
+                return quote! { crate::disasm::OperandKind::Fixed(#reg) };
+            }
+            Operand::Const(value) => {
+                return quote! { crate::disasm::OperandKind::Const(#value) };
+            }
+
+            Operand::Imm8 => "Imm8",
+            Operand::Imm16 => "Imm16",
+            Operand::Rel8 => "Rel8",
+            Operand::Rel16 => "Rel16",
+            Operand::Moffs8 => "Moffs8",
+            Operand::Moffs16 => "Moffs16",
+            Operand::R8 => "R8",
+            Operand::R16 => "R16",
+            Operand::Sreg => "Sreg",
+            Operand::M8 => "M8",
+            Operand::M16 => "M16",
+            Operand::Rm8 => "Rm8",
+            Operand::Rm16 => "Rm16",
+            Operand::Ptr16_16 => "Ptr16_16",
+            Operand::M16_16 => "M16_16",
+        };
+
+        let kind = format_ident!("{}", kind);
+        quote! { crate::disasm::OperandKind::#kind }
     }
 }
 
+const BYTE_REGS: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const WORD_REGS: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+const SEGMENT_REGS: [&str; 4] = ["es", "cs", "ss", "ds"];
+
+/// The most operands an instruction can have. This must match `crate::disasm::MAX_OPERANDS`.
+const MAX_OPERANDS: usize = 3;
+
+struct Mnemonic {
+    name: Ident,
+    operands: Vec<Operand>,
+}
+
 impl Parse for Mnemonic {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        input.parse::<Ident>()?;
+        let name = input.parse::<Ident>()?;
 
         let mut operands = Vec::new();
         while !input.is_empty() {
@@ -75,17 +125,41 @@ impl Parse for Mnemonic {
                 .replace('/', "");
             let operand = match Operand::from_str(&operand_str) {
                 Ok(operand) => operand,
-                Err(_) if Self::is_ignored(&operand_str) => Operand::Ignored,
-                Err(_) => return Err(Error::new_spanned(tokens, "invalid operand")),
+                Err(_) => match Operand::parse_implied(&operand_str) {
+                    Some(operand) => operand,
+                    None => return Err(Error::new_spanned(tokens, "invalid operand")),
+                },
             };
 
             operands.push(operand);
         }
 
-        Ok(Self { operands })
+        Ok(Self { name, operands })
     }
 }
 
+/// Converts flag letters (like `OSZAPC`) to a mask with the same bits as the FLAGS register.
+fn parse_flags(flags: &LitStr) -> syn::Result<u16> {
+    let mut mask = 0;
+    for flag in flags.value().chars() {
+        mask |= match flag.to_ascii_uppercase() {
+            'C' => 0x001,
+            'P' => 0x004,
+            'A' => 0x010,
+            'Z' => 0x040,
+            'S' => 0x080,
+            'T' => 0x100,
+            'I' => 0x200,
+            'D' => 0x400,
+            'O' => 0x800,
+
+            _ => return Err(Error::new_spanned(flags, format!("invalid flag: {}", flag))),
+        };
+    }
+
+    Ok(mask)
+}
+
 struct Instr {
     mnemonic_str: LitStr,
     mnemonic: Mnemonic,
@@ -93,6 +167,7 @@ struct Instr {
     rep: bool,
     rep_e: bool,
     rep_ne: bool,
+    modifies_flags: u16,
 }
 
 impl Parse for Instr {
@@ -100,19 +175,34 @@ impl Parse for Instr {
         let mnemonic_str = input.parse::<LitStr>()?;
         let mnemonic = mnemonic_str.parse()?;
 
-        let _ = input.parse::<Token![,]>();
-
         let mut rep = false;
         let mut rep_e = false;
         let mut rep_ne = false;
-        for prefix in Punctuated::<Ident, Token![,]>::parse_terminated(input)? {
-            let prefix_str = prefix.to_string().to_lowercase();
-            match prefix_str.as_str() {
+        let mut modifies_flags = 0;
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+
+            let arg = input.parse::<Ident>()?;
+            if input.peek(Token![=]) {
+                input.parse::<Token![=]>()?;
+                let value = input.parse::<LitStr>()?;
+
+                match arg.to_string().as_str() {
+                    "flags" => modifies_flags = parse_flags(&value)?,
+                    _ => return Err(Error::new_spanned(arg, "invalid argument")),
+                }
+                continue;
+            }
+
+            match arg.to_string().to_lowercase().as_str() {
                 "rep" => rep = true,
                 "repe" => rep_e = true,
                 "repne" => rep_ne = true,
 
-                _ => return Err(Error::new_spanned(prefix, "invalid prefix")),
+                _ => return Err(Error::new_spanned(arg, "invalid prefix")),
             }
         }
 
@@ -123,101 +213,62 @@ impl Parse for Instr {
             rep,
             rep_e,
             rep_ne,
+            modifies_flags,
         })
     }
 }
 
-enum ModrmReg {
-    ByteSized,
-    WordSized,
-    Segment,
-}
-
-impl ModrmReg {
-    fn to_token_stream(&self) -> TokenStream2 {
-        match self {
-            ModrmReg::ByteSized => quote! {
-                ByteSized
-            },
-            ModrmReg::WordSized => quote! {
-                WordSized
-            },
-            ModrmReg::Segment => quote! {
-                Segment
-            },
-        }
-    }
-}
-
 #[derive(Eq, PartialEq)]
 enum ModrmRm {
     Byte,
     Word,
 }
 
-impl ModrmRm {
-    fn to_token_stream(&self) -> TokenStream2 {
-        match self {
-            ModrmRm::Byte => quote! {
-                Byte
-            },
-            ModrmRm::Word => quote! {
-                Word
-            },
-        }
-    }
-}
-
-fn match_operand(operand: &Operand, modrm: &Option<ModrmRm>) -> Vec<TokenStream2> {
+fn match_operand(index: usize, operand: &Operand) -> Vec<TokenStream2> {
     let mut token_streams = Vec::new();
 
+    let decoded = quote! {
+        instr.operands[#index]
+    };
     match operand {
-        Operand::Ignored => (),
-        Operand::Imm8 => token_streams.push(quote! {
-            crate::ExtSystem::read_mem_8(sys)
-        }),
-        Operand::Imm16 => token_streams.push(quote! {
-            crate::ExtSystem::read_mem_16(sys)
-        }),
-        Operand::R8 if matches!(modrm, None) => token_streams.push(quote! {
-            crate::GeneralByteReg::from_u8(opcode % 0o10)
-                .expect("invalid byte-sized register in opcode")
+        Operand::Fixed(_) | Operand::Const(_) => (),
+        Operand::Imm8 | Operand::Rel8 => token_streams.push(quote! {
+            #decoded.imm_8()
         }),
+        Operand::Imm16 | Operand::Rel16 | Operand::Moffs8 | Operand::Moffs16 => {
+            token_streams.push(quote! {
+                #decoded.imm_16()
+            })
+        }
         Operand::R8 => token_streams.push(quote! {
-            modrm.byte_reg()
-        }),
-        Operand::R16 if matches!(modrm, None) => token_streams.push(quote! {
-            crate::GeneralWordReg::from_u8(opcode % 0o10)
-                .expect("invalid word-sized register in opcode")
+            #decoded.byte_reg()
         }),
         Operand::R16 => token_streams.push(quote! {
-            modrm.word_reg()
+            #decoded.word_reg()
         }),
         Operand::Sreg => token_streams.push(quote! {
-            modrm.segment_reg()
+            #decoded.segment_reg()
         }),
         Operand::M8 | Operand::M16 => token_streams.push(quote! {
-            match modrm.reg_mem {
-                crate::RegMem::Ptr(ptr) => ptr,
-                crate::RegMem::Reg(_) => panic!("expected memory pointer in ModRM byte"),
-            }
+            #decoded.ptr()
         }),
         Operand::Rm8 | Operand::Rm16 => token_streams.push(quote! {
-            modrm.reg_mem
+            #decoded.reg_mem()
         }),
         Operand::Ptr16_16 => {
-            for _ in 0..2 {
-                token_streams.push(quote! {
-                    crate::ExtSystem::read_mem_16(sys)
-                });
-            }
+            token_streams.push(quote! {
+                #decoded.far().0
+            });
+            token_streams.push(quote! {
+                #decoded.far().1
+            });
         }
         Operand::M16_16 => {
             token_streams.push(quote! {
-                double_address.1
+                #decoded.ptr().far_address(sys).0
             });
             token_streams.push(quote! {
-                double_address.0
+                #decoded.ptr().far_address(sys).1
             });
         }
     };
@@ -228,13 +279,20 @@ fn match_operand(operand: &Operand, modrm: &Option<ModrmRm>) -> Vec<TokenStream2
 pub fn instr_impl(args: TokenStream, input: TokenStream) -> TokenStream {
     let Instr {
         mnemonic_str,
-        mnemonic: Mnemonic { operands },
+        mnemonic: Mnemonic { name, operands },
         rep,
         rep_e,
         rep_ne,
+        modifies_flags,
     } = parse_macro_input!(args as Instr);
     let input = parse_macro_input!(input as ItemFn);
 
+    if operands.len() > MAX_OPERANDS {
+        return Error::new_spanned(mnemonic_str, "too many operands")
+            .into_compile_error()
+            .into();
+    }
+
     let mut modrm_rm = None;
     for operand in &operands {
         let modrm = match operand {
@@ -257,59 +315,16 @@ pub fn instr_impl(args: TokenStream, input: TokenStream) -> TokenStream {
     }
 
     let mut operand_decodes = Vec::new();
-    for operand in &operands {
-        let mut decodes = match_operand(operand, &modrm_rm);
+    for (index, operand) in operands.iter().enumerate() {
+        let mut decodes = match_operand(index, operand);
         operand_decodes.append(&mut decodes);
     }
 
-    let modrm_reg = match modrm_rm {
-        Some(_) if operands.contains(&Operand::R8) => Some(ModrmReg::ByteSized),
-        Some(_) if operands.contains(&Operand::R16) => Some(ModrmReg::WordSized),
-        Some(_) if operands.contains(&Operand::Sreg) => Some(ModrmReg::Segment),
-
-        _ => None,
-    };
-
     let vis = &input.vis;
     let fn_name = &input.sig.ident;
-    let meta_fn_name = format_ident!("{}_meta", fn_name);
-
-    let modrm_decode = modrm_rm.map(|modrm_rm| {
-        let rm_size = modrm_rm.to_token_stream();
-        let reg_type = if let Some(reg_type) = modrm_reg {
-            let reg_type = reg_type.to_token_stream();
-            quote! {
-                Some(crate::ModrmRegType::#reg_type)
-            }
-        } else {
-            quote! {
-                None
-            }
-        };
-
-        quote! {
-            let modrm = crate::ExtSystem::read_mem_8(sys);
-            let modrm = crate::Modrm::decode(
-                sys,
-                modrm,
-                #reg_type,
-                crate::Size::#rm_size,
-            );
-        }
-    });
-    let modrm_decode = modrm_decode.iter();
-
-    let double_address = if operands.contains(&Operand::M16_16) {
-        Some(quote! {
-            let double_address = match modrm.reg_mem {
-                crate::RegMem::Ptr(ptr) => ptr.double_address(sys),
-                _ => panic!("expected memory pointer in ModRM byte"),
-            };
-        })
-    } else {
-        None
-    };
-    let double_address = double_address.iter();
+    let static_name = format_ident!("{}", fn_name.to_string().to_uppercase());
+    let name = name.to_string();
+    let kinds = operands.iter().map(Operand::kind);
 
     let mut operand_names = Vec::new();
     let operand_decodes: Vec<_> = operand_decodes
@@ -378,6 +393,14 @@ pub fn instr_impl(args: TokenStream, input: TokenStream) -> TokenStream {
         });
     }
 
+    let prefixes = if takes_prefixes || !rep_checks.is_empty() {
+        quote! {
+            let prefixes = &instr.prefixes;
+        }
+    } else {
+        quote! {}
+    };
+
     let fn_call = if rep_checks.is_empty() {
         fn_call
     } else {
@@ -396,6 +419,10 @@ pub fn instr_impl(args: TokenStream, input: TokenStream) -> TokenStream {
         }
     };
 
+    let static_doc_comment = format!(
+        "The metadata and implementation of the `{}` instruction.",
+        mnemonic_str.value()
+    );
     let doc_comment = format!(
         "The `{}` instruction.\n\
         \n\
@@ -406,23 +433,30 @@ pub fn instr_impl(args: TokenStream, input: TokenStream) -> TokenStream {
 
     let expanded = quote! {
         #[doc = #doc_comment]
-        #vis fn #fn_name(sys: &mut crate::System, opcode: u8, prefixes: &crate::Prefixes) {
+        #vis fn #fn_name(sys: &mut crate::System, instr: &crate::DecodedInstr) {
             #input
 
-            #(#modrm_decode)*
-            #(#double_address)*
-
+            #prefixes
             #(#operand_decodes)*
 
             #fn_call
         }
 
-        #[doc(hidden)]
-        #vis fn #meta_fn_name() -> crate::InstrMeta {
-            crate::InstrMeta {
-                mnemonic: String::from(#mnemonic_str),
-            }
-        }
+        #[doc = #static_doc_comment]
+        #vis static #static_name: crate::Instr = crate::Instr {
+            meta: crate::InstrMeta {
+                mnemonic: #mnemonic_str,
+                name: #name,
+                operands: &[#(#kinds),*],
+                rep: crate::RepPrefixes {
+                    rep: #rep,
+                    rep_e: #rep_e,
+                    rep_ne: #rep_ne,
+                },
+                modifies_flags: crate::FlagSet::from_bits(#modifies_flags),
+            },
+            func: crate::InstrFunc(#fn_name),
+        };
     };
 
     expanded.into()
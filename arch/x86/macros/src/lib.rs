@@ -2,7 +2,7 @@ use proc_macro::TokenStream;
 
 mod arith_instr;
 mod instr;
-mod new_instr;
+mod opcode_table;
 mod shift_instr;
 
 #[proc_macro_attribute]
@@ -11,8 +11,8 @@ pub fn instr(args: TokenStream, input: TokenStream) -> TokenStream {
 }
 
 #[proc_macro]
-pub fn new_instr(input: TokenStream) -> TokenStream {
-    new_instr::new_instr_impl(input)
+pub fn opcode_table(input: TokenStream) -> TokenStream {
+    opcode_table::opcode_table_impl(input)
 }
 
 #[proc_macro]
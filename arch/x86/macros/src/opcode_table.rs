@@ -0,0 +1,152 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, Error, LitInt, Path, Token};
+
+struct Entry {
+    span: Span,
+
+    opcodes: (u8, u8),
+    extension: Option<u8>,
+    feature: Option<Ident>,
+    instr: Path,
+}
+
+impl Parse for Entry {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let span = input.span();
+
+        let first = input.parse::<LitInt>()?.base10_parse()?;
+        let last = if input.peek(Token![..=]) {
+            input.parse::<Token![..=]>()?;
+            input.parse::<LitInt>()?.base10_parse()?
+        } else {
+            first
+        };
+
+        let extension = if input.peek(Token![/]) {
+            input.parse::<Token![/]>()?;
+            let extension = input.parse::<LitInt>()?;
+            match extension.base10_parse()? {
+                extension @ 0..=7 => Some(extension),
+                _ => return Err(Error::new_spanned(extension, "invalid opcode extension")),
+            }
+        } else {
+            None
+        };
+
+        let feature = if input.peek(Token![if]) {
+            input.parse::<Token![if]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+
+        input.parse::<Token![=>]>()?;
+        let instr = input.parse()?;
+
+        Ok(Self {
+            span,
+
+            opcodes: (first, last),
+            extension,
+            feature,
+            instr,
+        })
+    }
+}
+
+enum Slot {
+    Invalid,
+    Instr(TokenStream2, Option<Ident>),
+    Group(Vec<Option<TokenStream2>>, Option<Ident>),
+}
+
+fn feature_tokens(feature: &Option<Ident>) -> TokenStream2 {
+    match feature {
+        Some(feature) => quote! { Some(crate::Feature::#feature) },
+        None => quote! { None },
+    }
+}
+
+fn feature_name(feature: &Option<Ident>) -> Option<String> {
+    feature.as_ref().map(Ident::to_string)
+}
+
+pub fn opcode_table_impl(input: TokenStream) -> TokenStream {
+    let entries = parse_macro_input!(input with Punctuated::<Entry, Token![,]>::parse_terminated);
+
+    let mut slots: Vec<Slot> = (0..=u8::MAX).map(|_| Slot::Invalid).collect();
+    for entry in entries {
+        // The `#[instr]` attribute generates a static with the uppercase name of the function
+        let mut instr = entry.instr.clone();
+        let last_segment = instr.segments.last_mut().unwrap();
+        last_segment.ident = format_ident!("{}", last_segment.ident.to_string().to_uppercase());
+        let instr = quote! { &#instr };
+
+        for opcode in entry.opcodes.0..=entry.opcodes.1 {
+            let slot = &mut slots[opcode as usize];
+            match (&mut *slot, entry.extension) {
+                (Slot::Invalid, None) => *slot = Slot::Instr(instr.clone(), entry.feature.clone()),
+                (Slot::Invalid, Some(extension)) => {
+                    let mut instrs = vec![None; 8];
+                    instrs[extension as usize] = Some(instr.clone());
+                    *slot = Slot::Group(instrs, entry.feature.clone());
+                }
+                (Slot::Group(instrs, feature), Some(extension))
+                    if instrs[extension as usize].is_none() =>
+                {
+                    if feature_name(feature) != feature_name(&entry.feature) {
+                        let message = format!(
+                            "opcode {:#04x} requires different features for different extensions",
+                            opcode
+                        );
+                        return Error::new(entry.span, message).into_compile_error().into();
+                    }
+
+                    instrs[extension as usize] = Some(instr.clone());
+                }
+                _ => {
+                    let message = format!("opcode {:#04x} is defined more than once", opcode);
+                    return Error::new(entry.span, message).into_compile_error().into();
+                }
+            }
+        }
+    }
+
+    let slots = slots.iter().map(|slot| match slot {
+        Slot::Invalid => quote! {
+            crate::opcodes::Opcode::Invalid
+        },
+        Slot::Instr(instr, feature) => {
+            let feature = feature_tokens(feature);
+            quote! {
+                crate::opcodes::Opcode::Instr {
+                    instr: #instr,
+                    feature: #feature,
+                }
+            }
+        }
+        Slot::Group(instrs, feature) => {
+            let instrs = instrs.iter().map(|instr| match instr {
+                Some(instr) => quote! { Some(#instr) },
+                None => quote! { None },
+            });
+            let feature = feature_tokens(feature);
+            quote! {
+                crate::opcodes::Opcode::Group {
+                    instrs: [#(#instrs),*],
+                    feature: #feature,
+                }
+            }
+        }
+    });
+
+    let expanded = quote! {
+        [#(#slots),*]
+    };
+
+    expanded.into()
+}
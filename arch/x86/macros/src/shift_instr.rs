@@ -10,6 +10,12 @@ pub fn shift_instr_impl(input: TokenStream) -> TokenStream {
     let operation_8 = format_ident!("{}_8", instr_lower);
     let operation_16 = format_ident!("{}_16", instr_lower);
 
+    // Rotates only change OF and CF, but shifts change every status flag
+    let flags = match instr.to_string().as_str() {
+        "ROL" | "ROR" | "RCL" | "RCR" => "OC",
+        _ => "OSZAPC",
+    };
+
     let rm8_1_attr = format!("{} r/m8, 1", instr);
     let rm8_cl_attr = format!("{} r/m8, CL", instr);
     let rm8_imm8_attr = format!("{} r/m8, imm8", instr);
@@ -25,14 +31,14 @@ pub fn shift_instr_impl(input: TokenStream) -> TokenStream {
     let rm16_imm8 = format_ident!("{}_rm16_imm8", instr_lower);
 
     let expanded = quote! {
-        #[firn_arch_x86_macros::instr(#rm8_1_attr)]
+        #[firn_arch_x86_macros::instr(#rm8_1_attr, flags = #flags)]
         pub fn #rm8_1(sys: &mut crate::System, rm: crate::RegMem) {
             let old = rm.get_8(sys);
             let value = crate::arith::#operation_8(sys, old, 1);
             rm.set_8(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm8_cl_attr)]
+        #[firn_arch_x86_macros::instr(#rm8_cl_attr, flags = #flags)]
         pub fn #rm8_cl(sys: &mut crate::System, rm: crate::RegMem) {
             let old = rm.get_8(sys);
             let reg = sys.cpu.reg_8(crate::GeneralByteReg::Cl);
@@ -40,21 +46,21 @@ pub fn shift_instr_impl(input: TokenStream) -> TokenStream {
             rm.set_8(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm8_imm8_attr)]
+        #[firn_arch_x86_macros::instr(#rm8_imm8_attr, flags = #flags)]
         pub fn #rm8_imm8(sys: &mut crate::System, rm: crate::RegMem, imm: u8) {
             let old = rm.get_8(sys);
             let value = crate::arith::#operation_8(sys, old, imm);
             rm.set_8(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm16_1_attr)]
+        #[firn_arch_x86_macros::instr(#rm16_1_attr, flags = #flags)]
         pub fn #rm16_1(sys: &mut crate::System, rm: crate::RegMem) {
             let old = rm.get_16(sys);
             let value = crate::arith::#operation_16(sys, old, 1);
             rm.set_16(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm16_cl_attr)]
+        #[firn_arch_x86_macros::instr(#rm16_cl_attr, flags = #flags)]
         pub fn #rm16_cl(sys: &mut crate::System, rm: crate::RegMem) {
             let old = rm.get_16(sys);
             let reg = sys.cpu.reg_8(crate::GeneralByteReg::Cl);
@@ -62,7 +68,7 @@ pub fn shift_instr_impl(input: TokenStream) -> TokenStream {
             rm.set_16(sys, value);
         }
 
-        #[firn_arch_x86_macros::instr(#rm16_imm8_attr)]
+        #[firn_arch_x86_macros::instr(#rm16_imm8_attr, flags = #flags)]
         pub fn #rm16_imm8(sys: &mut crate::System, rm: crate::RegMem, imm: u8) {
             let old = rm.get_16(sys);
             let value = crate::arith::#operation_16(sys, old, imm);
@@ -0,0 +1,82 @@
+use crate::DecodedInstr;
+use firn_core::mem::MemMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// A cache of decoded instructions, keyed by the linear address of their first byte.
+///
+/// Decoding an instruction is much slower than executing most of them, and most code runs many
+/// times, so the CPU keeps the instructions it decodes here. The pages that cached instructions
+/// are in are watched (see [`MemMap::watch_page`]), and [`invalidate_page`] should be called for
+/// every page that's written to so self-modifying code doesn't run stale instructions.
+///
+/// [`MemMap::watch_page`]: firn_core::mem::MemMap::watch_page
+/// [`invalidate_page`]: InstrCache::invalidate_page
+#[derive(Default)]
+pub struct InstrCache {
+    entries: HashMap<usize, Arc<DecodedInstr>>,
+    pages: HashMap<usize, HashSet<usize>>,
+}
+
+impl InstrCache {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            pages: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, address: usize) -> Option<Arc<DecodedInstr>> {
+        self.entries.get(&address).cloned()
+    }
+
+    /// Caches an instruction, watching every page that its bytes are in.
+    ///
+    /// `addresses` are the linear addresses of the instruction's bytes, which aren't necessarily
+    /// contiguous if the instruction wraps around the end of its segment.
+    pub fn insert(
+        &mut self,
+        mem: &mut MemMap,
+        addresses: impl IntoIterator<Item = usize>,
+        instr: Arc<DecodedInstr>,
+    ) {
+        let mut addresses = addresses.into_iter();
+        let address = match addresses.next() {
+            Some(address) => address,
+            None => return,
+        };
+
+        let mut pages = vec![MemMap::page(address)];
+        for page in addresses.map(MemMap::page) {
+            if !pages.contains(&page) {
+                pages.push(page);
+            }
+        }
+
+        for page in pages {
+            mem.watch_page(page);
+            self.pages.entry(page).or_default().insert(address);
+        }
+        self.entries.insert(address, instr);
+    }
+
+    /// Removes every cached instruction with a byte in `page`.
+    pub fn invalidate_page(&mut self, page: usize) {
+        for address in self.pages.remove(&page).unwrap_or_default() {
+            self.entries.remove(&address);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.pages.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -1,25 +1,68 @@
+use crate::disasm::Operand;
+use crate::disasm::{ByteSource, SegmentedMem};
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::trace::{TraceRecord, TraceRegs};
 use crate::SegmentReg::{Cs, Ds, Es, Ss};
-use crate::{ExtSystem, Flags, GeneralByteReg, Instr, WordReg};
+use crate::{
+    DecodedInstr, Disassembler, ExtSystem, Flags, GeneralByteReg, GeneralWordReg, InstrCache,
+    SegmentReg, TraceLevel, Tracer, WordReg,
+};
+use firn_core::breakpoint::{Inspect, Pc};
 use firn_core::cpu::Restrict;
+use firn_core::mem::MemMap;
+use firn_core::snapshot::{SaveState, SnapshotError};
 use firn_core::{cpu, System};
+use std::sync::Arc;
 
-#[derive(Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Feature {
     InstrCpu1,
 }
 
+/// How the CPU executes instructions.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Backend {
+    /// Decodes and executes one instruction per step.
+    Interpreter,
+    /// Compiles basic blocks to host code and executes a whole block per step.
+    ///
+    /// Instructions that the JIT can't compile are run by the interpreter from inside the block.
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
 pub struct Cpu {
     features: Vec<Feature>,
 
-    regs: [u8; 2 * 8],
+    pub(crate) regs: [u8; 2 * 8],
     segments: [u16; 4],
     pub flags: Flags,
     pub ip: u16,
+    /// The mask that the linear addresses of memory operands are ANDed with, which is how the A20
+    /// gate of an AT wraps them at 1 MiB (see [`KeyboardController`]). Nothing is masked by
+    /// default.
+    ///
+    /// [`KeyboardController`]: crate::device::KeyboardController
+    pub address_mask: usize,
 
     pub decoded: u64,
+    /// The cache of decoded instructions, or `None` to decode every instruction as it's executed.
+    pub instr_cache: Option<InstrCache>,
+
+    pub backend: Backend,
+    /// Decides which instructions are traced, which is nothing by default.
+    pub tracer: Tracer,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: Option<Jit>,
 }
 
 impl Cpu {
+    /// The size of the state saved by [`SaveState`].
+    ///
+    /// [`SaveState`]: firn_core::snapshot::SaveState
+    const STATE_LEN: usize = 2 * 8 + 2 * 4 + 2 + 2 + 8 + 8;
+
     pub fn new() -> Self {
         Self {
             features: Vec::new(),
@@ -28,11 +71,23 @@ impl Cpu {
             segments: [0; 4],
             flags: Flags::new(),
             ip: 0,
+            address_mask: usize::MAX,
 
             decoded: 0,
+            instr_cache: Some(InstrCache::new()),
+
+            backend: Backend::Interpreter,
+            tracer: Tracer::new(),
+            #[cfg(feature = "jit")]
+            jit: None,
         }
     }
 
+    /// The indices in the register file of the low and high bytes of a word-sized register.
+    pub(crate) fn word_reg_indices(reg: GeneralWordReg) -> (usize, usize) {
+        (reg as usize, reg as usize + 4)
+    }
+
     pub fn reg_8(&self, reg: GeneralByteReg) -> u8 {
         self.regs[reg as usize]
     }
@@ -40,8 +95,9 @@ impl Cpu {
     pub fn reg_16(&self, reg: WordReg) -> u16 {
         match reg {
             WordReg::General(reg) => {
-                let low = self.regs[reg as usize];
-                let high = self.regs[reg as usize + 4];
+                let (low, high) = Self::word_reg_indices(reg);
+                let low = self.regs[low];
+                let high = self.regs[high];
 
                 u16::from_le_bytes([low, high])
             }
@@ -56,10 +112,11 @@ impl Cpu {
     pub fn set_reg_16(&mut self, reg: WordReg, value: u16) {
         match reg {
             WordReg::General(reg) => {
+                let (low_index, high_index) = Self::word_reg_indices(reg);
                 let [low, high] = value.to_le_bytes();
 
-                self.regs[reg as usize] = low;
-                self.regs[reg as usize + 4] = high;
+                self.regs[low_index] = low;
+                self.regs[high_index] = high;
             }
             WordReg::Segment(reg) => self.segments[reg as usize] = value,
         };
@@ -87,15 +144,125 @@ impl Cpu {
 
     pub fn inc_ip_8(&mut self, amount: u8) {
         let amount = amount as i8 as u16;
-        self.ip = self.ip.wrapping_add(amount as u16);
+        self.ip = self.ip.wrapping_add(amount);
     }
 
     pub fn inc_ip_16(&mut self, amount: u16) {
         self.ip = self.ip.wrapping_add(amount);
     }
+
+    /// Decodes the instruction at `CS:IP`, or gets it from the instruction cache if it's enabled.
+    ///
+    /// Cached instructions in pages that have been written to since the last fetch are discarded
+    /// first, so self-modifying code always sees its latest instructions. A cached instruction is
+    /// only used if it was decoded at the same `IP`, since the same code can be reached through
+    /// different `CS:IP` pairs (like `07c0:0000` and `0000:7c00`) and decoded instructions know
+    /// their `IP`.
+    pub fn fetch(sys: &mut System<Self>) -> Arc<DecodedInstr> {
+        Cpu::invalidate_written_pages(sys);
+
+        let address = sys.linear_mem(Cs, sys.cpu.ip);
+        if let Some(instr) = sys
+            .cpu
+            .instr_cache
+            .as_ref()
+            .and_then(|cache| cache.get(address))
+            .filter(|instr| instr.ip == sys.cpu.ip)
+        {
+            return instr;
+        }
+
+        let segment = sys.cpu.reg_16(Cs.into());
+        let ip = sys.cpu.ip;
+        let source =
+            SegmentedMem::new(&sys.mem, segment, ip).with_address_mask(sys.cpu.address_mask);
+        let instr = match Disassembler::decode_with(&*sys.cpu, &source, ip) {
+            Ok(instr) => Arc::new(instr),
+            Err(err) => panic!("{} at {:#06x}:{:#06x}", err, segment, ip),
+        };
+
+        let address_mask = sys.cpu.address_mask;
+        if let Some(cache) = &mut sys.cpu.instr_cache {
+            let addresses = (0..instr.len as u16).map(|offset| {
+                ((segment as usize) << 4).wrapping_add(ip.wrapping_add(offset) as usize)
+                    & address_mask
+            });
+            cache.insert(&mut sys.mem, addresses, instr.clone());
+        }
+
+        instr
+    }
+
+    /// Discards cached instructions (and compiled blocks) in pages that have been written to.
+    pub(crate) fn invalidate_written_pages(sys: &mut System<Self>) {
+        if !sys.mem.has_written_pages() {
+            return;
+        }
+
+        for page in sys.mem.take_written_pages() {
+            if let Some(cache) = &mut sys.cpu.instr_cache {
+                cache.invalidate_page(page);
+            }
+            #[cfg(feature = "jit")]
+            if let Some(jit) = &mut sys.cpu.jit {
+                jit.invalidate_page(page);
+            }
+        }
+    }
+
+    fn interpret(sys: &mut System<Self>) {
+        let instr = Cpu::fetch(sys);
+        sys.cpu.decoded += 1;
+
+        if sys.cpu.tracer.is_enabled() {
+            Cpu::trace(sys, &instr);
+        }
+
+        instr.execute(sys);
+    }
+
+    fn trace(sys: &mut System<Self>, instr: &DecodedInstr) {
+        let segment = sys.cpu.reg_16(Cs.into());
+        let address = sys.linear_mem(Cs, instr.ip);
+        if !sys.cpu.tracer.matches(address, segment, instr.opcode) {
+            return;
+        }
+
+        let source =
+            SegmentedMem::new(&sys.mem, segment, instr.ip).with_address_mask(sys.cpu.address_mask);
+        let bytes = (0..instr.len)
+            .map(|offset| source.byte(offset).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let regs =
+            (sys.cpu.tracer.level >= TraceLevel::Regs).then(|| TraceRegs::from_cpu(&sys.cpu));
+
+        let record = TraceRecord::new(sys.cpu.decoded, segment, instr.ip, &bytes, regs);
+        sys.cpu.tracer.record(&record);
+    }
+
+    #[cfg(feature = "jit")]
+    fn run_block(sys: &mut System<Self>) {
+        Cpu::invalidate_written_pages(sys);
+
+        let mut jit = sys.cpu.jit.take().unwrap_or_default();
+        let executed = jit.run(sys);
+        sys.cpu.jit = Some(jit);
+        match executed {
+            Some(executed) => sys.cpu.decoded += executed as u64,
+            // Something has to happen in the middle of the block, so get there one instruction at
+            // a time
+            None => Cpu::interpret(sys),
+        }
+    }
 }
 
 impl cpu::Cpu for Cpu {
+    /// The frequency of the original IBM PC. Instructions aren't timed yet, so every instruction
+    /// takes one cycle.
+    fn frequency(&self) -> u64 {
+        4_772_727
+    }
+
     fn reset(&mut self) {
         self.set_reg_16(Cs.into(), 0xffff);
         self.set_reg_16(Ds.into(), 0x0000);
@@ -103,19 +270,153 @@ impl cpu::Cpu for Cpu {
         self.set_reg_16(Ss.into(), 0x0000);
 
         self.ip = 0;
+        self.address_mask = usize::MAX;
+
+        if let Some(cache) = &mut self.instr_cache {
+            cache.clear();
+        }
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.clear();
+        }
     }
 
     fn step(sys: &mut System<Self>) {
-        let instr = Instr::decode(sys);
-        sys.cpu.decoded += 1;
+        match sys.cpu.backend {
+            Backend::Interpreter => Cpu::interpret(sys),
+            // Compiled blocks don't stop between instructions, so they can't be traced,
+            // breakpoints inside of them wouldn't be checked and they couldn't be rewound into
+            #[cfg(feature = "jit")]
+            Backend::Jit
+                if sys.cpu.tracer.is_enabled()
+                    || !sys.breakpoints.is_empty()
+                    || sys.is_recording() =>
+            {
+                Cpu::interpret(sys)
+            }
+            #[cfg(feature = "jit")]
+            Backend::Jit => Cpu::run_block(sys),
+        }
+    }
+}
 
-        let address = sys.linear_mem(Cs, sys.cpu.ip);
-        println!(
-            "({:#x}) ({}) [{:#04x}] Decoded: {}",
-            address, sys.cpu.decoded, instr.opcode, instr.meta.mnemonic
-        );
+/// Saves the registers, flags, address mask and decoded instruction count (but not features or
+/// caches).
+///
+/// Loading state clears the instruction cache and compiled blocks, since memory might have been
+/// changed without marking it as written (like when a system is rewound).
+impl SaveState for Cpu {
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(Self::STATE_LEN);
+        state.extend(self.regs);
+        for segment in self.segments {
+            state.extend(segment.to_le_bytes());
+        }
+        state.extend(self.flags.get_16().to_le_bytes());
+        state.extend(self.ip.to_le_bytes());
+        state.extend((self.address_mask as u64).to_le_bytes());
+        state.extend(self.decoded.to_le_bytes());
 
-        instr.execute(sys);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        if state.len() != Self::STATE_LEN {
+            return Err(SnapshotError::Invalid("CPU state has the wrong size"));
+        }
+
+        let (regs, rest) = state.split_at(self.regs.len());
+        self.regs.copy_from_slice(regs);
+        let (segments, rest) = rest.split_at(self.segments.len() * 2);
+        for (segment, bytes) in self.segments.iter_mut().zip(segments.chunks(2)) {
+            *segment = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        self.flags.set_16(u16::from_le_bytes([rest[0], rest[1]]));
+        self.ip = u16::from_le_bytes([rest[2], rest[3]]);
+        self.address_mask = u64::from_le_bytes(rest[4..12].try_into().unwrap()) as usize;
+        self.decoded = u64::from_le_bytes(rest[12..20].try_into().unwrap());
+
+        if let Some(cache) = &mut self.instr_cache {
+            cache.clear();
+        }
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.clear();
+        }
+
+        Ok(())
+    }
+}
+
+/// Registers are named like in assembly (`AX`, `AL`, `DS`, ...), along with `IP`, `FLAGS` and the
+/// individual flags (`CF`, `PF`, `AF`, `ZF`, `SF`, `TF`, `IF`, `DF` and `OF`).
+impl Inspect for Cpu {
+    fn pc(&self) -> Pc {
+        let segment = self.reg_16(Cs.into());
+
+        Pc {
+            segment,
+            offset: self.ip,
+            linear: (((segment as usize) << 4) + self.ip as usize) & self.address_mask,
+        }
+    }
+
+    fn reg(&self, name: &str) -> Option<u64> {
+        let name = name.to_ascii_lowercase();
+        let flag = |flag: bool| Some(flag as u64);
+
+        match name.as_str() {
+            "ip" => return Some(self.ip as u64),
+            "flags" => return Some(self.flags.get_16() as u64),
+            "cf" => return flag(self.flags.carry),
+            "pf" => return flag(self.flags.parity),
+            "af" => return flag(self.flags.adjust),
+            "zf" => return flag(self.flags.zero),
+            "sf" => return flag(self.flags.sign),
+            "tf" => return flag(self.flags.trap),
+            "if" => return flag(self.flags.interrupt),
+            "df" => return flag(self.flags.direction),
+            "of" => return flag(self.flags.overflow),
+            _ => {}
+        }
+
+        for index in 0..8 {
+            let byte_reg = GeneralByteReg::from_u8(index).unwrap();
+            if byte_reg.name() == name {
+                return Some(self.reg_8(byte_reg) as u64);
+            }
+            let word_reg = GeneralWordReg::from_u8(index).unwrap();
+            if word_reg.name() == name {
+                return Some(self.reg_16(word_reg.into()) as u64);
+            }
+        }
+        (0..4)
+            .map(|index| SegmentReg::from_u8(index).unwrap())
+            .find(|reg| reg.name() == name)
+            .map(|reg| self.reg_16(reg.into()) as u64)
+    }
+
+    /// Decodes the next instruction and compares the operands with its immediate, constant and
+    /// memory offset operands, in order. If there are no operands, any operands match.
+    fn is_next_instr(&self, mem: &MemMap, mnemonic: &str, operands: &[u64]) -> bool {
+        let disassembler = Disassembler::from_cpu(self);
+        let source = SegmentedMem::new(mem, self.reg_16(Cs.into()), self.ip)
+            .with_address_mask(self.address_mask);
+        let instr = match disassembler.decode(&source, self.ip) {
+            Ok(instr) => instr,
+            Err(_) => return false,
+        };
+        if !instr.name().eq_ignore_ascii_case(mnemonic) {
+            return false;
+        }
+
+        let values = instr.operands.iter().filter_map(|operand| match *operand {
+            Operand::Imm8(value) | Operand::Const(value) => Some(value as u64),
+            Operand::Imm16(value) | Operand::Moffs(value) => Some(value as u64),
+            _ => None,
+        });
+
+        operands.is_empty() || values.eq(operands.iter().copied())
     }
 }
 
@@ -140,8 +441,15 @@ impl Default for Cpu {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::disasm::Operand;
+    use crate::trace::TraceFilter;
     use crate::GeneralByteReg::{Ah, Al, Bh, Cl};
-    use crate::GeneralWordReg::{Ax, Bp, Cx};
+    use crate::GeneralWordReg::{Ax, Bp, Bx, Cx};
+    use firn_core::breakpoint::{Breakpoint, Condition, Location};
+    use firn_core::device::{PortRequest, UnhandledPortPolicy};
+    use firn_core::mem::{BasicMem, MemMap};
+    use firn_core::system::StopReason;
+    use std::sync::Mutex;
 
     #[test]
     fn should_read_and_write_byte_reg() {
@@ -237,4 +545,237 @@ mod tests {
         cpu.inc_ip_16(25);
         assert_eq!(24, cpu.ip);
     }
+
+    fn create_sys(program: &[u8]) -> System<Cpu> {
+        let mut mem = BasicMem::new(0x10000);
+        for (index, byte) in program.iter().enumerate() {
+            mem[0x100 + index] = *byte;
+        }
+
+        let mut map = MemMap::new(0x10000);
+        map.map_full(mem);
+
+        let mut sys = System::new(Cpu::new(), map);
+        sys.cpu.ip = 0x100;
+        sys
+    }
+
+    #[test]
+    fn should_cache_decoded_instrs() {
+        // mov al, 0x1
+        let mut sys = create_sys(&[0xb0, 0x01]);
+
+        let first = Cpu::fetch(&mut sys);
+        let second = Cpu::fetch(&mut sys);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(1, sys.cpu.instr_cache.as_ref().unwrap().len());
+    }
+
+    #[test]
+    fn should_not_reuse_instrs_decoded_at_another_ip() {
+        // mov al, 0x1
+        let mut sys = create_sys(&[0xb0, 0x01]);
+
+        let first = Cpu::fetch(&mut sys);
+        sys.cpu.set_reg_16(Cs.into(), 0x10);
+        sys.cpu.ip = 0;
+        let aliased = Cpu::fetch(&mut sys);
+        assert!(!Arc::ptr_eq(&first, &aliased));
+        assert_eq!(0, aliased.ip);
+        assert!(Arc::ptr_eq(&aliased, &Cpu::fetch(&mut sys)));
+    }
+
+    #[test]
+    fn should_invalidate_modified_instrs() {
+        // mov al, 0x1
+        // mov byte [0x101], 0x2
+        let mut sys = create_sys(&[0xb0, 0x01, 0xc6, 0x06, 0x01, 0x01, 0x02]);
+
+        Cpu::fetch(&mut sys).execute(&mut sys);
+        assert_eq!(1, sys.cpu.reg_8(Al));
+        Cpu::fetch(&mut sys).execute(&mut sys);
+
+        sys.cpu.ip = 0x100;
+        Cpu::fetch(&mut sys).execute(&mut sys);
+        assert_eq!(2, sys.cpu.reg_8(Al));
+    }
+
+    #[test]
+    fn should_decode_without_cache() {
+        // mov al, 0x1
+        let mut sys = create_sys(&[0xb0, 0x01]);
+        sys.cpu.instr_cache = None;
+
+        let first = Cpu::fetch(&mut sys);
+        sys.mem[0x101] = 0x2;
+        let second = Cpu::fetch(&mut sys);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(Operand::Imm8(2), second.operands[1]);
+    }
+
+    #[test]
+    fn should_load_far_pointers() {
+        // les bx, [0x200]
+        let mut sys = create_sys(&[0xc4, 0x1e, 0x00, 0x02]);
+        for (index, byte) in [0x34, 0x12, 0x78, 0x56].into_iter().enumerate() {
+            sys.mem[0x200 + index] = byte;
+        }
+
+        Cpu::fetch(&mut sys).execute(&mut sys);
+        assert_eq!(0x1234, sys.cpu.reg_16(Bx.into()));
+        assert_eq!(0x5678, sys.cpu.reg_16(Es.into()));
+    }
+
+    #[test]
+    fn should_trace_instrs() {
+        // mov ax, 0x4c00
+        // nop
+        // mov bx, ax
+        let mut sys = create_sys(&[0xb8, 0x00, 0x4c, 0x90, 0x89, 0xc3]);
+        let records = Arc::new(Mutex::new(Vec::new()));
+        sys.cpu.tracer.start(TraceLevel::Regs, Arc::clone(&records));
+        sys.cpu
+            .tracer
+            .filters
+            .push(TraceFilter::Opcodes(vec![0xb8, 0x89]));
+
+        for _ in 0..3 {
+            <Cpu as cpu::Cpu>::step(&mut sys);
+        }
+
+        let records = records.lock().unwrap();
+        assert_eq!(2, records.len());
+        assert_eq!((1, 0x100), (records[0].count, records[0].ip));
+        assert_eq!(&[0xb8, 0x00, 0x4c], records[0].bytes());
+        assert_eq!((3, 0x104), (records[1].count, records[1].ip));
+        assert_eq!(0x4c00, records[1].regs.unwrap().ax);
+    }
+
+    #[test]
+    fn should_not_trace_by_default() {
+        // nop
+        let mut sys = create_sys(&[0x90]);
+        <Cpu as cpu::Cpu>::step(&mut sys);
+
+        assert!(!sys.cpu.tracer.is_enabled());
+        assert_eq!(1, sys.cpu.decoded);
+    }
+
+    #[test]
+    fn should_restore_snapshots() {
+        // mov ax, 0x1234
+        // mov [0x200], ax
+        let mut sys = create_sys(&[0xb8, 0x34, 0x12, 0xa3, 0x00, 0x02]);
+        sys.cpu.set_reg_16(Es.into(), 0x40);
+        sys.cpu.flags.carry = true;
+        let snapshot = sys.snapshot();
+
+        for _ in 0..2 {
+            <Cpu as cpu::Cpu>::step(&mut sys);
+        }
+        sys.cpu.flags.carry = false;
+        assert_eq!(0x1234, sys.mem_16(Ds, 0x200));
+
+        sys.restore(&snapshot).unwrap();
+        assert_eq!(0, sys.cpu.reg_16(Ax.into()));
+        assert_eq!(0x40, sys.cpu.reg_16(Es.into()));
+        assert!(sys.cpu.flags.carry);
+        assert_eq!((0x100, 0), (sys.cpu.ip, sys.cpu.decoded));
+        assert_eq!(0, sys.mem_16(Ds, 0x200));
+    }
+
+    #[test]
+    fn should_mask_fetches_and_save_the_address_mask() {
+        // mov al, 0x1
+        let mut sys = create_sys(&[0xb0, 0x01]);
+        sys.cpu.address_mask = 0xfffff;
+        sys.cpu.set_reg_16(Cs.into(), 0xffff);
+        sys.cpu.ip = 0x110;
+
+        // 0xffff:0x110 wraps around to 0x100 while the A20 gate is disabled
+        <Cpu as cpu::Cpu>::step(&mut sys);
+        assert_eq!(1, sys.cpu.reg_8(Al));
+        let snapshot = sys.snapshot();
+
+        sys.cpu.address_mask = usize::MAX;
+        sys.restore(&snapshot).unwrap();
+        assert_eq!(0xfffff, sys.cpu.address_mask);
+
+        cpu::Cpu::reset(&mut *sys.cpu);
+        assert_eq!(usize::MAX, sys.cpu.address_mask);
+    }
+
+    #[test]
+    fn should_stop_at_conditional_breakpoints() {
+        // mov ax, 0x4c00
+        // int 0x21
+        let mut sys = create_sys(&[0xb8, 0x00, 0x4c, 0xcd, 0x21]);
+        let location = Location::Segmented {
+            segment: 0,
+            offset: 0x103,
+        };
+        let never = sys.breakpoints.add(Breakpoint::conditional(
+            location,
+            Condition::parse("AX == 0x4c00 && INT 20h").unwrap(),
+        ));
+        let id = sys.breakpoints.add(Breakpoint::conditional(
+            location,
+            Condition::parse("AX == 0x4c00 && AH == 4ch && !CF && INT 21h").unwrap(),
+        ));
+
+        assert_eq!(StopReason::Breakpoint(id), sys.run_for(5));
+        assert_eq!(0x103, sys.cpu.ip);
+        assert_eq!(0, sys.breakpoints.get(never).unwrap().hits);
+    }
+
+    #[test]
+    fn should_mask_breakpoint_addresses() {
+        // mov ax, 0x4c00
+        // int 0x21
+        let mut sys = create_sys(&[]);
+        for (index, byte) in [0xb8, 0x00, 0x4c, 0xcd, 0x21].into_iter().enumerate() {
+            sys.mem[index] = byte;
+        }
+        // 0xffff:0x0010 is 0x00000 while the A20 gate is disabled
+        sys.cpu.address_mask = 0xfffff;
+        sys.cpu.set_reg_16(Cs.into(), 0xffff);
+        sys.cpu.ip = 0x0010;
+        let id = sys.breakpoints.add(Breakpoint::conditional(
+            Location::Linear(0x3),
+            Condition::parse("INT 21h").unwrap(),
+        ));
+
+        assert_eq!(StopReason::Breakpoint(id), sys.run_for(5));
+        assert_eq!(0x13, sys.cpu.ip);
+        assert_eq!(0x3, sys.cpu.pc().linear);
+    }
+
+    #[test]
+    fn should_treat_unhandled_ports_as_open_bus() {
+        // in al, 0x42
+        // mov dx, 0x201
+        // in ax, dx
+        // out dx, al
+        // out 0x42, ax
+        let mut sys = create_sys(&[0xe4, 0x42, 0xba, 0x01, 0x02, 0xed, 0xee, 0xe7, 0x42]);
+
+        assert_eq!(StopReason::StepLimit, sys.run_for(2));
+        assert_eq!(0xff, sys.cpu.reg_8(Al));
+        sys.cpu.set_reg_16(Ax.into(), 0);
+        sys.run_for(2);
+        assert_eq!(0xffff, sys.cpu.reg_16(Ax.into()));
+        assert_eq!(
+            "0x0042: 1 reads and 0 writes, first at step 0\n\
+             0x0201: 1 reads and 1 writes, first at step 2\n",
+            sys.unhandled_port_report()
+        );
+
+        sys.unhandled_port_policy = UnhandledPortPolicy::Break;
+        assert_eq!(
+            StopReason::UnhandledPort(PortRequest::Out16(0x42, 0xffff)),
+            sys.run_for(5)
+        );
+        assert_eq!(0x109, sys.cpu.ip);
+        assert_eq!(1, sys.unhandled_ports()[&0x42].writes);
+    }
 }
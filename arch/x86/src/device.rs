@@ -1,5 +1,81 @@
 pub mod cmos;
+pub mod debug;
+pub mod dma;
+pub mod kbc;
+pub mod keyboard;
+pub mod mouse;
+pub mod parallel;
 pub mod pic;
+pub mod pit;
+pub mod ppi;
+pub mod serial;
+pub mod uart;
 
 pub use cmos::Cmos;
+pub use debug::{DebugPorts, PostCode};
+pub use dma::Dma;
+pub use kbc::KeyboardController;
+pub use keyboard::{Key, Keyboard};
+pub use mouse::{Mouse, SerialMouse};
+pub use parallel::{
+    CapturePrinter, FilePrinter, LptPort, NullPrinter, ParallelBackend, ParallelPort, PrinterStatus,
+};
 pub use pic::{DualPic, Pic};
+pub use pit::Pit;
+pub use ppi::Ppi;
+#[cfg(unix)]
+pub use serial::PtyBackend;
+pub use serial::{
+    FileBackend, Loopback, LoopbackRemote, ModemStatus, NullBackend, SerialBackend, StdioBackend,
+    TcpBackend,
+};
+pub use uart::{ComPort, Uart, UartModel};
+
+/// Helpers for testing devices.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::DualPic;
+    use crate::{Cpu, System};
+    use firn_core::device::{Device, DeviceRef};
+    use firn_core::mem::{BasicMem, MemMap};
+    use std::time::Duration;
+
+    /// Creates an initialized system with 64 KiB of memory, a [`DualPic`] and the device that
+    /// `create` returns, which depends on the PIC.
+    pub(crate) fn create_sys<D>(
+        create: impl FnOnce(&mut System, DeviceRef<DualPic>) -> D,
+    ) -> (System, DeviceRef<DualPic>, DeviceRef<D>)
+    where
+        D: Device<Cpu>,
+    {
+        create_sys_with_mem(0x10000, create)
+    }
+
+    /// Like [`create_sys`], but with `size` bytes of memory.
+    pub(crate) fn create_sys_with_mem<D>(
+        size: usize,
+        create: impl FnOnce(&mut System, DeviceRef<DualPic>) -> D,
+    ) -> (System, DeviceRef<DualPic>, DeviceRef<D>)
+    where
+        D: Device<Cpu>,
+    {
+        let mut map = MemMap::new(size);
+        map.map_full(BasicMem::new(size));
+        let mut sys = System::new(Cpu::new(), map);
+        let pic = sys.add_device(DualPic::new());
+        let device = create(&mut sys, pic);
+        let device = sys.add_device(device);
+        sys.add_dependency(device, pic);
+        sys.init();
+
+        (sys, pic, device)
+    }
+
+    /// Steps the system until `time` has passed on its clock.
+    pub(crate) fn run_for(sys: &mut System, time: Duration) {
+        let end = sys.clock.now() + time;
+        while sys.clock.now() < end {
+            sys.step();
+        }
+    }
+}
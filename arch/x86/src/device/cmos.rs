@@ -1,8 +1,8 @@
 use crate::{Cpu, System};
 use chrono::{DateTime, Datelike, Timelike, Utc};
-use firn_core::device::{Device, PortRequest, PortResponse};
-use std::time;
-use std::time::{Duration, SystemTime};
+use firn_core::clock::TimerId;
+use firn_core::device::{Claims, Device, PortRequest, PortResponse};
+use std::time::Duration;
 
 pub const SECONDS_REG: usize = 0x00;
 pub const MINUTES_REG: usize = 0x02;
@@ -20,38 +20,47 @@ pub struct Cmos {
     selected_reg: u8,
     regs: [u8; 128],
 
-    sync_time: DateTime<Utc>,
-    start_time: Option<time::Duration>,
-
-    last_update_micros: u128,
+    /// The date and time to start the clock at, or `None` to start at the host's.
+    sync_time: Option<DateTime<Utc>>,
+    /// The virtual time of the next tick of the clock.
+    next_tick: Duration,
+    timer: Option<TimerId>,
 }
 
 impl Cmos {
     pub fn new(start_time: DateTime<Utc>) -> Self {
+        Self::with_sync_time(Some(start_time))
+    }
+
+    /// Creates a clock that starts at the host's date and time when the system is initialized.
+    pub fn new_current_time() -> Self {
+        Self::with_sync_time(None)
+    }
+
+    fn with_sync_time(sync_time: Option<DateTime<Utc>>) -> Self {
         Self {
             selected_reg: 0xd,
             regs: [0; 128],
 
-            sync_time: start_time,
-            start_time: None,
-
-            last_update_micros: 0,
+            sync_time,
+            next_tick: Duration::ZERO,
+            timer: None,
         }
     }
 
-    pub fn new_current_time() -> Self {
-        Self::new(Utc::now())
-    }
+    /// Sets the clock to the start time plus the virtual time that has passed.
+    pub fn sync(&mut self, sys: &mut System) {
+        let sync_time = *self.sync_time.get_or_insert_with(|| {
+            let timestamp =
+                sys.sample_array("cmos.start_time", || Utc::now().timestamp().to_le_bytes());
+            DateTime::from_timestamp(i64::from_le_bytes(timestamp), 0)
+                .expect("host time is out of range")
+        });
 
-    pub fn sync(&mut self) {
-        let start_time = self
-            .start_time
-            .expect("cannot sync real-time clock without a start time");
-        let current_time = self.current_time();
-
-        let difference = chrono::Duration::from_std(current_time - start_time)
-            .expect("time difference is too large to be synced");
-        let now = self.sync_time + difference;
+        let elapsed = sys.clock.now();
+        let difference =
+            chrono::Duration::from_std(elapsed).expect("time difference is too large to be synced");
+        let now = sync_time + difference;
 
         self.start_updating_rtc();
         self.regs[SECONDS_REG] = now.second() as u8;
@@ -62,17 +71,16 @@ impl Cmos {
         self.regs[MONTH_REG] = now.month() as u8;
         self.regs[YEAR_REG] = (now.year() % 100) as u8;
         self.stop_updating_rtc();
-    }
 
-    fn current_time(&self) -> Duration {
-        SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .expect("time went backwards")
+        if let Some(timer) = self.timer {
+            sys.cancel_timer(timer);
+        }
+        self.next_tick = Duration::from_secs(elapsed.as_secs() + 1);
+        self.timer = Some(sys.set_timer(self.next_tick, 0));
     }
 
     fn start_updating_rtc(&mut self) {
         self.regs[STATUS_REG_A] |= 0x80;
-        self.last_update_micros = self.current_time().as_micros();
     }
 
     fn stop_updating_rtc(&mut self) {
@@ -83,7 +91,7 @@ impl Cmos {
         // TODO: Properly account for leap years
         match month {
             1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
-            2 if year % 4 == 0 => 29,
+            2 if year.is_multiple_of(4) => 29,
             2 => 28,
             _ => 30,
         }
@@ -120,18 +128,13 @@ impl Cmos {
 }
 
 impl Device<Cpu> for Cmos {
-    fn init(&mut self, _sys: &mut System) {
-        let start_time = self.current_time();
-        self.start_time = Some(start_time);
-
-        self.sync();
+    fn init(&mut self, sys: &mut System) {
+        self.sync(sys);
     }
 
-    fn step(&mut self, _sys: &mut System) {
-        let current_time = self.current_time().as_micros();
-        if current_time - self.last_update_micros < 1_000_000 {
-            return;
-        }
+    fn timer(&mut self, sys: &mut System, _token: u64) {
+        self.next_tick += Duration::from_secs(1);
+        self.timer = Some(sys.set_timer(self.next_tick, 0));
 
         let mut seconds = self.regs[SECONDS_REG] + 1;
         let mut minutes = self.regs[MINUTES_REG];
@@ -189,4 +192,62 @@ impl Device<Cpu> for Cmos {
             _ => None,
         }
     }
+
+    fn claims(&self) -> Claims {
+        Claims {
+            ports: vec![0x70..=0x71],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        Some(format!(
+            "CMOS: selected register {:#04x}, time {:02}:{:02}:{:02}, date {:02}-{:02}-{:02}, \
+             status A {:#04x}, status B {:#04x}",
+            self.selected_reg,
+            self.regs[HOURS_REG],
+            self.regs[MINUTES_REG],
+            self.regs[SECONDS_REG],
+            self.regs[YEAR_REG],
+            self.regs[MONTH_REG],
+            self.regs[DAY_OF_MONTH_REG],
+            self.regs[STATUS_REG_A],
+            self.regs[STATUS_REG_B]
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firn_core::mem::{BasicMem, MemMap};
+    use firn_core::replay::{InputEvent, InputLog};
+
+    #[test]
+    fn should_tick_in_virtual_time() {
+        let mut map = MemMap::new(0x10000);
+        map.map_full(BasicMem::new(0x10000));
+        let mut sys = System::new(Cpu::new(), map);
+        let cmos = sys.add_device(Cmos::new_current_time());
+        sys.replay_inputs(InputLog {
+            events: vec![InputEvent {
+                step: 0,
+                channel: String::from("cmos.start_time"),
+                data: 43200i64.to_le_bytes().to_vec(),
+            }],
+        });
+
+        sys.init();
+        sys.clock.advance(sys.clock.frequency() * 2 - 1);
+        // The second tick is set by the first one, so it waits for the next step
+        sys.step();
+        sys.step();
+
+        let cmos = sys.get_device_mut(cmos);
+        cmos.select_reg(SECONDS_REG as u8);
+        assert!(matches!(cmos.reg_value(), PortResponse::In8(2)));
+        cmos.select_reg(HOURS_REG as u8);
+        assert!(matches!(cmos.reg_value(), PortResponse::In8(12)));
+        assert_eq!(None, sys.divergence());
+    }
 }
@@ -0,0 +1,256 @@
+use crate::device::dma::FIRST_PAGE_PORT;
+use crate::device::Dma;
+use crate::{Cpu, System};
+use firn_core::device::{Claims, Device, DeviceRef, PortRequest, PortResponse};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// The port of Bochs' debug console, which prints the bytes that are written to it.
+pub const CONSOLE_PORT: u16 = 0xe9;
+/// The port that BIOSes write POST codes to, which is the unused DMA page register on a PC.
+pub const POST_PORT: u16 = 0x80;
+
+type ConsoleCallback = Box<dyn FnMut(u8) + Send>;
+type PostCodeCallback = Box<dyn FnMut(&PostCode) + Send>;
+
+/// A POST code, with when it was written.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PostCode {
+    pub code: u8,
+    /// The virtual time of the write.
+    pub time: Duration,
+    /// The number of CPU steps before the write (see [`System::steps`]).
+    ///
+    /// [`System::steps`]: firn_core::System::steps
+    pub steps: u64,
+}
+
+/// The debug ports that BIOS and bootloader developers use to see what's going on without a
+/// screen: the 0xE9 console of Bochs and QEMU, and the POST codes at port 0x80 that a POST card
+/// would show.
+///
+/// Everything written to the ports is recorded (and survives resets, so that boot loops can be
+/// seen), and it can also be passed to callbacks as it's written (see [`on_console`] and
+/// [`on_post_code`]).
+///
+/// Port 0x80 is also a DMA page register, so the device takes it over from the [`Dma`] controllers
+/// and passes POST codes on to them after they're recorded, like a POST card snoops them.
+///
+/// [`on_console`]: DebugPorts::on_console
+/// [`on_post_code`]: DebugPorts::on_post_code
+pub struct DebugPorts {
+    dma: DeviceRef<Dma>,
+    console: Vec<u8>,
+    post_codes: Vec<PostCode>,
+    console_callback: Option<ConsoleCallback>,
+    post_code_callback: Option<PostCodeCallback>,
+}
+
+impl DebugPorts {
+    pub fn new(dma: DeviceRef<Dma>) -> Self {
+        Self {
+            dma,
+            console: Vec::new(),
+            post_codes: Vec::new(),
+            console_callback: None,
+            post_code_callback: None,
+        }
+    }
+
+    /// Calls `callback` with every byte that's written to the console port, like to print it on
+    /// the host's terminal.
+    pub fn on_console(&mut self, callback: impl FnMut(u8) + Send + 'static) {
+        self.console_callback = Some(Box::new(callback));
+    }
+
+    /// Calls `callback` with every POST code that's written.
+    pub fn on_post_code(&mut self, callback: impl FnMut(&PostCode) + Send + 'static) {
+        self.post_code_callback = Some(Box::new(callback));
+    }
+
+    /// Everything that has been written to the console port.
+    pub fn console(&self) -> &[u8] {
+        &self.console
+    }
+
+    /// The console output as text, with invalid UTF-8 replaced.
+    pub fn console_text(&self) -> String {
+        String::from_utf8_lossy(&self.console).into_owned()
+    }
+
+    pub fn post_codes(&self) -> &[PostCode] {
+        &self.post_codes
+    }
+
+    pub fn last_post_code(&self) -> Option<u8> {
+        self.post_codes.last().map(|post_code| post_code.code)
+    }
+
+    /// Forgets the console output and POST codes.
+    pub fn clear(&mut self) {
+        self.console.clear();
+        self.post_codes.clear();
+    }
+
+    /// Asserts that `expected` was written to the port 0x80 in order, possibly with other POST
+    /// codes in between, which is how tests check how far a BIOS got.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the POST codes that were written if they don't include `expected`.
+    #[track_caller]
+    pub fn assert_post_codes(&self, expected: &[u8]) {
+        let mut codes = self.post_codes.iter().map(|post_code| post_code.code);
+        let missing = expected
+            .iter()
+            .position(|code| !codes.any(|written| written == *code));
+
+        if let Some(index) = missing {
+            let mut written = String::new();
+            for post_code in &self.post_codes {
+                let _ = write!(written, " {:02x}", post_code.code);
+            }
+            panic!(
+                "POST code {:#04x} (#{} of {:02x?}) wasn't written in order, the POST codes \
+                 were:{}",
+                expected[index], index, expected, written,
+            );
+        }
+    }
+
+    /// Asserts that the console output contains `text`.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the console output if it doesn't contain `text`.
+    #[track_caller]
+    pub fn assert_console_contains(&self, text: &str) {
+        let console = self.console_text();
+        assert!(
+            console.contains(text),
+            "the console output doesn't contain {:?}, it was {:?}",
+            text,
+            console,
+        );
+    }
+}
+
+impl Device<Cpu> for DebugPorts {
+    fn init(&mut self, sys: &mut System) {
+        sys.get_device_mut(self.dma).release_post_port();
+    }
+
+    fn handle_port(&mut self, sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        let post_page = usize::from(POST_PORT - FIRST_PAGE_PORT);
+        match request {
+            // Reading the console port returns its number, which is how software detects it
+            PortRequest::In8(CONSOLE_PORT) => Some(PortResponse::In8(CONSOLE_PORT as u8)),
+            PortRequest::In8(POST_PORT) => {
+                Some(PortResponse::In8(sys.get_device(self.dma).pages[post_page]))
+            }
+            PortRequest::Out8(CONSOLE_PORT, value) => {
+                self.console.push(value);
+                if let Some(callback) = &mut self.console_callback {
+                    callback(value);
+                }
+                Some(PortResponse::Out)
+            }
+            PortRequest::Out8(POST_PORT, code) => {
+                let post_code = PostCode {
+                    code,
+                    time: sys.clock.now(),
+                    steps: sys.steps(),
+                };
+                if let Some(callback) = &mut self.post_code_callback {
+                    callback(&post_code);
+                }
+                self.post_codes.push(post_code);
+                sys.get_device_mut(self.dma).pages[post_page] = code;
+                Some(PortResponse::Out)
+            }
+            _ => None,
+        }
+    }
+
+    fn claims(&self) -> Claims {
+        Claims {
+            ports: vec![POST_PORT..=POST_PORT, CONSOLE_PORT..=CONSOLE_PORT],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        let last = match self.last_post_code() {
+            Some(code) => format!("{:#04x}", code),
+            None => String::from("none"),
+        };
+
+        Some(format!(
+            "{} console bytes, {} POST codes (last {})",
+            self.console.len(),
+            self.post_codes.len(),
+            last,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firn_core::mem::{BasicMem, MemMap};
+    use std::sync::{Arc, Mutex};
+
+    fn create_sys() -> (System, DeviceRef<DebugPorts>, DeviceRef<Dma>) {
+        let mut map = MemMap::new(0x10000);
+        map.map_full(BasicMem::new(0x10000));
+        let mut sys = System::new(Cpu::new(), map);
+        let dma = sys.add_device(Dma::new());
+        let debug = sys.add_device(DebugPorts::new(dma));
+        sys.add_dependency(debug, dma);
+        sys.init();
+
+        (sys, debug, dma)
+    }
+
+    #[test]
+    fn should_record_console_and_post_codes() {
+        let (mut sys, debug, dma) = create_sys();
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&printed);
+        sys.get_device_mut(debug)
+            .on_console(move |byte| sink.lock().unwrap().push(byte));
+
+        assert_eq!(Some(0xe9), sys.port_in_8(CONSOLE_PORT));
+        for byte in b"boot\n" {
+            sys.port_out_8(CONSOLE_PORT, *byte).unwrap();
+        }
+        sys.port_out_8(POST_PORT, 0x01).unwrap();
+        sys.step();
+        sys.step();
+        sys.port_out_8(POST_PORT, 0x2c).unwrap();
+
+        let ports = sys.get_device(debug);
+        assert_eq!(b"boot\n".to_vec(), *printed.lock().unwrap());
+        ports.assert_console_contains("boot");
+        ports.assert_post_codes(&[0x01, 0x2c]);
+        assert_eq!(
+            [0, 2],
+            [ports.post_codes()[0].steps, ports.post_codes()[1].steps]
+        );
+        assert!(ports.post_codes()[1].time > ports.post_codes()[0].time);
+        // The DMA page register still latches the POST code
+        assert_eq!(0x2c, sys.get_device(dma).pages[0]);
+        assert_eq!(Some(0x2c), sys.port_in_8(POST_PORT));
+    }
+
+    #[test]
+    #[should_panic(expected = "POST code 0x55 (#1 of [11, 55]) wasn't written in order")]
+    fn should_panic_when_post_codes_are_missing() {
+        let (mut sys, debug, _) = create_sys();
+        for code in [0x55, 0x11, 0x22] {
+            sys.port_out_8(POST_PORT, code).unwrap();
+        }
+
+        sys.get_device(debug).assert_post_codes(&[0x11, 0x55]);
+    }
+}
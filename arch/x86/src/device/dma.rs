@@ -0,0 +1,600 @@
+use crate::{Cpu, System};
+use firn_core::device::{Claims, Device, PortRequest, PortResponse};
+use firn_core::mem::MemMap;
+
+pub const FIRST_PAGE_PORT: u16 = 0x80;
+pub const LAST_PAGE_PORT: u16 = 0x8f;
+/// The page register of each channel.
+pub const PAGE_PORTS: [u16; 8] = [0x87, 0x83, 0x81, 0x82, 0x8f, 0x8b, 0x89, 0x8a];
+
+/// The channel of the second controller that the first one is cascaded through on an AT.
+pub const CASCADE_CHANNEL: usize = 4;
+
+const STATUS_REG: u16 = 0x8;
+const REQUEST_REG: u16 = 0x9;
+const SINGLE_MASK_REG: u16 = 0xa;
+const MODE_REG: u16 = 0xb;
+const CLEAR_FLIP_FLOP_REG: u16 = 0xc;
+const MASTER_CLEAR_REG: u16 = 0xd;
+const CLEAR_MASK_REG: u16 = 0xe;
+const MASK_REG: u16 = 0xf;
+
+/// Which way a channel transfers data.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TransferType {
+    /// Addresses and counts are updated, but no data is transferred.
+    Verify,
+    /// Data goes from the device to memory.
+    Write,
+    /// Data goes from memory to the device.
+    Read,
+    Illegal,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TransferMode {
+    Demand,
+    Single,
+    Block,
+    /// The channel is connected to another controller, and doesn't transfer anything itself.
+    Cascade,
+}
+
+/// The result of [`Dma::transfer`].
+///
+/// [`Dma::transfer`]: Dma::transfer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Transfer {
+    /// The number of bytes that were transferred.
+    pub bytes: usize,
+    /// Whether the count ran out, which ends the transfer.
+    pub terminal_count: bool,
+}
+
+pub struct Channel {
+    pub base_address: u16,
+    pub base_count: u16,
+    pub current_address: u16,
+    pub current_count: u16,
+
+    pub transfer_type: TransferType,
+    pub mode: TransferMode,
+    pub auto_init: bool,
+    pub decrement: bool,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Self {
+            base_address: 0,
+            base_count: 0,
+            current_address: 0,
+            current_count: 0,
+
+            transfer_type: TransferType::Verify,
+            mode: TransferMode::Demand,
+            auto_init: false,
+            decrement: false,
+        }
+    }
+
+    fn set_mode(&mut self, mode: u8) {
+        self.transfer_type = match (mode >> 2) & 0x3 {
+            0 => TransferType::Verify,
+            1 => TransferType::Write,
+            2 => TransferType::Read,
+            _ => TransferType::Illegal,
+        };
+        self.auto_init = mode & 0x10 != 0;
+        self.decrement = mode & 0x20 != 0;
+        self.mode = match mode >> 6 {
+            0 => TransferMode::Demand,
+            1 => TransferMode::Single,
+            2 => TransferMode::Block,
+            _ => TransferMode::Cascade,
+        };
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An Intel 8237A DMA controller, which has four channels.
+pub struct DmaController {
+    pub channels: [Channel; 4],
+    /// Whether the channels transfer 16-bit words, like the second controller of an AT.
+    pub wide: bool,
+
+    pub command: u8,
+    /// The channels that have reached their terminal count since the status was read.
+    pub terminal_counts: u8,
+    /// The channels that have been requested by software.
+    pub requests: u8,
+    /// The channels whose devices are requesting transfers (DREQ).
+    pub device_requests: u8,
+    pub mask: u8,
+    pub temporary: u8,
+    /// Whether the next byte of an address or count is the MSB.
+    flip_flop: bool,
+}
+
+impl DmaController {
+    pub fn new(wide: bool) -> Self {
+        Self {
+            channels: [
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+                Channel::new(),
+            ],
+            wide,
+
+            command: 0,
+            terminal_counts: 0,
+            requests: 0,
+            device_requests: 0,
+            mask: 0xf,
+            temporary: 0,
+            flip_flop: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.command & 0x04 == 0
+    }
+
+    fn master_clear(&mut self) {
+        self.command = 0;
+        self.terminal_counts = 0;
+        self.requests = 0;
+        self.temporary = 0;
+        self.flip_flop = false;
+        self.mask = 0xf;
+    }
+
+    /// Flips the flip-flop, returning whether the byte of a 16-bit register being accessed is the
+    /// MSB.
+    fn toggle(&mut self) -> bool {
+        let msb = self.flip_flop;
+        self.flip_flop = !msb;
+
+        msb
+    }
+
+    fn read(&mut self, reg: u16) -> u8 {
+        match reg {
+            0x0..=0x7 => {
+                let channel = &self.channels[usize::from(reg / 2)];
+                let value = if reg.is_multiple_of(2) {
+                    channel.current_address
+                } else {
+                    channel.current_count
+                };
+                let [lsb, msb] = value.to_le_bytes();
+                if self.toggle() {
+                    msb
+                } else {
+                    lsb
+                }
+            }
+            STATUS_REG => {
+                let status = ((self.requests | self.device_requests) << 4) | self.terminal_counts;
+                self.terminal_counts = 0;
+                status
+            }
+            MASTER_CLEAR_REG => self.temporary,
+            MASK_REG => self.mask | 0xf0,
+            _ => 0xff,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        let channel = usize::from(value & 0x3);
+        match reg {
+            0x0..=0x7 => {
+                let msb = self.toggle();
+                let channel = &mut self.channels[usize::from(reg / 2)];
+                let (base, current) = if reg.is_multiple_of(2) {
+                    (&mut channel.base_address, &mut channel.current_address)
+                } else {
+                    (&mut channel.base_count, &mut channel.current_count)
+                };
+                *base = if msb {
+                    (*base & 0x00ff) | (u16::from(value) << 8)
+                } else {
+                    (*base & 0xff00) | u16::from(value)
+                };
+                *current = *base;
+            }
+            STATUS_REG => self.command = value,
+            REQUEST_REG => set_bit(&mut self.requests, channel, value & 0x04 != 0),
+            SINGLE_MASK_REG => set_bit(&mut self.mask, channel, value & 0x04 != 0),
+            MODE_REG => self.channels[channel].set_mode(value),
+            CLEAR_FLIP_FLOP_REG => self.flip_flop = false,
+            MASTER_CLEAR_REG => self.master_clear(),
+            CLEAR_MASK_REG => self.mask = 0,
+            MASK_REG => self.mask = value & 0xf,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Whether a channel can transfer anything.
+    fn ready(&self, channel: usize) -> bool {
+        self.enabled()
+            && self.mask & (1 << channel) == 0
+            && self.channels[channel].mode != TransferMode::Cascade
+    }
+
+    fn transfer(
+        &mut self,
+        mem: &mut MemMap,
+        channel: usize,
+        page: u8,
+        buffer: &mut [u8],
+    ) -> Transfer {
+        let unit = if self.wide { 2 } else { 1 };
+        let wide = self.wide;
+        let state = &mut self.channels[channel];
+
+        let mut bytes = 0;
+        let mut terminal_count = false;
+        while bytes + unit <= buffer.len() {
+            let address = if wide {
+                (usize::from(page & 0xfe) << 16) | (usize::from(state.current_address) << 1)
+            } else {
+                (usize::from(page) << 16) | usize::from(state.current_address)
+            };
+            for offset in 0..unit {
+                let byte = &mut buffer[bytes + offset];
+                let address = address + offset;
+                match state.transfer_type {
+                    TransferType::Write if mem.is_mapped(address) => mem[address] = *byte,
+                    TransferType::Read if mem.is_mapped(address) => *byte = mem[address],
+                    TransferType::Read => *byte = 0xff,
+                    _ => {}
+                }
+            }
+            bytes += unit;
+
+            state.current_address = if state.decrement {
+                state.current_address.wrapping_sub(1)
+            } else {
+                state.current_address.wrapping_add(1)
+            };
+            state.current_count = state.current_count.wrapping_sub(1);
+            if state.current_count == 0xffff {
+                terminal_count = true;
+                break;
+            }
+        }
+
+        if terminal_count {
+            self.terminal_counts |= 1 << channel;
+            set_bit(&mut self.requests, channel, false);
+            if state.auto_init {
+                state.current_address = state.base_address;
+                state.current_count = state.base_count;
+            } else {
+                self.mask |= 1 << channel;
+            }
+        }
+
+        Transfer {
+            bytes,
+            terminal_count,
+        }
+    }
+
+    fn dump(&self, first_channel: usize) -> String {
+        let lines: Vec<_> = self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(index, channel)| {
+                format!(
+                    "DMA channel {}: address {:#06x}, count {:#06x}, {:?} {:?}{}{}{}",
+                    first_channel + index,
+                    channel.current_address,
+                    channel.current_count,
+                    channel.mode,
+                    channel.transfer_type,
+                    if channel.auto_init { ", auto-init" } else { "" },
+                    if channel.decrement { ", decrement" } else { "" },
+                    if self.mask & (1 << index) != 0 {
+                        ", masked"
+                    } else {
+                        ""
+                    }
+                )
+            })
+            .collect();
+
+        lines.join("\n")
+    }
+}
+
+/// The DMA controllers of a PC, which are an 8237A for channels 0 to 3, and on an AT, a second
+/// 8237A for the 16-bit channels 4 to 7 which the first controller is cascaded through.
+///
+/// Devices request transfers with [`transfer`], which moves data between a buffer and memory the
+/// way the channel was programmed.
+///
+/// [`transfer`]: Dma::transfer
+pub struct Dma {
+    pub controllers: Vec<DmaController>,
+    /// The page registers at ports 0x80 to 0x8f, which hold the upper bits of the addresses.
+    pub pages: [u8; 16],
+    /// Whether port 0x80 is left to another device (see [`release_post_port`]).
+    ///
+    /// [`release_post_port`]: Dma::release_post_port
+    post_port_released: bool,
+}
+
+impl Dma {
+    /// Creates the DMA controllers of an AT.
+    pub fn new() -> Self {
+        Self {
+            controllers: vec![DmaController::new(false), DmaController::new(true)],
+            pages: [0; 16],
+            post_port_released: false,
+        }
+    }
+
+    /// Creates the single DMA controller of a PC or XT.
+    pub fn new_xt() -> Self {
+        Self {
+            controllers: vec![DmaController::new(false)],
+            pages: [0; 16],
+            post_port_released: false,
+        }
+    }
+
+    /// Leaves port 0x80 to another device, which passes the accesses on to its page register (like
+    /// [`DebugPorts`] does to record POST codes).
+    ///
+    /// [`DebugPorts`]: crate::device::DebugPorts
+    pub fn release_post_port(&mut self) {
+        self.post_port_released = true;
+    }
+
+    /// The page register of a channel.
+    pub fn page(&self, channel: usize) -> u8 {
+        self.pages[usize::from(PAGE_PORTS[channel] - FIRST_PAGE_PORT)]
+    }
+
+    /// Sets whether a device is requesting transfers on a channel (DREQ), which shows up in the
+    /// status register.
+    pub fn set_request(&mut self, channel: usize, request: bool) {
+        let controller = &mut self.controllers[channel / 4];
+        set_bit(&mut controller.device_requests, channel % 4, request);
+    }
+
+    /// Transfers data between a device's buffer and memory on a channel, which returns how many
+    /// bytes were transferred and whether the terminal count was reached.
+    ///
+    /// Writes go from the buffer to memory and reads fill the buffer from memory. The transfer
+    /// stops when the count runs out or the buffer is full, so devices should pass the whole block
+    /// that they have. The single, demand and block modes only differ in how the real controller
+    /// shares the bus with the CPU, so they all transfer the same way. Channels 4 to 7 transfer
+    /// words, so their buffers should have an even number of bytes.
+    ///
+    /// Nothing is transferred if the channel is masked, the controller is disabled, or the
+    /// controller is cascaded through a channel that can't transfer.
+    pub fn transfer(&mut self, mem: &mut MemMap, channel: usize, buffer: &mut [u8]) -> Transfer {
+        let nothing = Transfer {
+            bytes: 0,
+            terminal_count: false,
+        };
+        let index = channel / 4;
+        if index >= self.controllers.len() || !self.controllers[index].ready(channel % 4) {
+            return nothing;
+        }
+        if index == 0 && self.controllers.len() > 1 {
+            let cascade = &self.controllers[1];
+            let masked = cascade.mask & (1 << (CASCADE_CHANNEL % 4)) != 0;
+            if !cascade.enabled() || masked {
+                return nothing;
+            }
+        }
+
+        let page = self.page(channel);
+        self.controllers[index].transfer(mem, channel % 4, page, buffer)
+    }
+
+    /// The controller and register of a port, if it's one of the controllers' ports.
+    fn register(&self, port: u16) -> Option<(usize, u16)> {
+        match port {
+            0x00..=0x0f => Some((0, port)),
+            0xc0..=0xdf if self.controllers.len() > 1 && port.is_multiple_of(2) => {
+                Some((1, (port - 0xc0) / 2))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Device<Cpu> for Dma {
+    fn reset(&mut self, _sys: &mut System) {
+        for controller in &mut self.controllers {
+            controller.master_clear();
+        }
+        self.pages = [0; 16];
+    }
+
+    fn handle_port(&mut self, _sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        match request {
+            PortRequest::In8(FIRST_PAGE_PORT) | PortRequest::Out8(FIRST_PAGE_PORT, _)
+                if self.post_port_released =>
+            {
+                None
+            }
+            PortRequest::In8(port @ FIRST_PAGE_PORT..=LAST_PAGE_PORT) => Some(PortResponse::In8(
+                self.pages[usize::from(port - FIRST_PAGE_PORT)],
+            )),
+            PortRequest::Out8(port @ FIRST_PAGE_PORT..=LAST_PAGE_PORT, value) => {
+                self.pages[usize::from(port - FIRST_PAGE_PORT)] = value;
+                Some(PortResponse::Out)
+            }
+            PortRequest::In8(port) => {
+                let (controller, reg) = self.register(port)?;
+                Some(PortResponse::In8(self.controllers[controller].read(reg)))
+            }
+            PortRequest::Out8(port, value) => {
+                let (controller, reg) = self.register(port)?;
+                self.controllers[controller].write(reg, value);
+                Some(PortResponse::Out)
+            }
+            _ => None,
+        }
+    }
+
+    fn claims(&self) -> Claims {
+        let first_page_port = FIRST_PAGE_PORT + u16::from(self.post_port_released);
+        let mut ports = vec![0x00..=0x0f, first_page_port..=LAST_PAGE_PORT];
+        if self.controllers.len() > 1 {
+            ports.push(0xc0..=0xdf);
+        }
+
+        Claims {
+            ports,
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        let lines: Vec<_> = self
+            .controllers
+            .iter()
+            .enumerate()
+            .map(|(index, controller)| controller.dump(index * 4))
+            .collect();
+
+        Some(lines.join("\n"))
+    }
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn set_bit(bits: &mut u8, bit: usize, set: bool) {
+    if set {
+        *bits |= 1 << bit;
+    } else {
+        *bits &= !(1 << bit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+    use firn_core::device::DeviceRef;
+    use firn_core::mem::Mem;
+
+    fn create_sys() -> (System, DeviceRef<Dma>) {
+        let (mut sys, _, dma) = device::tests::create_sys_with_mem(0x40000, |_, _| Dma::new());
+        sys.reset();
+
+        (sys, dma)
+    }
+
+    fn out(sys: &mut System, writes: &[(u16, u8)]) {
+        for (port, value) in writes {
+            sys.port_out_8(*port, *value).unwrap();
+        }
+    }
+
+    fn transfer(
+        sys: &mut System,
+        dma: DeviceRef<Dma>,
+        channel: usize,
+        buffer: &mut [u8],
+    ) -> Transfer {
+        sys.with_device(dma, |dma, sys| dma.transfer(&mut sys.mem, channel, buffer))
+    }
+
+    /// Unmasks channel 4, which the first controller is cascaded through.
+    fn enable_cascade(sys: &mut System) {
+        out(sys, &[(0xd6, 0xc0), (0xd4, 0x00)]);
+    }
+
+    #[test]
+    fn should_transfer_into_memory_until_terminal_count() {
+        let (mut sys, dma) = create_sys();
+        enable_cascade(&mut sys);
+        out(
+            &mut sys,
+            &[
+                (0x0a, 0x06),
+                (0x0c, 0x00),
+                (0x0b, 0x46),
+                (0x04, 0x00),
+                (0x04, 0x10),
+                (0x81, 0x01),
+                (0x05, 0x03),
+                (0x05, 0x00),
+                (0x0a, 0x02),
+            ],
+        );
+
+        let mut buffer = [1, 2, 3, 4, 5, 6];
+        let result = transfer(&mut sys, dma, 2, &mut buffer);
+        assert_eq!(
+            Transfer {
+                bytes: 4,
+                terminal_count: true,
+            },
+            result
+        );
+        assert_eq!([1, 2, 3, 4, 0], sys.mem.dump()[0x11000..0x11005]);
+
+        assert_eq!(Some(0x04), sys.port_in_8(0x08));
+        assert_eq!(Some(0x00), sys.port_in_8(0x08));
+        assert_eq!(0, transfer(&mut sys, dma, 2, &mut buffer).bytes);
+    }
+
+    #[test]
+    fn should_auto_initialize_word_transfers() {
+        let (mut sys, dma) = create_sys();
+        for (offset, value) in [0xa, 0xb, 0xc, 0xd].into_iter().enumerate() {
+            sys.mem[0x21000 + offset] = value;
+        }
+        out(
+            &mut sys,
+            &[
+                (0xd8, 0x00),
+                (0xd6, 0x79),
+                (0xc4, 0x01),
+                (0xc4, 0x08),
+                (0x8b, 0x02),
+                (0xc6, 0x01),
+                (0xc6, 0x00),
+                (0xd4, 0x01),
+            ],
+        );
+
+        for _ in 0..2 {
+            let mut buffer = [0; 6];
+            let result = transfer(&mut sys, dma, 5, &mut buffer);
+            assert!(result.terminal_count);
+            assert_eq!([0xc, 0xd, 0xa, 0xb, 0, 0], buffer);
+        }
+        assert_eq!(Some(0x02), sys.port_in_8(0xd0));
+    }
+
+    #[test]
+    fn should_not_transfer_through_masked_cascade() {
+        let (mut sys, dma) = create_sys();
+        out(&mut sys, &[(0x0b, 0x44), (0x0a, 0x00)]);
+
+        assert_eq!(0, transfer(&mut sys, dma, 0, &mut [1]).bytes);
+        enable_cascade(&mut sys);
+        assert_eq!(1, transfer(&mut sys, dma, 0, &mut [1]).bytes);
+    }
+}
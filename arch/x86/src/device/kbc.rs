@@ -0,0 +1,704 @@
+use crate::device::keyboard::{self, Key, Keyboard};
+use crate::device::mouse::{Buttons, Mouse};
+use crate::device::DualPic;
+use crate::{Cpu, System};
+use firn_core::clock::TimerId;
+use firn_core::cpu::Cpu as _;
+use firn_core::device::{Claims, Device, DeviceRef, PortRequest, PortResponse};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+pub const DATA_PORT: u16 = 0x60;
+/// The status register when it's read and the command register when it's written.
+pub const STATUS_PORT: u16 = 0x64;
+pub const COMMAND_PORT: u16 = 0x64;
+
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const MOUSE_IRQ: u8 = 12;
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_SYSTEM: u8 = 0x04;
+const STATUS_COMMAND: u8 = 0x08;
+/// The keyboard isn't inhibited by the keylock.
+const STATUS_UNLOCKED: u8 = 0x10;
+/// The byte in the output buffer is from the auxiliary device.
+const STATUS_AUX_FULL: u8 = 0x20;
+
+const COMMAND_KEYBOARD_IRQ: u8 = 0x01;
+const COMMAND_AUX_IRQ: u8 = 0x02;
+const COMMAND_SYSTEM: u8 = 0x04;
+const COMMAND_KEYBOARD_DISABLED: u8 = 0x10;
+const COMMAND_AUX_DISABLED: u8 = 0x20;
+const COMMAND_TRANSLATE: u8 = 0x40;
+
+/// The output port bit that resets the CPU while it's low.
+const OUTPUT_RESET: u8 = 0x01;
+const OUTPUT_A20: u8 = 0x02;
+
+/// The mask for linear addresses while the A20 gate is disabled, which wraps them at 1 MiB like an
+/// 8086.
+const A20_MASK: usize = !(1 << 20);
+
+/// The tokens of the timers for repeating the key that's held down, for the mouse's samples and
+/// for taking the host's events.
+const REPEAT_TIMER: u64 = 0;
+const SAMPLE_TIMER: u64 = 1;
+const SCAN_TIMER: u64 = 2;
+
+/// How often the host's key and mouse events are taken.
+pub const SCAN_PERIOD: Duration = Duration::from_millis(10);
+
+/// The kinds of mouse events in the `kbc.mouse` input channel.
+const MOUSE_MOVE: u8 = 0;
+const MOUSE_SCROLL: u8 = 1;
+
+/// The device that a byte in the output buffer is from, which decides the IRQ it raises.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Source {
+    Keyboard,
+    Aux,
+}
+
+/// An Intel 8042 keyboard controller, which connects a PS/2 keyboard and a PS/2 mouse (on the
+/// auxiliary port) to a PC/AT. It raises IRQ1 when it has a byte from the keyboard for the CPU and
+/// IRQ12 when it has one from the mouse.
+///
+/// The keyboard sends scan code set 2 by default, which the controller translates to set 1 unless
+/// bit 6 of the command byte is cleared. Two of the controller's output port bits are connected to
+/// the rest of the system: bit 0 resets the CPU (but not the devices) and bit 1 is the A20 gate,
+/// which sets the address mask of the CPU.
+///
+/// Frontends send host key events with [`press`] and [`release`] and mouse events with
+/// [`move_mouse`] and [`scroll_mouse`], usually through a [`DeviceHandle`]. The events are queued
+/// and taken every [`SCAN_PERIOD`] through the `kbc.key` and `kbc.mouse` inputs (see
+/// [`System::input`]), so that they can be recorded and replayed.
+///
+/// [`press`]: KeyboardController::press
+/// [`release`]: KeyboardController::release
+/// [`move_mouse`]: KeyboardController::move_mouse
+/// [`scroll_mouse`]: KeyboardController::scroll_mouse
+/// [`DeviceHandle`]: firn_core::device::DeviceHandle
+/// [`System::input`]: firn_core::System::input
+pub struct KeyboardController {
+    pic: DeviceRef<DualPic>,
+    pub keyboard: Keyboard,
+    pub mouse: Mouse,
+
+    /// The controller's RAM, where byte 0 is the command byte.
+    pub ram: [u8; 32],
+    pub output_port: u8,
+    /// The input port, where bit 7 is the keylock, bit 6 is the display switch (set for
+    /// monochrome) and bit 4 is the memory jumper.
+    pub input_port: u8,
+    /// The system flag of the status register, which is set by the self-test so that the BIOS can
+    /// tell a warm reset from a cold boot.
+    system_flag: bool,
+    /// Whether the last write was to the command port instead of the data port.
+    last_write_command: bool,
+
+    /// Whether the output buffer has a byte that hasn't been read.
+    output_full: bool,
+    /// The byte in the output buffer, which stays there after it's read.
+    output: u8,
+    output_source: Source,
+    /// The controller's responses to its commands, which go in the output buffer before anything
+    /// from the keyboard or mouse.
+    responses: VecDeque<(u8, Source)>,
+    /// The command that's waiting for its parameter on the data port.
+    command: Option<u8>,
+    /// Whether the last byte from the keyboard was the prefix of a break code, which isn't passed
+    /// on when translating.
+    released: bool,
+    repeat_timer: Option<TimerId>,
+    sample_timer: Option<TimerId>,
+    /// The earliest time that the mouse can take its next sample.
+    next_sample: Duration,
+    scan_timer: Option<TimerId>,
+    /// The host's events that haven't been taken yet, encoded like in the input log.
+    host_keys: VecDeque<Vec<u8>>,
+    host_mouse: VecDeque<Vec<u8>>,
+}
+
+impl KeyboardController {
+    /// Creates a keyboard controller with a keyboard and a mouse plugged into it.
+    pub fn new(pic: DeviceRef<DualPic>) -> Self {
+        Self {
+            pic,
+            keyboard: Keyboard::new(),
+            mouse: Mouse::new(),
+
+            ram: [0; 32],
+            output_port: 0,
+            input_port: 0xb0,
+            system_flag: false,
+            last_write_command: false,
+
+            output_full: false,
+            output: 0,
+            output_source: Source::Keyboard,
+            responses: VecDeque::new(),
+            command: None,
+            released: false,
+            repeat_timer: None,
+            sample_timer: None,
+            next_sample: Duration::ZERO,
+            scan_timer: None,
+            host_keys: VecDeque::new(),
+            host_mouse: VecDeque::new(),
+        }
+    }
+
+    /// Presses a key on the keyboard at the next scan. The key repeats until it's released or
+    /// another key is pressed.
+    pub fn press(&mut self, key: Key) {
+        self.host_keys.push_back(vec![key as u8, 1]);
+    }
+
+    /// Releases a key on the keyboard at the next scan.
+    pub fn release(&mut self, key: Key) {
+        self.host_keys.push_back(vec![key as u8, 0]);
+    }
+
+    /// Moves the mouse at the next scan, where `dy` is positive downwards like on the host's
+    /// screen, and sets the buttons that are held down. The movement is sent at the mouse's next
+    /// sample.
+    pub fn move_mouse(&mut self, dx: i32, dy: i32, buttons: Buttons) {
+        let mut event = vec![MOUSE_MOVE];
+        event.extend(dx.to_le_bytes());
+        event.extend(dy.to_le_bytes());
+        event.push(
+            u8::from(buttons.left) | u8::from(buttons.right) << 1 | u8::from(buttons.middle) << 2,
+        );
+        self.host_mouse.push_back(event);
+    }
+
+    /// Turns the mouse's wheel at the next scan, where `dz` is positive towards the user.
+    pub fn scroll_mouse(&mut self, dz: i32) {
+        let mut event = vec![MOUSE_SCROLL];
+        event.extend(dz.to_le_bytes());
+        self.host_mouse.push_back(event);
+    }
+
+    /// Whether the A20 gate is enabled, which lets addresses go past 1 MiB.
+    pub fn a20(&self) -> bool {
+        self.output_port & OUTPUT_A20 != 0
+    }
+
+    pub fn status(&self) -> u8 {
+        let mut status = STATUS_UNLOCKED;
+        if self.output_full {
+            status |= STATUS_OUTPUT_FULL;
+            if self.output_source == Source::Aux {
+                status |= STATUS_AUX_FULL;
+            }
+        }
+        if self.system_flag {
+            status |= STATUS_SYSTEM;
+        }
+        if self.last_write_command {
+            status |= STATUS_COMMAND;
+        }
+
+        status
+    }
+
+    fn read_data(&mut self, sys: &mut System) -> u8 {
+        self.output_full = false;
+        let value = self.output;
+        self.fill_output(sys);
+
+        value
+    }
+
+    fn write_command(&mut self, sys: &mut System, command: u8) {
+        self.last_write_command = true;
+        self.command = None;
+
+        match command {
+            // Read RAM
+            0x20..=0x3f => self.respond(self.ram[usize::from(command & 0x1f)]),
+            // Write RAM, write output port, write the keyboard's and the auxiliary device's output
+            // buffers and write to the auxiliary device
+            0x60..=0x7f | 0xd1..=0xd4 => self.command = Some(command),
+            // Disable, enable and test the auxiliary device's interface
+            0xa7 => self.ram[0] |= COMMAND_AUX_DISABLED,
+            0xa8 => self.ram[0] &= !COMMAND_AUX_DISABLED,
+            0xa9 => self.respond(0x00),
+            // Self-test
+            0xaa => {
+                self.system_flag = true;
+                self.respond(0x55);
+            }
+            // Test the keyboard's interface
+            0xab => self.respond(0x00),
+            // Disable and enable the keyboard
+            0xad => self.ram[0] |= COMMAND_KEYBOARD_DISABLED,
+            0xae => self.ram[0] &= !COMMAND_KEYBOARD_DISABLED,
+            0xc0 => self.respond(self.input_port),
+            0xd0 => self.respond(self.output_port),
+            // Disable and enable the A20 gate, which not every 8042 supports
+            0xdd => self.set_output_port(sys, self.output_port & !OUTPUT_A20),
+            0xdf => self.set_output_port(sys, self.output_port | OUTPUT_A20),
+            // Read test inputs
+            0xe0 => self.respond(0x00),
+            // Pulse the output port bits that are cleared in the low nibble, of which only the
+            // reset does anything
+            0xf0..=0xff if command & OUTPUT_RESET == 0 => Self::reset_cpu(sys),
+            _ => {}
+        }
+
+        self.fill_output(sys);
+    }
+
+    fn write_data(&mut self, sys: &mut System, value: u8) {
+        self.last_write_command = false;
+
+        match self.command.take() {
+            Some(command @ 0x60..=0x7f) => {
+                self.ram[usize::from(command & 0x1f)] = value;
+                if command == 0x60 {
+                    self.system_flag = value & COMMAND_SYSTEM != 0;
+                }
+            }
+            Some(0xd1) => self.set_output_port(sys, value),
+            Some(0xd2) => self.responses.push_back((value, Source::Keyboard)),
+            Some(0xd3) => self.responses.push_back((value, Source::Aux)),
+            Some(0xd4) => self.mouse.write(value),
+            _ => {
+                // Sending a byte to the keyboard enables it again
+                self.ram[0] &= !COMMAND_KEYBOARD_DISABLED;
+                self.keyboard.write(value);
+            }
+        }
+
+        self.fill_output(sys);
+    }
+
+    fn respond(&mut self, value: u8) {
+        self.responses.push_back((value, Source::Keyboard));
+    }
+
+    fn set_output_port(&mut self, sys: &mut System, value: u8) {
+        let previous = std::mem::replace(&mut self.output_port, value);
+        if (previous ^ value) & OUTPUT_A20 != 0 {
+            self.update_address_mask(sys);
+        }
+        if value & OUTPUT_RESET == 0 {
+            Self::reset_cpu(sys);
+        }
+    }
+
+    fn update_address_mask(&self, sys: &mut System) {
+        sys.cpu.address_mask = if self.a20() { usize::MAX } else { A20_MASK };
+    }
+
+    /// Resets the CPU after the instruction that's executing, which is usually the one that's
+    /// writing to the controller.
+    ///
+    /// The A20 gate is part of the controller, so it's left alone.
+    fn reset_cpu(sys: &mut System) {
+        let now = sys.clock.now();
+        sys.schedule(now, |sys| {
+            let address_mask = sys.cpu.address_mask;
+            sys.cpu.reset();
+            sys.cpu.address_mask = address_mask;
+        });
+    }
+
+    /// Moves the next byte for the CPU into the output buffer if it's empty, raising IRQ1 or IRQ12
+    /// if it's enabled. Bytes from the keyboard go before bytes from the mouse.
+    fn fill_output(&mut self, sys: &mut System) {
+        if self.output_full {
+            return;
+        }
+
+        let keyboard_enabled = self.ram[0] & COMMAND_KEYBOARD_DISABLED == 0;
+        let aux_enabled = self.ram[0] & COMMAND_AUX_DISABLED == 0;
+        let (byte, source) = match self.responses.pop_front() {
+            Some(response) => response,
+            None => match self.next_keyboard_byte().filter(|_| keyboard_enabled) {
+                Some(byte) => (byte, Source::Keyboard),
+                None => match self.mouse.next_byte().filter(|_| aux_enabled) {
+                    Some(byte) => (byte, Source::Aux),
+                    None => return,
+                },
+            },
+        };
+
+        self.output = byte;
+        self.output_source = source;
+        self.output_full = true;
+        let irq = match source {
+            Source::Keyboard if self.ram[0] & COMMAND_KEYBOARD_IRQ != 0 => KEYBOARD_IRQ,
+            Source::Aux if self.ram[0] & COMMAND_AUX_IRQ != 0 => MOUSE_IRQ,
+            _ => return,
+        };
+        sys.with_device(self.pic, |pic, _| pic.submit_irq(irq));
+    }
+
+    fn next_keyboard_byte(&mut self) -> Option<u8> {
+        loop {
+            let byte = self.keyboard.next_byte()?;
+            if self.ram[0] & COMMAND_TRANSLATE == 0 {
+                return Some(byte);
+            }
+
+            match keyboard::translate(byte, self.released) {
+                Some(translated) => {
+                    self.released = false;
+                    return Some(translated);
+                }
+                None => self.released = true,
+            }
+        }
+    }
+
+    /// Takes the host's events (or the logged ones while replaying) and applies them.
+    fn scan(&mut self, sys: &mut System) {
+        while let Some(event) = sys.input("kbc.key", || self.host_keys.pop_front()) {
+            let key = event
+                .first()
+                .and_then(|&key| Key::ALL.get(usize::from(key)));
+            match (key, event.get(1)) {
+                (Some(&key), Some(1)) => {
+                    self.cancel_repeat(sys);
+                    if let Some(delay) = self.keyboard.press(key) {
+                        let at = sys.clock.now() + delay;
+                        self.repeat_timer = Some(sys.set_timer(at, REPEAT_TIMER));
+                    }
+                }
+                (Some(&key), Some(0)) if self.keyboard.release(key) => self.cancel_repeat(sys),
+                _ => {}
+            }
+        }
+
+        while let Some(event) = sys.input("kbc.mouse", || self.host_mouse.pop_front()) {
+            let value = |offset: usize| {
+                let bytes = event.get(offset..offset + 4)?;
+                Some(i32::from_le_bytes(bytes.try_into().unwrap()))
+            };
+            match (event.first(), value(1), value(5), event.get(9)) {
+                (Some(&MOUSE_MOVE), Some(dx), Some(dy), Some(&buttons)) => {
+                    let buttons = Buttons {
+                        left: buttons & 0x01 != 0,
+                        right: buttons & 0x02 != 0,
+                        middle: buttons & 0x04 != 0,
+                    };
+                    self.mouse.move_by(dx, dy, buttons);
+                }
+                (Some(&MOUSE_SCROLL), Some(dz), _, _) => self.mouse.scroll(dz),
+                _ => continue,
+            }
+            self.schedule_sample(sys);
+        }
+    }
+
+    fn cancel_repeat(&mut self, sys: &mut System) {
+        if let Some(timer) = self.repeat_timer.take() {
+            sys.cancel_timer(timer);
+        }
+    }
+
+    /// Sets the timer for the mouse's next sample if it's streaming and doesn't have one, which
+    /// is as soon as the sample rate allows.
+    fn schedule_sample(&mut self, sys: &mut System) {
+        if self.sample_timer.is_some() || !self.mouse.is_streaming() {
+            return;
+        }
+
+        let at = sys.clock.now().max(self.next_sample);
+        self.sample_timer = Some(sys.set_timer(at, SAMPLE_TIMER));
+    }
+}
+
+impl Device<Cpu> for KeyboardController {
+    fn init(&mut self, sys: &mut System) {
+        Device::reset(self, sys);
+    }
+
+    fn reset(&mut self, sys: &mut System) {
+        self.cancel_repeat(sys);
+        if let Some(timer) = self.sample_timer.take() {
+            sys.cancel_timer(timer);
+        }
+        if let Some(timer) = self.scan_timer.take() {
+            sys.cancel_timer(timer);
+        }
+        self.scan_timer = Some(sys.set_timer(sys.clock.now() + SCAN_PERIOD, SCAN_TIMER));
+        self.next_sample = Duration::ZERO;
+        self.keyboard.reset();
+        self.mouse.reset();
+
+        self.ram = [0; 32];
+        self.ram[0] = COMMAND_KEYBOARD_IRQ | COMMAND_AUX_DISABLED | COMMAND_TRANSLATE;
+        // The CPU isn't held in reset, the A20 gate is enabled and the keyboard's clock and data
+        // lines are high
+        self.output_port = 0xcf;
+        self.update_address_mask(sys);
+        self.system_flag = false;
+        self.last_write_command = false;
+
+        self.output_full = false;
+        self.output = 0;
+        self.output_source = Source::Keyboard;
+        self.responses.clear();
+        self.command = None;
+        self.released = false;
+    }
+
+    fn timer(&mut self, sys: &mut System, token: u64) {
+        let now = sys.clock.now();
+        match token {
+            REPEAT_TIMER => {
+                self.repeat_timer = None;
+                if let Some(period) = self.keyboard.repeat() {
+                    self.repeat_timer = Some(sys.set_timer(now + period, REPEAT_TIMER));
+                }
+            }
+            SAMPLE_TIMER => {
+                self.sample_timer = None;
+                if self.mouse.sample() {
+                    self.next_sample = now + self.mouse.sample_period();
+                }
+            }
+            _ => {
+                self.scan_timer = Some(sys.set_timer(now + SCAN_PERIOD, SCAN_TIMER));
+                self.scan(sys);
+            }
+        }
+        self.fill_output(sys);
+    }
+
+    fn handle_port(&mut self, sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        match request {
+            PortRequest::In8(DATA_PORT) => Some(PortResponse::In8(self.read_data(sys))),
+            PortRequest::In8(STATUS_PORT) => Some(PortResponse::In8(self.status())),
+            PortRequest::Out8(DATA_PORT, value) => {
+                self.write_data(sys, value);
+                Some(PortResponse::Out)
+            }
+            PortRequest::Out8(COMMAND_PORT, command) => {
+                self.write_command(sys, command);
+                Some(PortResponse::Out)
+            }
+            _ => None,
+        }
+    }
+
+    fn claims(&self) -> Claims {
+        Claims {
+            ports: vec![DATA_PORT..=DATA_PORT, STATUS_PORT..=STATUS_PORT],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        Some(format!(
+            "8042: status {:#04x}, command byte {:#04x}, output port {:#04x}, keyboard {:?} with \
+             LEDs {:#03b}, mouse {:?} with ID {}",
+            self.status(),
+            self.ram[0],
+            self.output_port,
+            self.keyboard.scan_code_set,
+            self.keyboard.leds,
+            self.mouse.mode,
+            self.mouse.id
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device, ExtSystem, SegmentReg};
+    use std::time::Duration;
+
+    fn create_sys() -> (System, DeviceRef<DualPic>, DeviceRef<KeyboardController>) {
+        device::tests::create_sys(|_, pic| KeyboardController::new(pic))
+    }
+
+    /// Steps the system until the controller has taken the host's events.
+    fn scan(sys: &mut System) {
+        let cycles = sys.clock.cycles_at(SCAN_PERIOD);
+        sys.clock.advance(cycles);
+        sys.step();
+    }
+
+    fn read_all(sys: &mut System) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while sys.port_in_8(STATUS_PORT).unwrap() & STATUS_OUTPUT_FULL != 0 {
+            bytes.push(sys.port_in_8(DATA_PORT).unwrap());
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn should_run_controller_and_keyboard_commands() {
+        let (mut sys, _, _) = create_sys();
+        assert_eq!(0, sys.port_in_8(STATUS_PORT).unwrap() & STATUS_SYSTEM);
+
+        sys.port_out_8(COMMAND_PORT, 0xaa).unwrap();
+        assert_eq!(vec![0x55], read_all(&mut sys));
+        assert_eq!(
+            STATUS_SYSTEM | STATUS_COMMAND,
+            sys.port_in_8(STATUS_PORT).unwrap() & 0x0c
+        );
+
+        sys.port_out_8(COMMAND_PORT, 0x60).unwrap();
+        sys.port_out_8(DATA_PORT, 0x45).unwrap();
+        sys.port_out_8(COMMAND_PORT, 0x20).unwrap();
+        assert_eq!(vec![0x45], read_all(&mut sys));
+
+        // The keyboard's responses are translated too
+        sys.port_out_8(DATA_PORT, 0xff).unwrap();
+        assert_eq!(vec![0xfa, 0xaa], read_all(&mut sys));
+        sys.port_out_8(DATA_PORT, 0xf2).unwrap();
+        assert_eq!(vec![0xfa, 0xab, 0x41], read_all(&mut sys));
+        sys.port_out_8(DATA_PORT, 0xf0).unwrap();
+        sys.port_out_8(DATA_PORT, 0x01).unwrap();
+        assert_eq!(vec![0xfa, 0xfa], read_all(&mut sys));
+    }
+
+    #[test]
+    fn should_translate_scan_codes_and_raise_irq1() {
+        let (mut sys, pic, kbc) = create_sys();
+        sys.get_device_mut(kbc).press(Key::A);
+        sys.get_device_mut(kbc).release(Key::A);
+        scan(&mut sys);
+        assert_eq!(0x02, sys.get_device(pic).master.request_reg);
+        assert_eq!(vec![0x1e, 0x9e], read_all(&mut sys));
+
+        sys.get_device_mut(kbc).press(Key::Right);
+        sys.get_device_mut(kbc).release(Key::Right);
+        scan(&mut sys);
+        assert_eq!(vec![0xe0, 0x4d, 0xe0, 0xcd], read_all(&mut sys));
+
+        sys.port_out_8(COMMAND_PORT, 0x60).unwrap();
+        sys.port_out_8(DATA_PORT, COMMAND_KEYBOARD_IRQ).unwrap();
+        sys.get_device_mut(kbc).press(Key::Right);
+        sys.get_device_mut(kbc).release(Key::Right);
+        scan(&mut sys);
+        assert_eq!(vec![0xe0, 0x74, 0xe0, 0xf0, 0x74], read_all(&mut sys));
+    }
+
+    #[test]
+    fn should_repeat_held_key() {
+        let (mut sys, _, kbc) = create_sys();
+        sys.get_device_mut(kbc).press(Key::Z);
+        scan(&mut sys);
+        assert_eq!(vec![0x2c], read_all(&mut sys));
+
+        // The default delay is 500 ms and the default period is 91.74 ms
+        for (millis, repeats) in [(499, 0), (1, 1), (91, 0), (1, 1), (92, 1)] {
+            let cycles = sys.clock.cycles_at(Duration::from_millis(millis));
+            sys.clock.advance(cycles);
+            sys.step();
+            assert_eq!(vec![0x2c; repeats], read_all(&mut sys));
+        }
+
+        sys.get_device_mut(kbc).release(Key::Z);
+        scan(&mut sys);
+        assert_eq!(vec![0xac], read_all(&mut sys));
+        let cycles = sys.clock.cycles_at(Duration::from_secs(1));
+        sys.clock.advance(cycles);
+        sys.step();
+        assert_eq!(Vec::<u8>::new(), read_all(&mut sys));
+    }
+
+    #[test]
+    fn should_detect_intellimouse_and_raise_irq12() {
+        let (mut sys, pic, kbc) = create_sys();
+        sys.port_out_8(COMMAND_PORT, 0xa8).unwrap();
+        sys.port_out_8(COMMAND_PORT, 0x60).unwrap();
+        sys.port_out_8(DATA_PORT, 0x43).unwrap();
+
+        // Devices throw away unread responses when they get a command, so every response is read
+        // before the next byte is sent
+        let write_mouse = |sys: &mut System, bytes: &[u8]| {
+            let mut responses = Vec::new();
+            for byte in bytes {
+                sys.port_out_8(COMMAND_PORT, 0xd4).unwrap();
+                sys.port_out_8(DATA_PORT, *byte).unwrap();
+                assert_ne!(0, sys.port_in_8(STATUS_PORT).unwrap() & STATUS_AUX_FULL);
+                responses.extend(read_all(sys));
+            }
+            responses
+        };
+        assert_eq!(vec![0xfa, 0x00], write_mouse(&mut sys, &[0xf2]));
+        let knock = [0xf3, 200, 0xf3, 100, 0xf3, 80, 0xf2];
+        assert_eq!(
+            vec![0xfa, 0xfa, 0xfa, 0xfa, 0xfa, 0xfa, 0xfa, 0x03],
+            write_mouse(&mut sys, &knock)
+        );
+        assert_eq!(vec![0xfa], write_mouse(&mut sys, &[0xf4]));
+        assert_eq!(0x10, sys.get_device(pic).slave.request_reg);
+
+        // The next sample is 12.5 ms after the last one at 80 samples per second
+        let left = Buttons {
+            left: true,
+            ..Buttons::default()
+        };
+        sys.get_device_mut(kbc).move_mouse(5, 3, left);
+        sys.get_device_mut(kbc).scroll_mouse(-1);
+        scan(&mut sys);
+        sys.step();
+        assert_eq!(vec![0x29, 0x05, 0xfd, 0xff], read_all(&mut sys));
+        sys.get_device_mut(kbc).move_mouse(-1, 0, left);
+        scan(&mut sys);
+        assert_eq!(Vec::<u8>::new(), read_all(&mut sys));
+
+        let cycles = sys.clock.cycles_at(Duration::from_micros(12_500));
+        sys.clock.advance(cycles);
+        sys.step();
+        assert_eq!(vec![0x19, 0xff, 0x00, 0x00], read_all(&mut sys));
+    }
+
+    #[test]
+    fn should_replay_recorded_key_presses() {
+        let (mut sys, _, kbc) = create_sys();
+        sys.record_inputs();
+        sys.get_device_mut(kbc).press(Key::A);
+        scan(&mut sys);
+        sys.get_device_mut(kbc).release(Key::A);
+        scan(&mut sys);
+        let recorded = read_all(&mut sys);
+        let log = sys.stop_inputs().unwrap();
+        assert_eq!(2, log.events.len());
+
+        // The host's events are ignored while replaying
+        let (mut sys, _, kbc) = create_sys();
+        sys.replay_inputs(log);
+        sys.get_device_mut(kbc).press(Key::B);
+        scan(&mut sys);
+        scan(&mut sys);
+        assert_eq!(recorded, read_all(&mut sys));
+        assert_eq!(None, sys.divergence());
+    }
+
+    #[test]
+    fn should_gate_a20_and_reset_cpu() {
+        let (mut sys, _, kbc) = create_sys();
+        sys.cpu.set_reg_16(SegmentReg::Ds.into(), 0xffff);
+        assert_eq!(0x100000, sys.linear_mem(SegmentReg::Ds, 0x10));
+
+        sys.port_out_8(COMMAND_PORT, 0xd1).unwrap();
+        sys.port_out_8(DATA_PORT, 0xcd).unwrap();
+        assert!(!sys.get_device(kbc).a20());
+        assert_eq!(0x00000, sys.linear_mem(SegmentReg::Ds, 0x10));
+        sys.port_out_8(COMMAND_PORT, 0xd0).unwrap();
+        assert_eq!(vec![0xcd], read_all(&mut sys));
+
+        sys.port_out_8(COMMAND_PORT, 0xdf).unwrap();
+        assert_eq!(0x100000, sys.linear_mem(SegmentReg::Ds, 0x10));
+
+        // The reset happens after the instruction that pulses it
+        sys.cpu.set_reg_16(SegmentReg::Ds.into(), 0x0000);
+        sys.cpu.set_reg_16(SegmentReg::Cs.into(), 0x0100);
+        sys.port_out_8(COMMAND_PORT, 0xfe).unwrap();
+        assert_eq!(0x0100, sys.cpu.reg_16(SegmentReg::Cs.into()));
+        sys.step();
+        assert_eq!(0xffff, sys.cpu.reg_16(SegmentReg::Cs.into()));
+        assert!(sys.get_device(kbc).a20());
+    }
+}
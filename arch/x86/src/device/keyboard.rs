@@ -0,0 +1,647 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The number of bytes that the keyboard buffers before it stops sending scan codes.
+const BUFFER_SIZE: usize = 16;
+
+/// The typematic rate and delay that the keyboard uses after it's reset, which is 10.9 characters
+/// per second after 500 ms.
+const DEFAULT_TYPEMATIC: u8 = 0x2b;
+
+/// The scan code set 2 codes of the keys in scan code set 1, indexed by their set 1 code (without
+/// the `E0` prefix of extended keys). This is the inverse of the 8042's translation table.
+const SET_2_CODES: [u8; 0x60] = [
+    0x00, 0x76, 0x16, 0x1e, 0x26, 0x25, 0x2e, 0x36, 0x3d, 0x3e, 0x46, 0x45, 0x4e, 0x55, 0x66, 0x0d,
+    0x15, 0x1d, 0x24, 0x2d, 0x2c, 0x35, 0x3c, 0x43, 0x44, 0x4d, 0x54, 0x5b, 0x5a, 0x14, 0x1c, 0x1b,
+    0x23, 0x2b, 0x34, 0x33, 0x3b, 0x42, 0x4b, 0x4c, 0x52, 0x0e, 0x12, 0x5d, 0x1a, 0x22, 0x21, 0x2a,
+    0x32, 0x31, 0x3a, 0x41, 0x49, 0x4a, 0x59, 0x7c, 0x11, 0x29, 0x58, 0x05, 0x06, 0x04, 0x0c, 0x03,
+    0x0b, 0x83, 0x0a, 0x01, 0x09, 0x77, 0x7e, 0x6c, 0x75, 0x7d, 0x7b, 0x6b, 0x73, 0x74, 0x79, 0x69,
+    0x72, 0x7a, 0x70, 0x71, 0x84, 0x00, 0x61, 0x78, 0x07, 0x00, 0x00, 0x1f, 0x27, 0x2f, 0x00, 0x00,
+];
+
+/// A key on a 104-key keyboard, named after what it's labelled on a US layout.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Key {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    Backquote,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equal,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    /// The extra key next to the left shift on international keyboards.
+    IntlBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightCtrl,
+
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Up,
+    Left,
+    Down,
+    Right,
+
+    NumLock,
+    KeypadDivide,
+    KeypadMultiply,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+impl Key {
+    /// Every key, in the order they're declared, so that `Key::ALL[key as usize] == key`.
+    pub const ALL: [Key; 105] = [
+        Key::Escape,
+        Key::F1,
+        Key::F2,
+        Key::F3,
+        Key::F4,
+        Key::F5,
+        Key::F6,
+        Key::F7,
+        Key::F8,
+        Key::F9,
+        Key::F10,
+        Key::F11,
+        Key::F12,
+        Key::PrintScreen,
+        Key::ScrollLock,
+        Key::Pause,
+        Key::Backquote,
+        Key::Digit1,
+        Key::Digit2,
+        Key::Digit3,
+        Key::Digit4,
+        Key::Digit5,
+        Key::Digit6,
+        Key::Digit7,
+        Key::Digit8,
+        Key::Digit9,
+        Key::Digit0,
+        Key::Minus,
+        Key::Equal,
+        Key::Backspace,
+        Key::Tab,
+        Key::Q,
+        Key::W,
+        Key::E,
+        Key::R,
+        Key::T,
+        Key::Y,
+        Key::U,
+        Key::I,
+        Key::O,
+        Key::P,
+        Key::LeftBracket,
+        Key::RightBracket,
+        Key::Backslash,
+        Key::CapsLock,
+        Key::A,
+        Key::S,
+        Key::D,
+        Key::F,
+        Key::G,
+        Key::H,
+        Key::J,
+        Key::K,
+        Key::L,
+        Key::Semicolon,
+        Key::Quote,
+        Key::Enter,
+        Key::LeftShift,
+        Key::IntlBackslash,
+        Key::Z,
+        Key::X,
+        Key::C,
+        Key::V,
+        Key::B,
+        Key::N,
+        Key::M,
+        Key::Comma,
+        Key::Period,
+        Key::Slash,
+        Key::RightShift,
+        Key::LeftCtrl,
+        Key::LeftGui,
+        Key::LeftAlt,
+        Key::Space,
+        Key::RightAlt,
+        Key::RightGui,
+        Key::Menu,
+        Key::RightCtrl,
+        Key::Insert,
+        Key::Home,
+        Key::PageUp,
+        Key::Delete,
+        Key::End,
+        Key::PageDown,
+        Key::Up,
+        Key::Left,
+        Key::Down,
+        Key::Right,
+        Key::NumLock,
+        Key::KeypadDivide,
+        Key::KeypadMultiply,
+        Key::KeypadMinus,
+        Key::KeypadPlus,
+        Key::KeypadEnter,
+        Key::KeypadPeriod,
+        Key::Keypad0,
+        Key::Keypad1,
+        Key::Keypad2,
+        Key::Keypad3,
+        Key::Keypad4,
+        Key::Keypad5,
+        Key::Keypad6,
+        Key::Keypad7,
+        Key::Keypad8,
+        Key::Keypad9,
+    ];
+
+    /// The scan code set 1 make code of the key and whether it has an `E0` prefix, or `None` for
+    /// keys with special sequences (print screen and pause).
+    pub fn set_1_code(self) -> Option<(bool, u8)> {
+        use Key::*;
+
+        let code = match self {
+            PrintScreen | Pause => return None,
+
+            Escape => 0x01,
+            Digit1 => 0x02,
+            Digit2 => 0x03,
+            Digit3 => 0x04,
+            Digit4 => 0x05,
+            Digit5 => 0x06,
+            Digit6 => 0x07,
+            Digit7 => 0x08,
+            Digit8 => 0x09,
+            Digit9 => 0x0a,
+            Digit0 => 0x0b,
+            Minus => 0x0c,
+            Equal => 0x0d,
+            Backspace => 0x0e,
+            Tab => 0x0f,
+            Q => 0x10,
+            W => 0x11,
+            E => 0x12,
+            R => 0x13,
+            T => 0x14,
+            Y => 0x15,
+            U => 0x16,
+            I => 0x17,
+            O => 0x18,
+            P => 0x19,
+            LeftBracket => 0x1a,
+            RightBracket => 0x1b,
+            Enter => 0x1c,
+            LeftCtrl => 0x1d,
+            A => 0x1e,
+            S => 0x1f,
+            D => 0x20,
+            F => 0x21,
+            G => 0x22,
+            H => 0x23,
+            J => 0x24,
+            K => 0x25,
+            L => 0x26,
+            Semicolon => 0x27,
+            Quote => 0x28,
+            Backquote => 0x29,
+            LeftShift => 0x2a,
+            Backslash => 0x2b,
+            Z => 0x2c,
+            X => 0x2d,
+            C => 0x2e,
+            V => 0x2f,
+            B => 0x30,
+            N => 0x31,
+            M => 0x32,
+            Comma => 0x33,
+            Period => 0x34,
+            Slash => 0x35,
+            RightShift => 0x36,
+            KeypadMultiply => 0x37,
+            LeftAlt => 0x38,
+            Space => 0x39,
+            CapsLock => 0x3a,
+            F1 => 0x3b,
+            F2 => 0x3c,
+            F3 => 0x3d,
+            F4 => 0x3e,
+            F5 => 0x3f,
+            F6 => 0x40,
+            F7 => 0x41,
+            F8 => 0x42,
+            F9 => 0x43,
+            F10 => 0x44,
+            NumLock => 0x45,
+            ScrollLock => 0x46,
+            Keypad7 => 0x47,
+            Keypad8 => 0x48,
+            Keypad9 => 0x49,
+            KeypadMinus => 0x4a,
+            Keypad4 => 0x4b,
+            Keypad5 => 0x4c,
+            Keypad6 => 0x4d,
+            KeypadPlus => 0x4e,
+            Keypad1 => 0x4f,
+            Keypad2 => 0x50,
+            Keypad3 => 0x51,
+            Keypad0 => 0x52,
+            KeypadPeriod => 0x53,
+            IntlBackslash => 0x56,
+            F11 => 0x57,
+            F12 => 0x58,
+
+            KeypadEnter => return Some((true, 0x1c)),
+            RightCtrl => return Some((true, 0x1d)),
+            KeypadDivide => return Some((true, 0x35)),
+            RightAlt => return Some((true, 0x38)),
+            Home => return Some((true, 0x47)),
+            Up => return Some((true, 0x48)),
+            PageUp => return Some((true, 0x49)),
+            Left => return Some((true, 0x4b)),
+            Right => return Some((true, 0x4d)),
+            End => return Some((true, 0x4f)),
+            Down => return Some((true, 0x50)),
+            PageDown => return Some((true, 0x51)),
+            Insert => return Some((true, 0x52)),
+            Delete => return Some((true, 0x53)),
+            LeftGui => return Some((true, 0x5b)),
+            RightGui => return Some((true, 0x5c)),
+            Menu => return Some((true, 0x5d)),
+        };
+
+        Some((false, code))
+    }
+
+    /// The bytes that the keyboard sends when the key is pressed (`make`) or released in a scan
+    /// code set.
+    pub fn scan_codes(self, set: ScanCodeSet, make: bool) -> Vec<u8> {
+        match (self, set, make) {
+            (Key::PrintScreen, ScanCodeSet::Set1, true) => vec![0xe0, 0x2a, 0xe0, 0x37],
+            (Key::PrintScreen, ScanCodeSet::Set1, false) => vec![0xe0, 0xb7, 0xe0, 0xaa],
+            (Key::PrintScreen, ScanCodeSet::Set2, true) => vec![0xe0, 0x12, 0xe0, 0x7c],
+            (Key::PrintScreen, ScanCodeSet::Set2, false) => {
+                vec![0xe0, 0xf0, 0x7c, 0xe0, 0xf0, 0x12]
+            }
+            // Pause doesn't have a break code, its make code includes one
+            (Key::Pause, _, false) => Vec::new(),
+            (Key::Pause, ScanCodeSet::Set1, true) => vec![0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5],
+            (Key::Pause, ScanCodeSet::Set2, true) => {
+                vec![0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77]
+            }
+            _ => {
+                let (extended, code) = self.set_1_code().unwrap();
+                let mut codes = Vec::with_capacity(3);
+                if extended {
+                    codes.push(0xe0);
+                }
+                match (set, make) {
+                    (ScanCodeSet::Set1, true) => codes.push(code),
+                    (ScanCodeSet::Set1, false) => codes.push(code | 0x80),
+                    (ScanCodeSet::Set2, true) => codes.push(SET_2_CODES[code as usize]),
+                    (ScanCodeSet::Set2, false) => {
+                        codes.extend([0xf0, SET_2_CODES[code as usize]]);
+                    }
+                }
+
+                codes
+            }
+        }
+    }
+}
+
+/// The scan codes that a keyboard sends. Set 3 isn't supported.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ScanCodeSet {
+    /// The scan codes of the original PC and XT keyboards.
+    Set1,
+    /// The scan codes of AT and PS/2 keyboards, which the 8042 translates to set 1 by default.
+    Set2,
+}
+
+/// Translates a byte from scan code set 2 to set 1 like the 8042 does, where `released` is whether
+/// the previous byte was the `F0` prefix of a break code.
+///
+/// Returns `None` for the `F0` prefix itself, which isn't passed on.
+pub fn translate(code: u8, released: bool) -> Option<u8> {
+    if code == 0xf0 {
+        return None;
+    }
+
+    let translated = match code {
+        0x83 => 0x41,
+        0x84 => 0x54,
+        0x00..=0x7f => SET_2_CODES
+            .iter()
+            .position(|set_2| *set_2 == code && code != 0)
+            .map_or(code, |set_1| set_1 as u8),
+        _ => code,
+    };
+
+    Some(if released {
+        translated | 0x80
+    } else {
+        translated
+    })
+}
+
+/// A PS/2 keyboard, which sends scan codes (and responses to its commands) to the keyboard
+/// controller that it's plugged into.
+pub struct Keyboard {
+    pub scan_code_set: ScanCodeSet,
+    /// Whether key presses are sent, which can be changed with the enable and disable commands.
+    pub scanning: bool,
+    /// The typematic byte, where bits 0 to 4 are the rate and bits 5 and 6 are the delay.
+    pub typematic: u8,
+    /// The LEDs, where bit 0 is scroll lock, bit 1 is num lock and bit 2 is caps lock.
+    pub leds: u8,
+
+    /// The bytes that haven't been sent to the controller yet.
+    output: VecDeque<u8>,
+    /// The command that's waiting for its parameter.
+    command: Option<u8>,
+    /// The last byte that was sent, which the resend command sends again.
+    last_sent: u8,
+    /// The key that's repeated while it's held down, which is the last key that was pressed.
+    repeating: Option<Key>,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self {
+            scan_code_set: ScanCodeSet::Set2,
+            scanning: true,
+            typematic: DEFAULT_TYPEMATIC,
+            leds: 0,
+
+            output: VecDeque::new(),
+            command: None,
+            last_sent: 0,
+            repeating: None,
+        }
+    }
+
+    /// Resets the keyboard to its defaults and throws away anything it hasn't sent.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Takes the next byte that the keyboard wants to send.
+    pub fn next_byte(&mut self) -> Option<u8> {
+        let byte = self.output.pop_front()?;
+        self.last_sent = byte;
+
+        Some(byte)
+    }
+
+    /// Presses a key, which sends its make code if scanning is enabled. Returns the key's typematic
+    /// delay if it repeats.
+    pub fn press(&mut self, key: Key) -> Option<Duration> {
+        if !self.scanning {
+            return None;
+        }
+
+        self.send_codes(key, true);
+        if key == Key::Pause {
+            self.repeating = None;
+            return None;
+        }
+
+        self.repeating = Some(key);
+        Some(self.typematic_delay())
+    }
+
+    /// Releases a key, which sends its break code if scanning is enabled. Returns whether it
+    /// stopped repeating.
+    pub fn release(&mut self, key: Key) -> bool {
+        if !self.scanning {
+            return false;
+        }
+
+        self.send_codes(key, false);
+        if self.repeating != Some(key) {
+            return false;
+        }
+
+        self.repeating = None;
+        true
+    }
+
+    /// Sends the make code of the key that's held down again, returning the period until the
+    /// next repeat if it's still held down.
+    pub fn repeat(&mut self) -> Option<Duration> {
+        let key = self.repeating.filter(|_| self.scanning)?;
+        self.send_codes(key, true);
+
+        Some(self.typematic_period())
+    }
+
+    /// The time between a key being pressed and it starting to repeat.
+    pub fn typematic_delay(&self) -> Duration {
+        Duration::from_millis(250 * (((self.typematic >> 5) & 0x03) as u64 + 1))
+    }
+
+    /// The time between repeats of a key that's held down.
+    pub fn typematic_period(&self) -> Duration {
+        // The period is (8 + A) * 2^B * 4.17 ms, where A is bits 0 to 2 and B is bits 3 and 4
+        let a = (self.typematic & 0x07) as u64;
+        let b = ((self.typematic >> 3) & 0x03) as u32;
+
+        Duration::from_micros((8 + a) * 2u64.pow(b) * 4_170)
+    }
+
+    /// Handles a byte that the controller sent to the keyboard, which is a command or the
+    /// parameter of the previous command.
+    pub fn write(&mut self, byte: u8) {
+        if let Some(command) = self.command.take() {
+            self.write_parameter(command, byte);
+            return;
+        }
+
+        match byte {
+            // Set LEDs, set scan code set and set typematic rate and delay
+            0xed | 0xf0 | 0xf3 => {
+                self.command = Some(byte);
+                self.respond(&[0xfa]);
+            }
+            // Echo
+            0xee => self.respond(&[0xee]),
+            // Identify, which is an MF2 keyboard
+            0xf2 => self.respond(&[0xfa, 0xab, 0x83]),
+            // Enable scanning
+            0xf4 => {
+                self.scanning = true;
+                self.respond(&[0xfa]);
+            }
+            // Disable scanning (and set defaults)
+            0xf5 => {
+                self.set_defaults();
+                self.scanning = false;
+                self.respond(&[0xfa]);
+            }
+            // Set defaults
+            0xf6 => {
+                self.set_defaults();
+                self.respond(&[0xfa]);
+            }
+            // The scan code set 3 key type commands
+            0xf7..=0xfd => self.respond(&[0xfa]),
+            // Resend
+            0xfe => {
+                let last_sent = self.last_sent;
+                self.output.push_front(last_sent);
+            }
+            // Reset, which passes the self-test
+            0xff => {
+                self.reset();
+                self.respond(&[0xfa, 0xaa]);
+            }
+            _ => self.respond(&[0xfe]),
+        }
+    }
+
+    fn write_parameter(&mut self, command: u8, parameter: u8) {
+        match command {
+            0xed => {
+                self.leds = parameter & 0x07;
+                self.respond(&[0xfa]);
+            }
+            0xf0 => match parameter {
+                0 => {
+                    let set = match self.scan_code_set {
+                        ScanCodeSet::Set1 => 1,
+                        ScanCodeSet::Set2 => 2,
+                    };
+                    self.respond(&[0xfa, set]);
+                }
+                1 => {
+                    self.scan_code_set = ScanCodeSet::Set1;
+                    self.respond(&[0xfa]);
+                }
+                2 => {
+                    self.scan_code_set = ScanCodeSet::Set2;
+                    self.respond(&[0xfa]);
+                }
+                _ => self.respond(&[0xfe]),
+            },
+            0xf3 => {
+                self.typematic = parameter & 0x7f;
+                self.respond(&[0xfa]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_defaults(&mut self) {
+        self.typematic = DEFAULT_TYPEMATIC;
+        self.repeating = None;
+        self.output.clear();
+    }
+
+    /// Sends the response to a command, which goes before any scan codes that are waiting to be
+    /// sent.
+    fn respond(&mut self, bytes: &[u8]) {
+        self.output.clear();
+        self.output.extend(bytes);
+    }
+
+    fn send_codes(&mut self, key: Key, make: bool) {
+        let codes = key.scan_codes(self.scan_code_set, make);
+        if self.output.len() + codes.len() <= BUFFER_SIZE {
+            self.output.extend(codes);
+        }
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The number of bytes that the PS/2 mouse buffers before it stops sending packets.
+const BUFFER_SIZE: usize = 16;
+
+/// The sample rates that an IntelliMouse has to be set to, in order, before it reports the ID of
+/// a mouse with a wheel.
+const INTELLIMOUSE_SEQUENCE: [u8; 3] = [200, 100, 80];
+
+/// The buttons of a mouse that are held down.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Whether a PS/2 mouse sends packets by itself or only when they're asked for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MouseMode {
+    /// Packets are sent at the sample rate while the mouse moves, if data reporting is enabled.
+    Stream,
+    /// Packets are only sent in response to the read data command.
+    Remote,
+}
+
+/// A PS/2 mouse, which is plugged into the auxiliary port of a keyboard controller.
+///
+/// Movement is accumulated until the next packet, where X is positive to the right and Y is
+/// positive upwards. After the IntelliMouse sequence of sample rates, the mouse reports ID 3 and
+/// sends a fourth byte with the wheel's movement in every packet.
+pub struct Mouse {
+    pub mode: MouseMode,
+    /// Whether the echo mode is on, where every byte the mouse gets is sent back.
+    pub wrap: bool,
+    /// Whether packets are sent in stream mode.
+    pub reporting: bool,
+    /// The number of samples per second.
+    pub sample_rate: u8,
+    /// The resolution, from 0 (1 count per mm) to 3 (8 counts per mm).
+    pub resolution: u8,
+    /// Whether movement is scaled 2:1 in stream mode.
+    pub scaling: bool,
+    /// The ID that the mouse reports, which is 3 for an IntelliMouse with a wheel.
+    pub id: u8,
+    pub buttons: Buttons,
+
+    dx: i32,
+    dy: i32,
+    dz: i32,
+    /// The buttons in the last packet, so that button changes are reported even without movement.
+    reported_buttons: Buttons,
+    /// The last three sample rates, for detecting the IntelliMouse sequence.
+    rates: [u8; 3],
+
+    output: VecDeque<u8>,
+    command: Option<u8>,
+    last_sent: u8,
+}
+
+impl Mouse {
+    pub fn new() -> Self {
+        Self {
+            mode: MouseMode::Stream,
+            wrap: false,
+            reporting: false,
+            sample_rate: 100,
+            resolution: 2,
+            scaling: false,
+            id: 0,
+            buttons: Buttons::default(),
+
+            dx: 0,
+            dy: 0,
+            dz: 0,
+            reported_buttons: Buttons::default(),
+            rates: [0; 3],
+
+            output: VecDeque::new(),
+            command: None,
+            last_sent: 0,
+        }
+    }
+
+    /// Resets the mouse to its defaults (which also turns off the wheel) and throws away anything
+    /// it hasn't sent.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Takes the next byte that the mouse wants to send.
+    pub fn next_byte(&mut self) -> Option<u8> {
+        let byte = self.output.pop_front()?;
+        self.last_sent = byte;
+
+        Some(byte)
+    }
+
+    /// Whether the mouse sends packets by itself.
+    pub fn is_streaming(&self) -> bool {
+        self.mode == MouseMode::Stream && self.reporting && !self.wrap
+    }
+
+    /// The time between samples.
+    pub fn sample_period(&self) -> Duration {
+        Duration::from_secs(1) / u32::from(self.sample_rate.max(10))
+    }
+
+    /// Moves the mouse, where `dy` is positive downwards like on the host's screen, and sets the
+    /// buttons that are held down.
+    pub fn move_by(&mut self, dx: i32, dy: i32, buttons: Buttons) {
+        self.dx = self.dx.saturating_add(dx);
+        self.dy = self.dy.saturating_sub(dy);
+        self.buttons = buttons;
+    }
+
+    /// Turns the wheel, where `dz` is positive towards the user.
+    pub fn scroll(&mut self, dz: i32) {
+        self.dz = self.dz.saturating_add(dz);
+    }
+
+    /// Sends a packet in stream mode if the mouse moved or its buttons changed since the last one.
+    /// Returns whether a packet was sent.
+    pub fn sample(&mut self) -> bool {
+        let moved = self.dx != 0 || self.dy != 0 || (self.id == 3 && self.dz != 0);
+        if !self.is_streaming() || (!moved && self.buttons == self.reported_buttons) {
+            return false;
+        }
+        if self.output.len() + self.packet_len() > BUFFER_SIZE {
+            return false;
+        }
+
+        let packet = self.take_packet(self.scaling);
+        self.output.extend(packet);
+        true
+    }
+
+    /// Handles a byte that the controller sent to the mouse, which is a command or the parameter
+    /// of the previous command.
+    pub fn write(&mut self, byte: u8) {
+        if let Some(command) = self.command.take() {
+            self.write_parameter(command, byte);
+            return;
+        }
+        if self.wrap && byte != 0xec && byte != 0xff {
+            self.output.push_back(byte);
+            return;
+        }
+
+        match byte {
+            // Set scaling to 1:1 and 2:1
+            0xe6 => {
+                self.scaling = false;
+                self.respond(&[0xfa]);
+            }
+            0xe7 => {
+                self.scaling = true;
+                self.respond(&[0xfa]);
+            }
+            // Set resolution and set sample rate
+            0xe8 | 0xf3 => {
+                self.command = Some(byte);
+                self.respond(&[0xfa]);
+            }
+            // Status request
+            0xe9 => {
+                let status = u8::from(self.buttons.right)
+                    | (u8::from(self.buttons.middle) << 1)
+                    | (u8::from(self.buttons.left) << 2)
+                    | (u8::from(self.scaling) << 4)
+                    | (u8::from(self.reporting) << 5)
+                    | (u8::from(self.mode == MouseMode::Remote) << 6);
+                self.respond(&[0xfa, status, self.resolution, self.sample_rate]);
+            }
+            // Set stream mode
+            0xea => {
+                self.mode = MouseMode::Stream;
+                self.clear_movement();
+                self.respond(&[0xfa]);
+            }
+            // Read data, which is never scaled
+            0xeb => {
+                self.respond(&[0xfa]);
+                let packet = self.take_packet(false);
+                self.output.extend(packet);
+            }
+            // Reset and set wrap mode
+            0xec => {
+                self.wrap = false;
+                self.clear_movement();
+                self.respond(&[0xfa]);
+            }
+            0xee => {
+                self.wrap = true;
+                self.clear_movement();
+                self.respond(&[0xfa]);
+            }
+            // Set remote mode
+            0xf0 => {
+                self.mode = MouseMode::Remote;
+                self.clear_movement();
+                self.respond(&[0xfa]);
+            }
+            // Get device ID
+            0xf2 => self.respond(&[0xfa, self.id]),
+            // Enable and disable data reporting
+            0xf4 => {
+                self.reporting = true;
+                self.clear_movement();
+                self.respond(&[0xfa]);
+            }
+            0xf5 => {
+                self.reporting = false;
+                self.clear_movement();
+                self.respond(&[0xfa]);
+            }
+            // Set defaults
+            0xf6 => {
+                let (id, buttons) = (self.id, self.buttons);
+                self.reset();
+                self.id = id;
+                self.buttons = buttons;
+                self.respond(&[0xfa]);
+            }
+            // Resend
+            0xfe => {
+                let last_sent = self.last_sent;
+                self.output.push_front(last_sent);
+            }
+            // Reset, which passes the self-test
+            0xff => {
+                let buttons = self.buttons;
+                self.reset();
+                self.buttons = buttons;
+                self.respond(&[0xfa, 0xaa, 0x00]);
+            }
+            _ => self.respond(&[0xfe]),
+        }
+    }
+
+    fn write_parameter(&mut self, command: u8, parameter: u8) {
+        match command {
+            0xe8 => self.resolution = parameter & 0x03,
+            0xf3 => {
+                self.sample_rate = parameter;
+                self.rates = [self.rates[1], self.rates[2], parameter];
+                if self.rates == INTELLIMOUSE_SEQUENCE {
+                    self.id = 3;
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        self.respond(&[0xfa]);
+    }
+
+    fn packet_len(&self) -> usize {
+        if self.id == 3 {
+            4
+        } else {
+            3
+        }
+    }
+
+    /// Builds a packet from the movement since the last packet.
+    fn take_packet(&mut self, scaling: bool) -> Vec<u8> {
+        let scale = |delta: i32| match delta.abs() {
+            _ if !scaling => delta,
+            0 | 1 => delta,
+            2 => delta.signum(),
+            3 => delta,
+            4 => delta * 3 / 2,
+            5 => delta * 9 / 5,
+            _ => delta.saturating_mul(2),
+        };
+        let (dx, dy) = (scale(self.dx), scale(self.dy));
+        let x_overflow = !(-256..=255).contains(&dx);
+        let y_overflow = !(-256..=255).contains(&dy);
+        let (dx, dy) = (dx.clamp(-256, 255), dy.clamp(-256, 255));
+
+        let status = u8::from(self.buttons.left)
+            | (u8::from(self.buttons.right) << 1)
+            | (u8::from(self.buttons.middle) << 2)
+            | 0x08
+            | (u8::from(dx < 0) << 4)
+            | (u8::from(dy < 0) << 5)
+            | (u8::from(x_overflow) << 6)
+            | (u8::from(y_overflow) << 7);
+        let mut packet = vec![status, dx as u8, dy as u8];
+        if self.id == 3 {
+            packet.push(self.dz.clamp(-8, 7) as u8);
+        }
+
+        self.clear_movement();
+        self.reported_buttons = self.buttons;
+        packet
+    }
+
+    fn clear_movement(&mut self) {
+        self.dx = 0;
+        self.dy = 0;
+        self.dz = 0;
+    }
+
+    /// Sends the response to a command, which replaces any packets that are waiting to be sent.
+    fn respond(&mut self, bytes: &[u8]) {
+        self.output.clear();
+        self.output.extend(bytes);
+    }
+}
+
+impl Default for Mouse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A two-button Microsoft serial mouse, which sends 3-byte packets at 1200 baud (7N1) through a
+/// UART.
+///
+/// The mouse is powered by the UART's RTS line, and it identifies itself by sending `M` whenever
+/// RTS goes high. Packets are only built when the UART asks for the next byte, so the movement
+/// between packets is accumulated like on a real mouse.
+pub struct SerialMouse {
+    pub buttons: Buttons,
+
+    dx: i32,
+    dy: i32,
+    reported_buttons: Buttons,
+    powered: bool,
+    output: VecDeque<u8>,
+}
+
+impl SerialMouse {
+    pub fn new() -> Self {
+        Self {
+            buttons: Buttons::default(),
+
+            dx: 0,
+            dy: 0,
+            reported_buttons: Buttons::default(),
+            powered: false,
+            output: VecDeque::new(),
+        }
+    }
+
+    /// Moves the mouse, where `dy` is positive downwards like on the host's screen, and sets the
+    /// buttons that are held down. The middle button isn't reported.
+    pub fn move_by(&mut self, dx: i32, dy: i32, buttons: Buttons) {
+        self.dx = self.dx.saturating_add(dx);
+        self.dy = self.dy.saturating_add(dy);
+        self.buttons = buttons;
+    }
+
+    /// Sets the RTS line of the UART, which resets the mouse when it goes high.
+    pub fn set_rts(&mut self, rts: bool) {
+        if rts && !self.powered {
+            self.dx = 0;
+            self.dy = 0;
+            self.reported_buttons = self.buttons;
+            self.output.clear();
+            self.output.push_back(b'M');
+        }
+        self.powered = rts;
+    }
+
+    /// Takes the next byte that the mouse wants to send, which starts a new packet if the last one
+    /// was sent and the mouse moved or its buttons changed.
+    pub fn next_byte(&mut self) -> Option<u8> {
+        if !self.powered {
+            return None;
+        }
+        if self.output.is_empty() {
+            self.build_packet();
+        }
+
+        self.output.pop_front()
+    }
+
+    fn build_packet(&mut self) {
+        let buttons_changed = (self.buttons.left, self.buttons.right)
+            != (self.reported_buttons.left, self.reported_buttons.right);
+        if self.dx == 0 && self.dy == 0 && !buttons_changed {
+            return;
+        }
+
+        let dx = self.dx.clamp(-128, 127);
+        let dy = self.dy.clamp(-128, 127);
+        self.dx -= dx;
+        self.dy -= dy;
+        self.reported_buttons = self.buttons;
+
+        let (dx, dy) = (dx as u8, dy as u8);
+        self.output.extend([
+            0x40 | (u8::from(self.buttons.left) << 5)
+                | (u8::from(self.buttons.right) << 4)
+                | ((dy >> 6) << 2)
+                | (dx >> 6),
+            dx & 0x3f,
+            dy & 0x3f,
+        ]);
+    }
+}
+
+impl Default for SerialMouse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_send_serial_mouse_packets() {
+        let mut mouse = SerialMouse::new();
+        mouse.move_by(3, -2, Buttons::default());
+        assert_eq!(None, mouse.next_byte());
+
+        mouse.set_rts(true);
+        assert_eq!(Some(b'M'), mouse.next_byte());
+        assert_eq!(None, mouse.next_byte());
+
+        let left = Buttons {
+            left: true,
+            ..Buttons::default()
+        };
+        mouse.move_by(3, -2, left);
+        mouse.move_by(200, 0, left);
+        let bytes = std::iter::from_fn(|| mouse.next_byte()).collect::<Vec<_>>();
+        assert_eq!(vec![0x6d, 0x3f, 0x3e, 0x61, 0x0c, 0x00], bytes);
+    }
+
+    #[test]
+    fn should_scale_stream_packets_and_report_status() {
+        let mut mouse = Mouse::new();
+        mouse.write(0xf4);
+        assert_eq!(Some(0xfa), mouse.next_byte());
+        mouse.write(0xe7);
+        assert_eq!(Some(0xfa), mouse.next_byte());
+
+        mouse.move_by(4, 5, Buttons::default());
+        assert!(mouse.sample());
+        assert!(!mouse.sample());
+        let packet = std::iter::from_fn(|| mouse.next_byte()).collect::<Vec<_>>();
+        assert_eq!(vec![0x28, 6, (-9i8) as u8], packet);
+
+        mouse.write(0xf0);
+        assert_eq!(Some(0xfa), mouse.next_byte());
+        mouse.move_by(4, 5, Buttons::default());
+        assert!(!mouse.sample());
+        mouse.write(0xe9);
+        let status = std::iter::from_fn(|| mouse.next_byte()).collect::<Vec<_>>();
+        assert_eq!(vec![0xfa, 0x70, 2, 100], status);
+        mouse.write(0xeb);
+        let packet = std::iter::from_fn(|| mouse.next_byte()).collect::<Vec<_>>();
+        assert_eq!(vec![0xfa, 0x28, 4, (-5i8) as u8], packet);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::device::DualPic;
+use crate::{Cpu, System};
+use firn_core::clock::TimerId;
+use firn_core::device::{Claims, Device, DeviceRef, PortRequest, PortResponse};
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const DATA_REG: u16 = 0;
+const STATUS_REG: u16 = 1;
+const CONTROL_REG: u16 = 2;
+
+/// The status bits that aren't connected to anything, which read as set.
+const STATUS_UNUSED: u8 = 0x07;
+/// Set when the printer has no error, since the line is active low.
+const STATUS_NO_ERROR: u8 = 0x08;
+const STATUS_SELECTED: u8 = 0x10;
+const STATUS_PAPER_OUT: u8 = 0x20;
+/// Cleared while the printer acknowledges a byte, since the line is active low.
+const STATUS_NO_ACK: u8 = 0x40;
+/// Set when the printer isn't busy, since the line is inverted.
+const STATUS_NOT_BUSY: u8 = 0x80;
+
+const CONTROL_STROBE: u8 = 0x01;
+/// Initializes the printer while it's cleared, since the line is active low.
+const CONTROL_NO_INIT: u8 = 0x04;
+const CONTROL_IRQ_ENABLE: u8 = 0x10;
+/// The bits that read as set, since they aren't latched.
+const CONTROL_UNUSED: u8 = 0xc0;
+
+/// How long the printer stays busy after a byte is strobed, which is much faster than a real
+/// printer so that printing doesn't slow down the guest.
+const BUSY_TIME: Duration = Duration::from_micros(10);
+/// How long the acknowledge pulse lasts.
+const ACK_TIME: Duration = Duration::from_micros(5);
+
+const ACK_TIMER: u64 = 0;
+const ACK_END_TIMER: u64 = 1;
+
+/// The I/O ports of a parallel port, which also set its IRQ.
+///
+/// The BIOS numbers parallel ports in the order it finds them (0x3bc, 0x378 and then 0x278), so
+/// the port on an MDA card becomes LPT1 when there is one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LptPort {
+    Lpt1,
+    Lpt2,
+    /// The port on an MDA (or Hercules) card.
+    Mda,
+}
+
+impl LptPort {
+    pub fn base(self) -> u16 {
+        match self {
+            LptPort::Lpt1 => 0x378,
+            LptPort::Lpt2 => 0x278,
+            LptPort::Mda => 0x3bc,
+        }
+    }
+
+    pub fn irq(self) -> u8 {
+        match self {
+            LptPort::Lpt1 | LptPort::Mda => 7,
+            LptPort::Lpt2 => 5,
+        }
+    }
+}
+
+/// The status lines that a printer drives, which the parallel port reports in its status
+/// register.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PrinterStatus {
+    pub selected: bool,
+    pub paper_out: bool,
+    pub error: bool,
+}
+
+impl PrinterStatus {
+    /// A printer that's online and ready to print.
+    pub const READY: Self = Self {
+        selected: true,
+        paper_out: false,
+        error: false,
+    };
+    /// Nothing plugged in, which software sees as a printer that's offline.
+    pub const OFFLINE: Self = Self {
+        selected: false,
+        paper_out: false,
+        error: true,
+    };
+}
+
+/// The printer (or other device) that's plugged into a parallel port.
+pub trait ParallelBackend: Any + Send {
+    /// Handles a byte that was strobed while the printer was ready.
+    fn write(&mut self, byte: u8);
+
+    /// Handles the init line being pulled low, which resets a printer.
+    fn initialize(&mut self) {}
+
+    /// The printer's status lines, which are ready by default.
+    fn status(&self) -> PrinterStatus {
+        PrinterStatus::READY
+    }
+}
+
+/// Nothing plugged into the parallel port.
+pub struct NullPrinter;
+
+impl ParallelBackend for NullPrinter {
+    fn write(&mut self, _byte: u8) {}
+
+    fn status(&self) -> PrinterStatus {
+        PrinterStatus::OFFLINE
+    }
+}
+
+/// A printer that writes everything it prints to a file, including any control codes.
+pub struct FilePrinter {
+    output: File,
+}
+
+impl FilePrinter {
+    /// Creates (or truncates) the output file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            output: File::create(path)?,
+        })
+    }
+}
+
+impl ParallelBackend for FilePrinter {
+    fn write(&mut self, byte: u8) {
+        // Writes aren't buffered so that the file can be followed while the guest runs
+        let _ = self.output.write_all(&[byte]);
+    }
+}
+
+/// A printer that captures what it prints in memory, so that tests can check it (see
+/// [`ParallelPort::backend_mut`]).
+#[derive(Default)]
+pub struct CapturePrinter {
+    output: Vec<u8>,
+}
+
+impl CapturePrinter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything that has been printed since the last call to [`take_output`].
+    ///
+    /// [`take_output`]: CapturePrinter::take_output
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl ParallelBackend for CapturePrinter {
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+/// A PC parallel port, which sends bytes to a backend (usually a printer) when software strobes
+/// them.
+///
+/// The printer is busy for a short time after every byte and then acknowledges it, which raises
+/// an interrupt if the control register enables it.
+pub struct ParallelPort {
+    pic: DeviceRef<DualPic>,
+    pub port: LptPort,
+    backend: Box<dyn ParallelBackend>,
+
+    pub data: u8,
+    pub control: u8,
+    busy: bool,
+    acknowledging: bool,
+    timer: Option<TimerId>,
+}
+
+impl ParallelPort {
+    pub fn new(pic: DeviceRef<DualPic>, port: LptPort, backend: impl ParallelBackend) -> Self {
+        Self {
+            pic,
+            port,
+            backend: Box::new(backend),
+
+            data: 0,
+            control: 0,
+            busy: false,
+            acknowledging: false,
+            timer: None,
+        }
+    }
+
+    /// The backend, if it's a `B`.
+    pub fn backend_mut<B>(&mut self) -> Option<&mut B>
+    where
+        B: ParallelBackend,
+    {
+        let any: &mut dyn Any = &mut *self.backend;
+
+        any.downcast_mut()
+    }
+
+    /// Replaces the backend, which is like plugging in another printer.
+    pub fn set_backend(&mut self, backend: impl ParallelBackend) {
+        self.backend = Box::new(backend);
+    }
+
+    pub fn status(&self) -> u8 {
+        let printer = self.backend.status();
+        let mut status = STATUS_UNUSED;
+        if !printer.error {
+            status |= STATUS_NO_ERROR;
+        }
+        if printer.selected {
+            status |= STATUS_SELECTED;
+        }
+        if printer.paper_out {
+            status |= STATUS_PAPER_OUT;
+        }
+        if !self.acknowledging {
+            status |= STATUS_NO_ACK;
+        }
+        if !self.busy {
+            status |= STATUS_NOT_BUSY;
+        }
+
+        status
+    }
+
+    fn write_control(&mut self, sys: &mut System, value: u8) {
+        let previous = std::mem::replace(&mut self.control, value & !CONTROL_UNUSED);
+        let asserted = value & !previous;
+
+        if previous & CONTROL_NO_INIT != 0 && value & CONTROL_NO_INIT == 0 {
+            self.backend.initialize();
+        }
+
+        let printer = self.backend.status();
+        let ready = printer.selected && !printer.error && !printer.paper_out;
+        if asserted & CONTROL_STROBE != 0 && ready && !self.busy {
+            self.backend.write(self.data);
+            self.busy = true;
+            self.set_timer(sys, BUSY_TIME, ACK_TIMER);
+        }
+    }
+
+    fn set_timer(&mut self, sys: &mut System, delay: Duration, token: u64) {
+        let at = sys.clock.now() + delay;
+        self.timer = Some(sys.set_timer(at, token));
+    }
+}
+
+impl Device<Cpu> for ParallelPort {
+    fn reset(&mut self, sys: &mut System) {
+        if let Some(timer) = self.timer.take() {
+            sys.cancel_timer(timer);
+        }
+
+        self.data = 0;
+        self.control = 0;
+        self.busy = false;
+        self.acknowledging = false;
+    }
+
+    fn timer(&mut self, sys: &mut System, token: u64) {
+        self.timer = None;
+
+        if token == ACK_TIMER {
+            self.busy = false;
+            self.acknowledging = true;
+            self.set_timer(sys, ACK_TIME, ACK_END_TIMER);
+        } else {
+            self.acknowledging = false;
+            // The interrupt is raised at the end of the acknowledge pulse
+            if self.control & CONTROL_IRQ_ENABLE != 0 {
+                let irq = self.port.irq();
+                sys.with_device(self.pic, |pic, _| pic.submit_irq(irq));
+            }
+        }
+    }
+
+    fn handle_port(&mut self, sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        let base = self.port.base();
+
+        match request {
+            PortRequest::In8(port) if port == base + DATA_REG => Some(PortResponse::In8(self.data)),
+            PortRequest::In8(port) if port == base + STATUS_REG => {
+                Some(PortResponse::In8(self.status()))
+            }
+            PortRequest::In8(port) if port == base + CONTROL_REG => {
+                Some(PortResponse::In8(self.control | CONTROL_UNUSED))
+            }
+            PortRequest::Out8(port, value) if port == base + DATA_REG => {
+                self.data = value;
+                Some(PortResponse::Out)
+            }
+            // The status register is read-only
+            PortRequest::Out8(port, _) if port == base + STATUS_REG => Some(PortResponse::Out),
+            PortRequest::Out8(port, value) if port == base + CONTROL_REG => {
+                self.write_control(sys, value);
+                Some(PortResponse::Out)
+            }
+            _ => None,
+        }
+    }
+
+    fn claims(&self) -> Claims {
+        let base = self.port.base();
+
+        Claims {
+            ports: vec![base..=base + CONTROL_REG],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        Some(format!(
+            "{:?}: data {:#04x}, status {:#04x}, control {:#04x}",
+            self.port,
+            self.data,
+            self.status(),
+            self.control | CONTROL_UNUSED,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+    use crate::device::tests::run_for;
+
+    const LPT1: u16 = 0x378;
+
+    fn create_sys(
+        backend: impl ParallelBackend,
+    ) -> (System, DeviceRef<DualPic>, DeviceRef<ParallelPort>) {
+        device::tests::create_sys(|_, pic| ParallelPort::new(pic, LptPort::Lpt1, backend))
+    }
+
+    /// Prints like the BIOS does, by waiting until the printer isn't busy and strobing each byte.
+    fn print(sys: &mut System, bytes: &[u8]) {
+        for byte in bytes {
+            while sys.port_in_8(LPT1 + 1).unwrap() & STATUS_NOT_BUSY == 0 {
+                sys.step();
+            }
+            let control = sys.port_in_8(LPT1 + 2).unwrap();
+            sys.port_out_8(LPT1, *byte).unwrap();
+            sys.port_out_8(LPT1 + 2, control | CONTROL_STROBE).unwrap();
+            sys.port_out_8(LPT1 + 2, control).unwrap();
+        }
+    }
+
+    #[test]
+    fn should_capture_printed_bytes_with_handshake() {
+        let (mut sys, _, lpt) = create_sys(CapturePrinter::new());
+        sys.port_out_8(LPT1 + 2, 0x08).unwrap();
+        sys.port_out_8(LPT1 + 2, 0x0c).unwrap();
+        assert_eq!(Some(0xdf), sys.port_in_8(LPT1 + 1));
+        assert_eq!(Some(0xcc), sys.port_in_8(LPT1 + 2));
+
+        sys.port_out_8(LPT1, b'A').unwrap();
+        assert_eq!(Some(b'A'), sys.port_in_8(LPT1));
+        sys.port_out_8(LPT1 + 2, 0x0d).unwrap();
+        assert_eq!(Some(0x5f), sys.port_in_8(LPT1 + 1));
+        run_for(&mut sys, BUSY_TIME);
+        assert_eq!(Some(0x9f), sys.port_in_8(LPT1 + 1));
+        run_for(&mut sys, ACK_TIME);
+        assert_eq!(Some(0xdf), sys.port_in_8(LPT1 + 1));
+
+        sys.port_out_8(LPT1 + 2, 0x0c).unwrap();
+        print(&mut sys, b"REPORT\r\n\x0c");
+        let printer = sys.get_device_mut(lpt).backend_mut::<CapturePrinter>();
+        assert_eq!(b"AREPORT\r\n\x0c".to_vec(), printer.unwrap().take_output());
+    }
+
+    #[test]
+    fn should_raise_irq_on_acknowledge_when_enabled() {
+        let (mut sys, pic, _) = create_sys(CapturePrinter::new());
+        sys.port_out_8(LPT1 + 2, 0x0c).unwrap();
+        print(&mut sys, b"x");
+        run_for(&mut sys, (BUSY_TIME + ACK_TIME) * 2);
+        assert_eq!(0, sys.get_device(pic).master.request_reg);
+
+        sys.port_out_8(LPT1 + 2, 0x0c | CONTROL_IRQ_ENABLE).unwrap();
+        print(&mut sys, b"y");
+        run_for(&mut sys, (BUSY_TIME + ACK_TIME) * 2);
+        assert_eq!(0x80, sys.get_device(pic).master.request_reg);
+    }
+
+    #[test]
+    fn should_report_offline_without_printer() {
+        let (mut sys, _, _) = create_sys(NullPrinter);
+        sys.port_out_8(LPT1 + 2, 0x0c).unwrap();
+        assert_eq!(Some(0xc7), sys.port_in_8(LPT1 + 1));
+        // Strobing doesn't make an offline printer busy
+        sys.port_out_8(LPT1 + 2, 0x0d).unwrap();
+        assert_eq!(Some(0xc7), sys.port_in_8(LPT1 + 1));
+    }
+}
@@ -1,25 +1,38 @@
 use crate::{Cpu, System};
-use firn_core::device::{Device, PortRequest, PortResponse};
+use firn_core::device::{Claims, Device, PortRequest, PortResponse};
 
 pub const MASTER_COMMAND_PORT: u16 = 0x20;
 pub const MASTER_DATA_PORT: u16 = 0x21;
 pub const SLAVE_COMMAND_PORT: u16 = 0xa0;
 pub const SLAVE_DATA_PORT: u16 = 0xa1;
 
+/// The IRQ of the master that the slave is connected to on a PC.
+pub const CASCADE_IRQ: u8 = 2;
+
 #[derive(Eq, PartialEq)]
 pub enum PicType {
     Master,
     Slave,
 }
 
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 enum InitControlWord {
-    Icw1,
     Icw2,
     Icw3,
     Icw4,
 }
 
+/// An Intel 8259A programmable interrupt controller.
+///
+/// Devices drive its IRQ lines with [`raise_irq`] and [`lower_irq`] (or pulse them with
+/// [`submit_irq`]), and the CPU checks [`interrupt`] and calls [`acknowledge`] to get the vector of
+/// the interrupt to service.
+///
+/// [`raise_irq`]: Pic::raise_irq
+/// [`lower_irq`]: Pic::lower_irq
+/// [`submit_irq`]: Pic::submit_irq
+/// [`interrupt`]: Pic::interrupt
+/// [`acknowledge`]: Pic::acknowledge
 pub struct Pic {
     pub pic_type: PicType,
     pub vector_offset: u8,
@@ -27,14 +40,31 @@ pub struct Pic {
     pub request_reg: u8,
     pub in_service_reg: u8,
     pub mask_reg: u8,
+    /// The levels of the IRQ lines, which are used to detect edges.
+    pub line_reg: u8,
 
-    awaiting_icw: InitControlWord,
-    expecting_icw: InitControlWord,
+    /// The next initialization command word that's expected, or `None` if the PIC isn't being
+    /// initialized.
+    awaiting_icw: Option<InitControlWord>,
+    single: bool,
+    expecting_icw4: bool,
 
-    pub ocw2: u8,
-    pub ocw3: u8,
+    /// Whether requests follow the level of the lines instead of their rising edges (LTIM).
+    pub level_triggered: bool,
+    /// The IRQs that slaves are connected to on a master, or the ID of a slave (ICW3).
+    pub cascade: u8,
+    pub auto_eoi: bool,
+    pub special_fully_nested: bool,
 
-    priority: u8,
+    /// Whether reads from the command port return the ISR instead of the IRR.
+    pub read_isr: bool,
+    pub special_mask: bool,
+    /// Whether the next read is a poll.
+    pub poll: bool,
+    /// Whether priorities are rotated when an interrupt is automatically ended.
+    pub rotate_on_auto_eoi: bool,
+    /// The IRQ with the lowest priority, which is rotated to change the priorities.
+    pub lowest_priority: u8,
 }
 
 impl Pic {
@@ -46,109 +76,298 @@ impl Pic {
             request_reg: 0,
             in_service_reg: 0,
             mask_reg: 0,
+            line_reg: 0,
+
+            awaiting_icw: None,
+            single: false,
+            expecting_icw4: false,
+
+            level_triggered: false,
+            cascade: 0,
+            auto_eoi: false,
+            special_fully_nested: false,
 
-            awaiting_icw: InitControlWord::Icw1,
-            expecting_icw: InitControlWord::Icw2,
-            ocw2: 0,
-            ocw3: 0,
-            priority: 0,
+            read_isr: false,
+            special_mask: false,
+            poll: false,
+            rotate_on_auto_eoi: false,
+            lowest_priority: 7,
         }
     }
 
+    /// Puts the PIC back in its power-on state, which needs to be initialized again.
+    pub fn reset(&mut self) {
+        let pic_type = std::mem::replace(&mut self.pic_type, PicType::Master);
+        *self = Pic::new(pic_type);
+    }
+
+    /// Pulses an IRQ line, which requests an interrupt if the PIC is edge triggered.
     pub fn submit_irq(&mut self, irq: u8) {
+        self.raise_irq(irq);
+        self.lower_irq(irq);
+    }
+
+    pub fn raise_irq(&mut self, irq: u8) {
         assert!(irq < 8);
-        self.request_reg |= 1 << irq;
+        let bit = 1 << irq;
+        if self.level_triggered || self.line_reg & bit == 0 {
+            self.request_reg |= bit;
+        }
+        self.line_reg |= bit;
     }
 
-    fn handle_command(&mut self, command: u8) {
-        let init = command & 0x10 != 0;
-        if init {
-            let expecting_icw_4 = command & 0x1 != 0;
-            let single_pic = command & 0x2 != 0;
-            if expecting_icw_4 {
-                self.expecting_icw = InitControlWord::Icw4;
-            } else if !single_pic {
-                self.expecting_icw = InitControlWord::Icw3;
+    pub fn lower_irq(&mut self, irq: u8) {
+        assert!(irq < 8);
+        let bit = 1 << irq;
+        if self.level_triggered {
+            self.request_reg &= !bit;
+        }
+        self.line_reg &= !bit;
+    }
+
+    /// The IRQs in priority order, from highest to lowest.
+    fn priorities(&self) -> impl Iterator<Item = u8> {
+        let lowest = self.lowest_priority;
+
+        (1..=8).map(move |offset| (lowest + offset) & 7)
+    }
+
+    /// The IRQ that would be serviced next, if there's one that isn't masked and has a higher
+    /// priority than the ones being serviced.
+    pub fn pending(&self) -> Option<u8> {
+        let requests = self.request_reg & !self.mask_reg;
+        // Masked levels don't block lower priorities in special mask mode
+        let blocking = if self.special_mask {
+            self.in_service_reg & !self.mask_reg
+        } else {
+            self.in_service_reg
+        };
+
+        for irq in self.priorities() {
+            let bit = 1 << irq;
+            let cascaded = self.special_fully_nested && self.cascade & bit != 0;
+            if blocking & bit != 0 && !(cascaded && requests & bit != 0) {
+                // In special mask mode, other levels can be serviced at any priority
+                if self.special_mask {
+                    continue;
+                }
+                return None;
+            }
+            if requests & bit != 0 {
+                return Some(irq);
             }
+        }
+
+        None
+    }
+
+    /// Whether the PIC's interrupt output is asserted.
+    pub fn interrupt(&self) -> bool {
+        self.pending().is_some()
+    }
+
+    /// Acknowledges the interrupt that's pending and returns its IRQ, or IRQ 7 if there's none
+    /// (which is a spurious interrupt that isn't marked as in service).
+    pub fn acknowledge_irq(&mut self) -> u8 {
+        let Some(irq) = self.pending() else {
+            return 7;
+        };
 
-            self.awaiting_icw = InitControlWord::Icw2;
-            return;
+        let bit = 1 << irq;
+        if !self.level_triggered {
+            self.request_reg &= !bit;
         }
-        
-        let ocw3 = command & 0x08 != 0;
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.lowest_priority = irq;
+            }
+        } else {
+            self.in_service_reg |= bit;
+        }
+
+        irq
+    }
 
-        if ocw3 {
-            self.ocw3 = command;
+    /// Acknowledges the interrupt that's pending and returns its vector (see [`acknowledge_irq`]).
+    ///
+    /// [`acknowledge_irq`]: Pic::acknowledge_irq
+    pub fn acknowledge(&mut self) -> u8 {
+        self.vector_offset + self.acknowledge_irq()
+    }
+
+    fn handle_command(&mut self, command: u8) {
+        if command & 0x10 != 0 {
+            self.handle_icw1(command);
+        } else if command & 0x08 != 0 {
+            self.handle_ocw3(command);
+        } else {
+            self.handle_ocw2(command);
         }
-        else {
-            self.ocw2 = command;
+    }
 
-            if self.ocw2 & 0x60 != 0 {
-                if self.ocw2 & 0x40 != 0 {
-                    let eoi = self.ocw2 & 0x20 != 0;
-                    let rotate = self.ocw2 & 0x80 != 0;
+    /// Starts initializing the PIC, which resets it first.
+    fn handle_icw1(&mut self, command: u8) {
+        self.reset();
 
-                    let irq = self.ocw2 & 0x07;
-                    let b: u8 = 1 << irq;
+        self.expecting_icw4 = command & 0x01 != 0;
+        self.single = command & 0x02 != 0;
+        self.level_triggered = command & 0x08 != 0;
+        self.awaiting_icw = Some(InitControlWord::Icw2);
+    }
 
-                    if eoi {
-                        self.in_service_reg &= !b;
-                    }
-                    
-                    if rotate {
-                        self.priority = irq.wrapping_add(1) & 7;
+    fn handle_ocw2(&mut self, command: u8) {
+        let irq = command & 0x07;
+        match command >> 5 {
+            // Non-specific EOI, optionally rotating
+            0b001 | 0b101 => {
+                let serviced = self
+                    .priorities()
+                    .find(|irq| self.in_service_reg & (1 << irq) != 0);
+                if let Some(serviced) = serviced {
+                    self.in_service_reg &= !(1 << serviced);
+                    if command & 0x80 != 0 {
+                        self.lowest_priority = serviced;
                     }
                 }
             }
+            // Specific EOI, optionally rotating
+            0b011 | 0b111 => {
+                self.in_service_reg &= !(1 << irq);
+                if command & 0x80 != 0 {
+                    self.lowest_priority = irq;
+                }
+            }
+            0b100 => self.rotate_on_auto_eoi = true,
+            0b000 => self.rotate_on_auto_eoi = false,
+            0b110 => self.lowest_priority = irq,
+            _ => {}
+        }
+    }
+
+    fn handle_ocw3(&mut self, command: u8) {
+        match command & 0x60 {
+            0x60 => self.special_mask = true,
+            0x40 => self.special_mask = false,
+            _ => {}
+        }
+        self.poll = command & 0x04 != 0;
+        if command & 0x02 != 0 {
+            self.read_isr = command & 0x01 != 0;
+        }
+    }
+
+    /// Reads the poll byte if a poll was requested, which acknowledges the interrupt that's
+    /// pending.
+    fn read_poll(&mut self) -> Option<u8> {
+        if !std::mem::take(&mut self.poll) {
+            return None;
+        }
+
+        match self.pending() {
+            Some(_) => Some(0x80 | self.acknowledge_irq()),
+            None => Some(0),
+        }
+    }
+
+    fn handle_command_read(&mut self) -> u8 {
+        if let Some(poll) = self.read_poll() {
+            return poll;
+        }
+
+        if self.read_isr {
+            self.in_service_reg
+        } else {
+            self.request_reg
         }
     }
 
     fn handle_data_read(&mut self) -> u8 {
+        if let Some(poll) = self.read_poll() {
+            return poll;
+        }
+
         self.mask_reg
     }
 
     fn handle_data_write(&mut self, data: u8) {
         match self.awaiting_icw {
-            InitControlWord::Icw1 => self.mask_reg = data,
-            InitControlWord::Icw2 => {
-                self.vector_offset = data;
-                self.await_icw_if_expected(InitControlWord::Icw3);
+            None => self.mask_reg = data,
+            Some(InitControlWord::Icw2) => {
+                self.vector_offset = data & 0xf8;
+                self.await_icw_after(InitControlWord::Icw2);
             }
-            InitControlWord::Icw3 => self.await_icw_if_expected(InitControlWord::Icw4),
-            InitControlWord::Icw4 => {
-                self.awaiting_icw = InitControlWord::Icw1;
-                self.expecting_icw = InitControlWord::Icw2;
-
-                todo!("handle ICW4");
+            Some(InitControlWord::Icw3) => {
+                self.cascade = data;
+                self.await_icw_after(InitControlWord::Icw3);
+            }
+            Some(InitControlWord::Icw4) => {
+                self.auto_eoi = data & 0x02 != 0;
+                self.special_fully_nested = data & 0x10 != 0;
+                self.awaiting_icw = None;
             }
         }
     }
 
-    fn await_icw_if_expected(&mut self, icw: InitControlWord) {
-        if icw > self.expecting_icw {
-            self.awaiting_icw = InitControlWord::Icw1;
-            self.expecting_icw = InitControlWord::Icw2;
-        } else {
-            self.awaiting_icw = icw;
-        }
+    fn await_icw_after(&mut self, icw: InitControlWord) {
+        self.awaiting_icw = match icw {
+            InitControlWord::Icw2 if !self.single => Some(InitControlWord::Icw3),
+            InitControlWord::Icw2 | InitControlWord::Icw3 if self.expecting_icw4 => {
+                Some(InitControlWord::Icw4)
+            }
+            _ => None,
+        };
     }
-}
 
-impl Device<Cpu> for Pic {
-    fn handle_port(&mut self, _sys: &mut System, request: PortRequest) -> Option<PortResponse> {
-        let (command_port, data_port): (u16, u16) = match self.pic_type {
+    fn dump(&self) -> String {
+        let name = match self.pic_type {
+            PicType::Master => "master",
+            PicType::Slave => "slave",
+        };
+
+        format!(
+            "PIC ({}): IRR {:#010b}, ISR {:#010b}, IMR {:#010b}, vector offset {:#04x}, \
+             lowest priority {}, {} triggered{}{}",
+            name,
+            self.request_reg,
+            self.in_service_reg,
+            self.mask_reg,
+            self.vector_offset,
+            self.lowest_priority,
+            if self.level_triggered {
+                "level"
+            } else {
+                "edge"
+            },
+            if self.auto_eoi { ", auto EOI" } else { "" },
+            if self.special_mask {
+                ", special mask"
+            } else {
+                ""
+            }
+        )
+    }
+
+    fn ports(&self) -> (u16, u16) {
+        match self.pic_type {
             PicType::Master => (MASTER_COMMAND_PORT, MASTER_DATA_PORT),
             PicType::Slave => (SLAVE_COMMAND_PORT, SLAVE_DATA_PORT),
-        };
+        }
+    }
+
+    /// Handles a request to one of the PIC's ports.
+    fn handle_port(&mut self, request: PortRequest) -> Option<PortResponse> {
+        let (command_port, data_port) = self.ports();
 
         match request {
+            PortRequest::In8(port) if port == command_port => {
+                Some(PortResponse::In8(self.handle_command_read()))
+            }
             PortRequest::Out8(port, command) if port == command_port => {
                 self.handle_command(command);
                 Some(PortResponse::Out)
             }
             PortRequest::In8(port) if port == data_port => {
-                let data = self.handle_data_read();
-                Some(PortResponse::In8(data))
+                Some(PortResponse::In8(self.handle_data_read()))
             }
             PortRequest::Out8(port, data) if port == data_port => {
                 self.handle_data_write(data);
@@ -159,6 +378,31 @@ impl Device<Cpu> for Pic {
     }
 }
 
+impl Device<Cpu> for Pic {
+    fn reset(&mut self, _sys: &mut System) {
+        Pic::reset(self);
+    }
+
+    fn handle_port(&mut self, _sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        Pic::handle_port(self, request)
+    }
+
+    fn claims(&self) -> Claims {
+        let (command_port, data_port) = self.ports();
+
+        Claims {
+            ports: vec![command_port..=data_port],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        Some(Pic::dump(self))
+    }
+}
+
+/// The two cascaded PICs of a PC/AT, where the slave handles IRQs 8 to 15 and is connected to IRQ
+/// 2 of the master.
 pub struct DualPic {
     pub master: Pic,
     pub slave: Pic,
@@ -172,48 +416,95 @@ impl DualPic {
         }
     }
 
+    /// Pulses an IRQ line (see [`Pic::submit_irq`]).
+    ///
+    /// [`Pic::submit_irq`]: Pic::submit_irq
     pub fn submit_irq(&mut self, irq: u8) {
+        self.raise_irq(irq);
+        self.lower_irq(irq);
+    }
+
+    pub fn raise_irq(&mut self, irq: u8) {
         if irq < 8 {
-            self.master.submit_irq(irq);
+            self.master.raise_irq(irq);
+        } else {
+            self.slave.raise_irq(irq - 8);
+            self.update_cascade();
+        }
+    }
+
+    pub fn lower_irq(&mut self, irq: u8) {
+        if irq < 8 {
+            self.master.lower_irq(irq);
+        } else {
+            self.slave.lower_irq(irq - 8);
+            self.update_cascade();
+        }
+    }
+
+    /// Whether the master's interrupt output, which is connected to the CPU, is asserted.
+    pub fn interrupt(&self) -> bool {
+        self.master.interrupt()
+    }
+
+    /// Acknowledges the interrupt that's pending and returns its vector, which comes from the
+    /// slave if the master's IRQ is the cascade.
+    pub fn acknowledge(&mut self) -> u8 {
+        let irq = self.master.acknowledge_irq();
+        if irq != CASCADE_IRQ {
+            return self.master.vector_offset + irq;
+        }
+
+        let vector = self.slave.acknowledge();
+        // The slave's interrupt output drops during the acknowledge, so that another request from
+        // it is a new edge
+        self.master.lower_irq(CASCADE_IRQ);
+        self.update_cascade();
+
+        vector
+    }
+
+    /// Connects the slave's interrupt output to the master.
+    fn update_cascade(&mut self) {
+        if self.slave.interrupt() {
+            self.master.raise_irq(CASCADE_IRQ);
         } else {
-            let irq = irq - 8;
-            self.slave.submit_irq(irq);
+            self.master.lower_irq(CASCADE_IRQ);
         }
     }
 }
 
 impl Device<Cpu> for DualPic {
+    fn reset(&mut self, _sys: &mut System) {
+        self.master.reset();
+        self.slave.reset();
+    }
+
     fn handle_port(&mut self, _sys: &mut System, request: PortRequest) -> Option<PortResponse> {
-        match request {
-            PortRequest::Out8(port, command) if port == MASTER_COMMAND_PORT => {
-                self.master.handle_command(command);
-                Some(PortResponse::Out)
-            }
-            PortRequest::In8(port) if port == MASTER_DATA_PORT => {
-                let data = self.master.handle_data_read();
-                Some(PortResponse::In8(data))
-            }
-            PortRequest::Out8(port, data) if port == MASTER_DATA_PORT => {
-                self.master.handle_data_write(data);
-                Some(PortResponse::Out)
-            }
+        let response = self
+            .master
+            .handle_port(request)
+            .or_else(|| self.slave.handle_port(request));
+        // Initialization, masks, EOIs and polls change whether the slave's interrupt gets to the
+        // master
+        self.update_cascade();
 
-            PortRequest::Out8(port, command) if port == SLAVE_COMMAND_PORT => {
-                self.slave.handle_command(command);
-                Some(PortResponse::Out)
-            }
-            PortRequest::In8(port) if port == SLAVE_DATA_PORT => {
-                let data = self.slave.handle_data_read();
-                Some(PortResponse::In8(data))
-            }
-            PortRequest::Out8(port, data) if port == SLAVE_DATA_PORT => {
-                self.slave.handle_data_write(data);
-                Some(PortResponse::Out)
-            }
+        response
+    }
 
-            _ => None,
+    fn claims(&self) -> Claims {
+        Claims {
+            ports: vec![
+                MASTER_COMMAND_PORT..=MASTER_DATA_PORT,
+                SLAVE_COMMAND_PORT..=SLAVE_DATA_PORT,
+            ],
+            mem: Vec::new(),
         }
     }
+
+    fn dump(&self) -> Option<String> {
+        Some(format!("{}\n{}", self.master.dump(), self.slave.dump()))
+    }
 }
 
 impl Default for DualPic {
@@ -221,3 +512,92 @@ impl Default for DualPic {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Initializes a PIC with ICW1, ICW2, ICW3 (if it's cascaded) and ICW4.
+    fn init(pic: &mut Pic, icw1: u8, vector_offset: u8, cascade: u8, icw4: u8) {
+        pic.handle_command(icw1);
+        pic.handle_data_write(vector_offset);
+        if icw1 & 0x02 == 0 {
+            pic.handle_data_write(cascade);
+        }
+        pic.handle_data_write(icw4);
+    }
+
+    #[test]
+    fn should_acknowledge_by_priority() {
+        let mut pic = Pic::new(PicType::Master);
+        init(&mut pic, 0x13, 0x08, 0, 0x01);
+        pic.raise_irq(3);
+        pic.raise_irq(1);
+
+        assert_eq!(0x09, pic.acknowledge());
+        assert!(!pic.interrupt());
+        pic.handle_command(0x0b);
+        assert_eq!(0x02, pic.handle_command_read());
+
+        pic.handle_command(0x20);
+        assert_eq!(0x0b, pic.acknowledge());
+        assert_eq!(0x08, pic.handle_command_read());
+        assert_eq!(0x0f, pic.acknowledge());
+    }
+
+    #[test]
+    fn should_rotate_priorities_with_auto_eoi() {
+        let mut pic = Pic::new(PicType::Master);
+        init(&mut pic, 0x13, 0x08, 0, 0x03);
+        pic.handle_command(0x80);
+        pic.submit_irq(0);
+        pic.submit_irq(1);
+
+        assert_eq!(0x08, pic.acknowledge());
+        pic.submit_irq(0);
+        assert_eq!(0x09, pic.acknowledge());
+        assert_eq!(0x08, pic.acknowledge());
+        assert_eq!(0, pic.in_service_reg);
+    }
+
+    #[test]
+    fn should_poll_level_triggered_irqs_and_use_special_mask() {
+        let mut pic = Pic::new(PicType::Master);
+        init(&mut pic, 0x1b, 0x08, 0, 0x01);
+        pic.raise_irq(5);
+
+        pic.handle_command(0x0c);
+        assert_eq!(0x85, pic.handle_command_read());
+        assert_eq!(0x20, pic.request_reg);
+        assert_eq!(0x20, pic.in_service_reg);
+        pic.lower_irq(5);
+        assert_eq!(0, pic.request_reg);
+
+        pic.raise_irq(6);
+        assert_eq!(None, pic.pending());
+        pic.handle_data_write(0x20);
+        pic.handle_command(0x68);
+        assert_eq!(Some(6), pic.pending());
+    }
+
+    #[test]
+    fn should_cascade_slave_irqs_through_master() {
+        let mut pic = DualPic::new();
+        init(&mut pic.master, 0x11, 0x08, 0x04, 0x01);
+        init(&mut pic.slave, 0x11, 0x70, 0x02, 0x01);
+        pic.update_cascade();
+
+        pic.submit_irq(8);
+        pic.submit_irq(12);
+        assert!(pic.interrupt());
+        assert_eq!(0x70, pic.acknowledge());
+        assert_eq!(0x04, pic.master.in_service_reg);
+        assert_eq!(0x01, pic.slave.in_service_reg);
+        assert!(!pic.interrupt());
+
+        pic.slave.handle_command(0x20);
+        pic.master.handle_command(0x20);
+        pic.update_cascade();
+        assert_eq!(0x74, pic.acknowledge());
+    }
+}
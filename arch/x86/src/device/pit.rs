@@ -0,0 +1,668 @@
+use crate::device::DualPic;
+use crate::{Cpu, System};
+use firn_core::clock::TimerId;
+use firn_core::device::{Claims, Device, DeviceRef, PortRequest, PortResponse};
+use std::time::Duration;
+
+pub const CHANNEL_0_PORT: u16 = 0x40;
+pub const CHANNEL_1_PORT: u16 = 0x41;
+pub const CHANNEL_2_PORT: u16 = 0x42;
+pub const COMMAND_PORT: u16 = 0x43;
+
+/// The frequency that the counters are clocked at, in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// The counter that drives IRQ0, which is the system tick.
+pub const SYSTEM_TIMER: usize = 0;
+/// The counter that's used for DRAM refresh, which isn't connected to anything.
+pub const REFRESH: usize = 1;
+/// The counter that feeds the PC speaker.
+pub const SPEAKER: usize = 2;
+
+/// How the count of a channel is read and written.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Access {
+    Lsb,
+    Msb,
+    LsbThenMsb,
+}
+
+/// A counter of the PIT.
+///
+/// Counters aren't decremented every clock. Instead, the number of clocks that have been counted
+/// since the counter was loaded is worked out from virtual time whenever it's needed, and the count
+/// and output are derived from that.
+pub struct Channel {
+    pub mode: u8,
+    pub bcd: bool,
+    pub access: Access,
+    /// The count register, as it was written (so it's in BCD if the channel counts in BCD).
+    pub count: u16,
+    pub gate: bool,
+
+    /// The number of clocks between reloads, which comes from the count when it's loaded into
+    /// the counting element.
+    modulus: u64,
+    /// The modulus of the period that's ending before a new count is loaded (see
+    /// `load_at_period_end`).
+    previous_modulus: u64,
+    /// Whether a count has been written since the mode was set.
+    armed: bool,
+    /// Whether a gate trigger has started counting, in modes 1 and 5.
+    triggered: bool,
+    null_count: bool,
+    /// The number of clocks counted until `running_since`.
+    elapsed: u64,
+    /// The clock that the counter has been counting since, or `None` if it's stopped.
+    running_since: Option<u64>,
+
+    count_latch: Option<u16>,
+    status_latch: Option<u8>,
+    /// Whether the next byte read or written is the MSB, for channels that access both.
+    read_msb: bool,
+    write_msb: bool,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Self {
+            mode: 0,
+            bcd: false,
+            access: Access::LsbThenMsb,
+            count: 0,
+            gate: true,
+
+            modulus: 0x10000,
+            previous_modulus: 0x10000,
+            armed: false,
+            triggered: false,
+            null_count: true,
+            elapsed: 0,
+            running_since: None,
+
+            count_latch: None,
+            status_latch: None,
+            read_msb: false,
+            write_msb: false,
+        }
+    }
+
+    /// The number of clocks counted at clock `now`.
+    fn elapsed(&self, now: u64) -> u64 {
+        self.elapsed
+            + self
+                .running_since
+                .map_or(0, |since| now.saturating_sub(since))
+    }
+
+    /// Whether the counter counts at all, without taking the gate into account.
+    fn counting(&self) -> bool {
+        match self.mode {
+            1 | 5 => self.triggered,
+            _ => self.armed,
+        }
+    }
+
+    /// Starts or stops counting to match the gate and mode.
+    fn update_running(&mut self, now: u64) {
+        let running = self.counting() && (self.gate || matches!(self.mode, 1 | 5));
+        match (running, self.running_since) {
+            (true, None) => self.running_since = Some(now),
+            (false, Some(since)) => {
+                self.elapsed += now.saturating_sub(since);
+                self.running_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Loads the count register into the counting element and starts counting from it.
+    fn load(&mut self, now: u64) {
+        self.modulus = match (self.count, self.bcd) {
+            (0, false) => 0x10000,
+            (0, true) => 10000,
+            (count, false) => u64::from(count),
+            (count, true) => u64::from(from_bcd(count)),
+        };
+        self.null_count = false;
+        self.elapsed = 0;
+        self.running_since = None;
+        self.update_running(now);
+    }
+
+    fn write_control(&mut self, control: u8, now: u64) {
+        self.access = match (control >> 4) & 0x3 {
+            1 => Access::Lsb,
+            2 => Access::Msb,
+            _ => Access::LsbThenMsb,
+        };
+        self.mode = match (control >> 1) & 0x7 {
+            6 => 2,
+            7 => 3,
+            mode => mode,
+        };
+        self.bcd = control & 0x1 != 0;
+
+        self.armed = false;
+        self.triggered = false;
+        self.null_count = true;
+        self.count_latch = None;
+        self.status_latch = None;
+        self.read_msb = false;
+        self.write_msb = false;
+        self.update_running(now);
+    }
+
+    fn write_count(&mut self, value: u8, now: u64) {
+        match self.access {
+            Access::Lsb => self.count = u16::from(value),
+            Access::Msb => self.count = u16::from(value) << 8,
+            Access::LsbThenMsb if !self.write_msb => {
+                self.count = (self.count & 0xff00) | u16::from(value);
+                self.write_msb = true;
+                // Writing the first byte stops the counter in mode 0
+                if self.mode == 0 {
+                    self.armed = false;
+                    self.update_running(now);
+                }
+                return;
+            }
+            Access::LsbThenMsb => {
+                self.count = (self.count & 0x00ff) | (u16::from(value) << 8);
+                self.write_msb = false;
+            }
+        }
+
+        self.null_count = true;
+        self.armed = true;
+        // Modes 1 and 5 wait for a gate trigger, and modes 2 and 3 don't restart when they're
+        // already counting until the current period ends
+        match self.mode {
+            1 | 5 => self.update_running(now),
+            2 | 3 if self.running_since.is_some() => self.load_at_period_end(now),
+            _ => self.load(now),
+        }
+    }
+
+    /// Loads a new count in modes 2 and 3 when the current period ends. Periods don't overlap, so
+    /// this is done by pretending that the new count was loaded at the end of the current period.
+    fn load_at_period_end(&mut self, now: u64) {
+        let (modulus, elapsed) = self.period(now);
+        let remaining = modulus - elapsed % modulus;
+        self.load(now);
+        self.previous_modulus = modulus;
+        self.running_since = Some(now + remaining);
+    }
+
+    fn set_gate(&mut self, gate: bool, now: u64) {
+        let rising = gate && !self.gate;
+        self.gate = gate;
+        if rising {
+            match self.mode {
+                // A rising edge triggers (or retriggers) modes 1 and 5
+                1 | 5 if self.armed => {
+                    self.triggered = true;
+                    self.load(now);
+                }
+                // ...and reloads the counter in modes 2 and 3
+                2 | 3 if self.armed => self.load(now),
+                _ => {}
+            }
+        }
+        self.update_running(now);
+    }
+
+    /// Whether a new count is waiting for the current period to end (see `load_at_period_end`).
+    fn loading(&self, now: u64) -> bool {
+        self.running_since.is_some_and(|since| since > now)
+    }
+
+    /// The modulus of the current period and the clocks counted at `now`, which come from the
+    /// period that's ending if a new count is waiting to be loaded.
+    fn period(&self, now: u64) -> (u64, u64) {
+        match self.running_since {
+            Some(since) if since > now => {
+                (self.previous_modulus, self.previous_modulus - (since - now))
+            }
+            _ => (self.modulus, self.elapsed(now)),
+        }
+    }
+
+    /// The value of the counting element at clock `now`, in binary.
+    pub fn counter(&self, now: u64) -> u16 {
+        if !self.counting() {
+            return self.count_in_binary();
+        }
+
+        let wrap = if self.bcd { 10000 } else { 0x10000 };
+        let (modulus, elapsed) = self.period(now);
+        let value = match self.mode {
+            2 => modulus - elapsed % modulus,
+            3 => {
+                // The count goes down by two, once for each half of the period
+                let high = modulus.div_ceil(2);
+                let position = elapsed % modulus;
+                let half = if position < high {
+                    position
+                } else {
+                    position - high
+                };
+                modulus - 2 * half
+            }
+            _ => (modulus + wrap - elapsed % wrap) % wrap,
+        };
+
+        value as u16
+    }
+
+    /// The output of the counter at clock `now`.
+    pub fn output(&self, now: u64) -> bool {
+        match self.mode {
+            0 => self.armed && self.elapsed(now) >= self.modulus,
+            1 => !self.triggered || self.elapsed(now) >= self.modulus,
+            2 | 3 if !self.armed || !self.gate => true,
+            2 => {
+                let (modulus, elapsed) = self.period(now);
+                elapsed % modulus != modulus - 1
+            }
+            3 => {
+                let (modulus, elapsed) = self.period(now);
+                elapsed % modulus < modulus.div_ceil(2)
+            }
+            4 => !self.armed || self.elapsed(now) != self.modulus,
+            _ => !self.triggered || self.elapsed(now) != self.modulus,
+        }
+    }
+
+    /// The first clock after `now` where the output goes from low to high, if it ever does
+    /// without the channel being reprogrammed.
+    pub fn next_rising_edge(&self, now: u64) -> Option<u64> {
+        let since = self.running_since?;
+        if since > now {
+            return Some(since);
+        }
+        let elapsed = self.elapsed(now);
+        let periodic = |modulus: u64| Some(now + modulus - elapsed % modulus);
+
+        match self.mode {
+            0 | 1 if elapsed < self.modulus => Some(now + self.modulus - elapsed),
+            2 | 3 if self.modulus > 1 => periodic(self.modulus),
+            4 | 5 if elapsed <= self.modulus => Some(now + self.modulus + 1 - elapsed),
+            _ => None,
+        }
+    }
+
+    fn latch_count(&mut self, now: u64) {
+        if self.count_latch.is_none() {
+            self.count_latch = Some(self.encode(self.counter(now)));
+            self.read_msb = false;
+        }
+    }
+
+    fn latch_status(&mut self, now: u64) {
+        if self.status_latch.is_none() {
+            let access = match self.access {
+                Access::Lsb => 1,
+                Access::Msb => 2,
+                Access::LsbThenMsb => 3,
+            };
+            self.status_latch = Some(
+                (u8::from(self.output(now)) << 7)
+                    | (u8::from(self.null_count || self.loading(now)) << 6)
+                    | (access << 4)
+                    | (self.mode << 1)
+                    | u8::from(self.bcd),
+            );
+        }
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        if let Some(status) = self.status_latch.take() {
+            return status;
+        }
+
+        let value = self
+            .count_latch
+            .unwrap_or_else(|| self.encode(self.counter(now)));
+        let (byte, done) = match self.access {
+            Access::Lsb => (value as u8, true),
+            Access::Msb => ((value >> 8) as u8, true),
+            Access::LsbThenMsb if !self.read_msb => (value as u8, false),
+            Access::LsbThenMsb => ((value >> 8) as u8, true),
+        };
+        self.read_msb = !done;
+        if done {
+            self.count_latch = None;
+        }
+
+        byte
+    }
+
+    fn count_in_binary(&self) -> u16 {
+        if self.bcd {
+            from_bcd(self.count)
+        } else {
+            self.count
+        }
+    }
+
+    fn encode(&self, value: u16) -> u16 {
+        if self.bcd {
+            to_bcd(value)
+        } else {
+            value
+        }
+    }
+
+    fn dump(&self) -> String {
+        format!(
+            "mode {}{}, count {:#06x}, gate {}{}",
+            self.mode,
+            if self.bcd { " (BCD)" } else { "" },
+            self.count,
+            u8::from(self.gate),
+            if self.armed {
+                ""
+            } else {
+                ", waiting for a count"
+            }
+        )
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An Intel 8253/8254 programmable interval timer, which is clocked at [`FREQUENCY`] from virtual
+/// time.
+///
+/// Channel 0 raises IRQ0 on the PIC whenever its output rises, and channel 2 feeds the PC speaker
+/// (see [`speaker_frequency`]). Its gate is controlled with [`set_gate`], which is usually done
+/// through port 0x61. The PIT should be made to depend on the PIC (see
+/// [`System::add_dependency`]).
+///
+/// [`FREQUENCY`]: FREQUENCY
+/// [`speaker_frequency`]: Pit::speaker_frequency
+/// [`set_gate`]: Pit::set_gate
+/// [`System::add_dependency`]: firn_core::System::add_dependency
+pub struct Pit {
+    pic: DeviceRef<DualPic>,
+    pub channels: [Channel; 3],
+
+    /// The timer for the next rising edge of channel 0.
+    timer: Option<TimerId>,
+}
+
+impl Pit {
+    pub fn new(pic: DeviceRef<DualPic>) -> Self {
+        Self {
+            pic,
+            channels: [Channel::new(), Channel::new(), Channel::new()],
+
+            timer: None,
+        }
+    }
+
+    /// The clock of the PIT at a virtual time.
+    pub fn clock_at(time: Duration) -> u64 {
+        (time.as_nanos() * FREQUENCY as u128 / 1_000_000_000) as u64
+    }
+
+    /// The first virtual time that's at or after a clock of the PIT.
+    pub fn time_at(clock: u64) -> Duration {
+        let nanos = (clock as u128 * 1_000_000_000).div_ceil(FREQUENCY as u128);
+
+        Duration::from_nanos(nanos as u64)
+    }
+
+    fn now(sys: &System) -> u64 {
+        Self::clock_at(sys.clock.now())
+    }
+
+    /// The output of a channel.
+    pub fn output(&self, sys: &System, channel: usize) -> bool {
+        self.channels[channel].output(Self::now(sys))
+    }
+
+    /// Sets the gate input of a channel. Only channel 2's gate is connected on a PC, and the gates
+    /// of channels 0 and 1 are always high.
+    pub fn set_gate(&mut self, sys: &mut System, channel: usize, gate: bool) {
+        self.channels[channel].set_gate(gate, Self::now(sys));
+        if channel == SYSTEM_TIMER {
+            self.schedule_irq(sys);
+        }
+    }
+
+    /// The frequency of the square wave that channel 2 is sending to the speaker, or `None` if it
+    /// isn't sending one.
+    pub fn speaker_frequency(&self) -> Option<f64> {
+        let channel = &self.channels[SPEAKER];
+        if channel.mode != 3 || !channel.armed || !channel.gate {
+            return None;
+        }
+
+        Some(FREQUENCY as f64 / channel.modulus as f64)
+    }
+
+    fn write_command(&mut self, sys: &mut System, command: u8) {
+        let now = Self::now(sys);
+        let select = usize::from(command >> 6);
+        if select == 3 {
+            // Read-back, where the bits are active low
+            let count = command & 0x20 == 0;
+            let status = command & 0x10 == 0;
+            for (index, channel) in self.channels.iter_mut().enumerate() {
+                if command & (2 << index) != 0 {
+                    if status {
+                        channel.latch_status(now);
+                    }
+                    if count {
+                        channel.latch_count(now);
+                    }
+                }
+            }
+        } else if command & 0x30 == 0 {
+            self.channels[select].latch_count(now);
+        } else {
+            self.channels[select].write_control(command, now);
+            if select == SYSTEM_TIMER {
+                self.schedule_irq(sys);
+            }
+        }
+    }
+
+    fn write_count(&mut self, sys: &mut System, channel: usize, value: u8) {
+        self.channels[channel].write_count(value, Self::now(sys));
+        if channel == SYSTEM_TIMER {
+            self.schedule_irq(sys);
+        }
+    }
+
+    /// Sets the timer for the next rising edge of channel 0, replacing the previous one.
+    fn schedule_irq(&mut self, sys: &mut System) {
+        if let Some(timer) = self.timer.take() {
+            sys.cancel_timer(timer);
+        }
+
+        let now = Self::now(sys);
+        if let Some(edge) = self.channels[SYSTEM_TIMER].next_rising_edge(now) {
+            self.timer = Some(sys.set_timer(Self::time_at(edge), 0));
+        }
+    }
+}
+
+impl Device<Cpu> for Pit {
+    fn reset(&mut self, sys: &mut System) {
+        if let Some(timer) = self.timer.take() {
+            sys.cancel_timer(timer);
+        }
+        self.channels = [Channel::new(), Channel::new(), Channel::new()];
+    }
+
+    fn timer(&mut self, sys: &mut System, _token: u64) {
+        self.timer = None;
+        sys.with_device(self.pic, |pic, _| pic.submit_irq(0));
+        self.schedule_irq(sys);
+    }
+
+    fn handle_port(&mut self, sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        match request {
+            PortRequest::Out8(COMMAND_PORT, command) => {
+                self.write_command(sys, command);
+                Some(PortResponse::Out)
+            }
+            PortRequest::In8(port @ CHANNEL_0_PORT..=CHANNEL_2_PORT) => {
+                let channel = usize::from(port - CHANNEL_0_PORT);
+                Some(PortResponse::In8(
+                    self.channels[channel].read(Self::now(sys)),
+                ))
+            }
+            PortRequest::Out8(port @ CHANNEL_0_PORT..=CHANNEL_2_PORT, value) => {
+                self.write_count(sys, usize::from(port - CHANNEL_0_PORT), value);
+                Some(PortResponse::Out)
+            }
+            _ => None,
+        }
+    }
+
+    fn claims(&self) -> Claims {
+        Claims {
+            ports: vec![CHANNEL_0_PORT..=COMMAND_PORT],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        let lines: Vec<_> = self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(index, channel)| format!("PIT channel {}: {}", index, channel.dump()))
+            .collect();
+
+        Some(lines.join("\n"))
+    }
+}
+
+fn from_bcd(value: u16) -> u16 {
+    ((value >> 12) & 0xf) * 1000
+        + ((value >> 8) & 0xf) * 100
+        + ((value >> 4) & 0xf) * 10
+        + (value & 0xf)
+}
+
+fn to_bcd(value: u16) -> u16 {
+    let value = value % 10000;
+
+    ((value / 1000) << 12) | ((value / 100 % 10) << 8) | ((value / 10 % 10) << 4) | (value % 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+
+    fn create_sys() -> (System, DeviceRef<DualPic>, DeviceRef<Pit>) {
+        device::tests::create_sys(|_, pic| Pit::new(pic))
+    }
+
+    /// Advances the clock to a clock of the PIT.
+    fn advance_to(sys: &mut System, clock: u64) {
+        let cycles = sys.clock.cycles_at(Pit::time_at(clock));
+        sys.clock.advance(cycles - sys.clock.cycles());
+    }
+
+    fn write_count(sys: &mut System, port: u16, count: u16) {
+        sys.port_out_8(port, count as u8).unwrap();
+        sys.port_out_8(port, (count >> 8) as u8).unwrap();
+    }
+
+    fn read_count(sys: &mut System, port: u16) -> u16 {
+        let lsb = sys.port_in_8(port).unwrap();
+        let msb = sys.port_in_8(port).unwrap();
+
+        u16::from_le_bytes([lsb, msb])
+    }
+
+    #[test]
+    fn should_latch_counts_in_binary_and_bcd() {
+        let (mut sys, _, _) = create_sys();
+        sys.port_out_8(COMMAND_PORT, 0xb4).unwrap();
+        write_count(&mut sys, CHANNEL_2_PORT, 1000);
+        sys.port_out_8(COMMAND_PORT, 0x75).unwrap();
+        write_count(&mut sys, CHANNEL_1_PORT, 0x1000);
+
+        advance_to(&mut sys, 300);
+        sys.port_out_8(COMMAND_PORT, 0x80).unwrap();
+        sys.port_out_8(COMMAND_PORT, 0x40).unwrap();
+        advance_to(&mut sys, 400);
+
+        assert_eq!(700, read_count(&mut sys, CHANNEL_2_PORT));
+        assert_eq!(0x0700, read_count(&mut sys, CHANNEL_1_PORT));
+        assert_eq!(600, read_count(&mut sys, CHANNEL_2_PORT));
+    }
+
+    #[test]
+    fn should_raise_irq0_on_rising_edges() {
+        let (mut sys, pic, _) = create_sys();
+        sys.port_out_8(COMMAND_PORT, 0x34).unwrap();
+        write_count(&mut sys, CHANNEL_0_PORT, 100);
+
+        advance_to(&mut sys, 99);
+        sys.step();
+        assert_eq!(0, sys.get_device(pic).master.request_reg & 1);
+
+        advance_to(&mut sys, 100);
+        sys.step();
+        assert_eq!(1, sys.get_device(pic).master.request_reg & 1);
+
+        sys.get_device_mut(pic).master.request_reg = 0;
+        advance_to(&mut sys, 200);
+        sys.step();
+        assert_eq!(1, sys.get_device(pic).master.request_reg & 1);
+    }
+
+    #[test]
+    fn should_read_back_status_and_trigger_one_shots() {
+        let (mut sys, _, pit) = create_sys();
+        sys.with_device(pit, |pit, sys| pit.set_gate(sys, SPEAKER, false));
+        sys.port_out_8(COMMAND_PORT, 0xb2).unwrap();
+        write_count(&mut sys, CHANNEL_2_PORT, 10);
+
+        sys.port_out_8(COMMAND_PORT, 0xe8).unwrap();
+        assert_eq!(Some(0xf2), sys.port_in_8(CHANNEL_2_PORT));
+
+        sys.with_device(pit, |pit, sys| pit.set_gate(sys, SPEAKER, true));
+        sys.port_out_8(COMMAND_PORT, 0xc8).unwrap();
+        assert_eq!(Some(0x32), sys.port_in_8(CHANNEL_2_PORT));
+        assert_eq!(10, read_count(&mut sys, CHANNEL_2_PORT));
+
+        advance_to(&mut sys, 9);
+        assert!(!sys.get_device(pit).output(&sys, SPEAKER));
+        advance_to(&mut sys, 10);
+        assert!(sys.get_device(pit).output(&sys, SPEAKER));
+    }
+
+    #[test]
+    fn should_generate_square_waves() {
+        let (mut sys, _, pit) = create_sys();
+        sys.port_out_8(COMMAND_PORT, 0xb6).unwrap();
+        write_count(&mut sys, CHANNEL_2_PORT, 1193);
+
+        let frequency = sys.get_device(pit).speaker_frequency().unwrap();
+        assert!((frequency - 1000.15).abs() < 0.01);
+
+        let mut outputs = Vec::new();
+        for clock in [0, 596, 597, 1192, 1193] {
+            advance_to(&mut sys, clock);
+            outputs.push(sys.get_device(pit).output(&sys, SPEAKER));
+        }
+        assert_eq!(vec![true, true, false, false, true], outputs);
+
+        sys.with_device(pit, |pit, sys| pit.set_gate(sys, SPEAKER, false));
+        assert_eq!(None, sys.get_device(pit).speaker_frequency());
+    }
+}
@@ -0,0 +1,394 @@
+use crate::device::pit::SPEAKER;
+use crate::device::{DualPic, Pit};
+use crate::{Cpu, System};
+use firn_core::clock::TimerId;
+use firn_core::device::{Claims, Device, DeviceRef, PortRequest, PortResponse};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+pub const PORT_A: u16 = 0x60;
+pub const PORT_B: u16 = 0x61;
+pub const PORT_C: u16 = 0x62;
+pub const CONTROL_PORT: u16 = 0x63;
+
+/// How often the refresh bit of port 0x61 toggles on an AT, in nanoseconds.
+const REFRESH_PERIOD: u128 = 15_085;
+
+/// How often the host's scan codes are taken on a PC or XT, which is one scan code at a time.
+const SCAN_PERIOD: Duration = Duration::from_millis(10);
+
+/// The machine that the PPI (or system control port) belongs to, which changes what its ports
+/// mean.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Model {
+    /// The IBM PC (5150), which has two banks of DIP switches.
+    Pc,
+    /// The IBM PC/XT (5160), which has one bank of DIP switches that's read through port C.
+    Xt,
+    /// The IBM PC/AT and compatibles, which only have the system control port at 0x61 (the
+    /// keyboard controller is at 0x60).
+    At,
+}
+
+/// The display adapter that the DIP switches say is installed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Video {
+    /// An adapter with its own BIOS, like an EGA or VGA.
+    Ega,
+    Cga40,
+    Cga80,
+    Mda,
+}
+
+/// The settings of the DIP switches on the motherboard of a PC or XT.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DipSwitches {
+    /// The amount of memory in KiB, including expansion cards.
+    pub memory: u32,
+    pub video: Video,
+    /// The number of floppy drives, up to 4.
+    pub floppies: u8,
+    pub fpu: bool,
+}
+
+impl DipSwitches {
+    /// Switch block 1, where memory is counted in banks of 16 KiB on a PC and 64 KiB on an XT.
+    pub fn sw1(&self, model: Model) -> u8 {
+        let bank = if model == Model::Pc { 16 } else { 64 };
+        let banks = (self.memory / bank).clamp(1, 4) as u8 - 1;
+        let video = match self.video {
+            Video::Ega => 0,
+            Video::Cga40 => 1,
+            Video::Cga80 => 2,
+            Video::Mda => 3,
+        };
+
+        u8::from(self.floppies > 0)
+            | (u8::from(self.fpu) << 1)
+            | (banks << 2)
+            | (video << 4)
+            | ((self.floppies.clamp(1, 4) - 1) << 6)
+    }
+
+    /// Switch block 2 of a PC, which has the memory on expansion cards in banks of 32 KiB.
+    pub fn sw2(&self) -> u8 {
+        let expansion = self.memory.saturating_sub(64) / 32;
+
+        expansion.min(0x1f) as u8
+    }
+}
+
+impl Default for DipSwitches {
+    fn default() -> Self {
+        Self {
+            memory: 640,
+            video: Video::Cga80,
+            floppies: 1,
+            fpu: false,
+        }
+    }
+}
+
+/// An Intel 8255 programmable peripheral interface, which connects the DIP switches, speaker and
+/// keyboard of a PC or XT, or the system control port at 0x61 of an AT.
+///
+/// Bits 0 and 1 of port B are the gate of the PIT's speaker channel and the speaker enable, and
+/// port C (or bit 5 of port 0x61 on an AT) reads the speaker channel's output. On a PC or XT, the
+/// keyboard's scan codes are read through port A (see [`receive_scan_code`]), which are taken
+/// through the `ppi.key` input (see [`System::input`]) so that they can be recorded and replayed.
+/// The PPI should be made to depend on the PIT and the PIC (see [`System::add_dependency`]).
+///
+/// [`receive_scan_code`]: Ppi::receive_scan_code
+/// [`System::input`]: firn_core::System::input
+/// [`System::add_dependency`]: firn_core::System::add_dependency
+pub struct Ppi {
+    pit: DeviceRef<Pit>,
+    pic: DeviceRef<DualPic>,
+    pub model: Model,
+    pub switches: DipSwitches,
+
+    pub port_b: u8,
+    pub control: u8,
+    /// The scan code that the keyboard has sent, which is read through port A.
+    pub keyboard_data: u8,
+    scan_timer: Option<TimerId>,
+    /// The host's scan codes that haven't been taken yet.
+    host_scan_codes: VecDeque<u8>,
+}
+
+impl Ppi {
+    /// Creates the PPI of an IBM PC.
+    pub fn new(pit: DeviceRef<Pit>, pic: DeviceRef<DualPic>, switches: DipSwitches) -> Self {
+        Self::with_model(pit, pic, Model::Pc, switches)
+    }
+
+    /// Creates the PPI of an IBM PC/XT.
+    pub fn new_xt(pit: DeviceRef<Pit>, pic: DeviceRef<DualPic>, switches: DipSwitches) -> Self {
+        Self::with_model(pit, pic, Model::Xt, switches)
+    }
+
+    /// Creates the system control port of an AT, which doesn't have DIP switches.
+    pub fn new_at(pit: DeviceRef<Pit>, pic: DeviceRef<DualPic>) -> Self {
+        Self::with_model(pit, pic, Model::At, DipSwitches::default())
+    }
+
+    fn with_model(
+        pit: DeviceRef<Pit>,
+        pic: DeviceRef<DualPic>,
+        model: Model,
+        switches: DipSwitches,
+    ) -> Self {
+        Self {
+            pit,
+            pic,
+            model,
+            switches,
+
+            port_b: 0,
+            control: 0x99,
+            keyboard_data: 0,
+            scan_timer: None,
+            host_scan_codes: VecDeque::new(),
+        }
+    }
+
+    /// Queues a scan code from the host's keyboard of a PC or XT, which is latched at the next
+    /// scan.
+    pub fn receive_scan_code(&mut self, code: u8) {
+        self.host_scan_codes.push_back(code);
+    }
+
+    /// Latches a scan code from the keyboard and raises IRQ1.
+    fn latch_scan_code(&mut self, sys: &mut System, code: u8) {
+        self.keyboard_data = code;
+        sys.with_device(self.pic, |pic, _| pic.submit_irq(1));
+    }
+
+    /// The frequency of the tone that the speaker is playing, if it's playing one.
+    pub fn speaker_frequency(&self, sys: &System) -> Option<f64> {
+        if self.port_b & 0x03 != 0x03 {
+            return None;
+        }
+
+        sys.get_device(self.pit).speaker_frequency()
+    }
+
+    fn speaker_output(&self, sys: &System) -> bool {
+        sys.get_device(self.pit).output(sys, SPEAKER)
+    }
+
+    fn read_port_a(&self) -> u8 {
+        if self.model == Model::Pc && self.port_b & 0x80 != 0 {
+            self.switches.sw1(self.model)
+        } else {
+            self.keyboard_data
+        }
+    }
+
+    fn read_port_b(&self, sys: &System) -> u8 {
+        if self.model != Model::At {
+            return self.port_b;
+        }
+
+        let refresh = (sys.clock.now().as_nanos() / REFRESH_PERIOD) % 2 == 1;
+        (self.port_b & 0x0f) | (u8::from(refresh) << 4) | (u8::from(self.speaker_output(sys)) << 5)
+    }
+
+    fn read_port_c(&self, sys: &System) -> u8 {
+        let switches = match self.model {
+            Model::Pc if self.port_b & 0x04 != 0 => self.switches.sw2() & 0x0f,
+            Model::Pc => self.switches.sw2() >> 4,
+            Model::Xt if self.port_b & 0x08 != 0 => self.switches.sw1(self.model) >> 4,
+            Model::Xt => self.switches.sw1(self.model) & 0x0f,
+            Model::At => 0,
+        };
+
+        switches | (u8::from(self.speaker_output(sys)) << 5)
+    }
+
+    fn write_port_b(&mut self, sys: &mut System, value: u8) {
+        let previous = std::mem::replace(&mut self.port_b, value);
+        if (previous ^ value) & 0x01 != 0 {
+            sys.with_device(self.pit, |pit, sys| {
+                pit.set_gate(sys, SPEAKER, value & 0x01 != 0)
+            });
+        }
+        if self.model == Model::At {
+            return;
+        }
+
+        // Setting bit 7 clears the keyboard's data, and releasing the keyboard's clock after
+        // holding it low resets it, which makes it send the result of its self-test
+        if value & 0x80 != 0 {
+            self.keyboard_data = 0;
+        }
+        if previous & 0x40 == 0 && value & 0x40 != 0 {
+            self.latch_scan_code(sys, 0xaa);
+        }
+    }
+}
+
+impl Device<Cpu> for Ppi {
+    fn init(&mut self, sys: &mut System) {
+        Device::reset(self, sys);
+    }
+
+    fn reset(&mut self, sys: &mut System) {
+        self.port_b = 0;
+        self.control = 0x99;
+        self.keyboard_data = 0;
+        sys.with_device(self.pit, |pit, sys| pit.set_gate(sys, SPEAKER, false));
+
+        if let Some(timer) = self.scan_timer.take() {
+            sys.cancel_timer(timer);
+        }
+        if self.model != Model::At {
+            self.scan_timer = Some(sys.set_timer(sys.clock.now() + SCAN_PERIOD, 0));
+        }
+    }
+
+    fn timer(&mut self, sys: &mut System, _token: u64) {
+        self.scan_timer = Some(sys.set_timer(sys.clock.now() + SCAN_PERIOD, 0));
+        let host = || self.host_scan_codes.pop_front().map(|code| vec![code]);
+        if let Some(&code) = sys
+            .input("ppi.key", host)
+            .as_deref()
+            .and_then(<[u8]>::first)
+        {
+            self.latch_scan_code(sys, code);
+        }
+    }
+
+    fn handle_port(&mut self, sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        match request {
+            PortRequest::In8(PORT_B) => Some(PortResponse::In8(self.read_port_b(sys))),
+            PortRequest::Out8(PORT_B, value) => {
+                self.write_port_b(sys, value);
+                Some(PortResponse::Out)
+            }
+            _ if self.model == Model::At => None,
+            PortRequest::In8(PORT_A) => Some(PortResponse::In8(self.read_port_a())),
+            PortRequest::In8(PORT_C) => Some(PortResponse::In8(self.read_port_c(sys))),
+            PortRequest::In8(CONTROL_PORT) => Some(PortResponse::In8(self.control)),
+            // Port A and C are inputs on a PC, so writes to them (and bit set/reset commands for
+            // port C) don't do anything
+            PortRequest::Out8(PORT_A | PORT_C, _) => Some(PortResponse::Out),
+            PortRequest::Out8(CONTROL_PORT, value) => {
+                if value & 0x80 != 0 {
+                    self.control = value;
+                }
+                Some(PortResponse::Out)
+            }
+            _ => None,
+        }
+    }
+
+    fn claims(&self) -> Claims {
+        let ports = match self.model {
+            Model::At => PORT_B..=PORT_B,
+            _ => PORT_A..=CONTROL_PORT,
+        };
+
+        Claims {
+            ports: vec![ports],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        Some(format!(
+            "PPI ({:?}): port B {:#04x}, keyboard data {:#04x}, switches {:?}",
+            self.model, self.port_b, self.keyboard_data, self.switches
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+
+    fn create_sys(model: Model) -> (System, DeviceRef<DualPic>, DeviceRef<Ppi>) {
+        let mut pit = None;
+        let (mut sys, pic, ppi) = device::tests::create_sys(|sys, pic| {
+            let switches = DipSwitches {
+                memory: 640,
+                video: Video::Cga80,
+                floppies: 2,
+                fpu: false,
+            };
+            let added = sys.add_device(Pit::new(pic));
+            sys.add_dependency(added, pic);
+            pit = Some(added);
+            Ppi::with_model(added, pic, model, switches)
+        });
+        sys.add_dependency(ppi, pit.unwrap());
+
+        (sys, pic, ppi)
+    }
+
+    #[test]
+    fn should_read_dip_switches() {
+        let (mut sys, _, _) = create_sys(Model::Pc);
+        sys.port_out_8(CONTROL_PORT, 0x99).unwrap();
+        sys.port_out_8(PORT_B, 0xfc).unwrap();
+        assert_eq!(Some(0x6d), sys.port_in_8(PORT_A));
+        assert_eq!(Some(0x02), sys.port_in_8(PORT_C).map(|value| value & 0x0f));
+        sys.port_out_8(PORT_B, 0xf8).unwrap();
+        assert_eq!(Some(0x01), sys.port_in_8(PORT_C).map(|value| value & 0x0f));
+
+        let (mut sys, _, _) = create_sys(Model::Xt);
+        sys.port_out_8(PORT_B, 0x00).unwrap();
+        assert_eq!(Some(0x0d), sys.port_in_8(PORT_C).map(|value| value & 0x0f));
+        sys.port_out_8(PORT_B, 0x08).unwrap();
+        assert_eq!(Some(0x06), sys.port_in_8(PORT_C).map(|value| value & 0x0f));
+    }
+
+    #[test]
+    fn should_gate_speaker_and_toggle_refresh() {
+        let (mut sys, _, ppi) = create_sys(Model::At);
+        sys.port_out_8(0x43, 0xb6).unwrap();
+        sys.port_out_8(0x42, 0xa9).unwrap();
+        sys.port_out_8(0x42, 0x04).unwrap();
+        assert_eq!(None, sys.get_device(ppi).speaker_frequency(&sys));
+
+        sys.port_out_8(PORT_B, 0x03).unwrap();
+        let frequency = sys.get_device(ppi).speaker_frequency(&sys).unwrap();
+        assert!((frequency - 1000.15).abs() < 0.01);
+        assert_eq!(None, sys.port_in_8(PORT_A));
+
+        let before = sys.port_in_8(PORT_B).unwrap();
+        sys.clock
+            .advance(sys.clock.frequency() * 15_085 / 1_000_000_000 + 1);
+        let after = sys.port_in_8(PORT_B).unwrap();
+        assert_eq!(0x03, before & 0x0f);
+        assert_eq!(0x10, (before ^ after) & 0x10);
+    }
+
+    #[test]
+    fn should_latch_the_host_scan_codes_one_at_a_time() {
+        let (mut sys, pic, ppi) = create_sys(Model::Xt);
+        sys.get_device_mut(ppi).receive_scan_code(0x1e);
+        sys.get_device_mut(ppi).receive_scan_code(0x9e);
+
+        for code in [0x1e, 0x9e] {
+            let cycles = sys.clock.cycles_at(SCAN_PERIOD);
+            sys.clock.advance(cycles);
+            sys.step();
+            assert_eq!(Some(code), sys.port_in_8(PORT_A));
+            assert_eq!(0x02, sys.get_device(pic).master.request_reg);
+        }
+    }
+
+    #[test]
+    fn should_reset_keyboard_when_clock_is_released() {
+        let (mut sys, pic, _) = create_sys(Model::Xt);
+        sys.port_out_8(PORT_B, 0x08).unwrap();
+        sys.port_out_8(PORT_B, 0x48).unwrap();
+
+        assert_eq!(Some(0xaa), sys.port_in_8(PORT_A));
+        assert_eq!(0x02, sys.get_device(pic).master.request_reg);
+        sys.port_out_8(PORT_B, 0xc8).unwrap();
+        assert_eq!(Some(0x00), sys.port_in_8(PORT_A));
+    }
+}
@@ -0,0 +1,408 @@
+//! The other ends of serial ports, which are what a UART's transmitted bytes go to and its received
+//! bytes come from (see [`Uart`]).
+//!
+//! Backends that read from the host (like stdio and TCP) do it on their own threads, so that the
+//! UART can poll them without blocking the system.
+//!
+//! [`Uart`]: crate::device::Uart
+
+use crate::device::SerialMouse;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The modem status lines that a backend drives, which the UART reports in its modem status
+/// register.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ModemStatus {
+    /// Clear to send.
+    pub cts: bool,
+    /// Data set ready.
+    pub dsr: bool,
+    /// Ring indicator.
+    pub ri: bool,
+    /// Data carrier detect.
+    pub dcd: bool,
+}
+
+impl ModemStatus {
+    /// A modem that's connected and ready, which is what most software waits for.
+    pub const CONNECTED: Self = Self {
+        cts: true,
+        dsr: true,
+        ri: false,
+        dcd: true,
+    };
+    /// Nothing plugged in.
+    pub const DISCONNECTED: Self = Self {
+        cts: false,
+        dsr: false,
+        ri: false,
+        dcd: false,
+    };
+}
+
+/// The other end of a serial port.
+pub trait SerialBackend: Any + Send {
+    /// Handles a byte that the UART finished transmitting.
+    fn write(&mut self, byte: u8);
+
+    /// Takes the next byte for the UART to receive, if one has arrived. This is called at most once
+    /// per character time at the UART's baud rate.
+    fn read(&mut self) -> Option<u8>;
+
+    /// Whether bytes can ever arrive from the backend. The UART only polls backends that can send
+    /// bytes while its interrupts are waiting for them.
+    fn can_receive(&self) -> bool {
+        true
+    }
+
+    /// Handles a change of the UART's modem control lines.
+    fn set_modem_control(&mut self, _dtr: bool, _rts: bool) {}
+
+    /// The modem status lines, which are connected by default.
+    fn modem_status(&self) -> ModemStatus {
+        ModemStatus::CONNECTED
+    }
+}
+
+/// Nothing plugged into the serial port, so transmitted bytes are thrown away.
+pub struct NullBackend;
+
+impl SerialBackend for NullBackend {
+    fn write(&mut self, _byte: u8) {}
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn can_receive(&self) -> bool {
+        false
+    }
+
+    fn modem_status(&self) -> ModemStatus {
+        ModemStatus::DISCONNECTED
+    }
+}
+
+/// Reads from a host stream on a new thread until it ends or fails, sending every byte to the
+/// returned receiver.
+fn spawn_reader(mut reader: impl Read + Send + 'static) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 256];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => {
+                    if buf[..len].iter().any(|byte| sender.send(*byte).is_err()) {
+                        return;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return,
+            }
+        }
+    });
+
+    receiver
+}
+
+/// The host's standard input and output, which is the simplest way to talk to a guest's serial
+/// console.
+pub struct StdioBackend {
+    input: Receiver<u8>,
+}
+
+impl StdioBackend {
+    pub fn new() -> Self {
+        Self {
+            input: spawn_reader(io::stdin()),
+        }
+    }
+}
+
+impl Default for StdioBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialBackend for StdioBackend {
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout().lock();
+        // There's nothing the guest could do about a closed stdout
+        let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+/// Writes transmitted bytes to a file, with optional input that's read from another file up front.
+pub struct FileBackend {
+    output: File,
+    input: VecDeque<u8>,
+}
+
+impl FileBackend {
+    /// Creates (or truncates) the output file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            output: File::create(path)?,
+            input: VecDeque::new(),
+        })
+    }
+
+    /// Reads the whole input file, which the UART receives one byte at a time.
+    pub fn with_input(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        self.input = std::fs::read(path)?.into();
+
+        Ok(self)
+    }
+}
+
+impl SerialBackend for FileBackend {
+    fn write(&mut self, byte: u8) {
+        // Writes aren't buffered so that the file can be followed while the guest runs
+        let _ = self.output.write_all(&[byte]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+/// A Unix pseudoterminal, which terminal programs (like `screen` or `minicom`) can open at
+/// [`path`] to talk to the guest.
+///
+/// [`path`]: PtyBackend::path
+#[cfg(unix)]
+pub struct PtyBackend {
+    master: File,
+    path: std::path::PathBuf,
+    input: Receiver<u8>,
+}
+
+#[cfg(unix)]
+impl PtyBackend {
+    pub fn open() -> io::Result<Self> {
+        use std::ffi::CStr;
+        use std::os::raw::{c_char, c_int};
+        use std::os::unix::io::AsRawFd;
+
+        extern "C" {
+            fn grantpt(fd: c_int) -> c_int;
+            fn unlockpt(fd: c_int) -> c_int;
+            fn ptsname(fd: c_int) -> *mut c_char;
+        }
+
+        let master = File::options().read(true).write(true).open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+        // SAFETY: The file descriptor is open for as long as `master` is, and `ptsname` returns
+        // either null or a C string that's valid until the next call, which is copied right away
+        let path = unsafe {
+            if grantpt(fd) != 0 || unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            CStr::from_ptr(name).to_string_lossy().into_owned().into()
+        };
+
+        let mut reader = master.try_clone()?;
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                // Reading fails while nothing has the other end open, which isn't the end of the
+                // pseudoterminal since something can open it again
+                match reader.read(&mut buf) {
+                    Ok(len) if len > 0 => {
+                        if buf[..len].iter().any(|byte| sender.send(*byte).is_err()) {
+                            return;
+                        }
+                    }
+                    _ => thread::sleep(std::time::Duration::from_millis(50)),
+                }
+            }
+        });
+
+        Ok(Self {
+            master,
+            path,
+            input,
+        })
+    }
+
+    /// The path of the pseudoterminal's other end, like `/dev/pts/3`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl SerialBackend for PtyBackend {
+    fn write(&mut self, byte: u8) {
+        let _ = self.master.write_all(&[byte]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+/// A TCP server that one client at a time can connect to, like with `nc localhost <port>`.
+///
+/// Transmitted bytes are thrown away while no client is connected, and the carrier detect line is
+/// only set while one is.
+pub struct TcpBackend {
+    address: SocketAddr,
+    client: Arc<Mutex<Option<TcpStream>>>,
+    input: Receiver<u8>,
+}
+
+impl TcpBackend {
+    pub fn listen(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let client = Arc::new(Mutex::new(None::<TcpStream>));
+        let (sender, input) = mpsc::channel();
+
+        let accepted = Arc::clone(&client);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let _ = stream.set_nodelay(true);
+                match stream.try_clone() {
+                    Ok(writer) => *accepted.lock().unwrap() = Some(writer),
+                    Err(_) => continue,
+                }
+
+                let mut buf = [0; 256];
+                while let Ok(len @ 1..) = stream.read(&mut buf) {
+                    if buf[..len].iter().any(|byte| sender.send(*byte).is_err()) {
+                        return;
+                    }
+                }
+                *accepted.lock().unwrap() = None;
+            }
+        });
+
+        Ok(Self {
+            address,
+            client,
+            input,
+        })
+    }
+
+    /// The address that the server is listening on, which has the actual port if it was bound to
+    /// port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl SerialBackend for TcpBackend {
+    fn write(&mut self, byte: u8) {
+        let mut client = self.client.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            if stream.write_all(&[byte]).is_err() {
+                *client = None;
+            }
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn modem_status(&self) -> ModemStatus {
+        ModemStatus {
+            dcd: self.client.lock().unwrap().is_some(),
+            ..ModemStatus::CONNECTED
+        }
+    }
+}
+
+#[derive(Default)]
+struct LoopbackBuffers {
+    to_uart: VecDeque<u8>,
+    from_uart: Vec<u8>,
+}
+
+/// An in-memory serial line, where the other end is a [`LoopbackRemote`] that tests and scripts
+/// can send and receive bytes with.
+///
+/// [`LoopbackRemote`]: LoopbackRemote
+pub struct Loopback {
+    buffers: Arc<Mutex<LoopbackBuffers>>,
+}
+
+impl Loopback {
+    /// Creates a serial line, returning the end that's plugged into the UART and the other end.
+    pub fn new() -> (Self, LoopbackRemote) {
+        let buffers = Arc::new(Mutex::new(LoopbackBuffers::default()));
+
+        (
+            Self {
+                buffers: Arc::clone(&buffers),
+            },
+            LoopbackRemote { buffers },
+        )
+    }
+}
+
+impl SerialBackend for Loopback {
+    fn write(&mut self, byte: u8) {
+        self.buffers.lock().unwrap().from_uart.push(byte);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.buffers.lock().unwrap().to_uart.pop_front()
+    }
+}
+
+/// The other end of a [`Loopback`], which can be used from any thread.
+///
+/// [`Loopback`]: Loopback
+#[derive(Clone)]
+pub struct LoopbackRemote {
+    buffers: Arc<Mutex<LoopbackBuffers>>,
+}
+
+impl LoopbackRemote {
+    /// Sends bytes for the UART to receive.
+    pub fn send(&self, bytes: &[u8]) {
+        self.buffers.lock().unwrap().to_uart.extend(bytes);
+    }
+
+    /// Takes every byte that the UART has transmitted since the last call.
+    pub fn take_received(&self) -> Vec<u8> {
+        std::mem::take(&mut self.buffers.lock().unwrap().from_uart)
+    }
+}
+
+/// The mouse is powered by RTS and only ever sends.
+impl SerialBackend for SerialMouse {
+    fn write(&mut self, _byte: u8) {}
+
+    fn read(&mut self) -> Option<u8> {
+        self.next_byte()
+    }
+
+    fn set_modem_control(&mut self, _dtr: bool, rts: bool) {
+        self.set_rts(rts);
+    }
+}
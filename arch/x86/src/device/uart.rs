@@ -0,0 +1,837 @@
+use crate::device::serial::{ModemStatus, SerialBackend};
+use crate::device::DualPic;
+use crate::{Cpu, System};
+use firn_core::clock::TimerId;
+use firn_core::device::{Claims, Device, DeviceRef, PortRequest, PortResponse};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The baud rate with a divisor of 1, which comes from the 1.8432 MHz crystal of a PC's serial
+/// ports.
+pub const MAX_BAUD_RATE: u32 = 115_200;
+
+const DATA_REG: u16 = 0;
+const INTERRUPT_ENABLE_REG: u16 = 1;
+/// The interrupt identification register when it's read and the FIFO control register when it's
+/// written.
+const INTERRUPT_ID_REG: u16 = 2;
+const LINE_CONTROL_REG: u16 = 3;
+const MODEM_CONTROL_REG: u16 = 4;
+const LINE_STATUS_REG: u16 = 5;
+const MODEM_STATUS_REG: u16 = 6;
+const SCRATCH_REG: u16 = 7;
+
+const IER_RECEIVED: u8 = 0x01;
+const IER_TRANSMIT_EMPTY: u8 = 0x02;
+const IER_LINE_STATUS: u8 = 0x04;
+const IER_MODEM_STATUS: u8 = 0x08;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_TRANSMIT_EMPTY: u8 = 0x02;
+const IIR_RECEIVED: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_TIMEOUT: u8 = 0x0c;
+const IIR_FIFOS_ENABLED: u8 = 0xc0;
+
+const LCR_DIVISOR_LATCH: u8 = 0x80;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+/// Connects the interrupt output to the PIC on a PC.
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOPBACK: u8 = 0x10;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_OVERRUN: u8 = 0x02;
+const LSR_TRANSMIT_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_IDLE: u8 = 0x40;
+
+const FIFO_SIZE: usize = 16;
+/// The number of character times without any activity before a character timeout interrupt.
+const TIMEOUT_CHARACTERS: u32 = 4;
+
+const TRANSMIT_TIMER: u64 = 0;
+const RECEIVE_TIMER: u64 = 1;
+
+/// One of the four serial ports of a PC, which sets the UART's I/O ports and IRQ.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    /// COM3 and COM4 share the IRQs of COM1 and COM2.
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+}
+
+/// The chip that a UART emulates, which software tells apart by the scratch register and the FIFOs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UartModel {
+    /// The UART of the original PC, which doesn't have a scratch register.
+    Ns8250,
+    /// The UART of the AT, which has a scratch register but no FIFOs.
+    Ns16450,
+    /// A UART with working 16-byte FIFOs.
+    Ns16550A,
+}
+
+/// A National Semiconductor 8250-family UART, which connects a serial port to a backend (see
+/// [`serial`]).
+///
+/// Characters take as long to transmit and receive as they would at the UART's baud rate. The
+/// backend is polled for received bytes once per character time while the received data interrupt
+/// is enabled, and otherwise when the line status or receiver buffer register is read (at most once
+/// per character time). Interrupts only reach the PIC while OUT2 of the modem control register is
+/// set, like on a PC.
+///
+/// The received bytes and the modem status lines come from the host, so they're taken through the
+/// `Com1.rx` input and the `Com1.modem` sample (for COM1, see [`System::input`] and
+/// [`System::sample`]) so that they can be recorded and replayed.
+///
+/// The backend can be accessed with [`backend_mut`], which is how frontends move a
+/// [`SerialMouse`] (usually through a [`DeviceHandle`]).
+///
+/// [`serial`]: crate::device::serial
+/// [`backend_mut`]: Uart::backend_mut
+/// [`SerialMouse`]: crate::device::SerialMouse
+/// [`DeviceHandle`]: firn_core::device::DeviceHandle
+/// [`System::input`]: firn_core::System::input
+/// [`System::sample`]: firn_core::System::sample
+pub struct Uart {
+    pic: DeviceRef<DualPic>,
+    pub port: ComPort,
+    pub model: UartModel,
+    backend: Box<dyn SerialBackend>,
+
+    pub divisor: u16,
+    pub interrupt_enable: u8,
+    pub line_control: u8,
+    pub modem_control: u8,
+    pub scratch: u8,
+    /// Whether the FIFOs are enabled, which is only possible on a 16550A.
+    pub fifos_enabled: bool,
+    /// The number of received bytes that raises a received data interrupt while the FIFOs are
+    /// enabled.
+    pub trigger_level: usize,
+
+    receive: VecDeque<u8>,
+    transmit: VecDeque<u8>,
+    /// The byte in the transmitter shift register, which is being sent.
+    shifting: Option<u8>,
+    /// The error bits of the line status register, which are cleared when it's read.
+    line_errors: u8,
+    /// The modem status lines, in the upper nibble of the modem status register.
+    modem_lines: u8,
+    /// The lines that changed since the modem status register was read, in its lower nibble.
+    modem_deltas: u8,
+    transmit_empty_pending: bool,
+    timeout_pending: bool,
+    /// The last time a byte was received or read, for character timeouts.
+    receive_activity: Duration,
+    /// The last time the backend was polled for a received byte.
+    last_poll: Duration,
+
+    irq_raised: bool,
+    transmit_timer: Option<TimerId>,
+    receive_timer: Option<TimerId>,
+}
+
+impl Uart {
+    pub fn new(
+        pic: DeviceRef<DualPic>,
+        port: ComPort,
+        model: UartModel,
+        backend: impl SerialBackend,
+    ) -> Self {
+        Self {
+            pic,
+            port,
+            model,
+            backend: Box::new(backend),
+
+            divisor: 12,
+            interrupt_enable: 0,
+            line_control: 0,
+            modem_control: 0,
+            scratch: 0,
+            fifos_enabled: false,
+            trigger_level: 1,
+
+            receive: VecDeque::new(),
+            transmit: VecDeque::new(),
+            shifting: None,
+            line_errors: 0,
+            modem_lines: 0,
+            modem_deltas: 0,
+            transmit_empty_pending: false,
+            timeout_pending: false,
+            receive_activity: Duration::ZERO,
+            last_poll: Duration::ZERO,
+
+            irq_raised: false,
+            transmit_timer: None,
+            receive_timer: None,
+        }
+    }
+
+    /// The backend, if it's a `B`.
+    pub fn backend_mut<B>(&mut self) -> Option<&mut B>
+    where
+        B: SerialBackend,
+    {
+        let any: &mut dyn Any = &mut *self.backend;
+
+        any.downcast_mut()
+    }
+
+    /// Replaces the backend, which is like plugging something else into the serial port.
+    pub fn set_backend(&mut self, sys: &mut System, backend: impl SerialBackend) {
+        self.backend = Box::new(backend);
+        self.update_receive_timer(sys);
+    }
+
+    pub fn baud_rate(&self) -> u32 {
+        MAX_BAUD_RATE / u32::from(self.divisor.max(1))
+    }
+
+    /// The time that one character takes to send, including its start, parity and stop bits.
+    pub fn character_time(&self) -> Duration {
+        let data = 5 + u32::from(self.line_control & 0x03);
+        let parity = u32::from((self.line_control >> 3) & 0x01);
+        let stop = 1 + u32::from((self.line_control >> 2) & 0x01);
+        let bits = 1 + data + parity + stop;
+
+        Duration::from_secs(1) * bits * u32::from(self.divisor.max(1)) / MAX_BAUD_RATE
+    }
+
+    fn data_mask(&self) -> u8 {
+        0xff >> (3 - (self.line_control & 0x03))
+    }
+
+    fn fifo_capacity(&self) -> usize {
+        if self.fifos_enabled {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn is_loopback(&self) -> bool {
+        self.modem_control & MCR_LOOPBACK != 0
+    }
+
+    /// The highest priority interrupt that's pending and enabled, as it's identified in the
+    /// interrupt identification register.
+    fn interrupt_id(&self) -> u8 {
+        let enabled = |bit: u8| self.interrupt_enable & bit != 0;
+        let trigger = if self.fifos_enabled {
+            self.trigger_level
+        } else {
+            1
+        };
+
+        if enabled(IER_LINE_STATUS) && self.line_errors != 0 {
+            IIR_LINE_STATUS
+        } else if enabled(IER_RECEIVED) && self.receive.len() >= trigger {
+            IIR_RECEIVED
+        } else if enabled(IER_RECEIVED) && self.timeout_pending {
+            IIR_TIMEOUT
+        } else if enabled(IER_TRANSMIT_EMPTY) && self.transmit_empty_pending {
+            IIR_TRANSMIT_EMPTY
+        } else if enabled(IER_MODEM_STATUS) && self.modem_deltas != 0 {
+            IIR_MODEM_STATUS
+        } else {
+            IIR_NONE
+        }
+    }
+
+    /// Raises or lowers the IRQ to match whether an interrupt is pending.
+    fn update_irq(&mut self, sys: &mut System) {
+        let raised = self.interrupt_id() != IIR_NONE && self.modem_control & MCR_OUT2 != 0;
+        if raised == self.irq_raised {
+            return;
+        }
+
+        self.irq_raised = raised;
+        let irq = self.port.irq();
+        sys.with_device(self.pic, |pic, _| {
+            if raised {
+                pic.raise_irq(irq);
+            } else {
+                pic.lower_irq(irq);
+            }
+        });
+    }
+
+    fn read(&mut self, sys: &mut System, reg: u16) -> u8 {
+        let divisor_latch = self.line_control & LCR_DIVISOR_LATCH != 0;
+
+        let value = match reg {
+            DATA_REG if divisor_latch => self.divisor as u8,
+            DATA_REG => {
+                if self.receive.is_empty() {
+                    self.poll_receive(sys);
+                }
+                self.receive_activity = sys.clock.now();
+                self.timeout_pending = false;
+                self.receive.pop_front().unwrap_or(0)
+            }
+            INTERRUPT_ENABLE_REG if divisor_latch => (self.divisor >> 8) as u8,
+            INTERRUPT_ENABLE_REG => self.interrupt_enable,
+            INTERRUPT_ID_REG => {
+                let id = self.interrupt_id();
+                // Identifying the transmitter holding register empty interrupt clears it
+                if id == IIR_TRANSMIT_EMPTY {
+                    self.transmit_empty_pending = false;
+                }
+                if self.fifos_enabled {
+                    id | IIR_FIFOS_ENABLED
+                } else {
+                    id
+                }
+            }
+            LINE_CONTROL_REG => self.line_control,
+            MODEM_CONTROL_REG => self.modem_control,
+            LINE_STATUS_REG => {
+                self.poll_receive(sys);
+                let mut status = std::mem::take(&mut self.line_errors);
+                if !self.receive.is_empty() {
+                    status |= LSR_DATA_READY;
+                }
+                if self.transmit.is_empty() {
+                    status |= LSR_TRANSMIT_EMPTY;
+                    if self.shifting.is_none() {
+                        status |= LSR_TRANSMITTER_IDLE;
+                    }
+                }
+                status
+            }
+            MODEM_STATUS_REG => {
+                self.update_modem_status(sys);
+                self.modem_lines | std::mem::take(&mut self.modem_deltas)
+            }
+            // The 8250 doesn't have a scratch register, so nothing drives the bus
+            SCRATCH_REG if self.model == UartModel::Ns8250 => 0xff,
+            _ => self.scratch,
+        };
+
+        self.update_receive_timer(sys);
+        self.update_irq(sys);
+        value
+    }
+
+    fn write(&mut self, sys: &mut System, reg: u16, value: u8) {
+        let divisor_latch = self.line_control & LCR_DIVISOR_LATCH != 0;
+
+        match reg {
+            DATA_REG if divisor_latch => self.divisor = (self.divisor & 0xff00) | value as u16,
+            DATA_REG => self.write_transmit(sys, value),
+            INTERRUPT_ENABLE_REG if divisor_latch => {
+                self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8);
+            }
+            INTERRUPT_ENABLE_REG => {
+                let enabled = value & !self.interrupt_enable;
+                self.interrupt_enable = value & 0x0f;
+                // Enabling the interrupt while the holding register is empty raises it right away
+                if enabled & IER_TRANSMIT_EMPTY != 0 && self.transmit.is_empty() {
+                    self.transmit_empty_pending = true;
+                }
+            }
+            INTERRUPT_ID_REG if self.model == UartModel::Ns16550A => self.write_fifo_control(value),
+            INTERRUPT_ID_REG => {}
+            LINE_CONTROL_REG => {
+                self.line_control = value;
+                // Polls the backend at the new character time, since the divisor and the character
+                // length are only set before this is written
+                if let Some(timer) = self.receive_timer.take() {
+                    sys.cancel_timer(timer);
+                }
+            }
+            MODEM_CONTROL_REG => {
+                let previous = std::mem::replace(&mut self.modem_control, value & 0x1f);
+                let lines = MCR_DTR | MCR_RTS | MCR_LOOPBACK;
+                if (previous ^ self.modem_control) & lines != 0 && !self.is_loopback() {
+                    self.backend.set_modem_control(
+                        self.modem_control & MCR_DTR != 0,
+                        self.modem_control & MCR_RTS != 0,
+                    );
+                }
+                self.update_modem_status(sys);
+            }
+            // The line and modem status registers are read-only
+            LINE_STATUS_REG | MODEM_STATUS_REG => {}
+            _ => self.scratch = value,
+        }
+
+        self.update_receive_timer(sys);
+        self.update_irq(sys);
+    }
+
+    fn write_fifo_control(&mut self, value: u8) {
+        let enabled = value & 0x01 != 0;
+        if enabled != self.fifos_enabled {
+            self.receive.clear();
+            self.transmit.clear();
+        }
+        self.fifos_enabled = enabled;
+        if value & 0x02 != 0 {
+            self.receive.clear();
+            self.timeout_pending = false;
+        }
+        if value & 0x04 != 0 {
+            self.transmit.clear();
+        }
+        self.trigger_level = [1, 4, 8, 14][usize::from(value >> 6)];
+    }
+
+    fn write_transmit(&mut self, sys: &mut System, value: u8) {
+        self.transmit_empty_pending = false;
+        if self.transmit.len() < self.fifo_capacity() {
+            self.transmit.push_back(value & self.data_mask());
+        }
+        if self.shifting.is_none() {
+            self.start_transmit(sys);
+        }
+    }
+
+    /// Moves the next byte to transmit into the shift register, if there is one.
+    fn start_transmit(&mut self, sys: &mut System) {
+        let Some(byte) = self.transmit.pop_front() else {
+            return;
+        };
+
+        self.shifting = Some(byte);
+        if self.transmit.is_empty() {
+            self.transmit_empty_pending = true;
+        }
+        let at = sys.clock.now() + self.character_time();
+        self.transmit_timer = Some(sys.set_timer(at, TRANSMIT_TIMER));
+    }
+
+    fn receive_byte(&mut self, sys: &System, byte: u8) {
+        if self.receive.len() == self.fifo_capacity() {
+            self.line_errors |= LSR_OVERRUN;
+            // A full FIFO keeps its bytes, but the receiver buffer register is overwritten
+            if self.fifos_enabled {
+                return;
+            }
+            self.receive.pop_front();
+        }
+        self.receive.push_back(byte & self.data_mask());
+        self.receive_activity = sys.clock.now();
+        self.timeout_pending = false;
+    }
+
+    /// Updates the modem status lines, which come from the modem control register in loopback
+    /// mode and from the backend otherwise.
+    fn update_modem_status(&mut self, sys: &mut System) {
+        let status_lines = |status: ModemStatus| {
+            (u8::from(status.cts) << 4)
+                | (u8::from(status.dsr) << 5)
+                | (u8::from(status.ri) << 6)
+                | (u8::from(status.dcd) << 7)
+        };
+        let lines = if self.is_loopback() {
+            status_lines(ModemStatus {
+                cts: self.modem_control & MCR_RTS != 0,
+                dsr: self.modem_control & MCR_DTR != 0,
+                ri: self.modem_control & MCR_OUT1 != 0,
+                dcd: self.modem_control & MCR_OUT2 != 0,
+            })
+        } else {
+            let channel = format!("{:?}.modem", self.port);
+            let [lines] =
+                sys.sample_array(&channel, || [status_lines(self.backend.modem_status())]);
+            lines
+        };
+
+        let changed = (lines ^ self.modem_lines) >> 4;
+        // The ring indicator's delta is only set when it turns off
+        let ring_ended = (self.modem_lines & !lines & 0x40) >> 4;
+        self.modem_deltas |= (changed & 0x0b) | ring_ended;
+        self.modem_lines = lines;
+    }
+
+    /// Takes the next byte from the backend, if there is one.
+    fn receive_from_backend(&mut self, sys: &mut System) {
+        self.last_poll = sys.clock.now();
+        if self.is_loopback() {
+            return;
+        }
+
+        let channel = format!("{:?}.rx", self.port);
+        let backend = &mut self.backend;
+        let received = sys.input(&channel, || backend.read().map(|byte| vec![byte]));
+        if let Some(&byte) = received.as_deref().and_then(<[u8]>::first) {
+            self.receive_byte(sys, byte);
+        }
+    }
+
+    /// Polls the backend for software that reads the registers instead of waiting for interrupts,
+    /// at most once per character time.
+    fn poll_receive(&mut self, sys: &mut System) {
+        if self.receive_timer.is_none() && sys.clock.now() >= self.last_poll + self.character_time()
+        {
+            self.receive_from_backend(sys);
+        }
+    }
+
+    /// Whether the receive timer has to run, which is while interrupts are waiting for bytes or
+    /// modem status changes from the backend, or for a character timeout.
+    fn needs_receive_timer(&self) -> bool {
+        let enabled = |bit: u8| self.interrupt_enable & bit != 0;
+        let receiving = self.backend.can_receive() && !self.is_loopback();
+        let timeout = self.fifos_enabled && !self.receive.is_empty();
+
+        (enabled(IER_RECEIVED) && (receiving || timeout))
+            || (enabled(IER_MODEM_STATUS) && receiving)
+    }
+
+    /// Sets the receive timer for the next character time if it's needed and not set, or cancels
+    /// it if it isn't needed.
+    fn update_receive_timer(&mut self, sys: &mut System) {
+        if !self.needs_receive_timer() {
+            if let Some(timer) = self.receive_timer.take() {
+                sys.cancel_timer(timer);
+            }
+        } else if self.receive_timer.is_none() {
+            let at = sys.clock.now() + self.character_time();
+            self.receive_timer = Some(sys.set_timer(at, RECEIVE_TIMER));
+        }
+    }
+
+    fn cancel_timers(&mut self, sys: &mut System) {
+        for timer in [self.transmit_timer.take(), self.receive_timer.take()]
+            .into_iter()
+            .flatten()
+        {
+            sys.cancel_timer(timer);
+        }
+    }
+}
+
+impl Device<Cpu> for Uart {
+    fn init(&mut self, sys: &mut System) {
+        Device::reset(self, sys);
+    }
+
+    fn reset(&mut self, sys: &mut System) {
+        self.cancel_timers(sys);
+
+        self.divisor = 12;
+        self.interrupt_enable = 0;
+        self.line_control = 0;
+        self.modem_control = 0;
+        self.fifos_enabled = false;
+        self.trigger_level = 1;
+
+        self.receive.clear();
+        self.transmit.clear();
+        self.shifting = None;
+        self.line_errors = 0;
+        self.modem_deltas = 0;
+        self.transmit_empty_pending = false;
+        self.timeout_pending = false;
+        self.receive_activity = sys.clock.now();
+        self.last_poll = sys.clock.now();
+
+        self.backend.set_modem_control(false, false);
+        self.update_modem_status(sys);
+        self.modem_deltas = 0;
+        self.update_irq(sys);
+    }
+
+    fn timer(&mut self, sys: &mut System, token: u64) {
+        if token == TRANSMIT_TIMER {
+            self.transmit_timer = None;
+            if let Some(byte) = self.shifting.take() {
+                if self.is_loopback() {
+                    self.receive_byte(sys, byte);
+                } else {
+                    self.backend.write(byte);
+                }
+            }
+            self.start_transmit(sys);
+        } else {
+            self.receive_timer = None;
+            self.receive_from_backend(sys);
+
+            let idle = sys.clock.now().saturating_sub(self.receive_activity);
+            if self.fifos_enabled
+                && !self.receive.is_empty()
+                && idle >= self.character_time() * TIMEOUT_CHARACTERS
+            {
+                self.timeout_pending = true;
+            }
+            self.update_modem_status(sys);
+        }
+
+        self.update_receive_timer(sys);
+        self.update_irq(sys);
+    }
+
+    fn handle_port(&mut self, sys: &mut System, request: PortRequest) -> Option<PortResponse> {
+        let base = self.port.base();
+
+        match request {
+            PortRequest::In8(port) if (base..base + 8).contains(&port) => {
+                Some(PortResponse::In8(self.read(sys, port - base)))
+            }
+            PortRequest::Out8(port, value) if (base..base + 8).contains(&port) => {
+                self.write(sys, port - base, value);
+                Some(PortResponse::Out)
+            }
+            _ => None,
+        }
+    }
+
+    fn claims(&self) -> Claims {
+        let base = self.port.base();
+
+        Claims {
+            ports: vec![base..=base + 7],
+            mem: Vec::new(),
+        }
+    }
+
+    fn dump(&self) -> Option<String> {
+        Some(format!(
+            "{:?} ({:?}): {} baud, IER {:#04x}, LCR {:#04x}, MCR {:#04x}, {} received and {} to \
+             transmit",
+            self.port,
+            self.model,
+            self.baud_rate(),
+            self.interrupt_enable,
+            self.line_control,
+            self.modem_control,
+            self.receive.len(),
+            self.transmit.len() + usize::from(self.shifting.is_some()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mouse::Buttons;
+    use crate::device::serial::{Loopback, LoopbackRemote, NullBackend};
+    use crate::device::tests::run_for;
+    use crate::device::{self, SerialMouse};
+
+    const COM1: u16 = 0x3f8;
+
+    fn create_sys(
+        model: UartModel,
+        backend: impl SerialBackend,
+    ) -> (System, DeviceRef<DualPic>, DeviceRef<Uart>) {
+        device::tests::create_sys(|_, pic| Uart::new(pic, ComPort::Com1, model, backend))
+    }
+
+    fn create_loopback_sys(model: UartModel) -> (System, DeviceRef<DualPic>, LoopbackRemote) {
+        let (backend, remote) = Loopback::new();
+        let (mut sys, pic, _) = create_sys(model, backend);
+        // 115200 baud, 8N1
+        sys.port_out_8(COM1 + 3, 0x80).unwrap();
+        sys.port_out_8(COM1, 0x01).unwrap();
+        sys.port_out_8(COM1 + 1, 0x00).unwrap();
+        sys.port_out_8(COM1 + 3, 0x03).unwrap();
+
+        (sys, pic, remote)
+    }
+
+    #[test]
+    fn should_transmit_and_receive_with_interrupts() {
+        let (mut sys, pic, remote) = create_loopback_sys(UartModel::Ns16450);
+        sys.port_out_8(COM1 + 4, MCR_OUT2 | MCR_RTS | MCR_DTR)
+            .unwrap();
+        sys.port_out_8(COM1 + 1, IER_RECEIVED | IER_TRANSMIT_EMPTY)
+            .unwrap();
+        assert_eq!(Some(IIR_TRANSMIT_EMPTY), sys.port_in_8(COM1 + 2));
+        assert_eq!(Some(IIR_NONE), sys.port_in_8(COM1 + 2));
+        assert_eq!(0x10, sys.get_device(pic).master.request_reg);
+
+        sys.port_out_8(COM1, b'h').unwrap();
+        assert_eq!(Some(LSR_TRANSMIT_EMPTY), sys.port_in_8(COM1 + 5));
+        run_for(&mut sys, Duration::from_micros(100));
+        assert_eq!(b"h".to_vec(), remote.take_received());
+        assert_eq!(Some(0x60), sys.port_in_8(COM1 + 5));
+        assert_eq!(Some(IIR_TRANSMIT_EMPTY), sys.port_in_8(COM1 + 2));
+
+        remote.send(b"ok");
+        run_for(&mut sys, Duration::from_micros(200));
+        assert_eq!(Some(IIR_RECEIVED), sys.port_in_8(COM1 + 2));
+        // Without a FIFO, the second byte overruns the first
+        assert_eq!(Some(b'k'), sys.port_in_8(COM1));
+        assert_eq!(
+            Some(LSR_OVERRUN),
+            sys.port_in_8(COM1 + 5).map(|lsr| lsr & 3)
+        );
+
+        // Interrupts don't reach the PIC without OUT2
+        sys.port_out_8(COM1 + 4, 0x00).unwrap();
+        sys.get_device_mut(pic).master.request_reg = 0;
+        remote.send(b"!");
+        run_for(&mut sys, Duration::from_micros(100));
+        assert_eq!(0, sys.get_device(pic).master.request_reg);
+    }
+
+    #[test]
+    fn should_buffer_in_fifo_until_trigger_level_or_timeout() {
+        let (mut sys, _, remote) = create_loopback_sys(UartModel::Ns16550A);
+        // Enable and clear the FIFOs with a trigger level of 4
+        sys.port_out_8(COM1 + 2, 0x47).unwrap();
+        sys.port_out_8(COM1 + 1, IER_RECEIVED).unwrap();
+        assert_eq!(Some(IIR_FIFOS_ENABLED | IIR_NONE), sys.port_in_8(COM1 + 2));
+
+        remote.send(b"abcde");
+        run_for(&mut sys, Duration::from_micros(270));
+        assert_eq!(Some(IIR_FIFOS_ENABLED | IIR_NONE), sys.port_in_8(COM1 + 2));
+        run_for(&mut sys, Duration::from_micros(90));
+        assert_eq!(
+            Some(IIR_FIFOS_ENABLED | IIR_RECEIVED),
+            sys.port_in_8(COM1 + 2)
+        );
+        for byte in b"abcd" {
+            assert_eq!(Some(*byte), sys.port_in_8(COM1));
+        }
+
+        run_for(&mut sys, Duration::from_micros(100));
+        assert_eq!(Some(IIR_FIFOS_ENABLED | IIR_NONE), sys.port_in_8(COM1 + 2));
+        run_for(&mut sys, Duration::from_micros(400));
+        assert_eq!(
+            Some(IIR_FIFOS_ENABLED | IIR_TIMEOUT),
+            sys.port_in_8(COM1 + 2)
+        );
+        assert_eq!(Some(b'e'), sys.port_in_8(COM1));
+        assert_eq!(Some(IIR_FIFOS_ENABLED | IIR_NONE), sys.port_in_8(COM1 + 2));
+    }
+
+    #[test]
+    fn should_identify_model_and_loop_back_modem_lines() {
+        for (model, scratch, iir) in [
+            (UartModel::Ns8250, 0xff, 0x01),
+            (UartModel::Ns16450, 0x5a, 0x01),
+            (UartModel::Ns16550A, 0x5a, 0xc1),
+        ] {
+            let (mut sys, _, _) = create_loopback_sys(model);
+            sys.port_out_8(COM1 + 7, 0x5a).unwrap();
+            sys.port_out_8(COM1 + 2, 0x01).unwrap();
+            assert_eq!(Some(scratch), sys.port_in_8(COM1 + 7));
+            assert_eq!(Some(iir), sys.port_in_8(COM1 + 2));
+        }
+
+        let (mut sys, _, remote) = create_loopback_sys(UartModel::Ns16550A);
+        assert_eq!(Some(0xb0), sys.port_in_8(COM1 + 6));
+        sys.port_out_8(COM1 + 4, MCR_LOOPBACK | MCR_RTS | MCR_OUT1)
+            .unwrap();
+        assert_eq!(Some(0x5a), sys.port_in_8(COM1 + 6));
+        sys.port_out_8(COM1 + 4, MCR_LOOPBACK).unwrap();
+        assert_eq!(Some(0x05), sys.port_in_8(COM1 + 6));
+
+        sys.port_out_8(COM1, 0x42).unwrap();
+        run_for(&mut sys, Duration::from_micros(100));
+        assert_eq!(Some(0x42), sys.port_in_8(COM1));
+        assert!(remote.take_received().is_empty());
+    }
+
+    #[test]
+    fn should_only_poll_backends_that_can_send_bytes() {
+        let (mut sys, _, uart) = create_sys(UartModel::Ns16550A, NullBackend);
+        sys.port_out_8(COM1 + 1, IER_RECEIVED | IER_MODEM_STATUS)
+            .unwrap();
+        assert!(sys.get_device(uart).receive_timer.is_none());
+
+        let (mut sys, _, remote) = create_loopback_sys(UartModel::Ns16450);
+        let uart = sys.device::<Uart>().unwrap();
+        assert!(sys.get_device(uart).receive_timer.is_none());
+        sys.port_out_8(COM1 + 1, IER_RECEIVED).unwrap();
+        assert!(sys.get_device(uart).receive_timer.is_some());
+        sys.port_out_8(COM1 + 1, 0x00).unwrap();
+        assert!(sys.get_device(uart).receive_timer.is_none());
+
+        // Polling the line status register receives without the timer
+        remote.send(b"p");
+        run_for(&mut sys, Duration::from_micros(100));
+        assert_eq!(
+            Some(LSR_DATA_READY),
+            sys.port_in_8(COM1 + 5).map(|lsr| lsr & 1)
+        );
+        assert_eq!(Some(b'p'), sys.port_in_8(COM1));
+    }
+
+    #[test]
+    fn should_replay_received_bytes_and_modem_lines() {
+        let (mut sys, _, remote) = create_loopback_sys(UartModel::Ns16450);
+        sys.record_inputs();
+        sys.port_out_8(COM1 + 1, IER_RECEIVED | IER_MODEM_STATUS)
+            .unwrap();
+        remote.send(b"r");
+        run_for(&mut sys, Duration::from_micros(100));
+        let received = sys.port_in_8(COM1);
+        let log = sys.stop_inputs().unwrap();
+        let channels: Vec<_> = log.events.iter().map(|event| &event.channel[..]).collect();
+        assert_eq!(vec!["Com1.rx", "Com1.modem"], channels);
+
+        // The host's bytes are ignored while replaying
+        let (mut sys, _, remote) = create_loopback_sys(UartModel::Ns16450);
+        sys.replay_inputs(log);
+        sys.port_out_8(COM1 + 1, IER_RECEIVED | IER_MODEM_STATUS)
+            .unwrap();
+        remote.send(b"x");
+        run_for(&mut sys, Duration::from_micros(100));
+        assert_eq!(Some(b'r'), received);
+        assert_eq!(received, sys.port_in_8(COM1));
+        assert_eq!(None, sys.divergence());
+    }
+
+    #[test]
+    fn should_identify_serial_mouse_when_rts_rises() {
+        let (mut sys, _, uart) = create_sys(UartModel::Ns16450, SerialMouse::new());
+        // 1200 baud, 7N1
+        sys.port_out_8(COM1 + 3, 0x80).unwrap();
+        sys.port_out_8(COM1, 0x60).unwrap();
+        sys.port_out_8(COM1 + 1, 0x00).unwrap();
+        sys.port_out_8(COM1 + 3, 0x02).unwrap();
+        assert_eq!(1200, sys.get_device(uart).baud_rate());
+
+        sys.port_out_8(COM1 + 4, MCR_DTR | MCR_RTS).unwrap();
+        run_for(&mut sys, Duration::from_millis(10));
+        assert_eq!(Some(b'M'), sys.port_in_8(COM1));
+
+        let buttons = Buttons {
+            right: true,
+            ..Buttons::default()
+        };
+        sys.with_device(uart, |uart, _| {
+            uart.backend_mut::<SerialMouse>()
+                .unwrap()
+                .move_by(-1, 1, buttons);
+        });
+        let mut packet = Vec::new();
+        for _ in 0..3 {
+            run_for(&mut sys, Duration::from_micros(7_500));
+            packet.extend(sys.port_in_8(COM1));
+        }
+        assert_eq!(vec![0x53, 0x3f, 0x01], packet);
+    }
+}
@@ -0,0 +1,819 @@
+use crate::modrm::Displacement;
+use crate::opcodes::{self, InvalidOpcode};
+use crate::{
+    Cpu, Feature, GeneralByteReg, GeneralWordReg, InstrFunc, InstrMeta, Modrm, ModrmRegType,
+    Prefixes, Reg, RegMem, RmPtr, SegmentReg, Size, System, WordReg,
+};
+use firn_core::cpu::Restrict;
+use firn_core::mem::MemMap;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::{Deref, DerefMut};
+
+/// The longest an instruction (including its prefixes) can be.
+pub const MAX_INSTR_LEN: usize = 15;
+/// The most operands an instruction can have.
+pub const MAX_OPERANDS: usize = 3;
+
+/// A source of instruction bytes for the [`Disassembler`].
+///
+/// Offsets are relative to the first byte of the instruction being decoded. Implementations should
+/// return `None` once there are no more bytes to read.
+///
+/// [`Disassembler`]: Disassembler
+pub trait ByteSource {
+    fn byte(&self, offset: usize) -> Option<u8>;
+}
+
+impl ByteSource for [u8] {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.get(offset).copied()
+    }
+}
+
+impl ByteSource for Vec<u8> {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.get(offset).copied()
+    }
+}
+
+/// Instruction bytes read from a segment of system memory.
+///
+/// Offsets wrap around at the end of the segment just like the instruction pointer does. Reading
+/// past the end of addressable memory returns `None` instead of panicking.
+pub struct SegmentedMem<'a> {
+    mem: &'a MemMap,
+    segment: u16,
+    offset: u16,
+    address_mask: usize,
+}
+
+impl<'a> SegmentedMem<'a> {
+    pub fn new(mem: &'a MemMap, segment: u16, offset: u16) -> Self {
+        Self {
+            mem,
+            segment,
+            offset,
+            address_mask: usize::MAX,
+        }
+    }
+
+    /// Masks linear addresses like [`Cpu::address_mask`] does, which nothing is by default.
+    ///
+    /// [`Cpu::address_mask`]: crate::Cpu::address_mask
+    pub fn with_address_mask(mut self, address_mask: usize) -> Self {
+        self.address_mask = address_mask;
+        self
+    }
+}
+
+impl ByteSource for SegmentedMem<'_> {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        let offset = self.offset.wrapping_add(offset as u16);
+        let linear = (((self.segment as usize) << 4) + offset as usize) & self.address_mask;
+
+        (linear < self.mem.addressable).then(|| self.mem[linear])
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The opcode doesn't map to an instruction that the disassembler knows about.
+    InvalidOpcode(InvalidOpcode),
+    /// The ModRM byte refers to a register that doesn't exist.
+    InvalidModrm(u8),
+    /// The byte source ran out of bytes in the middle of an instruction.
+    UnexpectedEnd,
+    /// The instruction is longer than [`MAX_INSTR_LEN`] bytes.
+    ///
+    /// [`MAX_INSTR_LEN`]: MAX_INSTR_LEN
+    TooLong,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::InvalidOpcode(invalid) => Display::fmt(invalid, f),
+            DecodeError::InvalidModrm(modrm) => write!(f, "invalid ModRM byte: {:#04x}", modrm),
+            DecodeError::UnexpectedEnd => f.write_str("unexpected end of instruction bytes"),
+            DecodeError::TooLong => write!(f, "instruction is longer than {} bytes", MAX_INSTR_LEN),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl From<InvalidOpcode> for DecodeError {
+    fn from(invalid: InvalidOpcode) -> Self {
+        DecodeError::InvalidOpcode(invalid)
+    }
+}
+
+/// The kind of an operand, as written in an instruction's mnemonic.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OperandKind {
+    Imm8,
+    Imm16,
+    Rel8,
+    Rel16,
+    Moffs8,
+    Moffs16,
+
+    R8,
+    R16,
+    Sreg,
+
+    M8,
+    M16,
+
+    Rm8,
+    Rm16,
+
+    Ptr16_16,
+    M16_16,
+
+    /// A register that's implied by the opcode, like the `AL` in `ADD AL, imm8`.
+    Fixed(Reg),
+    /// A constant that's implied by the opcode, like the `1` in `SHL r/m8, 1`.
+    Const(u8),
+}
+
+impl OperandKind {
+    /// Whether or not the operand is encoded in a ModRM byte's r/m field.
+    pub fn uses_modrm_rm(&self) -> bool {
+        matches!(
+            self,
+            OperandKind::M8
+                | OperandKind::M16
+                | OperandKind::Rm8
+                | OperandKind::Rm16
+                | OperandKind::M16_16
+        )
+    }
+}
+
+/// A decoded operand.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    /// A memory operand from a ModRM byte, along with the size of the value it points to.
+    ///
+    /// The size is `None` if the value isn't actually accessed, like the operand of `LEA`.
+    Ptr(RmPtr, Option<PtrSize>),
+    /// A memory offset relative to the instruction's segment (`DS` unless it's overridden).
+    Moffs(u16),
+    Imm8(u8),
+    Imm16(u16),
+    Rel8(u8),
+    Rel16(u16),
+    Far {
+        segment: u16,
+        offset: u16,
+    },
+    Const(u8),
+}
+
+impl Operand {
+    /// The value of an 8-bit immediate, relative offset or constant operand.
+    pub fn imm_8(&self) -> u8 {
+        match *self {
+            Operand::Imm8(imm) | Operand::Rel8(imm) | Operand::Const(imm) => imm,
+            _ => panic!("expected a byte-sized immediate operand"),
+        }
+    }
+
+    /// The value of a 16-bit immediate, relative offset or memory offset operand.
+    pub fn imm_16(&self) -> u16 {
+        match *self {
+            Operand::Imm16(imm) | Operand::Rel16(imm) | Operand::Moffs(imm) => imm,
+            _ => panic!("expected a word-sized immediate operand"),
+        }
+    }
+
+    pub fn byte_reg(&self) -> GeneralByteReg {
+        match *self {
+            Operand::Reg(Reg::Byte(reg)) => reg,
+            _ => panic!("expected a byte-sized register operand"),
+        }
+    }
+
+    pub fn word_reg(&self) -> GeneralWordReg {
+        match *self {
+            Operand::Reg(Reg::Word(WordReg::General(reg))) => reg,
+            _ => panic!("expected a word-sized register operand"),
+        }
+    }
+
+    pub fn segment_reg(&self) -> SegmentReg {
+        match *self {
+            Operand::Reg(Reg::Word(WordReg::Segment(reg))) => reg,
+            _ => panic!("expected a segment register operand"),
+        }
+    }
+
+    pub fn ptr(&self) -> RmPtr {
+        match *self {
+            Operand::Ptr(ptr, _) => ptr,
+            _ => panic!("expected a memory pointer operand"),
+        }
+    }
+
+    pub fn reg_mem(&self) -> RegMem {
+        match *self {
+            Operand::Reg(Reg::Byte(reg)) => RegMem::Reg(reg.into()),
+            Operand::Reg(Reg::Word(WordReg::General(reg))) => RegMem::Reg(reg.into()),
+            Operand::Ptr(ptr, _) => RegMem::Ptr(ptr),
+            _ => panic!("expected a register or memory operand"),
+        }
+    }
+
+    /// The offset and segment of a far pointer operand.
+    pub fn far(&self) -> (u16, u16) {
+        match *self {
+            Operand::Far { segment, offset } => (offset, segment),
+            _ => panic!("expected a far pointer operand"),
+        }
+    }
+}
+
+/// The operands of a [`DecodedInstr`], which dereference to a slice.
+///
+/// The operands are stored inline so that decoding an instruction doesn't allocate.
+///
+/// [`DecodedInstr`]: DecodedInstr
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Operands {
+    operands: [Operand; MAX_OPERANDS],
+    len: usize,
+}
+
+impl Operands {
+    pub fn new() -> Self {
+        Self {
+            operands: [Operand::Const(0); MAX_OPERANDS],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, operand: Operand) {
+        if self.len == MAX_OPERANDS {
+            panic!(
+                "an instruction can't have more than {} operands",
+                MAX_OPERANDS
+            );
+        }
+
+        self.operands[self.len] = operand;
+        self.len += 1;
+    }
+}
+
+impl Default for Operands {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Operands {
+    type Target = [Operand];
+
+    fn deref(&self) -> &Self::Target {
+        &self.operands[..self.len]
+    }
+}
+
+impl DerefMut for Operands {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.operands[..self.len]
+    }
+}
+
+impl Debug for Operands {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PtrSize {
+    Byte,
+    Word,
+    /// A far pointer (`m16:16`).
+    DoubleWord,
+}
+
+impl PtrSize {
+    fn name(&self) -> &'static str {
+        match self {
+            PtrSize::Byte => "byte",
+            PtrSize::Word => "word",
+            PtrSize::DoubleWord => "dword",
+        }
+    }
+}
+
+/// An instruction decoded by the [`Disassembler`].
+///
+/// Formatting a `DecodedInstr` with [`Display`] produces Intel syntax with relative jumps resolved
+/// to the offset that they jump to.
+///
+/// [`Disassembler`]: Disassembler
+/// [`Display`]: std::fmt::Display
+#[derive(Debug)]
+pub struct DecodedInstr {
+    /// The offset (in the code segment) of the first byte of the instruction, including prefixes.
+    pub ip: u16,
+    /// The length of the instruction in bytes, including prefixes.
+    pub len: usize,
+
+    pub prefixes: Prefixes,
+    /// The segment from a segment override prefix, if the instruction has one.
+    pub segment_override: Option<SegmentReg>,
+    pub opcode: u8,
+    pub operands: Operands,
+
+    pub meta: &'static InstrMeta,
+    func: InstrFunc,
+}
+
+impl DecodedInstr {
+    /// Executes the instruction, assuming that `IP` points to its first byte.
+    pub fn execute(&self, sys: &mut System) {
+        sys.cpu.inc_ip_16(self.len as u16);
+        (self.func.0)(sys, self);
+    }
+
+    /// The name of the instruction, like `ADD` or `MOVSB`.
+    pub fn name(&self) -> &'static str {
+        self.meta.name
+    }
+
+    /// The offset of the instruction directly after this one.
+    pub fn next_ip(&self) -> u16 {
+        self.ip.wrapping_add(self.len as u16)
+    }
+
+    fn fmt_operand(&self, f: &mut Formatter<'_>, operand: &Operand) -> std::fmt::Result {
+        match *operand {
+            Operand::Reg(reg) => write!(f, "{}", reg),
+            Operand::Ptr(ptr, size) => {
+                if let Some(size) = size {
+                    write!(f, "{} ptr ", size.name())?;
+                }
+                if let Some(segment) = self.segment_override {
+                    write!(f, "{}:", segment)?;
+                }
+
+                f.write_str("[")?;
+                let mut first = true;
+                for reg in ptr.regs() {
+                    if !first {
+                        f.write_str("+")?;
+                    }
+                    write!(f, "{}", reg)?;
+                    first = false;
+                }
+                match ptr.displacement() {
+                    Some(Displacement::SignedByte(disp)) if disp < 0 => {
+                        write!(f, "-{:#x}", disp.unsigned_abs())?
+                    }
+                    Some(Displacement::SignedByte(disp)) => write!(f, "+{:#x}", disp)?,
+                    Some(Displacement::UnsignedWord(disp)) if first => write!(f, "{:#x}", disp)?,
+                    Some(Displacement::UnsignedWord(disp)) => write!(f, "+{:#x}", disp)?,
+                    None => (),
+                }
+                f.write_str("]")
+            }
+            Operand::Moffs(offset) => {
+                if let Some(segment) = self.segment_override {
+                    write!(f, "{}:", segment)?;
+                }
+                write!(f, "[{:#x}]", offset)
+            }
+            Operand::Imm8(imm) => write!(f, "{:#x}", imm),
+            Operand::Imm16(imm) => write!(f, "{:#x}", imm),
+            Operand::Rel8(rel) => {
+                let target = self.next_ip().wrapping_add(rel as i8 as u16);
+                write!(f, "{:#x}", target)
+            }
+            Operand::Rel16(rel) => write!(f, "{:#x}", self.next_ip().wrapping_add(rel)),
+            Operand::Far { segment, offset } => write!(f, "{:#x}:{:#x}", segment, offset),
+            Operand::Const(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl Display for DecodedInstr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = self.name().to_lowercase();
+
+        if self.prefixes.lock {
+            f.write_str("lock ")?;
+        }
+        if self.prefixes.rep_ne {
+            f.write_str("repne ")?;
+        } else if self.prefixes.rep_or_rep_e {
+            let compares = name.starts_with("cmps") || name.starts_with("scas");
+            f.write_str(if compares { "repe " } else { "rep " })?;
+        }
+
+        f.write_str(&name)?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            f.write_str(if index == 0 { " " } else { ", " })?;
+            self.fmt_operand(f, operand)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A standalone x86 instruction decoder.
+///
+/// The disassembler doesn't need a [`System`] and has no side effects, so it can decode
+/// instructions from any [`ByteSource`] (a byte slice, a file that's been read into memory, or
+/// system memory through [`SegmentedMem`]) without executing them. The CPU uses it to decode
+/// instructions before executing them.
+///
+/// The disassembler supports the same instructions as the CPU. Like the CPU, it implements
+/// [`Restrict`] to control which features (and therefore which instructions) it supports.
+///
+/// [`System`]: crate::System
+/// [`ByteSource`]: ByteSource
+/// [`SegmentedMem`]: SegmentedMem
+/// [`Restrict`]: firn_core::cpu::Restrict
+pub struct Disassembler {
+    features: Vec<Feature>,
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Self {
+            features: Vec::new(),
+        }
+    }
+
+    /// Creates a disassembler which has the same features as a CPU.
+    pub fn from_cpu(cpu: &Cpu) -> Self {
+        let mut disassembler = Self::new();
+        for feature in [Feature::InstrCpu1] {
+            if cpu.has_feature(feature) {
+                disassembler.add_feature(feature);
+            }
+        }
+
+        disassembler
+    }
+
+    /// Decodes a single instruction from the start of `source`.
+    ///
+    /// `ip` is the offset of the instruction in its code segment, which is used to resolve
+    /// relative jumps.
+    pub fn decode(
+        &self,
+        source: &(impl ByteSource + ?Sized),
+        ip: u16,
+    ) -> Result<DecodedInstr, DecodeError> {
+        Self::decode_with(self, source, ip)
+    }
+
+    /// Decodes a single instruction from the start of `source`, supporting the instructions
+    /// allowed by `features` (usually a [`Cpu`]).
+    ///
+    /// [`Cpu`]: crate::Cpu
+    pub fn decode_with(
+        features: &impl Restrict<Feature = Feature>,
+        source: &(impl ByteSource + ?Sized),
+        ip: u16,
+    ) -> Result<DecodedInstr, DecodeError> {
+        let mut reader = Reader { source, len: 0 };
+
+        let mut prefixes = Prefixes::new();
+        let mut segment_override = None;
+        let opcode = loop {
+            let byte = reader.read_8()?;
+            if !opcodes::apply_prefix(&mut prefixes, byte) {
+                break byte;
+            }
+
+            if let 0x26 | 0x2e | 0x36 | 0x3e = byte {
+                segment_override = Some(prefixes.segment);
+            }
+        };
+
+        let extension = opcodes::extension(reader.peek_8().unwrap_or_default());
+        let instr = opcodes::match_opcode(features, opcode, extension)?;
+        let mut operands = Self::decode_operands(&mut reader, opcode, instr.meta.operands)?;
+
+        // LEA only calculates an address, so the value that its operand points to has no size
+        if instr.meta.name == "LEA" {
+            for operand in operands.iter_mut() {
+                if let Operand::Ptr(_, size) = operand {
+                    *size = None;
+                }
+            }
+        }
+
+        Ok(DecodedInstr {
+            ip,
+            len: reader.len,
+
+            prefixes,
+            segment_override,
+            opcode,
+            operands,
+
+            meta: &instr.meta,
+            func: instr.func,
+        })
+    }
+
+    /// Decodes a single instruction from system memory at `segment:offset`.
+    pub fn decode_at(
+        &self,
+        mem: &MemMap,
+        segment: u16,
+        offset: u16,
+    ) -> Result<DecodedInstr, DecodeError> {
+        let source = SegmentedMem::new(mem, segment, offset);
+        self.decode(&source, offset)
+    }
+
+    /// Decodes instructions from the start of `bytes` until it runs out of bytes or finds an
+    /// instruction that it can't decode.
+    pub fn decode_all(&self, bytes: &[u8], ip: u16) -> Vec<DecodedInstr> {
+        let mut instrs = Vec::new();
+
+        let mut offset = 0;
+        while let Ok(instr) = self.decode(&bytes[offset..], ip.wrapping_add(offset as u16)) {
+            offset += instr.len;
+            instrs.push(instr);
+        }
+
+        instrs
+    }
+
+    fn decode_operands(
+        reader: &mut Reader<impl ByteSource + ?Sized>,
+        opcode: u8,
+        kinds: &[OperandKind],
+    ) -> Result<Operands, DecodeError> {
+        let rm_size = kinds.iter().find_map(|kind| match kind {
+            OperandKind::M8 | OperandKind::Rm8 => Some(Size::Byte),
+            kind if kind.uses_modrm_rm() => Some(Size::Word),
+            _ => None,
+        });
+
+        let mut modrm_byte = 0;
+        let modrm = match rm_size {
+            Some(rm_size) => {
+                let reg_type = kinds.iter().find_map(|kind| match kind {
+                    OperandKind::R8 => Some(ModrmRegType::ByteSized),
+                    OperandKind::R16 => Some(ModrmRegType::WordSized),
+                    OperandKind::Sreg => Some(ModrmRegType::Segment),
+                    _ => None,
+                });
+
+                let byte = reader.read_8()?;
+                modrm_byte = byte;
+                let mut missing = false;
+                let modrm = Modrm::decode_with(byte, reg_type, rm_size, |size| {
+                    let displacement = match size {
+                        Size::Byte => reader.read_8().map(u16::from),
+                        Size::Word => reader.read_16(),
+                    };
+                    missing = displacement.is_err();
+
+                    displacement.ok()
+                });
+
+                match modrm {
+                    Some(modrm) => Some(modrm),
+                    None if missing => return Err(DecodeError::UnexpectedEnd),
+                    None => return Err(DecodeError::InvalidModrm(byte)),
+                }
+            }
+            None => None,
+        };
+
+        let reg_mem_ptr = |size| match modrm.as_ref().map(|modrm| modrm.reg_mem) {
+            Some(RegMem::Reg(reg)) => Operand::Reg(reg.into()),
+            Some(RegMem::Ptr(ptr)) => Operand::Ptr(ptr, size),
+            None => unreachable!("operand requires a ModRM byte"),
+        };
+        let modrm_reg = || match modrm.as_ref().and_then(|modrm| modrm.reg) {
+            Some(reg) => Operand::Reg(reg),
+            None => unreachable!("operand requires a ModRM byte"),
+        };
+
+        let mut operands = Operands::new();
+        for kind in kinds {
+            let operand = match kind {
+                OperandKind::Imm8 => Operand::Imm8(reader.read_8()?),
+                OperandKind::Imm16 => Operand::Imm16(reader.read_16()?),
+                OperandKind::Rel8 => Operand::Rel8(reader.read_8()?),
+                OperandKind::Rel16 => Operand::Rel16(reader.read_16()?),
+                OperandKind::Moffs8 | OperandKind::Moffs16 => Operand::Moffs(reader.read_16()?),
+
+                OperandKind::R8 | OperandKind::R16 | OperandKind::Sreg if modrm.is_some() => {
+                    modrm_reg()
+                }
+                OperandKind::R8 => Operand::Reg(
+                    GeneralByteReg::from_u8(opcode % 0o10)
+                        .expect("invalid byte-sized register in opcode")
+                        .into(),
+                ),
+                OperandKind::R16 => Operand::Reg(
+                    GeneralWordReg::from_u8(opcode % 0o10)
+                        .expect("invalid word-sized register in opcode")
+                        .into(),
+                ),
+                OperandKind::Sreg => unreachable!("segment register operand requires ModRM"),
+
+                OperandKind::M8 | OperandKind::M16 | OperandKind::M16_16
+                    if matches!(
+                        modrm.as_ref().map(|modrm| modrm.reg_mem),
+                        Some(RegMem::Reg(_))
+                    ) =>
+                {
+                    return Err(DecodeError::InvalidModrm(modrm_byte));
+                }
+                OperandKind::M8 => reg_mem_ptr(Some(PtrSize::Byte)),
+                OperandKind::M16 => reg_mem_ptr(Some(PtrSize::Word)),
+                OperandKind::Rm8 => reg_mem_ptr(Some(PtrSize::Byte)),
+                OperandKind::Rm16 => reg_mem_ptr(Some(PtrSize::Word)),
+                OperandKind::M16_16 => reg_mem_ptr(Some(PtrSize::DoubleWord)),
+
+                OperandKind::Ptr16_16 => {
+                    let offset = reader.read_16()?;
+                    let segment = reader.read_16()?;
+                    Operand::Far { segment, offset }
+                }
+
+                OperandKind::Fixed(reg) => Operand::Reg(*reg),
+                OperandKind::Const(value) => Operand::Const(*value),
+            };
+
+            operands.push(operand);
+        }
+
+        Ok(operands)
+    }
+}
+
+impl Restrict for Disassembler {
+    type Feature = Feature;
+
+    fn add_feature(&mut self, feature: Self::Feature) {
+        self.features.push(feature);
+    }
+
+    fn has_feature(&self, feature: Self::Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Reader<'a, S>
+where
+    S: ByteSource + ?Sized,
+{
+    source: &'a S,
+    len: usize,
+}
+
+impl<S> Reader<'_, S>
+where
+    S: ByteSource + ?Sized,
+{
+    fn peek_8(&self) -> Option<u8> {
+        self.source.byte(self.len)
+    }
+
+    fn read_8(&mut self) -> Result<u8, DecodeError> {
+        if self.len >= MAX_INSTR_LEN {
+            return Err(DecodeError::TooLong);
+        }
+
+        let byte = self.peek_8().ok_or(DecodeError::UnexpectedEnd)?;
+        self.len += 1;
+
+        Ok(byte)
+    }
+
+    fn read_16(&mut self) -> Result<u16, DecodeError> {
+        let low = self.read_8()?;
+        let high = self.read_8()?;
+
+        Ok(u16::from_le_bytes([low, high]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble(bytes: &[u8]) -> String {
+        let mut disassembler = Disassembler::new();
+        disassembler.add_feature(Feature::InstrCpu1);
+
+        disassembler.decode(bytes, 0x100).unwrap().to_string()
+    }
+
+    #[test]
+    fn should_decode_register_operands() {
+        assert_eq!("add ax, bx", disassemble(&[0x01, 0xd8]));
+    }
+
+    #[test]
+    fn should_decode_memory_operands() {
+        assert_eq!(
+            "mov ax, word ptr [bp-0x2]",
+            disassemble(&[0x8b, 0x46, 0xfe])
+        );
+    }
+
+    #[test]
+    fn should_decode_direct_addresses() {
+        assert_eq!(
+            "mov byte ptr [0x1234], 0x56",
+            disassemble(&[0xc6, 0x06, 0x34, 0x12, 0x56])
+        );
+    }
+
+    #[test]
+    fn should_decode_opcode_registers() {
+        assert_eq!("mov cx, 0x1234", disassemble(&[0xb9, 0x34, 0x12]));
+    }
+
+    #[test]
+    fn should_resolve_relative_jumps() {
+        assert_eq!("jmp 0x100", disassemble(&[0xeb, 0xfe]));
+    }
+
+    #[test]
+    fn should_decode_far_pointers() {
+        assert_eq!(
+            "jmp 0xf000:0xe05b",
+            disassemble(&[0xea, 0x5b, 0xe0, 0x00, 0xf0])
+        );
+    }
+
+    #[test]
+    fn should_decode_prefixes() {
+        assert_eq!("rep movsb", disassemble(&[0xf3, 0xa4]));
+        assert_eq!("repe cmpsw", disassemble(&[0xf3, 0xa7]));
+        assert_eq!("mov al, byte ptr es:[bx]", disassemble(&[0x26, 0x8a, 0x07]));
+    }
+
+    #[test]
+    fn should_not_size_lea_operands() {
+        assert_eq!("lea si, [bx+di+0x10]", disassemble(&[0x8d, 0x71, 0x10]));
+    }
+
+    #[test]
+    fn should_calculate_length() {
+        let instr = Disassembler::new()
+            .decode(&[0x2e, 0x81, 0x87, 0x34, 0x12, 0x78, 0x56][..], 0)
+            .unwrap();
+        assert_eq!(7, instr.len);
+    }
+
+    #[test]
+    fn should_respect_features() {
+        let disassembler = Disassembler::new();
+        let result = disassembler.decode(&[0x60][..], 0);
+        assert!(matches!(result, Err(DecodeError::InvalidOpcode(_))));
+    }
+
+    #[test]
+    fn should_fail_on_truncated_instructions() {
+        let result = Disassembler::new().decode(&[0xb8, 0x34][..], 0);
+        assert_eq!(DecodeError::UnexpectedEnd, result.unwrap_err());
+    }
+
+    #[test]
+    fn should_decode_all_instructions() {
+        let bytes = [0xfa, 0xb8, 0x00, 0x00, 0x8e, 0xd0, 0xf4];
+        let instrs = Disassembler::new().decode_all(&bytes, 0x7c00);
+        let text: Vec<_> = instrs.iter().map(ToString::to_string).collect();
+        assert_eq!(vec!["cli", "mov ax, 0x0", "mov ss, ax", "hlt"], text);
+    }
+
+    #[test]
+    fn should_decode_every_opcode_without_panicking() {
+        let mut disassembler = Disassembler::new();
+        disassembler.add_feature(Feature::InstrCpu1);
+
+        for opcode in 0..=u8::MAX {
+            for modrm in 0..=u8::MAX {
+                let bytes = [opcode, modrm, 0x12, 0x34, 0x56, 0x78];
+                let _ = disassembler.decode(&bytes[..], 0);
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::ops::BitOr;
+
 pub struct Flags {
     pub carry: bool,
     pub parity: bool,
@@ -84,7 +86,7 @@ impl Flags {
     }
 
     pub fn set_parity_from_u8(&mut self, value: u8) {
-        self.parity = value.count_ones() % 2 == 0;
+        self.parity = value.count_ones().is_multiple_of(2);
     }
 
     pub fn set_parity_from_u16(&mut self, value: u16) {
@@ -117,6 +119,57 @@ impl Default for Flags {
     }
 }
 
+/// A set of flags, using the same bits as the FLAGS register.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct FlagSet(u16);
+
+impl FlagSet {
+    pub const NONE: Self = Self(0);
+
+    pub const CARRY: Self = Self(0x001);
+    pub const PARITY: Self = Self(0x004);
+    pub const ADJUST: Self = Self(0x010);
+    pub const ZERO: Self = Self(0x040);
+    pub const SIGN: Self = Self(0x080);
+    pub const TRAP: Self = Self(0x100);
+    pub const INTERRUPT: Self = Self(0x200);
+    pub const DIRECTION: Self = Self(0x400);
+    pub const OVERFLOW: Self = Self(0x800);
+
+    /// The flags that arithmetic instructions set: OF, SF, ZF, AF, PF and CF.
+    pub const STATUS: Self = Self(0x8d5);
+    pub const ALL: Self = Self(0xfd5);
+
+    /// Creates a set from FLAGS register bits, ignoring bits that aren't flags.
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(&self, other: FlagSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: FlagSet) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOr for FlagSet {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        self.union(rhs)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,4 +225,13 @@ mod tests {
                 && flags.overflow
         );
     }
+
+    #[test]
+    fn should_check_flag_sets() {
+        let flags = FlagSet::CARRY | FlagSet::ZERO;
+        assert!(flags.contains(FlagSet::ZERO));
+        assert!(!flags.contains(FlagSet::ZERO | FlagSet::SIGN));
+        assert!(FlagSet::STATUS.contains(flags));
+        assert_eq!(FlagSet::ALL, FlagSet::from_bits(0xffff));
+    }
 }
@@ -0,0 +1,1009 @@
+//! A stub for GDB's remote serial protocol, which lets `gdb` (or any other client) debug a system
+//! over TCP.
+//!
+//! The stub describes the CPU to GDB as an `i8086`, which makes GDB disassemble 16-bit code. GDB
+//! only knows about linear addresses, so the stub reports `eip` as the linear address of `CS:IP`
+//! and treats every memory address and breakpoint as a linear address. The real value of IP is
+//! available as the extra `ip` register.
+//!
+//! GDB's breakpoints are added to the system's breakpoints while it's connected. The system runs
+//! through the run API, so it also stops for its own breakpoints and for unhandled ports (see
+//! [`StopReason`]), which are reported to GDB as `SIGTRAP`.
+//!
+//! If the system is recording (see [`System::start_recording`]), GDB's `reverse-stepi` and
+//! `reverse-continue` commands can be used to go back through its history.
+//!
+//! ```no_run
+//! # fn create_sys() -> firn_arch_x86::System { unimplemented!() }
+//! use firn_arch_x86::gdb::GdbStub;
+//!
+//! let mut sys = create_sys();
+//! let stub = GdbStub::bind("127.0.0.1:1234").unwrap();
+//!
+//! // In another terminal: gdb -ex "target remote 127.0.0.1:1234"
+//! stub.run(&mut sys).unwrap();
+//! ```
+//!
+//! [`System::start_recording`]: firn_core::System::start_recording
+//! [`StopReason`]: firn_core::system::StopReason
+
+use crate::GeneralWordReg;
+use crate::SegmentReg::{Cs, Ds, Es, Ss};
+use crate::{Backend, ExtSystem, System};
+use firn_core::breakpoint::{Breakpoint, BreakpointId, Location};
+use firn_core::system::StopReason;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+/// The most hardware breakpoints that can be set at once, like the 4 debug address registers of
+/// later x86 CPUs.
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+
+/// The largest packet that the stub accepts.
+const PACKET_SIZE: usize = 0x4000;
+
+/// How many instructions are executed between checks for an interrupt from the client.
+const INTERRUPT_INTERVAL: u64 = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The register number of the extra `ip` register.
+const IP_REG: usize = 32;
+
+/// The target description that's sent to GDB.
+///
+/// The core feature has to include the x87 registers for GDB to accept it, but they're always
+/// zero.
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>i8086</architecture>
+  <feature name="org.gnu.gdb.i386.core">
+    <flags id="i386_eflags" size="4">
+      <field name="CF" start="0" end="0"/>
+      <field name="" start="1" end="1"/>
+      <field name="PF" start="2" end="2"/>
+      <field name="AF" start="4" end="4"/>
+      <field name="ZF" start="6" end="6"/>
+      <field name="SF" start="7" end="7"/>
+      <field name="TF" start="8" end="8"/>
+      <field name="IF" start="9" end="9"/>
+      <field name="DF" start="10" end="10"/>
+      <field name="OF" start="11" end="11"/>
+    </flags>
+    <reg name="eax" bitsize="32" type="int32" regnum="0"/>
+    <reg name="ecx" bitsize="32" type="int32"/>
+    <reg name="edx" bitsize="32" type="int32"/>
+    <reg name="ebx" bitsize="32" type="int32"/>
+    <reg name="esp" bitsize="32" type="data_ptr"/>
+    <reg name="ebp" bitsize="32" type="data_ptr"/>
+    <reg name="esi" bitsize="32" type="int32"/>
+    <reg name="edi" bitsize="32" type="int32"/>
+    <reg name="eip" bitsize="32" type="code_ptr"/>
+    <reg name="eflags" bitsize="32" type="i386_eflags"/>
+    <reg name="cs" bitsize="32" type="int32"/>
+    <reg name="ss" bitsize="32" type="int32"/>
+    <reg name="ds" bitsize="32" type="int32"/>
+    <reg name="es" bitsize="32" type="int32"/>
+    <reg name="fs" bitsize="32" type="int32"/>
+    <reg name="gs" bitsize="32" type="int32"/>
+    <reg name="st0" bitsize="80" type="i387_ext"/>
+    <reg name="st1" bitsize="80" type="i387_ext"/>
+    <reg name="st2" bitsize="80" type="i387_ext"/>
+    <reg name="st3" bitsize="80" type="i387_ext"/>
+    <reg name="st4" bitsize="80" type="i387_ext"/>
+    <reg name="st5" bitsize="80" type="i387_ext"/>
+    <reg name="st6" bitsize="80" type="i387_ext"/>
+    <reg name="st7" bitsize="80" type="i387_ext"/>
+    <reg name="fctrl" bitsize="32" type="int" group="float"/>
+    <reg name="fstat" bitsize="32" type="int" group="float"/>
+    <reg name="ftag" bitsize="32" type="int" group="float"/>
+    <reg name="fiseg" bitsize="32" type="int" group="float"/>
+    <reg name="fioff" bitsize="32" type="int" group="float"/>
+    <reg name="foseg" bitsize="32" type="int" group="float"/>
+    <reg name="fooff" bitsize="32" type="int" group="float"/>
+    <reg name="fop" bitsize="32" type="int" group="float"/>
+  </feature>
+  <feature name="org.firn.i8086.real">
+    <reg name="ip" bitsize="16" type="uint16" regnum="32"/>
+  </feature>
+</target>
+"#;
+
+/// How a debugging session ended.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SessionEnd {
+    /// The client detached, so the system can keep running.
+    Detached,
+    /// The client asked for the system to be killed.
+    Killed,
+    /// The client disconnected without detaching.
+    Disconnected,
+}
+
+/// A GDB stub which listens for clients on a TCP socket.
+pub struct GdbStub {
+    listener: TcpListener,
+}
+
+impl GdbStub {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for a client to connect and lets it debug `sys` until the session ends.
+    ///
+    /// The system is stopped until the client continues or steps it, and it only runs on the
+    /// calling thread. The interpreter is used for the whole session so the CPU stops after every
+    /// instruction.
+    pub fn run(&self, sys: &mut System) -> io::Result<SessionEnd> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        let backend = sys.cpu.backend;
+        sys.cpu.backend = Backend::Interpreter;
+        let result = Session::new(Connection::new(stream), sys).run();
+        sys.cpu.backend = backend;
+
+        match result {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(SessionEnd::Disconnected),
+            result => result,
+        }
+    }
+}
+
+enum Packet {
+    Command(Vec<u8>),
+    Interrupt,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    acks: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            acks: true,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if !self.buffer.is_empty() {
+            return Ok(self.buffer.remove(0));
+        }
+
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+
+        Ok(byte[0])
+    }
+
+    fn read_packet(&mut self) -> io::Result<Packet> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {}
+                0x03 => return Ok(Packet::Interrupt),
+                // Acknowledgements (and anything else between packets) are ignored
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            let mut checksum = 0u8;
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b'}' => {
+                        checksum = checksum.wrapping_add(b'}');
+                        let byte = self.read_byte()?;
+                        checksum = checksum.wrapping_add(byte);
+                        data.push(byte ^ 0x20);
+                    }
+                    byte => {
+                        checksum = checksum.wrapping_add(byte);
+                        data.push(byte);
+                    }
+                }
+
+                if data.len() > PACKET_SIZE {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "packet is too long"));
+                }
+            }
+
+            let expected = [self.read_byte()?, self.read_byte()?];
+            let valid = parse_hex(&expected) == Some(checksum as usize);
+            if self.acks {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid || !self.acks {
+                return Ok(Packet::Command(data));
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = vec![b'$'];
+        for byte in data {
+            match byte {
+                b'#' | b'$' | b'}' | b'*' => packet.extend([b'}', byte ^ 0x20]),
+                _ => packet.push(*byte),
+            }
+        }
+        let checksum = packet[1..]
+            .iter()
+            .fold(0u8, |checksum, byte| checksum.wrapping_add(*byte));
+        packet.extend(format!("#{:02x}", checksum).bytes());
+
+        loop {
+            self.stream.write_all(&packet)?;
+            if !self.acks || self.read_byte()? != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Checks for an interrupt from the client without blocking.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut bytes = [0; 64];
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut bytes);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(len) => {
+                let bytes = &bytes[..len];
+                match bytes.iter().position(|byte| *byte == 0x03) {
+                    Some(index) => {
+                        self.buffer.extend(&bytes[index + 1..]);
+                        Ok(true)
+                    }
+                    None => {
+                        self.buffer.extend(bytes);
+                        Ok(false)
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+enum Resume {
+    Step,
+    Continue,
+    ReverseStep,
+    ReverseContinue,
+}
+
+struct Session<'a> {
+    conn: Connection,
+    sys: &'a mut System,
+
+    /// The breakpoints that GDB set by their address, which are added to the system's breakpoints
+    /// while the session lasts.
+    sw_breakpoints: HashMap<usize, BreakpointId>,
+    hw_breakpoints: Vec<(usize, BreakpointId)>,
+}
+
+impl<'a> Session<'a> {
+    fn new(conn: Connection, sys: &'a mut System) -> Self {
+        Self {
+            conn,
+            sys,
+
+            sw_breakpoints: HashMap::new(),
+            hw_breakpoints: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<SessionEnd> {
+        loop {
+            let packet = match self.conn.read_packet()? {
+                Packet::Command(packet) => packet,
+                Packet::Interrupt => {
+                    self.send_stop(SIGINT)?;
+                    continue;
+                }
+            };
+
+            // The data of X packets is binary, so it can't be converted to a string
+            if let Some(args) = packet.strip_prefix(b"X") {
+                let response = self.write_mem_binary(args);
+                self.conn.send_packet(&response)?;
+                continue;
+            }
+
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match packet.as_str() {
+                "k" | "vKill;1" => return Ok(SessionEnd::Killed),
+                packet if packet.starts_with('D') => {
+                    self.conn.send_packet(b"OK")?;
+                    return Ok(SessionEnd::Detached);
+                }
+                packet => {
+                    if let Some(resume) = self.resume_command(packet) {
+                        match resume {
+                            Resume::ReverseStep | Resume::ReverseContinue => {
+                                if self.reverse(resume) {
+                                    self.send_stop(SIGTRAP)?;
+                                } else {
+                                    // Tells GDB that there's no more history
+                                    let stop = format!("T{:02x}replaylog:begin;", SIGTRAP);
+                                    self.conn.send_packet(stop.as_bytes())?;
+                                }
+                            }
+                            _ => {
+                                let signal = self.resume(resume)?;
+                                self.send_stop(signal)?;
+                            }
+                        }
+                    } else {
+                        let response = self.handle(packet);
+                        self.conn.send_packet(&response)?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_stop(&mut self, signal: u8) -> io::Result<()> {
+        self.conn.send_packet(format!("S{:02x}", signal).as_bytes())
+    }
+
+    /// Parses a command that resumes the system, setting the PC first if the command has an
+    /// address.
+    fn resume_command(&mut self, packet: &str) -> Option<Resume> {
+        match packet {
+            "bs" => return Some(Resume::ReverseStep),
+            "bc" => return Some(Resume::ReverseContinue),
+            _ => {}
+        }
+
+        let (resume, address) = match packet.as_bytes().first()? {
+            b'c' => (Resume::Continue, &packet[1..]),
+            b's' => (Resume::Step, &packet[1..]),
+            // The signal is ignored since there's nothing to deliver it to
+            b'C' | b'S' => {
+                let resume = if packet.starts_with('C') {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                };
+                let address = packet.split_once(';').map_or("", |(_, address)| address);
+                (resume, address)
+            }
+            _ => {
+                // Only the first action matters since there's only one thread
+                let action = packet.strip_prefix("vCont;")?;
+                return match action.as_bytes().first()? {
+                    b'c' | b'C' => Some(Resume::Continue),
+                    b's' | b'S' => Some(Resume::Step),
+                    _ => None,
+                };
+            }
+        };
+
+        if let Some(address) = parse_hex(address.as_bytes()) {
+            self.set_pc(address);
+        }
+
+        Some(resume)
+    }
+
+    /// Runs the system until it stops (see [`StopReason`]) or GDB interrupts it, returning the
+    /// signal to report.
+    fn resume(&mut self, resume: Resume) -> io::Result<u8> {
+        let steps = match resume {
+            Resume::Step => 1,
+            _ => INTERRUPT_INTERVAL,
+        };
+
+        loop {
+            match self.sys.run_for(steps) {
+                StopReason::Breakpoint(_) | StopReason::UnhandledPort(_) => return Ok(SIGTRAP),
+                StopReason::StepLimit if steps == 1 => return Ok(SIGTRAP),
+                StopReason::StepLimit => {
+                    if self.conn.poll_interrupt()? {
+                        return Ok(SIGINT);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Goes back through the system's history, returning `false` if it reached the start of the
+    /// history (or there's no history).
+    fn reverse(&mut self, resume: Resume) -> bool {
+        let history = match self.sys.history() {
+            Some(history) => history,
+            None => return false,
+        };
+        let now = self.sys.steps();
+        let start = *history.start();
+
+        let target = match resume {
+            Resume::ReverseStep => now.checked_sub(1).filter(|step| *step >= start),
+            _ => {
+                // Find the last breakpoint that was reached by replaying the whole history
+                let _ = self.sys.rewind_to(start);
+                let mut last = None;
+                while self.sys.steps() < now {
+                    if self.is_breakpoint(self.pc()) {
+                        last = Some(self.sys.steps());
+                    }
+                    self.sys.step();
+                }
+
+                last
+            }
+        };
+
+        self.sys.rewind_to(target.unwrap_or(start)).is_ok() && target.is_some()
+    }
+
+    fn is_breakpoint(&self, address: usize) -> bool {
+        self.sw_breakpoints.contains_key(&address)
+            || self
+                .hw_breakpoints
+                .iter()
+                .any(|(other, _)| *other == address)
+    }
+
+    /// Handles a packet that doesn't resume the system, returning the response.
+    fn handle(&mut self, packet: &str) -> Vec<u8> {
+        let response = match packet {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..=IP_REG)
+                .map(|reg| encode_hex(&self.read_reg(reg).unwrap()))
+                .collect(),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "QStartNoAckMode" => {
+                self.conn.acks = false;
+                String::from("OK")
+            }
+            "vCont?" => String::from("vCont;c;C;s;S"),
+            packet if packet.starts_with("qSupported") => format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+;\
+                 ReverseStep+;ReverseContinue+",
+                PACKET_SIZE
+            ),
+            packet if packet.starts_with("qXfer:features:read:") => {
+                return self.read_features(&packet["qXfer:features:read:".len()..]);
+            }
+            packet if packet.starts_with('H') || packet.starts_with('T') => String::from("OK"),
+            packet => {
+                let (command, args) = match (packet.get(..1), packet.get(1..)) {
+                    (Some(command), Some(args)) => (command, args),
+                    _ => return Vec::new(),
+                };
+                let result = match command {
+                    "G" => self.write_regs(args),
+                    "p" => self.read_reg_command(args),
+                    "P" => self.write_reg_command(args),
+                    "m" => self.read_mem(args),
+                    "M" => self.write_mem(args),
+                    "Z" => self.set_breakpoint(args, true),
+                    "z" => self.set_breakpoint(args, false),
+                    _ => return Vec::new(),
+                };
+
+                match result {
+                    Some(response) => response,
+                    None => return b"E01".to_vec(),
+                }
+            }
+        };
+
+        response.into_bytes()
+    }
+
+    fn read_features(&self, args: &str) -> Vec<u8> {
+        let (annex, range) = match args.split_once(':') {
+            Some(args) => args,
+            None => return b"E00".to_vec(),
+        };
+        let (offset, len) = match parse_pair(range, ',') {
+            Some(range) if annex == "target.xml" => range,
+            _ => return b"E00".to_vec(),
+        };
+
+        let xml = TARGET_XML.as_bytes();
+        let start = offset.min(xml.len());
+        let end = offset.saturating_add(len).min(xml.len());
+
+        let mut response = vec![if end == xml.len() { b'l' } else { b'm' }];
+        response.extend(&xml[start..end]);
+
+        response
+    }
+
+    fn pc(&self) -> usize {
+        self.sys.linear_mem(Cs, self.sys.cpu.ip)
+    }
+
+    /// Sets `CS:IP` to a linear address, keeping CS if the address is in the current code segment.
+    fn set_pc(&mut self, address: usize) {
+        let base = (self.sys.cpu.reg_16(Cs.into()) as usize) << 4;
+        match address.checked_sub(base) {
+            Some(offset) if offset <= u16::MAX as usize => self.sys.cpu.ip = offset as u16,
+            _ => {
+                self.sys.cpu.set_reg_16(Cs.into(), (address >> 4) as u16);
+                self.sys.cpu.ip = (address & 0xf) as u16;
+            }
+        }
+    }
+
+    fn read_reg(&self, reg: usize) -> Option<Vec<u8>> {
+        let cpu = &self.sys.cpu;
+        let value = match reg {
+            0..=7 => cpu.reg_16(GeneralWordReg::from_u8(reg as u8)?.into()) as u32,
+            8 => self.pc() as u32,
+            9 => cpu.flags.get_16() as u32,
+            10 => cpu.reg_16(Cs.into()) as u32,
+            11 => cpu.reg_16(Ss.into()) as u32,
+            12 => cpu.reg_16(Ds.into()) as u32,
+            13 => cpu.reg_16(Es.into()) as u32,
+            14 | 15 => 0,
+            16..=23 => return Some(vec![0; 10]),
+            24..=31 => 0,
+            IP_REG => return Some(cpu.ip.to_le_bytes().to_vec()),
+            _ => return None,
+        };
+
+        Some(value.to_le_bytes().to_vec())
+    }
+
+    /// Writes a register from its little-endian bytes, ignoring registers that don't exist on the
+    /// 8086.
+    fn write_reg(&mut self, reg: usize, bytes: &[u8]) -> Option<()> {
+        let value = match *bytes {
+            [low, high, ..] => u16::from_le_bytes([low, high]),
+            _ => return None,
+        };
+
+        let cpu = &mut self.sys.cpu;
+        match reg {
+            0..=7 => cpu.set_reg_16(GeneralWordReg::from_u8(reg as u8)?.into(), value),
+            8 => {
+                let mut address = [0; 4];
+                for (index, byte) in bytes.iter().take(4).enumerate() {
+                    address[index] = *byte;
+                }
+                self.set_pc(u32::from_le_bytes(address) as usize);
+            }
+            9 => cpu.flags.set_16(value),
+            10 => cpu.set_reg_16(Cs.into(), value),
+            11 => cpu.set_reg_16(Ss.into(), value),
+            12 => cpu.set_reg_16(Ds.into(), value),
+            13 => cpu.set_reg_16(Es.into(), value),
+            14..=31 => {}
+            IP_REG => cpu.ip = value,
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    fn write_regs(&mut self, args: &str) -> Option<String> {
+        let bytes = decode_hex(args)?;
+
+        let mut offset = 0;
+        for reg in 0..=IP_REG {
+            let len = reg_size(reg);
+            if offset + len > bytes.len() {
+                break;
+            }
+
+            // EIP is written last since it depends on CS
+            if reg != 8 {
+                self.write_reg(reg, &bytes[offset..offset + len])?;
+            }
+            offset += len;
+        }
+        if bytes.len() >= 9 * 4 {
+            self.write_reg(8, &bytes[8 * 4..9 * 4])?;
+        }
+
+        Some(String::from("OK"))
+    }
+
+    fn read_reg_command(&self, args: &str) -> Option<String> {
+        let reg = parse_hex(args.as_bytes())?;
+
+        Some(encode_hex(&self.read_reg(reg)?))
+    }
+
+    fn write_reg_command(&mut self, args: &str) -> Option<String> {
+        let (reg, value) = args.split_once('=')?;
+        let reg = parse_hex(reg.as_bytes())?;
+        self.write_reg(reg, &decode_hex(value)?)?;
+
+        Some(String::from("OK"))
+    }
+
+    fn read_mem(&self, args: &str) -> Option<String> {
+        let (address, len) = parse_pair(args, ',')?;
+        let end = address.saturating_add(len).min(self.sys.mem.addressable);
+        if address >= end && len > 0 {
+            return None;
+        }
+
+        let bytes = (address..end)
+            .map(|address| self.sys.mem[address])
+            .collect::<Vec<_>>();
+
+        Some(encode_hex(&bytes))
+    }
+
+    fn write_mem(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (address, len) = parse_pair(range, ',')?;
+        let bytes = decode_hex(data)?;
+        if bytes.len() != len {
+            return None;
+        }
+
+        self.write_bytes(address, &bytes)?;
+
+        Some(String::from("OK"))
+    }
+
+    fn write_mem_binary(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let result = bytes
+            .iter()
+            .position(|byte| *byte == b':')
+            .and_then(|colon| {
+                let range = std::str::from_utf8(&bytes[..colon]).ok()?;
+                let (address, len) = parse_pair(range, ',')?;
+                let data = &bytes[colon + 1..];
+                if data.len() != len {
+                    return None;
+                }
+
+                self.write_bytes(address, data)
+            });
+
+        match result {
+            Some(()) => b"OK".to_vec(),
+            None => b"E01".to_vec(),
+        }
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Option<()> {
+        let end = address.checked_add(bytes.len())?;
+        if !(address..end).all(|address| self.sys.mem.is_mapped(address)) {
+            return None;
+        }
+
+        for (index, byte) in bytes.iter().enumerate() {
+            self.sys.mem[address + index] = *byte;
+        }
+
+        Some(())
+    }
+
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut args = args.split(',');
+        let kind = args.next()?;
+        let address = parse_hex(args.next()?.as_bytes())?;
+
+        let breakpoints = &mut self.sys.breakpoints;
+        match (kind, insert) {
+            ("0", true) => {
+                self.sw_breakpoints
+                    .entry(address)
+                    .or_insert_with(|| breakpoints.add(Breakpoint::new(Location::Linear(address))));
+            }
+            ("0", false) => {
+                if let Some(id) = self.sw_breakpoints.remove(&address) {
+                    breakpoints.remove(id);
+                }
+            }
+            ("1", true) => {
+                if !self
+                    .hw_breakpoints
+                    .iter()
+                    .any(|(other, _)| *other == address)
+                {
+                    if self.hw_breakpoints.len() >= MAX_HW_BREAKPOINTS {
+                        return None;
+                    }
+                    let id = breakpoints.add(Breakpoint::new(Location::Linear(address)));
+                    self.hw_breakpoints.push((address, id));
+                }
+            }
+            ("1", false) => self.hw_breakpoints.retain(|(other, id)| {
+                if *other == address {
+                    breakpoints.remove(*id);
+                }
+                *other != address
+            }),
+            // Watchpoints aren't supported
+            _ => return Some(String::new()),
+        }
+
+        Some(String::from("OK"))
+    }
+}
+
+/// Removes GDB's breakpoints from the system.
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        let sw_breakpoints = self.sw_breakpoints.values();
+        let hw_breakpoints = self.hw_breakpoints.iter().map(|(_, id)| id);
+        for id in sw_breakpoints.chain(hw_breakpoints) {
+            self.sys.breakpoints.remove(*id);
+        }
+    }
+}
+
+/// The size of a register in the `g` packet.
+fn reg_size(reg: usize) -> usize {
+    match reg {
+        16..=23 => 10,
+        IP_REG => 2,
+        _ => 4,
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|byte| parse_hex(byte).map(|byte| byte as u8))
+        .collect()
+}
+
+fn parse_hex(hex: &[u8]) -> Option<usize> {
+    let hex = std::str::from_utf8(hex).ok()?;
+    if hex.is_empty() {
+        return None;
+    }
+
+    usize::from_str_radix(hex, 16).ok()
+}
+
+fn parse_pair(args: &str, separator: char) -> Option<(usize, usize)> {
+    let (first, second) = args.split_once(separator)?;
+
+    Some((parse_hex(first.as_bytes())?, parse_hex(second.as_bytes())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+    use firn_core::mem::{BasicMem, MemMap};
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(b'+', byte[0]);
+
+            self.receive()
+        }
+
+        fn receive(&mut self) -> String {
+            let mut byte = [0];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+
+            String::from_utf8(data).unwrap()
+        }
+    }
+
+    // 0x100: mov ax, 0x1234
+    // 0x103: inc ax
+    // 0x104: inc ax
+    // 0x105: jmp 0x105
+    const PROGRAM: &[u8] = &[0xb8, 0x34, 0x12, 0x40, 0x40, 0xeb, 0xfe];
+
+    fn start() -> (Client, JoinHandle<(System, SessionEnd)>) {
+        start_with(|_| {})
+    }
+
+    fn start_with(setup: impl FnOnce(&mut System)) -> (Client, JoinHandle<(System, SessionEnd)>) {
+        let mut mem = BasicMem::new(0x10000);
+        for (index, byte) in PROGRAM.iter().enumerate() {
+            mem[0x100 + index] = *byte;
+        }
+        let mut map = MemMap::new(0x10000);
+        map.map_full(mem);
+
+        let mut sys = System::new(Cpu::new(), map);
+        sys.cpu.ip = 0x100;
+        sys.start_recording(0x1000, 0x10);
+        setup(&mut sys);
+
+        let stub = GdbStub::bind("127.0.0.1:0").unwrap();
+        let address = stub.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let end = stub.run(&mut sys).unwrap();
+            (sys, end)
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        (Client { stream }, handle)
+    }
+
+    #[test]
+    fn should_describe_target() {
+        let (mut client, handle) = start();
+
+        assert!(client
+            .send("qSupported:xmlRegisters=i386")
+            .contains("qXfer:features:read+"));
+        let xml = client.send("qXfer:features:read:target.xml:0,4000");
+        assert_eq!(format!("l{}", TARGET_XML), xml);
+        assert_eq!("m<?xml", client.send("qXfer:features:read:target.xml:0,5"));
+
+        client.send("D");
+        assert_eq!(SessionEnd::Detached, handle.join().unwrap().1);
+    }
+
+    #[test]
+    fn should_read_and_write_regs() {
+        let (mut client, handle) = start();
+
+        let regs = client.send("g");
+        assert_eq!(176 * 2 + 4, regs.len());
+        // eip is the linear address of CS:IP
+        assert_eq!("00010000", &regs[8 * 8..9 * 8]);
+
+        assert_eq!("OK", client.send("P0=2143"));
+        assert_eq!("21430000", client.send("p0"));
+        assert_eq!("OK", client.send("P20=0002"));
+        assert_eq!("00020000", client.send("p8"));
+
+        client.send("D");
+        let (sys, _) = handle.join().unwrap();
+        assert_eq!(0x4321, sys.cpu.reg_16(GeneralWordReg::Ax.into()));
+        assert_eq!(0x200, sys.cpu.ip);
+    }
+
+    #[test]
+    fn should_read_and_write_mem() {
+        let (mut client, handle) = start();
+
+        assert_eq!("b83412", client.send("m100,3"));
+        assert_eq!("OK", client.send("M200,2:abcd"));
+        assert_eq!("abcd", client.send("m200,2"));
+        assert_eq!("E01", client.send("M20000,1:00"));
+        assert_eq!("OK", client.send("X300,2:}\x03}\x03"));
+        assert_eq!("2323", client.send("m300,2"));
+
+        client.send("D");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn should_step_and_continue() {
+        let (mut client, handle) = start();
+
+        assert_eq!("S05", client.send("s"));
+        assert_eq!("03010000", client.send("p8"));
+
+        assert_eq!("OK", client.send("Z0,104,1"));
+        assert_eq!("S05", client.send("c"));
+        assert_eq!("04010000", client.send("p8"));
+        assert_eq!("OK", client.send("z0,104,1"));
+
+        for address in 0x200..0x200 + MAX_HW_BREAKPOINTS {
+            assert_eq!("OK", client.send(&format!("Z1,{:x},1", address)));
+        }
+        assert_eq!("E01", client.send("Z1,105,1"));
+        assert_eq!("OK", client.send("z1,200,1"));
+        assert_eq!("OK", client.send("Z1,105,1"));
+        assert_eq!("S05", client.send("vCont;c"));
+        assert_eq!("05010000", client.send("p8"));
+
+        // Kill packets don't have a response
+        client.stream.write_all(b"$k#6b").unwrap();
+        let (sys, end) = handle.join().unwrap();
+        assert_eq!(SessionEnd::Killed, end);
+        assert_eq!(0x1236, sys.cpu.reg_16(GeneralWordReg::Ax.into()));
+        assert!(sys.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn should_stop_at_system_breakpoints() {
+        let (mut client, handle) = start_with(|sys| {
+            sys.breakpoints
+                .add(Breakpoint::new(Location::Linear(0x104)));
+        });
+
+        assert_eq!("S05", client.send("c"));
+        assert_eq!("04010000", client.send("p8"));
+
+        client.send("D");
+        let (sys, _) = handle.join().unwrap();
+        assert_eq!(1, sys.breakpoints.iter().count());
+    }
+
+    #[test]
+    fn should_reverse_step_and_continue() {
+        let (mut client, handle) = start();
+
+        assert_eq!("T05replaylog:begin;", client.send("bs"));
+        for _ in 0..3 {
+            client.send("s");
+        }
+        assert_eq!("S05", client.send("bs"));
+        assert_eq!("04010000", client.send("p8"));
+
+        assert_eq!("OK", client.send("Z0,103,1"));
+        assert_eq!("S05", client.send("bc"));
+        assert_eq!("03010000", client.send("p8"));
+        assert_eq!("T05replaylog:begin;", client.send("bc"));
+        assert_eq!("00010000", client.send("p8"));
+
+        client.send("D");
+        let (sys, _) = handle.join().unwrap();
+        assert_eq!(0, sys.cpu.reg_16(GeneralWordReg::Ax.into()));
+    }
+
+    #[test]
+    fn should_interrupt_running_system() {
+        let (mut client, handle) = start();
+
+        client.stream.write_all(b"$c#63").unwrap();
+        let mut ack = [0];
+        client.stream.read_exact(&mut ack).unwrap();
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!("S02", client.receive());
+
+        client.send("D");
+        handle.join().unwrap();
+    }
+}
@@ -1,5 +1,6 @@
+use crate::disasm::OperandKind;
 use crate::SegmentReg::Ds;
-use crate::{opcodes, SegmentReg, System};
+use crate::{DecodedInstr, FlagSet, SegmentReg, System};
 use std::fmt::{Debug, Formatter};
 
 pub mod arith;
@@ -13,7 +14,7 @@ pub mod stack;
 pub mod strings;
 pub mod transfer;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct Prefixes {
     pub lock: bool,
 
@@ -42,12 +43,32 @@ impl Default for Prefixes {
     }
 }
 
+/// The REP prefixes that an instruction supports.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RepPrefixes {
+    /// `REP`, which repeats the instruction `CX` times.
+    pub rep: bool,
+    /// `REPE`, which also stops repeating once ZF is cleared.
+    pub rep_e: bool,
+    /// `REPNE`, which also stops repeating once ZF is set.
+    pub rep_ne: bool,
+}
+
+/// Information about an instruction, generated by the `#[instr]` attribute.
 #[derive(Debug)]
 pub struct InstrMeta {
-    pub mnemonic: String,
+    /// The full mnemonic, like `ADD r/m8, imm8`.
+    pub mnemonic: &'static str,
+    /// The name of the instruction without its operands, like `ADD`.
+    pub name: &'static str,
+    pub operands: &'static [OperandKind],
+    pub rep: RepPrefixes,
+    /// The flags that the instruction can change, including flags that it leaves undefined.
+    pub modifies_flags: FlagSet,
 }
 
-pub struct InstrFunc(fn(sys: &mut System, opcode: u8, prefixes: &Prefixes));
+#[derive(Copy, Clone)]
+pub struct InstrFunc(pub(crate) fn(sys: &mut System, instr: &DecodedInstr));
 
 impl Debug for InstrFunc {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -55,36 +76,14 @@ impl Debug for InstrFunc {
     }
 }
 
+/// An instruction that the CPU supports.
+///
+/// Every function with the `#[instr]` attribute has a static `Instr` with the same name in
+/// uppercase (like `ADD_RM8_R8` for `add_rm8_r8`). [`OPCODES`] maps opcodes to them.
+///
+/// [`OPCODES`]: crate::opcodes::OPCODES
 #[derive(Debug)]
 pub struct Instr {
-    pub opcode: u8,
-    pub prefixes: Prefixes,
-
     pub meta: InstrMeta,
-    func: InstrFunc,
-}
-
-impl Instr {
-    pub fn decode(sys: &mut System) -> Instr {
-        opcodes::decode(sys)
-    }
-
-    pub fn new(
-        opcode: u8,
-        prefixes: Prefixes,
-        func: fn(sys: &mut System, opcode: u8, prefixes: &Prefixes),
-        meta_func: fn() -> InstrMeta,
-    ) -> Self {
-        Self {
-            opcode,
-            prefixes,
-
-            meta: meta_func(),
-            func: InstrFunc(func),
-        }
-    }
-
-    pub fn execute(&self, sys: &mut System) {
-        self.func.0(sys, self.opcode, &self.prefixes);
-    }
+    pub(crate) func: InstrFunc,
 }
@@ -10,58 +10,58 @@ arith_instr!(ADC);
 arith_instr!(SUB);
 arith_instr!(SBB);
 
-#[instr("CMP AL, imm8")]
+#[instr("CMP AL, imm8", flags = "OSZAPC")]
 pub fn cmp_al_imm8(sys: &mut System, imm: u8) {
     let old = sys.cpu.reg_8(Al);
     arith::sub_8(sys, old, imm);
 }
 
-#[instr("CMP AX, imm16")]
+#[instr("CMP AX, imm16", flags = "OSZAPC")]
 pub fn cmp_ax_imm16(sys: &mut System, imm: u16) {
     let old = sys.cpu.reg_16(Ax.into());
     arith::sub_16(sys, old, imm);
 }
 
-#[instr("CMP r/m8, imm8")]
+#[instr("CMP r/m8, imm8", flags = "OSZAPC")]
 pub fn cmp_rm8_imm8(sys: &mut System, rm: RegMem, imm: u8) {
     let old = rm.get_8(sys);
     arith::sub_8(sys, old, imm);
 }
 
-#[instr("CMP r/m16, imm16")]
+#[instr("CMP r/m16, imm16", flags = "OSZAPC")]
 pub fn cmp_rm16_imm16(sys: &mut System, rm: RegMem, imm: u16) {
     let old = rm.get_16(sys);
     arith::sub_16(sys, old, imm);
 }
 
-#[instr("CMP r/m16, imm8")]
+#[instr("CMP r/m16, imm8", flags = "OSZAPC")]
 pub fn cmp_rm16_imm8(sys: &mut System, rm: RegMem, imm: u8) {
     let old = rm.get_16(sys);
     arith::sub_16(sys, old, imm as u16);
 }
 
-#[instr("CMP r/m8, r8")]
+#[instr("CMP r/m8, r8", flags = "OSZAPC")]
 pub fn cmp_rm8_r8(sys: &mut System, rm: RegMem, reg: GeneralByteReg) {
     let old = rm.get_8(sys);
     let reg = sys.cpu.reg_8(reg);
     arith::sub_8(sys, old, reg);
 }
 
-#[instr("CMP r/m16, r16")]
+#[instr("CMP r/m16, r16", flags = "OSZAPC")]
 pub fn cmp_rm16_r16(sys: &mut System, rm: RegMem, reg: GeneralWordReg) {
     let old = rm.get_16(sys);
     let reg = sys.cpu.reg_16(reg.into());
     arith::sub_16(sys, old, reg);
 }
 
-#[instr("CMP r8, r/m8")]
+#[instr("CMP r8, r/m8", flags = "OSZAPC")]
 pub fn cmp_r8_rm8(sys: &mut System, reg: GeneralByteReg, rm: RegMem) {
     let old = sys.cpu.reg_8(reg);
     let rm = rm.get_8(sys);
     arith::sub_8(sys, old, rm);
 }
 
-#[instr("CMP r16, r/m16")]
+#[instr("CMP r16, r/m16", flags = "OSZAPC")]
 pub fn cmp_r16_rm16(sys: &mut System, reg: GeneralWordReg, rm: RegMem) {
     let old = sys.cpu.reg_16(reg.into());
     let rm = rm.get_16(sys);
@@ -84,7 +84,7 @@ pub fn not_rm16(sys: &mut System, rm: RegMem) {
     rm.set_16(sys, !old);
 }
 
-#[instr("NEG r/m8")]
+#[instr("NEG r/m8", flags = "OSZAPC")]
 pub fn neg_rm8(sys: &mut System, rm: RegMem) {
     let old = rm.get_8(sys);
     let overflow = old != 0;
@@ -95,7 +95,7 @@ pub fn neg_rm8(sys: &mut System, rm: RegMem) {
     rm.set_8(sys, value);
 }
 
-#[instr("NEG r/m16")]
+#[instr("NEG r/m16", flags = "OSZAPC")]
 pub fn neg_rm16(sys: &mut System, rm: RegMem) {
     let old = rm.get_16(sys);
     let overflow = old != 0;
@@ -106,87 +106,87 @@ pub fn neg_rm16(sys: &mut System, rm: RegMem) {
     rm.set_16(sys, value);
 }
 
-#[instr("INC r/m8")]
+#[instr("INC r/m8", flags = "OSZAP")]
 pub fn inc_rm8(sys: &mut System, rm: RegMem) {
     let old = rm.get_8(sys);
     let value = arith::add_8(sys, old, 1);
     rm.set_8(sys, value);
 }
 
-#[instr("INC r/m16")]
+#[instr("INC r/m16", flags = "OSZAP")]
 pub fn inc_rm16(sys: &mut System, rm: RegMem) {
     let old = rm.get_16(sys);
     let value = arith::add_16(sys, old, 1);
     rm.set_16(sys, value);
 }
 
-#[instr("INC r16")]
+#[instr("INC r16", flags = "OSZAP")]
 pub fn inc_r16(sys: &mut System, reg: GeneralWordReg) {
     let old = sys.cpu.reg_16(reg.into());
     let value = arith::add_16(sys, old, 1);
     sys.cpu.set_reg_16(reg.into(), value);
 }
 
-#[instr("DEC r/m8")]
+#[instr("DEC r/m8", flags = "OSZAP")]
 pub fn dec_rm8(sys: &mut System, rm: RegMem) {
     let old = rm.get_8(sys);
     let value = arith::sub_8(sys, old, 1);
     rm.set_8(sys, value);
 }
 
-#[instr("DEC r/m16")]
+#[instr("DEC r/m16", flags = "OSZAP")]
 pub fn dec_rm16(sys: &mut System, rm: RegMem) {
     let old = rm.get_16(sys);
     let value = arith::sub_16(sys, old, 1);
     rm.set_16(sys, value);
 }
 
-#[instr("DEC r16")]
+#[instr("DEC r16", flags = "OSZAP")]
 pub fn dec_r16(sys: &mut System, reg: GeneralWordReg) {
     let old = sys.cpu.reg_16(reg.into());
     let value = arith::sub_16(sys, old, 1);
     sys.cpu.set_reg_16(reg.into(), value);
 }
 
-#[instr("TEST AL, imm8")]
+#[instr("TEST AL, imm8", flags = "OSZAPC")]
 pub fn test_al_imm8(sys: &mut System, imm: u8) {
     let old = sys.cpu.reg_8(Al);
     arith::and_8(sys, old, imm);
 }
 
-#[instr("TEST AX, imm16")]
+#[instr("TEST AX, imm16", flags = "OSZAPC")]
 pub fn test_ax_imm16(sys: &mut System, imm: u16) {
     let old = sys.cpu.reg_16(Ax.into());
     arith::and_16(sys, old, imm);
 }
 
-#[instr("TEST r/m8, imm8")]
+#[instr("TEST r/m8, imm8", flags = "OSZAPC")]
 pub fn test_rm8_imm8(sys: &mut System, rm: RegMem, imm: u8) {
     let old = rm.get_8(sys);
     arith::and_8(sys, old, imm);
 }
 
-#[instr("TEST r/m16, imm16")]
+#[instr("TEST r/m16, imm16", flags = "OSZAPC")]
 pub fn test_rm16_imm16(sys: &mut System, rm: RegMem, imm: u16) {
     let old = rm.get_16(sys);
     arith::and_16(sys, old, imm);
 }
 
-#[instr("TEST r/m8, r8")]
+#[instr("TEST r/m8, r8", flags = "OSZAPC")]
 pub fn test_rm8_r8(sys: &mut System, rm: RegMem, reg: GeneralByteReg) {
     let old = rm.get_8(sys);
     let reg = sys.cpu.reg_8(reg);
     arith::and_8(sys, old, reg);
 }
 
-#[instr("TEST r/m16, r16")]
+#[instr("TEST r/m16, r16", flags = "OSZAPC")]
 pub fn test_rm16_r16(sys: &mut System, rm: RegMem, reg: GeneralWordReg) {
     let old = rm.get_16(sys);
     let reg = sys.cpu.reg_16(reg.into());
     arith::and_16(sys, old, reg);
 }
 
-#[instr("MUL r/m8")]
+#[instr("MUL r/m8", flags = "OSZAPC")]
 pub fn mul_rm8(sys: &mut System, rm: RegMem) {
     let multiplicand = rm.get_8(sys);
     let multiplier = sys.cpu.reg_8(Al);
@@ -198,7 +198,7 @@ pub fn mul_rm8(sys: &mut System, rm: RegMem) {
     sys.cpu.flags.overflow = extended;
 }
 
-#[instr("MUL r/m16")]
+#[instr("MUL r/m16", flags = "OSZAPC")]
 pub fn mul_rm16(sys: &mut System, rm: RegMem) {
     let multiplicand = rm.get_16(sys);
     let multiplier = sys.cpu.reg_16(Ax.into());
@@ -240,7 +240,7 @@ macro_rules! check_div_16 {
     };
 }
 
-#[instr("DIV r/m8")]
+#[instr("DIV r/m8", flags = "OSZAPC")]
 pub fn div_rm8(sys: &mut System, rm: RegMem) {
     let dividend = sys.cpu.reg_16(Ax.into());
     let divisor = rm.get_8(sys) as u16;
@@ -249,7 +249,7 @@ pub fn div_rm8(sys: &mut System, rm: RegMem) {
     check_div_8!(sys, value, dividend, divisor);
 }
 
-#[instr("DIV r/m16")]
+#[instr("DIV r/m16", flags = "OSZAPC")]
 pub fn div_rm16(sys: &mut System, rm: RegMem) {
     let dx = sys.cpu.reg_16(Dx.into());
     let ax = sys.cpu.reg_16(Ax.into());
@@ -260,7 +260,7 @@ pub fn div_rm16(sys: &mut System, rm: RegMem) {
     check_div_16!(sys, value, dividend, divisor);
 }
 
-#[instr("IDIV r/m8")]
+#[instr("IDIV r/m8", flags = "OSZAPC")]
 pub fn idiv_rm8(sys: &mut System, rm: RegMem) {
     let dividend = sys.cpu.reg_16(Ax.into()) as i16;
     let divisor = rm.get_8(sys) as i16;
@@ -269,7 +269,7 @@ pub fn idiv_rm8(sys: &mut System, rm: RegMem) {
     check_div_8!(sys, value, dividend, divisor);
 }
 
-#[instr("IDIV r/m16")]
+#[instr("IDIV r/m16", flags = "OSZAPC")]
 pub fn idiv_rm16(sys: &mut System, rm: RegMem) {
     let dx = sys.cpu.reg_16(Dx.into());
     let ax = sys.cpu.reg_16(Ax.into());
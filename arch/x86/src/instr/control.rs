@@ -104,7 +104,7 @@ pub fn ret_near(sys: &mut System) {
     sys.cpu.ip = sys.pop_16();
 }
 
-#[instr("RET")]
+#[instr("RETF")]
 pub fn ret_far(sys: &mut System) {
     sys.cpu.ip = sys.pop_16();
     let cs = sys.pop_16();
@@ -117,7 +117,7 @@ pub fn ret_imm16_near(sys: &mut System, imm: u16) {
     sys.cpu.inc_reg_16(Sp.into(), imm);
 }
 
-#[instr("RET imm16")]
+#[instr("RETF imm16")]
 pub fn ret_imm16_far(sys: &mut System, imm: u16) {
     sys.cpu.ip = sys.pop_16();
     let cs = sys.pop_16();
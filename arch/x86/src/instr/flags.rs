@@ -2,7 +2,7 @@ use crate::GeneralByteReg::Ah;
 use crate::{ExtSystem, System};
 use firn_arch_x86_macros::instr;
 
-#[instr("POPF")]
+#[instr("POPF", flags = "ODITSZAPC")]
 pub fn popf(sys: &mut System) {
     let value = sys.pop_16();
     sys.cpu.flags.set_16(value);
@@ -14,7 +14,7 @@ pub fn pushf(sys: &mut System) {
     sys.push_16(value);
 }
 
-#[instr("SAHF")]
+#[instr("SAHF", flags = "SZAPC")]
 pub fn sahf(sys: &mut System) {
     let value = sys.cpu.reg_8(Ah);
     sys.cpu.flags.set_8(value);
@@ -26,37 +26,37 @@ pub fn lahf(sys: &mut System) {
     sys.cpu.set_reg_8(Ah, value);
 }
 
-#[instr("CMC")]
+#[instr("CMC", flags = "C")]
 pub fn cmc(sys: &mut System) {
     sys.cpu.flags.carry = !sys.cpu.flags.carry;
 }
 
-#[instr("CLC")]
+#[instr("CLC", flags = "C")]
 pub fn clc(sys: &mut System) {
     sys.cpu.flags.carry = false;
 }
 
-#[instr("STC")]
+#[instr("STC", flags = "C")]
 pub fn stc(sys: &mut System) {
     sys.cpu.flags.carry = true;
 }
 
-#[instr("CLI")]
+#[instr("CLI", flags = "I")]
 pub fn cli(sys: &mut System) {
     sys.cpu.flags.interrupt = false;
 }
 
-#[instr("STI")]
+#[instr("STI", flags = "I")]
 pub fn sti(sys: &mut System) {
     sys.cpu.flags.interrupt = true;
 }
 
-#[instr("CLD")]
+#[instr("CLD", flags = "D")]
 pub fn cld(sys: &mut System) {
     sys.cpu.flags.direction = false;
 }
 
-#[instr("STD")]
+#[instr("STD", flags = "D")]
 pub fn std(sys: &mut System) {
     sys.cpu.flags.direction = true;
 }
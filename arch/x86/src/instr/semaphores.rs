@@ -23,24 +23,24 @@ pub fn hlt(_sys: &mut System) {
     }
 }
 
-#[instr("INT 3")]
+#[instr("INT 3", flags = "IT")]
 pub fn int_3(sys: &mut System) {
     sys.interrupt(3);
 }
 
-#[instr("INT imm8")]
+#[instr("INT imm8", flags = "IT")]
 pub fn int_imm8(sys: &mut System, imm: u8) {
     sys.interrupt(imm);
 }
 
-#[instr("INTO")]
+#[instr("INTO", flags = "IT")]
 pub fn into(sys: &mut System) {
     if sys.cpu.flags.overflow {
         sys.interrupt(4);
     }
 }
 
-#[instr("IRET")]
+#[instr("IRET", flags = "ODITSZAPC")]
 pub fn iret(sys: &mut System) {
     sys.cpu.ip = sys.pop_16();
     let cs = sys.pop_16();
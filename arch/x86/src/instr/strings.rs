@@ -3,13 +3,14 @@ use crate::GeneralWordReg::{Ax, Di, Dx, Si};
 use crate::SegmentReg::{Ds, Es};
 use crate::{arith, ExtSystem, GeneralWordReg, Prefixes, System};
 use firn_arch_x86_macros::instr;
+use firn_core::device::PortRequest;
 
 #[instr("INSB", REP)]
 pub fn insb(sys: &mut System) {
     let port = sys.cpu.reg_16(Dx.into());
     let value = sys
         .port_in_8(port)
-        .unwrap_or_else(|| panic!("unimplemented IO port: {:#x}", port));
+        .unwrap_or_else(|| sys.unhandled_port(PortRequest::In8(port)) as u8);
     sys.set_mem_reg_8(Es, Di, value);
 
     increment(sys, Di, 1);
@@ -20,7 +21,7 @@ pub fn insw(sys: &mut System) {
     let port = sys.cpu.reg_16(Dx.into());
     let value = sys
         .port_in_16(port)
-        .unwrap_or_else(|| panic!("unimplemented IO port: {:#x}", port));
+        .unwrap_or_else(|| sys.unhandled_port(PortRequest::In16(port)));
     sys.set_mem_reg_16(Es, Di, value);
 
     increment(sys, Di, 2);
@@ -30,8 +31,9 @@ pub fn insw(sys: &mut System) {
 pub fn outsb(sys: &mut System) {
     let port = sys.cpu.reg_16(Dx.into());
     let value = sys.mem_reg_8(Ds, Si);
-    sys.port_out_8(port, value)
-        .unwrap_or_else(|| panic!("unimplemented IO port: {:#x}", port));
+    if sys.port_out_8(port, value).is_none() {
+        sys.unhandled_port(PortRequest::Out8(port, value));
+    }
 
     increment(sys, Si, 1);
 }
@@ -40,8 +42,9 @@ pub fn outsb(sys: &mut System) {
 pub fn outsw(sys: &mut System) {
     let port = sys.cpu.reg_16(Dx.into());
     let value = sys.mem_reg_16(Ds, Si);
-    sys.port_out_16(port, value)
-        .unwrap_or_else(|| panic!("unimplemented IO port: {:#x}", port));
+    if sys.port_out_16(port, value).is_none() {
+        sys.unhandled_port(PortRequest::Out16(port, value));
+    }
 
     increment(sys, Si, 2);
 }
@@ -64,7 +67,7 @@ pub fn movsw(sys: &mut System, prefixes: &Prefixes) {
     increment(sys, Si, 2);
 }
 
-#[instr("CMPSB", REPE, REPNE)]
+#[instr("CMPSB", REPE, REPNE, flags = "OSZAPC")]
 pub fn cmpsb(sys: &mut System, prefixes: &Prefixes) {
     let left = sys.mem_reg_8(prefixes.segment, Si);
     let right = sys.mem_reg_8(Es, Di);
@@ -74,7 +77,7 @@ pub fn cmpsb(sys: &mut System, prefixes: &Prefixes) {
     increment(sys, Di, 1);
 }
 
-#[instr("CMPSW", REPE, REPNE)]
+#[instr("CMPSW", REPE, REPNE, flags = "OSZAPC")]
 pub fn cmpsw(sys: &mut System, prefixes: &Prefixes) {
     let left = sys.mem_reg_16(prefixes.segment, Si);
     let right = sys.mem_reg_16(Es, Di);
@@ -116,7 +119,7 @@ pub fn lodsw(sys: &mut System, prefixes: &Prefixes) {
     increment(sys, Si, 2);
 }
 
-#[instr("SCASB", REPE, REPNE)]
+#[instr("SCASB", REPE, REPNE, flags = "OSZAPC")]
 pub fn scasb(sys: &mut System) {
     let left = sys.cpu.reg_8(Al);
     let right = sys.mem_reg_8(Es, Di);
@@ -125,7 +128,7 @@ pub fn scasb(sys: &mut System) {
     increment(sys, Di, 1);
 }
 
-#[instr("SCASW", REPE, REPNE)]
+#[instr("SCASW", REPE, REPNE, flags = "OSZAPC")]
 pub fn scasw(sys: &mut System) {
     let left = sys.cpu.reg_16(Ax.into());
     let right = sys.mem_reg_16(Es, Di);
@@ -0,0 +1,1015 @@
+//! A JIT compiler which translates basic blocks of x86 code to host code with Cranelift.
+//!
+//! A block starts at some `CS:IP` and runs until an instruction that changes control flow (or
+//! until [`MAX_BLOCK_LEN`] instructions). Simple instructions that only touch registers and flags
+//! are compiled to host code. Every other instruction is compiled to a call into the interpreter,
+//! which leaves the block early if the instruction jumped somewhere unexpected (like an interrupt
+//! handler), wrote to a page that contains compiled code or made something due that has to happen
+//! between instructions (like a timer).
+//!
+//! Every instruction in a block counts as a step of the system, and blocks only run if they fit in
+//! its step budget, so devices, timers and the run API see the same steps as with the interpreter.
+//!
+//! The JIT is enabled per system with [`Backend::Jit`].
+//!
+//! [`MAX_BLOCK_LEN`]: MAX_BLOCK_LEN
+//! [`Backend::Jit`]: crate::Backend::Jit
+
+use crate::disasm::{Operand, SegmentedMem};
+use crate::modrm::Displacement;
+use crate::SegmentReg::Cs;
+use crate::{
+    Cpu, DecodedInstr, Disassembler, Flags, GeneralByteReg, GeneralWordReg, Reg, RmPtr, System,
+    WordReg,
+};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, InstBuilder, MemFlags, SigRef, Signature, Type, Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use firn_core::mem::MemMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::{mem, ptr};
+
+/// The most instructions that a block can contain.
+pub const MAX_BLOCK_LEN: usize = 64;
+
+/// A compiled block, which returns the number of instructions that it executed.
+type BlockFunc = unsafe extern "C" fn(sys: *mut System) -> u32;
+
+struct Block {
+    func: BlockFunc,
+
+    // The compiled code points to these, so they need to live as long as the block
+    instrs: Vec<Arc<DecodedInstr>>,
+}
+
+pub struct Jit {
+    module: JITModule,
+    ctx: Context,
+    builder_ctx: FunctionBuilderContext,
+
+    blocks: HashMap<(u16, u16), Block>,
+    pages: HashMap<usize, HashSet<(u16, u16)>>,
+    /// The address mask that the blocks were decoded with.
+    address_mask: usize,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false").unwrap();
+        flags.set("is_pic", "false").unwrap();
+        flags.set("opt_level", "speed").unwrap();
+
+        let isa = cranelift_native::builder()
+            .unwrap_or_else(|err| panic!("the host isn't supported by the JIT: {}", err))
+            .finish(settings::Flags::new(flags))
+            .expect("failed to create the JIT's target ISA");
+
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        let ctx = module.make_context();
+
+        Self {
+            module,
+            ctx,
+            builder_ctx: FunctionBuilderContext::new(),
+
+            blocks: HashMap::new(),
+            pages: HashMap::new(),
+            address_mask: usize::MAX,
+        }
+    }
+
+    /// Runs the block at `CS:IP`, compiling it first if it hasn't been compiled yet.
+    ///
+    /// Returns the number of instructions that were executed, or `None` without running the block
+    /// if it's longer than the system's step budget (see [`System::step_budget`]). Every
+    /// instruction but the last one is counted as a step (see [`System::count_steps`]).
+    ///
+    /// [`System::step_budget`]: firn_core::System::step_budget
+    /// [`System::count_steps`]: firn_core::System::count_steps
+    pub fn run(&mut self, sys: &mut System) -> Option<u32> {
+        // Blocks are looked up by `CS:IP`, which is at another linear address once the A20 gate is
+        // toggled
+        if sys.cpu.address_mask != self.address_mask {
+            self.clear();
+            self.address_mask = sys.cpu.address_mask;
+        }
+
+        let key = (sys.cpu.reg_16(Cs.into()), sys.cpu.ip);
+        if !self.blocks.contains_key(&key) {
+            self.compile(sys, key);
+        }
+        let block = &self.blocks[&key];
+        if block.instrs.len() as u64 > sys.step_budget() {
+            return None;
+        }
+
+        let steps = sys.steps();
+        let cycles = sys.clock.cycles();
+        // SAFETY: The block only accesses the system through this pointer (see `cpu` and
+        // `interpret`), and every instruction that it points to is kept alive by the block.
+        let executed = unsafe { (block.func)(sys) };
+        sys.count_steps(steps + executed as u64 - 1 - sys.steps());
+        sys.clock
+            .advance((cycles + executed as u64).saturating_sub(sys.clock.cycles()));
+
+        Some(executed)
+    }
+
+    /// Discards every compiled block with an instruction in `page`.
+    ///
+    /// The host code of discarded blocks isn't freed until the JIT is dropped.
+    pub fn invalidate_page(&mut self, page: usize) {
+        for key in self.pages.remove(&page).unwrap_or_default() {
+            self.blocks.remove(&key);
+        }
+    }
+
+    /// Discards every compiled block.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+    }
+
+    /// The number of compiled blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn compile(&mut self, sys: &mut System, key: (u16, u16)) {
+        let instrs = Self::decode_block(sys, key);
+
+        let pointer = self.module.target_config().pointer_type();
+        let mut signature = self.module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I32));
+
+        self.ctx.func.signature = signature;
+        {
+            let builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+            Translator::new(builder, pointer, instrs.len()).translate(&instrs);
+        }
+
+        let id = self
+            .module
+            .declare_anonymous_function(&self.ctx.func.signature)
+            .expect("failed to declare a JIT block");
+        self.module
+            .define_function(id, &mut self.ctx)
+            .unwrap_or_else(|err| panic!("failed to compile a JIT block: {:?}", err));
+        self.module.clear_context(&mut self.ctx);
+        self.module
+            .finalize_definitions()
+            .expect("failed to finalize a JIT block");
+
+        // SAFETY: The function was compiled with the same signature as `BlockFunc`
+        let func: BlockFunc = unsafe { mem::transmute(self.module.get_finalized_function(id)) };
+
+        let (segment, _) = key;
+        let mut pages = Vec::new();
+        for instr in &instrs {
+            for offset in 0..instr.len as u16 {
+                let offset = instr.ip.wrapping_add(offset) as usize;
+                let linear = ((segment as usize) << 4).wrapping_add(offset);
+                let page = MemMap::page(linear & sys.cpu.address_mask);
+                if !pages.contains(&page) {
+                    pages.push(page);
+                }
+            }
+        }
+        for page in &pages {
+            sys.mem.watch_page(*page);
+            self.pages.entry(*page).or_default().insert(key);
+        }
+
+        self.blocks.insert(key, Block { func, instrs });
+    }
+
+    fn decode_block(sys: &System, (segment, ip): (u16, u16)) -> Vec<Arc<DecodedInstr>> {
+        let mut instrs = Vec::new();
+
+        let mut ip = ip;
+        while instrs.len() < MAX_BLOCK_LEN {
+            let source =
+                SegmentedMem::new(&sys.mem, segment, ip).with_address_mask(sys.cpu.address_mask);
+            let instr = match Disassembler::decode_with(&*sys.cpu, &source, ip) {
+                Ok(instr) => instr,
+                // Let the interpreter panic once it actually reaches the invalid instruction
+                Err(_) if !instrs.is_empty() => break,
+                Err(err) => panic!("{} at {:#06x}:{:#06x}", err, segment, ip),
+            };
+
+            ip = instr.next_ip();
+            let ends_block = ends_block(&instr);
+            instrs.push(Arc::new(instr));
+
+            if ends_block {
+                break;
+            }
+        }
+
+        instrs
+    }
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether or not an instruction can change `CS:IP` (other than moving to the next instruction).
+fn ends_block(instr: &DecodedInstr) -> bool {
+    matches!(
+        instr.name(),
+        "JMP"
+            | "CALL"
+            | "RET"
+            | "RETF"
+            | "INT"
+            | "INTO"
+            | "IRET"
+            | "HLT"
+            | "LOOP"
+            | "LOOPE"
+            | "LOOPNE"
+            | "JCXZ"
+    ) || (instr.name().starts_with('J'))
+}
+
+/// Gets the CPU of the system that a block is running with.
+///
+/// The block accesses registers, flags and `IP` through the returned pointer, which is only valid
+/// until the next call to [`interpret`] (since it accesses the CPU through the system).
+extern "C" fn cpu(sys: *mut System) -> *mut Cpu {
+    // SAFETY: Blocks call this with the system that they were run with, and this doesn't create
+    // any references
+    unsafe { ptr::addr_of_mut!(*(*sys).cpu) }
+}
+
+/// Executes an instruction that the JIT can't compile.
+///
+/// `skipped` is the number of compiled instructions that ran since the last step was counted,
+/// which are counted first so that the devices see the same step and time as they would with the
+/// interpreter. `left` is the number of instructions from this one to the end of the block.
+///
+/// Returns whether or not the block can keep running, which it can't if the instruction didn't
+/// continue to the next instruction, if it wrote to a page with compiled code or if something has
+/// to happen before the rest of the block (see [`System::step_budget`]).
+///
+/// [`System::step_budget`]: firn_core::System::step_budget
+extern "C" fn interpret(
+    sys: *mut System,
+    instr: *const DecodedInstr,
+    skipped: u32,
+    left: u32,
+) -> u8 {
+    // SAFETY: Blocks call this with the system that they were run with and an instruction that
+    // they own, and they don't hold any references into the system
+    let (sys, instr) = unsafe { (&mut *sys, &*instr) };
+
+    sys.count_steps(skipped as u64);
+    sys.clock.advance(skipped as u64);
+
+    let segment = sys.cpu.reg_16(Cs.into());
+    sys.cpu.ip = instr.ip;
+    instr.execute(sys);
+
+    let continues = sys.cpu.ip == instr.next_ip() && sys.cpu.reg_16(Cs.into()) == segment;
+    (continues && !sys.mem.has_written_pages() && sys.step_budget() >= left as u64) as u8
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer: Type,
+
+    sys: Value,
+    regs: Value,
+    flags: Value,
+    ip: Value,
+
+    /// The number of instructions in the block.
+    len: u32,
+    /// The number of instructions that have been counted as steps when the block gets here.
+    counted: u32,
+
+    cpu: SigRef,
+    interpret: SigRef,
+}
+
+impl<'a> Translator<'a> {
+    fn new(mut builder: FunctionBuilder<'a>, pointer: Type, len: usize) -> Self {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+
+        let sys = builder.block_params(entry)[0];
+
+        let call_conv = builder.func.signature.call_conv;
+        let mut signature = Signature::new(call_conv);
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(pointer));
+        let cpu = builder.import_signature(signature);
+
+        let mut signature = Signature::new(call_conv);
+        signature.params.extend([AbiParam::new(pointer); 2]);
+        signature.params.extend([AbiParam::new(types::I32); 2]);
+        signature.returns.push(AbiParam::new(types::I8));
+        let interpret = builder.import_signature(signature);
+
+        let mut translator = Self {
+            builder,
+            pointer,
+
+            sys,
+            regs: sys,
+            flags: sys,
+            ip: sys,
+
+            len: len as u32,
+            counted: 0,
+
+            cpu,
+            interpret,
+        };
+        translator.locate_cpu();
+
+        translator
+    }
+
+    /// Gets the pointers to the registers, flags and `IP` from the system, which has to be done
+    /// again after every call to the interpreter.
+    fn locate_cpu(&mut self) {
+        let func = self
+            .builder
+            .ins()
+            .iconst(self.pointer, cpu as extern "C" fn(_) -> _ as usize as i64);
+        let call = self
+            .builder
+            .ins()
+            .call_indirect(self.cpu, func, &[self.sys]);
+        let cpu = self.builder.inst_results(call)[0];
+
+        self.regs = self
+            .builder
+            .ins()
+            .iadd_imm(cpu, mem::offset_of!(Cpu, regs) as i64);
+        self.flags = self
+            .builder
+            .ins()
+            .iadd_imm(cpu, mem::offset_of!(Cpu, flags) as i64);
+        self.ip = self
+            .builder
+            .ins()
+            .iadd_imm(cpu, mem::offset_of!(Cpu, ip) as i64);
+    }
+
+    fn translate(mut self, instrs: &[Arc<DecodedInstr>]) {
+        for (index, instr) in instrs.iter().enumerate() {
+            let executed = index as u32 + 1;
+
+            if self.translate_jump(instr, executed) {
+                // Jumps always end the block
+                self.builder.finalize();
+                return;
+            }
+            if !self.translate_native(instr) {
+                self.call_interpreter(instr, executed);
+            }
+        }
+
+        // The block ended without a jump, so continue after the last instruction (unless it was
+        // interpreted, which already updated IP)
+        let last = instrs.last().expect("blocks always have an instruction");
+        let executed = instrs.len() as u32;
+        if !ends_block(last) {
+            self.set_ip(last.next_ip());
+        }
+        self.exit(executed);
+        self.builder.finalize();
+    }
+
+    fn call_interpreter(&mut self, instr: &Arc<DecodedInstr>, executed: u32) {
+        let func = self.builder.ins().iconst(
+            self.pointer,
+            interpret as extern "C" fn(_, _, _, _) -> _ as usize as i64,
+        );
+        let instr_ptr = self
+            .builder
+            .ins()
+            .iconst(self.pointer, Arc::as_ptr(instr) as usize as i64);
+        let index = executed - 1;
+        let skipped = self
+            .builder
+            .ins()
+            .iconst(types::I32, (index - self.counted) as i64);
+        let left = self
+            .builder
+            .ins()
+            .iconst(types::I32, (self.len - index) as i64);
+        self.counted = index;
+        let call = self.builder.ins().call_indirect(
+            self.interpret,
+            func,
+            &[self.sys, instr_ptr, skipped, left],
+        );
+        let continues = self.builder.inst_results(call)[0];
+
+        if ends_block(instr) {
+            return;
+        }
+
+        let next = self.builder.create_block();
+        let exit = self.builder.create_block();
+        self.builder.ins().brif(continues, next, &[], exit, &[]);
+
+        self.builder.switch_to_block(exit);
+        self.builder.seal_block(exit);
+        self.exit(executed);
+
+        self.builder.switch_to_block(next);
+        self.builder.seal_block(next);
+        self.locate_cpu();
+    }
+
+    fn exit(&mut self, executed: u32) {
+        let executed = self.builder.ins().iconst(types::I32, executed as i64);
+        self.builder.ins().return_(&[executed]);
+    }
+
+    fn set_ip(&mut self, ip: u16) {
+        let ip = self.builder.ins().iconst(types::I16, ip as i64);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), ip, self.ip, 0);
+    }
+
+    fn load_byte_reg(&mut self, reg: GeneralByteReg) -> Value {
+        self.builder
+            .ins()
+            .load(types::I8, MemFlags::trusted(), self.regs, reg as i32)
+    }
+
+    fn store_byte_reg(&mut self, reg: GeneralByteReg, value: Value) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, self.regs, reg as i32);
+    }
+
+    fn load_word_reg(&mut self, reg: GeneralWordReg) -> Value {
+        let (low, high) = Cpu::word_reg_indices(reg);
+        let ins = self.builder.ins();
+        let low = ins.load(types::I8, MemFlags::trusted(), self.regs, low as i32);
+        let low = self.builder.ins().uextend(types::I16, low);
+        let high = self
+            .builder
+            .ins()
+            .load(types::I8, MemFlags::trusted(), self.regs, high as i32);
+        let high = self.builder.ins().uextend(types::I16, high);
+        let high = self.builder.ins().ishl_imm(high, 8);
+
+        self.builder.ins().bor(low, high)
+    }
+
+    fn store_word_reg(&mut self, reg: GeneralWordReg, value: Value) {
+        let (low_index, high_index) = Cpu::word_reg_indices(reg);
+        let low = self.builder.ins().ireduce(types::I8, value);
+        let high = self.builder.ins().ushr_imm(value, 8);
+        let high = self.builder.ins().ireduce(types::I8, high);
+
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), low, self.regs, low_index as i32);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), high, self.regs, high_index as i32);
+    }
+
+    fn load_flag(&mut self, offset: usize) -> Value {
+        self.builder
+            .ins()
+            .load(types::I8, MemFlags::trusted(), self.flags, offset as i32)
+    }
+
+    fn store_flag(&mut self, offset: usize, value: Value) {
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, self.flags, offset as i32);
+    }
+
+    fn store_flag_const(&mut self, offset: usize, value: bool) {
+        let value = self.builder.ins().iconst(types::I8, value as i64);
+        self.store_flag(offset, value);
+    }
+
+    /// Loads a register or immediate operand, returning `None` for memory operands.
+    fn load_operand(&mut self, operand: &Operand) -> Option<Value> {
+        let value = match *operand {
+            Operand::Reg(Reg::Byte(reg)) => self.load_byte_reg(reg),
+            Operand::Reg(Reg::Word(WordReg::General(reg))) => self.load_word_reg(reg),
+            Operand::Imm8(imm) => self.builder.ins().iconst(types::I8, imm as i64),
+            Operand::Imm16(imm) => self.builder.ins().iconst(types::I16, imm as i64),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    fn store_operand(&mut self, operand: &Operand, value: Value) {
+        match *operand {
+            Operand::Reg(Reg::Byte(reg)) => self.store_byte_reg(reg, value),
+            Operand::Reg(Reg::Word(WordReg::General(reg))) => self.store_word_reg(reg, value),
+            _ => unreachable!("only general registers can be stored to"),
+        }
+    }
+
+    /// Whether an operand is a general register, which is the only kind of operand that the JIT
+    /// writes to.
+    fn is_general_reg(operand: &Operand) -> bool {
+        matches!(
+            operand,
+            Operand::Reg(Reg::Byte(_)) | Operand::Reg(Reg::Word(WordReg::General(_)))
+        )
+    }
+
+    /// Loads both operands of a two-operand instruction if they're registers or immediates of the
+    /// same size and the first one is a general register.
+    fn load_operands(&mut self, instr: &DecodedInstr) -> Option<(Value, Value)> {
+        let [dest, src] = match *instr.operands {
+            [dest, src] => [dest, src],
+            _ => return None,
+        };
+        if !Self::is_general_reg(&dest) {
+            return None;
+        }
+
+        let dest = self.load_operand(&dest)?;
+        let src = self.load_operand(&src)?;
+        let func = &self.builder.func;
+        if func.dfg.value_type(dest) != func.dfg.value_type(src) {
+            return None;
+        }
+
+        Some((dest, src))
+    }
+
+    /// Compiles an instruction that only uses registers and flags, returning `false` if the
+    /// instruction isn't supported.
+    fn translate_native(&mut self, instr: &DecodedInstr) -> bool {
+        match instr.name() {
+            "MOV" => {
+                if self.load_operands(instr).is_none() {
+                    return false;
+                }
+
+                let value = self.load_operand(&instr.operands[1]).unwrap();
+                self.store_operand(&instr.operands[0], value);
+            }
+            "XCHG" => {
+                if !instr.operands.iter().all(Self::is_general_reg) {
+                    return false;
+                }
+                let (first, second) = match self.load_operands(instr) {
+                    Some(values) => values,
+                    None => return false,
+                };
+
+                self.store_operand(&instr.operands[0], second);
+                self.store_operand(&instr.operands[1], first);
+            }
+            "LEA" => match *instr.operands {
+                [Operand::Reg(Reg::Word(WordReg::General(dest))), Operand::Ptr(ptr, _)] => {
+                    let offset = self.effective_offset(&ptr);
+                    self.store_word_reg(dest, offset);
+                }
+                _ => return false,
+            },
+            "ADD" | "SUB" | "CMP" | "AND" | "OR" | "XOR" | "TEST" => {
+                let (left, right) = match self.load_operands(instr) {
+                    Some(values) => values,
+                    None => return false,
+                };
+
+                let value = self.arith(instr.name(), left, right);
+                if !matches!(instr.name(), "CMP" | "TEST") {
+                    self.store_operand(&instr.operands[0], value);
+                }
+            }
+
+            "CLC" => self.store_flag_const(mem::offset_of!(Flags, carry), false),
+            "STC" => self.store_flag_const(mem::offset_of!(Flags, carry), true),
+            "CMC" => {
+                let carry = self.load_flag(mem::offset_of!(Flags, carry));
+                let carry = self.builder.ins().bxor_imm(carry, 1);
+                self.store_flag(mem::offset_of!(Flags, carry), carry);
+            }
+            "CLD" => self.store_flag_const(mem::offset_of!(Flags, direction), false),
+            "STD" => self.store_flag_const(mem::offset_of!(Flags, direction), true),
+            "CLI" => self.store_flag_const(mem::offset_of!(Flags, interrupt), false),
+            "STI" => self.store_flag_const(mem::offset_of!(Flags, interrupt), true),
+
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn effective_offset(&mut self, ptr: &RmPtr) -> Value {
+        let displacement = match ptr.displacement() {
+            Some(Displacement::SignedByte(displacement)) => displacement as u16,
+            Some(Displacement::UnsignedWord(displacement)) => displacement,
+            None => 0,
+        };
+
+        let mut offset = self.builder.ins().iconst(types::I16, displacement as i64);
+        for reg in ptr.regs() {
+            let value = self.load_word_reg(reg);
+            offset = self.builder.ins().iadd(offset, value);
+        }
+
+        offset
+    }
+
+    /// Calculates an arithmetic or logical operation and sets the flags like the interpreter does.
+    fn arith(&mut self, name: &str, left: Value, right: Value) -> Value {
+        let ins = self.builder.ins();
+        let (value, carry, overflow) = match name {
+            "ADD" => {
+                let value = ins.iadd(left, right);
+                let carry = self
+                    .builder
+                    .ins()
+                    .icmp(IntCC::UnsignedLessThan, value, left);
+                let left_diff = self.builder.ins().bxor(left, value);
+                let right_diff = self.builder.ins().bxor(right, value);
+                let overflow = self.builder.ins().band(left_diff, right_diff);
+                let overflow = self
+                    .builder
+                    .ins()
+                    .icmp_imm(IntCC::SignedLessThan, overflow, 0);
+
+                (value, Some(carry), Some(overflow))
+            }
+            "SUB" | "CMP" => {
+                let value = ins.isub(left, right);
+                let carry = self
+                    .builder
+                    .ins()
+                    .icmp(IntCC::UnsignedLessThan, left, right);
+                let operand_diff = self.builder.ins().bxor(left, right);
+                let result_diff = self.builder.ins().bxor(left, value);
+                let overflow = self.builder.ins().band(operand_diff, result_diff);
+                let overflow = self
+                    .builder
+                    .ins()
+                    .icmp_imm(IntCC::SignedLessThan, overflow, 0);
+
+                (value, Some(carry), Some(overflow))
+            }
+            "AND" | "TEST" => (ins.band(left, right), None, None),
+            "OR" => (ins.bor(left, right), None, None),
+            "XOR" => (ins.bxor(left, right), None, None),
+            _ => unreachable!("unsupported arithmetic instruction: {}", name),
+        };
+
+        let zero = self.builder.ins().iconst(types::I8, 0);
+        let carry = carry.unwrap_or(zero);
+        let overflow = overflow.unwrap_or(zero);
+        self.store_flag(mem::offset_of!(Flags, carry), carry);
+        self.store_flag(mem::offset_of!(Flags, overflow), overflow);
+
+        let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, value, 0);
+        self.store_flag(mem::offset_of!(Flags, zero), is_zero);
+        let sign = self.builder.ins().icmp_imm(IntCC::SignedLessThan, value, 0);
+        self.store_flag(mem::offset_of!(Flags, sign), sign);
+
+        let low = if self.builder.func.dfg.value_type(value) == types::I8 {
+            value
+        } else {
+            self.builder.ins().ireduce(types::I8, value)
+        };
+        let ones = self.builder.ins().popcnt(low);
+        let odd = self.builder.ins().band_imm(ones, 1);
+        let parity = self.builder.ins().icmp_imm(IntCC::Equal, odd, 0);
+        self.store_flag(mem::offset_of!(Flags, parity), parity);
+
+        value
+    }
+
+    /// Compiles a relative jump, returning `false` if the instruction isn't a supported jump.
+    fn translate_jump(&mut self, instr: &DecodedInstr, executed: u32) -> bool {
+        let target = match *instr.operands {
+            [Operand::Rel8(rel)] => instr.next_ip().wrapping_add(rel as i8 as u16),
+            [Operand::Rel16(rel)] => instr.next_ip().wrapping_add(rel),
+            _ => return false,
+        };
+
+        let condition = match instr.name() {
+            "JMP" => None,
+            "LOOP" | "LOOPE" | "LOOPNE" => {
+                let cx = self.load_word_reg(GeneralWordReg::Cx);
+                let cx = self.builder.ins().iadd_imm(cx, -1);
+                self.store_word_reg(GeneralWordReg::Cx, cx);
+                let not_zero = self.builder.ins().icmp_imm(IntCC::NotEqual, cx, 0);
+
+                let condition = match instr.name() {
+                    "LOOPE" => {
+                        let zero = self.load_flag(mem::offset_of!(Flags, zero));
+                        self.builder.ins().band(not_zero, zero)
+                    }
+                    "LOOPNE" => {
+                        let zero = self.load_flag(mem::offset_of!(Flags, zero));
+                        let not_zero_flag = self.builder.ins().bxor_imm(zero, 1);
+                        self.builder.ins().band(not_zero, not_zero_flag)
+                    }
+                    _ => not_zero,
+                };
+                Some(condition)
+            }
+            "JCXZ" => {
+                let cx = self.load_word_reg(GeneralWordReg::Cx);
+                Some(self.builder.ins().icmp_imm(IntCC::Equal, cx, 0))
+            }
+            name => match self.condition(name) {
+                Some(condition) => Some(condition),
+                None => return false,
+            },
+        };
+
+        match condition {
+            Some(condition) => {
+                let taken = self.builder.create_block();
+                let not_taken = self.builder.create_block();
+                self.builder
+                    .ins()
+                    .brif(condition, taken, &[], not_taken, &[]);
+
+                self.builder.switch_to_block(taken);
+                self.builder.seal_block(taken);
+                self.set_ip(target);
+                self.exit(executed);
+
+                self.builder.switch_to_block(not_taken);
+                self.builder.seal_block(not_taken);
+                self.set_ip(instr.next_ip());
+                self.exit(executed);
+            }
+            None => {
+                self.set_ip(target);
+                self.exit(executed);
+            }
+        }
+
+        true
+    }
+
+    /// Calculates the condition of a conditional jump (`Jcc`), like the interpreter does.
+    fn condition(&mut self, name: &str) -> Option<Value> {
+        let mut flag = |offset| self.load_flag(offset);
+        let condition = match name {
+            "JO" | "JNO" => flag(mem::offset_of!(Flags, overflow)),
+            "JC" | "JNC" => flag(mem::offset_of!(Flags, carry)),
+            "JZ" | "JNZ" => flag(mem::offset_of!(Flags, zero)),
+            "JS" | "JNS" => flag(mem::offset_of!(Flags, sign)),
+            "JP" | "JNP" => flag(mem::offset_of!(Flags, parity)),
+            "JBE" | "JA" => {
+                let carry = flag(mem::offset_of!(Flags, carry));
+                let zero = flag(mem::offset_of!(Flags, zero));
+                self.builder.ins().bor(carry, zero)
+            }
+            "JL" | "JGE" => {
+                let sign = flag(mem::offset_of!(Flags, sign));
+                let overflow = flag(mem::offset_of!(Flags, overflow));
+                self.builder.ins().bxor(sign, overflow)
+            }
+            "JLE" | "JG" => {
+                let sign = flag(mem::offset_of!(Flags, sign));
+                let overflow = flag(mem::offset_of!(Flags, overflow));
+                let zero = flag(mem::offset_of!(Flags, zero));
+                let less = self.builder.ins().bxor(sign, overflow);
+                self.builder.ins().bor(zero, less)
+            }
+            _ => return None,
+        };
+
+        // The negated conditions are the ones with an N (other than JA and JG, which are the
+        // negations of JBE and JLE)
+        let negated = matches!(
+            name,
+            "JNO" | "JNC" | "JNZ" | "JNS" | "JNP" | "JA" | "JGE" | "JG"
+        );
+        if negated {
+            Some(self.builder.ins().bxor_imm(condition, 1))
+        } else {
+            Some(condition)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Backend;
+    use firn_core::cpu::Cpu as _;
+    use firn_core::mem::BasicMem;
+    use firn_core::system::StopReason;
+    use std::sync::Mutex;
+
+    const MAX_STEPS: usize = 1000;
+
+    // Every program ends with `jmp $`, which is where the tests stop running
+    fn create_sys(program: &[u8], backend: Backend) -> System {
+        let mut mem = BasicMem::new(0x10000);
+        for (index, byte) in program.iter().enumerate() {
+            mem[0x100 + index] = *byte;
+        }
+
+        let mut map = MemMap::new(0x10000);
+        map.map_full(mem);
+
+        let mut sys = System::new(Cpu::new(), map);
+        sys.cpu.ip = 0x100;
+        sys.cpu.backend = backend;
+        sys
+    }
+
+    fn run(program: &[u8], backend: Backend) -> System {
+        let mut sys = create_sys(program, backend);
+        let end = 0x100 + program.len() as u16 - 2;
+
+        for _ in 0..MAX_STEPS {
+            if sys.cpu.ip == end {
+                return sys;
+            }
+            Cpu::step(&mut sys);
+        }
+
+        panic!("the program didn't finish in {} steps", MAX_STEPS);
+    }
+
+    fn assert_same_state(expected: &System, actual: &System) {
+        assert_eq!(expected.cpu.regs, actual.cpu.regs);
+        assert_eq!(expected.cpu.flags.get_16(), actual.cpu.flags.get_16());
+        assert_eq!(expected.cpu.ip, actual.cpu.ip);
+    }
+
+    #[test]
+    fn should_match_interpreter() {
+        // 0x100: mov ax, 0x1234
+        // 0x103: mov bx, 0xf00f
+        // 0x106: mov cx, 0x5
+        // 0x109: add ax, bx
+        // 0x10b: sub dl, 0x7f
+        // 0x10e: xor bh, al
+        // 0x110: cmp ax, bx
+        // 0x112: jg 0x116
+        // 0x114: or si, ax
+        // 0x116: xchg ax, di
+        // 0x117: lea si, [bx+di-0x3]
+        // 0x11a: push ax
+        // 0x11b: pop dx
+        // 0x11c: mov [0x2000], al
+        // 0x11f: test al, 0x81
+        // 0x121: stc
+        // 0x122: cmc
+        // 0x123: loop 0x109
+        // 0x125: jmp 0x125
+        let program = [
+            0xb8, 0x34, 0x12, 0xbb, 0x0f, 0xf0, 0xb9, 0x05, 0x00, 0x01, 0xd8, 0x80, 0xea, 0x7f,
+            0x30, 0xc7, 0x39, 0xd8, 0x7f, 0x02, 0x09, 0xc6, 0x97, 0x8d, 0x71, 0xfd, 0x50, 0x5a,
+            0xa2, 0x00, 0x20, 0xa8, 0x81, 0xf9, 0xf5, 0xe2, 0xe4, 0xeb, 0xfe,
+        ];
+
+        let expected = run(&program, Backend::Interpreter);
+        let actual = run(&program, Backend::Jit);
+
+        assert_same_state(&expected, &actual);
+        assert_eq!(expected.mem[0x2000], actual.mem[0x2000]);
+        assert_eq!(expected.mem[0xfffe], actual.mem[0xfffe]);
+        assert_eq!(expected.mem[0xffff], actual.mem[0xffff]);
+    }
+
+    #[test]
+    fn should_set_flags_like_interpreter() {
+        // The ALU opcodes with `r/m8, r8` and `r/m16, r16` operands: ADD, OR, AND, SUB, XOR, CMP
+        // and TEST
+        let opcodes = [0x00, 0x08, 0x20, 0x28, 0x30, 0x38, 0x84];
+        let values = [
+            0x0000, 0x0001, 0x007f, 0x0080, 0x00ff, 0x7fff, 0x8000, 0xffff,
+        ];
+
+        for opcode in opcodes.into_iter().flat_map(|opcode| [opcode, opcode + 1]) {
+            for left in values {
+                for right in values {
+                    // mov ax, left
+                    // mov bx, right
+                    // <op> al, bl (or ax, bx)
+                    // jmp $
+                    let [left_low, left_high] = u16::to_le_bytes(left);
+                    let [right_low, right_high] = u16::to_le_bytes(right);
+                    let program = [
+                        0xb8, left_low, left_high, 0xbb, right_low, right_high, opcode, 0xd8, 0xeb,
+                        0xfe,
+                    ];
+
+                    let expected = run(&program, Backend::Interpreter);
+                    let actual = run(&program, Backend::Jit);
+
+                    assert_same_state(&expected, &actual);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_execute_blocks() {
+        // 0x100: mov ax, 0x1
+        // 0x103: add ax, ax
+        // 0x105: jmp 0x107
+        // 0x107: jmp 0x107
+        let program = [0xb8, 0x01, 0x00, 0x01, 0xc0, 0xeb, 0x00, 0xeb, 0xfe];
+        let mut sys = create_sys(&program, Backend::Jit);
+
+        Cpu::step(&mut sys);
+
+        assert_eq!(0x107, sys.cpu.ip);
+        assert_eq!(0x2, sys.cpu.reg_16(GeneralWordReg::Ax.into()));
+        assert_eq!(3, sys.cpu.decoded);
+        assert_eq!(1, sys.cpu.jit.as_ref().unwrap().len());
+    }
+
+    #[test]
+    fn should_count_every_instr_as_a_step() {
+        // 0x100: mov ax, 0x1
+        // 0x103: mov ax, 0x2
+        // 0x106: mov ax, 0x3
+        // 0x109: jmp 0x109
+        let program = [
+            0xb8, 0x01, 0x00, 0xb8, 0x02, 0x00, 0xb8, 0x03, 0x00, 0xeb, 0xfe,
+        ];
+        let mut sys = create_sys(&program, Backend::Jit);
+
+        assert_eq!(StopReason::StepLimit, sys.run_for(2));
+        assert_eq!((0x106, 2), (sys.cpu.ip, sys.steps()));
+        assert_eq!(0x2, sys.cpu.reg_16(GeneralWordReg::Ax.into()));
+
+        assert_eq!(StopReason::StepLimit, sys.run_for(10));
+        assert_eq!((0x109, 12), (sys.cpu.ip, sys.steps()));
+        assert_eq!(12, sys.clock.cycles());
+    }
+
+    #[test]
+    fn should_expire_timers_in_the_middle_of_blocks() {
+        // 0x100: mov ax, 0x1
+        // 0x103: mov ax, 0x2
+        // 0x106: mov ax, 0x3
+        // 0x109: jmp 0x109
+        let program = [
+            0xb8, 0x01, 0x00, 0xb8, 0x02, 0x00, 0xb8, 0x03, 0x00, 0xeb, 0xfe,
+        ];
+        let mut sys = create_sys(&program, Backend::Jit);
+        let seen = Arc::new(Mutex::new(None));
+
+        let at = sys.clock.time_at(2);
+        let timer_seen = Arc::clone(&seen);
+        sys.schedule(at, move |sys| {
+            *timer_seen.lock().unwrap() = Some(sys.cpu.reg_16(GeneralWordReg::Ax.into()));
+        });
+        sys.run_for(10);
+
+        assert_eq!(Some(0x2), *seen.lock().unwrap());
+        assert_eq!(0x3, sys.cpu.reg_16(GeneralWordReg::Ax.into()));
+    }
+
+    #[test]
+    fn should_recompile_modified_blocks() {
+        // The block modifies the immediate of its own ADD, so it has to be recompiled every time
+        //
+        // 0x100: mov cx, 0x3
+        // 0x103: add ax, 0x1
+        // 0x106: inc byte [0x104]
+        // 0x10a: loop 0x103
+        // 0x10c: jmp 0x10c
+        let program = [
+            0xb9, 0x03, 0x00, 0x05, 0x01, 0x00, 0xfe, 0x06, 0x04, 0x01, 0xe2, 0xf7, 0xeb, 0xfe,
+        ];
+
+        let expected = run(&program, Backend::Interpreter);
+        let actual = run(&program, Backend::Jit);
+
+        assert_eq!(0x6, actual.cpu.reg_16(GeneralWordReg::Ax.into()));
+        assert_same_state(&expected, &actual);
+    }
+}
@@ -1,19 +1,29 @@
 pub mod arith;
+pub mod cache;
 pub mod cpu;
 pub mod device;
+pub mod disasm;
 pub mod flags;
+pub mod gdb;
 pub mod instr;
+#[cfg(feature = "jit")]
+pub mod jit;
 pub mod modrm;
+pub mod monitor;
 pub mod opcodes;
 pub mod regs;
 pub mod system;
+pub mod trace;
 
-pub use cpu::{Cpu, Feature};
-pub use flags::Flags;
-pub use instr::{Instr, InstrFunc, InstrMeta, Prefixes};
+pub use cache::InstrCache;
+pub use cpu::{Backend, Cpu, Feature};
+pub use disasm::{DecodedInstr, Disassembler};
+pub use flags::{FlagSet, Flags};
+pub use instr::{Instr, InstrFunc, InstrMeta, Prefixes, RepPrefixes};
 pub use modrm::{Displacement, Modrm, ModrmRegType, RegMem, RmPtr};
 pub use regs::{GeneralByteReg, GeneralReg, GeneralWordReg, Reg, SegmentReg, WordReg};
 pub use system::{ExtSystem, System};
+pub use trace::{TraceLevel, TraceSink, Tracer};
 
 pub const DEFAULT_BIOS: &[u8] = include_bytes!("../resources/default_bios.bin");
 
@@ -4,20 +4,20 @@ use crate::{
     ExtSystem, GeneralByteReg, GeneralReg, GeneralWordReg, Reg, SegmentReg, Size, System, WordReg,
 };
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ModrmRegType {
     ByteSized,
     WordSized,
     Segment,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Displacement {
     SignedByte(i8),
     UnsignedWord(u16),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct RmPtr {
     segment: SegmentReg,
     first_reg: Option<GeneralWordReg>,
@@ -26,10 +26,22 @@ pub struct RmPtr {
 }
 
 impl RmPtr {
+    pub fn segment(&self) -> SegmentReg {
+        self.segment
+    }
+
+    pub fn regs(&self) -> impl Iterator<Item = GeneralWordReg> {
+        [self.first_reg, self.second_reg].into_iter().flatten()
+    }
+
+    pub fn displacement(&self) -> Option<Displacement> {
+        self.displacement
+    }
+
     pub fn address(&self, sys: &System) -> (SegmentReg, u16) {
         let mut offset: u16 = 0;
 
-        for reg in [self.first_reg, self.second_reg].into_iter().flatten() {
+        for reg in self.regs() {
             let value = sys.cpu.reg_16(reg.into());
             offset = offset.wrapping_add(value);
         }
@@ -63,16 +75,17 @@ impl RmPtr {
         sys.set_mem_16(segment, offset, value);
     }
 
-    pub fn double_address(&self, sys: &System) -> (u16, u16) {
-        let (original_segment, original_offset) = self.address(sys);
-        let segment = sys.mem_16(original_segment, original_offset);
-        let offset = sys.mem_16(original_segment, original_offset + 2);
+    /// Reads the far pointer (`m16:16`) that this points to, returning its offset and segment.
+    pub fn far_address(&self, sys: &System) -> (u16, u16) {
+        let (segment, offset) = self.address(sys);
+        let far_offset = sys.mem_16(segment, offset);
+        let far_segment = sys.mem_16(segment, offset.wrapping_add(2));
 
-        (segment, offset)
+        (far_offset, far_segment)
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RegMem {
     Reg(GeneralReg),
     Ptr(RmPtr),
@@ -133,21 +146,46 @@ impl Modrm {
         reg_type: Option<ModrmRegType>,
         rm_size: Size,
     ) -> Self {
+        let read_displacement = |size| match size {
+            Size::Byte => Some(sys.read_mem_8() as u16),
+            Size::Word => Some(sys.read_mem_16()),
+        };
+
+        Self::decode_with(modrm, reg_type, rm_size, read_displacement).unwrap_or_else(|| {
+            let r = (modrm / 0o10) % 0o10;
+            panic!("invalid s (in xsm octal) in ModRM byte: {}", r)
+        })
+    }
+
+    /// Decodes a ModRM byte without touching the system.
+    ///
+    /// Displacements are requested from `read_displacement`, which should return `None` if there
+    /// are no more bytes to read. This returns `None` if a displacement couldn't be read or if the
+    /// ModRM byte refers to a segment register that doesn't exist.
+    pub fn decode_with(
+        modrm: u8,
+        reg_type: Option<ModrmRegType>,
+        rm_size: Size,
+        mut read_displacement: impl FnMut(Size) -> Option<u16>,
+    ) -> Option<Self> {
         let x = (modrm / 0o100) % 0o10;
         let r = (modrm / 0o10) % 0o10;
         let m = modrm % 0o10;
 
-        let reg = reg_type.map(|reg_type| match reg_type {
-            ModrmRegType::ByteSized => GeneralByteReg::from_u8(r)
-                .unwrap_or_else(|| panic!("invalid r (in xrm octal) in ModRM byte: {}", r))
-                .into(),
-            ModrmRegType::WordSized => GeneralWordReg::from_u8(r)
-                .unwrap_or_else(|| panic!("invalid r (in xrm octal) in ModRM byte: {}", r))
-                .into(),
-            ModrmRegType::Segment => SegmentReg::from_u8(r)
-                .unwrap_or_else(|| panic!("invalid s (in xsm octal) in ModRM byte: {}", r))
-                .into(),
-        });
+        let reg = match reg_type {
+            Some(ModrmRegType::ByteSized) => Some(
+                GeneralByteReg::from_u8(r)
+                    .unwrap_or_else(|| panic!("invalid r (in xrm octal) in ModRM byte: {}", r))
+                    .into(),
+            ),
+            Some(ModrmRegType::WordSized) => Some(
+                GeneralWordReg::from_u8(r)
+                    .unwrap_or_else(|| panic!("invalid r (in xrm octal) in ModRM byte: {}", r))
+                    .into(),
+            ),
+            Some(ModrmRegType::Segment) => Some(SegmentReg::from_u8(r)?.into()),
+            None => None,
+        };
 
         if x == 3 {
             let rm_reg = match rm_size {
@@ -159,17 +197,19 @@ impl Modrm {
                     .into(),
             };
 
-            return Modrm {
+            return Some(Modrm {
                 reg,
                 reg_mem: RegMem::Reg(rm_reg),
-            };
+            });
         }
 
         let displacement = match x {
-            0 if m == 6 => Some(Displacement::UnsignedWord(sys.read_mem_16())),
+            0 if m == 6 => Some(Displacement::UnsignedWord(read_displacement(Size::Word)?)),
             0 => None,
-            1 => Some(Displacement::SignedByte(sys.read_mem_8() as i8)),
-            2 => Some(Displacement::UnsignedWord(sys.read_mem_16())),
+            1 => Some(Displacement::SignedByte(
+                read_displacement(Size::Byte)? as u8 as i8,
+            )),
+            2 => Some(Displacement::UnsignedWord(read_displacement(Size::Word)?)),
 
             _ => panic!("invalid x (in xrm octal) in ModRM byte: {}", x),
         };
@@ -188,7 +228,7 @@ impl Modrm {
             _ => panic!("invalid m (in xrm octal) in ModRM byte: {}", m),
         };
 
-        Modrm {
+        Some(Modrm {
             reg,
             reg_mem: RegMem::Ptr(RmPtr {
                 segment,
@@ -196,7 +236,7 @@ impl Modrm {
                 second_reg,
                 displacement,
             }),
-        }
+        })
     }
 
     pub fn byte_reg(&self) -> GeneralByteReg {
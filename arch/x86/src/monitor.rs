@@ -0,0 +1,733 @@
+//! A command console for inspecting and controlling a system, like QEMU's monitor.
+//!
+//! The [`Monitor`] owns the execution of a system and takes commands through a channel, so a
+//! frontend can run it on the emulator's thread and send commands from another thread (a
+//! terminal, a debugger window, ...) with a [`MonitorClient`]. Every command gets a text response.
+//!
+//! ```no_run
+//! # fn create_sys() -> firn_arch_x86::System { unimplemented!() }
+//! use firn_arch_x86::monitor::Monitor;
+//! use std::io::BufRead;
+//! use std::thread;
+//!
+//! let (mut monitor, client) = Monitor::new();
+//! thread::spawn(move || {
+//!     for line in std::io::stdin().lock().lines() {
+//!         match client.execute(&line.unwrap()) {
+//!             Some(response) => println!("{}", response),
+//!             None => break,
+//!         }
+//!     }
+//! });
+//!
+//! let mut sys = create_sys();
+//! monitor.run(&mut sys);
+//! ```
+//!
+//! Numbers are hexadecimal (with an optional `0x` prefix), except for counts which are decimal.
+//! Addresses are either linear or `segment:offset`.
+
+use crate::disasm::{ByteSource, SegmentedMem};
+use crate::trace::TraceRegs;
+use crate::SegmentReg::Cs;
+use crate::{Disassembler, System};
+use firn_core::breakpoint::{Breakpoint, Condition, Location};
+use firn_core::device::UnhandledPortPolicy;
+use firn_core::mem::{self, DumpRadix};
+use firn_core::snapshot::Snapshot;
+use firn_core::system::StopReason;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+
+/// How many instructions are executed between checks for new commands while the system runs.
+const POLL_INTERVAL: usize = 0x100;
+
+/// How many bytes are shown on each line by `x`.
+const BYTES_PER_LINE: usize = 16;
+
+/// How many steps there are between checkpoints if `record` isn't given an interval.
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 0x1000;
+
+/// How many instructions are disassembled by `disas` if no count is given.
+const DEFAULT_DISAS_COUNT: usize = 8;
+
+const HELP: &str = "\
+info registers            show the registers (also: regs)
+info breakpoints          list the breakpoints
+info devices              dump the state of every device
+info claims               list the ports and memory that every device handles
+info status               show whether the system is running
+info history              show the steps that the system can be rewound to
+info ports                list the ports that were accessed without a device handling them
+x/<count><fmt> <addr>     examine memory, fmt is x (hex), d (decimal), o (octal) or t (binary)
+disas [addr] [count]      disassemble instructions, starting at CS:IP by default
+break <addr> [if <cond>]  add a breakpoint, optionally with a condition like AX == 0x4c00
+delete <id>               remove a breakpoint
+inb <port>                read a byte from a port (also: inw)
+outb <port> <value>       write a byte to a port (also: outw)
+unhandled <policy>        set what unhandled port accesses do: open, log, break or panic
+savevm <path>             save a snapshot of the CPU and memory
+loadvm <path>             restore a snapshot
+step [count]              execute instructions
+record <steps> [interval] record the last steps so they can be rewound
+rstep [count]             rewind by some steps
+rewind <step>             rewind to a step
+cont                      run the system until a breakpoint or stop
+stop                      stop the system
+quit                      stop the monitor";
+
+struct Request {
+    command: String,
+    response: Sender<String>,
+}
+
+/// A handle for sending commands to a [`Monitor`], which can be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct MonitorClient {
+    requests: Sender<Request>,
+}
+
+impl MonitorClient {
+    /// Executes a command, waiting for the monitor to handle it.
+    ///
+    /// Returns [`None`] if the monitor has stopped.
+    pub fn execute(&self, command: &str) -> Option<String> {
+        let (response, receiver) = mpsc::channel();
+        self.requests
+            .send(Request {
+                command: command.to_string(),
+                response,
+            })
+            .ok()?;
+
+        receiver.recv().ok()
+    }
+}
+
+pub struct Monitor {
+    requests: Receiver<Request>,
+
+    running: bool,
+    quit: bool,
+    /// Why the system stopped, if it was stopped by a breakpoint or an unhandled port.
+    stopped_at: Option<StopReason>,
+}
+
+impl Monitor {
+    /// Creates a stopped monitor and a client for sending commands to it.
+    pub fn new() -> (Self, MonitorClient) {
+        let (sender, receiver) = mpsc::channel();
+        let monitor = Self {
+            requests: receiver,
+
+            running: false,
+            quit: false,
+            stopped_at: None,
+        };
+
+        (monitor, MonitorClient { requests: sender })
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Runs the monitor until `quit` is executed, stepping the system while it's running and
+    /// waiting for commands while it's stopped.
+    ///
+    /// This also returns if every client is dropped while the system is stopped, since nothing
+    /// could resume it.
+    pub fn run(&mut self, sys: &mut System) {
+        while !self.quit {
+            if self.running {
+                self.run_for(sys, POLL_INTERVAL);
+                self.process(sys);
+            } else {
+                match self.requests.recv() {
+                    Ok(request) => self.handle(sys, request),
+                    Err(_) => return,
+                }
+            }
+        }
+    }
+
+    /// Handles every pending command without blocking, returning `false` once `quit` has been
+    /// executed.
+    ///
+    /// This is for frontends that drive the system themselves instead of using [`run`].
+    ///
+    /// [`run`]: Monitor::run
+    pub fn process(&mut self, sys: &mut System) -> bool {
+        while !self.quit {
+            match self.requests.try_recv() {
+                Ok(request) => self.handle(sys, request),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+            }
+        }
+
+        !self.quit
+    }
+
+    /// Executes a single command, returning its response.
+    pub fn execute(&mut self, sys: &mut System, command: &str) -> String {
+        match self.execute_command(sys, command) {
+            Ok(response) => response,
+            Err(err) => format!("error: {}", err),
+        }
+    }
+
+    fn handle(&mut self, sys: &mut System, request: Request) {
+        let response = self.execute(sys, &request.command);
+        // The client might have given up waiting, which is fine
+        let _ = request.response.send(response);
+    }
+
+    /// Steps the system until it has executed `count` instructions or reached a breakpoint.
+    fn run_for(&mut self, sys: &mut System, count: usize) {
+        match sys.run_for(count as u64) {
+            StopReason::StepLimit => {}
+            reason => {
+                self.running = false;
+                self.stopped_at = Some(reason);
+            }
+        }
+    }
+
+    fn rewind(&mut self, sys: &mut System, step: u64) -> Result<String, String> {
+        sys.rewind_to(step).map_err(|err| err.to_string())?;
+        self.stopped_at = None;
+
+        Ok(format!(
+            "{:04x}:{:04x}",
+            sys.cpu.reg_16(Cs.into()),
+            sys.cpu.ip
+        ))
+    }
+
+    fn execute_command(&mut self, sys: &mut System, command: &str) -> Result<String, String> {
+        let mut words = command.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Ok(String::new()),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let examine_format = match name {
+            "x" => Some(""),
+            name => name.strip_prefix("x/"),
+        };
+        if let Some(format) = examine_format {
+            let address = parse_address(sys, single_arg(&args)?)?;
+            return examine(sys, format, address);
+        }
+
+        match (name, args.as_slice()) {
+            ("help", []) => Ok(HELP.to_string()),
+            ("info", ["registers"]) | ("regs", []) => Ok(format!(
+                "{} IP={:04x}",
+                TraceRegs::from_cpu(&sys.cpu),
+                sys.cpu.ip
+            )),
+            ("info", ["breakpoints"]) => Ok(sys
+                .breakpoints
+                .iter()
+                .map(|(id, breakpoint)| format!("{}: {}", id, breakpoint))
+                .collect::<Vec<String>>()
+                .join("\n")),
+            ("info", ["devices"]) => Ok(dump_devices(sys)),
+            ("info", ["claims"]) => Ok(list_claims(sys)),
+            ("info", ["status"]) => Ok(match (self.running, self.stopped_at) {
+                (true, _) => String::from("running"),
+                (false, Some(StopReason::Breakpoint(id))) => {
+                    format!("stopped at breakpoint {}", id)
+                }
+                (false, Some(StopReason::UnhandledPort(request))) => {
+                    format!("stopped after unhandled {}", request)
+                }
+                (false, _) => String::from("stopped"),
+            }),
+            ("info", ["history"]) => Ok(match sys.history() {
+                Some(history) => format!("steps {} to {}", history.start(), history.end()),
+                None => String::from("not recording"),
+            }),
+            ("info", ["ports"]) => Ok(sys.unhandled_port_report().trim_end().to_string()),
+            ("disas", args) => disassemble(sys, args),
+            ("break", [address, rest @ ..]) => {
+                let location = parse_location(sys, address)?;
+                let breakpoint = match rest {
+                    [] => Breakpoint::new(location),
+                    ["if", condition @ ..] if !condition.is_empty() => {
+                        let condition = Condition::parse(&condition.join(" "))
+                            .map_err(|err| format!("invalid condition: {}", err))?;
+                        Breakpoint::conditional(location, condition)
+                    }
+                    _ => return Err(String::from("expected `if <condition>` after the address")),
+                };
+
+                let id = sys.breakpoints.add(breakpoint);
+                Ok(format!("breakpoint {} at {}", id, location))
+            }
+            ("delete", [id]) => {
+                let id = parse_count(id)?;
+                match sys.breakpoints.remove(id) {
+                    Some(_) => Ok(String::new()),
+                    None => Err(format!("no breakpoint {}", id)),
+                }
+            }
+            ("inb", [port]) => {
+                let port = parse_port(port)?;
+                let value = sys.port_in_8(port).ok_or_else(|| unhandled_port(port))?;
+                Ok(format!("{:#04x}", value))
+            }
+            ("inw", [port]) => {
+                let port = parse_port(port)?;
+                let value = sys.port_in_16(port).ok_or_else(|| unhandled_port(port))?;
+                Ok(format!("{:#06x}", value))
+            }
+            ("outb", [port, value]) => {
+                let port = parse_port(port)?;
+                let value = u8::try_from(parse_number(value)?)
+                    .map_err(|_| format!("{} doesn't fit in a byte", value))?;
+                sys.port_out_8(port, value)
+                    .ok_or_else(|| unhandled_port(port))?;
+                Ok(String::new())
+            }
+            ("outw", [port, value]) => {
+                let port = parse_port(port)?;
+                let value = u16::try_from(parse_number(value)?)
+                    .map_err(|_| format!("{} doesn't fit in a word", value))?;
+                sys.port_out_16(port, value)
+                    .ok_or_else(|| unhandled_port(port))?;
+                Ok(String::new())
+            }
+            ("unhandled", [policy]) => {
+                sys.unhandled_port_policy = match *policy {
+                    "open" => UnhandledPortPolicy::OpenBus,
+                    "log" => UnhandledPortPolicy::Log,
+                    "break" => UnhandledPortPolicy::Break,
+                    "panic" => UnhandledPortPolicy::Panic,
+                    _ => return Err(format!("unknown policy: {}", policy)),
+                };
+                Ok(String::new())
+            }
+            ("savevm", [path]) => {
+                sys.snapshot()
+                    .save_to_file(path)
+                    .map_err(|err| err.to_string())?;
+                Ok(String::new())
+            }
+            ("loadvm", [path]) => {
+                let snapshot = Snapshot::load_from_file(path).map_err(|err| err.to_string())?;
+                sys.restore(&snapshot).map_err(|err| err.to_string())?;
+                Ok(String::new())
+            }
+            ("step", args) => {
+                let count = match args {
+                    [] => 1,
+                    [count] => parse_count(count)?,
+                    _ => return Err(String::from("too many arguments")),
+                };
+
+                self.stopped_at = None;
+                self.run_for(sys, count);
+                Ok(format!(
+                    "{:04x}:{:04x}",
+                    sys.cpu.reg_16(Cs.into()),
+                    sys.cpu.ip
+                ))
+            }
+            ("record", [window, rest @ ..]) => {
+                let window = parse_count(window)? as u64;
+                let interval = match rest {
+                    [] => DEFAULT_CHECKPOINT_INTERVAL,
+                    [interval] => parse_count(interval)?.max(1) as u64,
+                    _ => return Err(String::from("too many arguments")),
+                };
+
+                sys.start_recording(window, interval);
+                Ok(String::new())
+            }
+            ("rstep", args) => {
+                let count = match args {
+                    [] => 1,
+                    [count] => parse_count(count)? as u64,
+                    _ => return Err(String::from("too many arguments")),
+                };
+                let step = sys.steps().saturating_sub(count);
+
+                self.rewind(sys, step)
+            }
+            ("rewind", [step]) => {
+                let step = parse_count(step)? as u64;
+                self.rewind(sys, step)
+            }
+            ("cont", []) => {
+                self.running = true;
+                self.stopped_at = None;
+                Ok(String::new())
+            }
+            ("stop", []) => {
+                self.running = false;
+                Ok(String::new())
+            }
+            ("quit", []) => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Err(format!(
+                "unknown command or wrong arguments: {} (see help)",
+                command.trim()
+            )),
+        }
+    }
+}
+
+fn examine(sys: &System, format: &str, address: usize) -> Result<String, String> {
+    let digits = format
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(format.len());
+    let (count, radix) = format.split_at(digits);
+
+    let count = if count.is_empty() {
+        1
+    } else {
+        parse_count(count)?
+    };
+    let radix = match radix {
+        "" | "x" => DumpRadix::Hexadecimal,
+        "d" => DumpRadix::Decimal,
+        "o" => DumpRadix::Octal,
+        "t" => DumpRadix::Binary,
+        radix => return Err(format!("unknown format: {}", radix)),
+    };
+
+    let end = address.saturating_add(count).min(sys.mem.addressable);
+    let lines = (address..end)
+        .step_by(BYTES_PER_LINE)
+        .map(|start| {
+            let bytes = (start..end.min(start + BYTES_PER_LINE)).map(|address| sys.mem[address]);
+            format!("{:08x}: {}", start, mem::format_str_dump(radix, bytes))
+        })
+        .collect::<Vec<String>>();
+
+    Ok(lines.join("\n"))
+}
+
+fn disassemble(sys: &System, args: &[&str]) -> Result<String, String> {
+    let ((mut segment, mut offset), count) = match args {
+        [] => (current_pc(sys), DEFAULT_DISAS_COUNT),
+        [address] => (parse_segmented(sys, address)?, DEFAULT_DISAS_COUNT),
+        [address, count] => (parse_segmented(sys, address)?, parse_count(count)?),
+        _ => return Err(String::from("too many arguments")),
+    };
+
+    let disassembler = Disassembler::from_cpu(&sys.cpu);
+    let mut lines = Vec::with_capacity(count);
+    for _ in 0..count {
+        let source =
+            SegmentedMem::new(&sys.mem, segment, offset).with_address_mask(sys.cpu.address_mask);
+        let line = match disassembler.decode(&source, offset) {
+            Ok(instr) => {
+                let bytes = (0..instr.len).map(|index| source.byte(index).unwrap_or_default());
+                let line = format!(
+                    "{:04x}:{:04x}  {:<20}{}",
+                    segment,
+                    offset,
+                    mem::format_str_dump(DumpRadix::Hexadecimal, bytes),
+                    instr
+                );
+                offset = offset.wrapping_add(instr.len as u16);
+
+                line
+            }
+            Err(_) => {
+                lines.push(format!("{:04x}:{:04x}  (bad)", segment, offset));
+                break;
+            }
+        };
+
+        lines.push(line);
+        // Keep going in the next segment instead of wrapping around
+        if offset == 0 {
+            segment = segment.wrapping_add(0x1000);
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
+
+fn dump_devices(sys: &System) -> String {
+    sys.dump_devices()
+        .into_iter()
+        .enumerate()
+        .map(|(index, dump)| {
+            let dump = dump.unwrap_or_else(|| String::from("(no state)"));
+            format!("{}: {}", index, dump.replace('\n', "\n   "))
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn list_claims(sys: &System) -> String {
+    sys.device_infos()
+        .into_iter()
+        .map(|info| {
+            let mut claims: Vec<String> = info
+                .claims
+                .ports
+                .iter()
+                .map(|ports| match (ports.start(), ports.end()) {
+                    (start, end) if start == end => format!("port {:#06x}", start),
+                    (start, end) => format!("ports {:#06x}-{:#06x}", start, end),
+                })
+                .collect();
+            claims.extend(
+                info.claims
+                    .mem
+                    .iter()
+                    .map(|range| format!("memory {:#07x}-{:#07x}", range.start(), range.end())),
+            );
+            if claims.is_empty() {
+                claims.push(String::from("(nothing)"));
+            }
+
+            format!("{} {}: {}", info.id, info.name, claims.join(", "))
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn current_pc(sys: &System) -> (u16, u16) {
+    (sys.cpu.reg_16(Cs.into()), sys.cpu.ip)
+}
+
+fn unhandled_port(port: u16) -> String {
+    format!("no device handles port {:#06x}", port)
+}
+
+fn single_arg<'a>(args: &[&'a str]) -> Result<&'a str, String> {
+    match args {
+        [arg] => Ok(arg),
+        [] => Err(String::from("missing argument")),
+        _ => Err(String::from("too many arguments")),
+    }
+}
+
+fn parse_number(number: &str) -> Result<usize, String> {
+    let digits = number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+        .unwrap_or(number);
+
+    usize::from_str_radix(digits, 16).map_err(|_| format!("invalid number: {}", number))
+}
+
+fn parse_count(count: &str) -> Result<usize, String> {
+    count
+        .parse()
+        .map_err(|_| format!("invalid count: {}", count))
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    u16::try_from(parse_number(port)?).map_err(|_| format!("invalid port: {}", port))
+}
+
+/// Parses a `segment:offset` address, or a linear address which is split into a segment and an
+/// offset below 0x10.
+fn parse_segmented(sys: &System, address: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("invalid address: {}", address);
+
+    match address.split_once(':') {
+        Some((segment, offset)) => {
+            let segment = u16::try_from(parse_number(segment)?).map_err(|_| invalid())?;
+            let offset = u16::try_from(parse_number(offset)?).map_err(|_| invalid())?;
+            Ok((segment, offset))
+        }
+        None => {
+            let linear = parse_number(address)?;
+            if linear >= sys.mem.addressable {
+                return Err(invalid());
+            }
+            Ok(((linear >> 4) as u16, (linear & 0xf) as u16))
+        }
+    }
+}
+
+fn parse_location(sys: &System, address: &str) -> Result<Location, String> {
+    let linear = parse_address(sys, address)?;
+
+    Ok(if address.contains(':') {
+        let (segment, offset) = parse_segmented(sys, address)?;
+        Location::Segmented { segment, offset }
+    } else {
+        Location::Linear(linear)
+    })
+}
+
+fn parse_address(sys: &System, address: &str) -> Result<usize, String> {
+    let (segment, offset) = parse_segmented(sys, address)?;
+    let linear = ((segment as usize) << 4) + offset as usize;
+    if linear >= sys.mem.addressable {
+        return Err(format!("address is out of range: {}", address));
+    }
+
+    Ok(linear)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::cmos::Cmos;
+    use crate::Cpu;
+    use firn_core::mem::{BasicMem, MemMap};
+    use std::thread;
+
+    // 0x100: mov ax, 0x1234
+    // 0x103: inc ax
+    // 0x104: inc ax
+    // 0x105: jmp 0x105
+    const PROGRAM: &[u8] = &[0xb8, 0x34, 0x12, 0x40, 0x40, 0xeb, 0xfe];
+
+    fn create_sys() -> System {
+        let mut mem = BasicMem::new(0x10000);
+        for (index, byte) in PROGRAM.iter().enumerate() {
+            mem[0x100 + index] = *byte;
+        }
+        let mut map = MemMap::new(0x10000);
+        map.map_full(mem);
+
+        let mut sys = System::new(Cpu::new(), map);
+        sys.cpu.ip = 0x100;
+
+        sys
+    }
+
+    #[test]
+    fn should_examine_memory() {
+        let mut sys = create_sys();
+        let (mut monitor, _) = Monitor::new();
+
+        assert_eq!("00000100: b8", monitor.execute(&mut sys, "x 100"));
+        assert_eq!(
+            "000000fe: 000 000 184 052 018",
+            monitor.execute(&mut sys, "x/5d 0:fe")
+        );
+        let lines = monitor.execute(&mut sys, "x/20x 0x100");
+        assert_eq!(2, lines.lines().count());
+        assert!(lines.ends_with("00000110: 00 00 00 00"));
+        assert!(monitor.execute(&mut sys, "x/4q 100").starts_with("error: "));
+    }
+
+    #[test]
+    fn should_disassemble() {
+        let mut sys = create_sys();
+        let (mut monitor, _) = Monitor::new();
+
+        let disas = monitor.execute(&mut sys, "disas 0:100 3");
+        let lines: Vec<&str> = disas.lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("0000:0100  b8 34 12"));
+        assert!(lines[2].starts_with("0000:0104  40"));
+    }
+
+    #[test]
+    fn should_step_and_show_registers() {
+        let mut sys = create_sys();
+        let (mut monitor, _) = Monitor::new();
+
+        assert_eq!("0000:0104", monitor.execute(&mut sys, "step 2"));
+        let regs = monitor.execute(&mut sys, "info registers");
+        assert!(regs.starts_with("AX=1235 "));
+        assert!(regs.ends_with(" IP=0104"));
+    }
+
+    #[test]
+    fn should_rewind_steps() {
+        let mut sys = create_sys();
+        let (mut monitor, _) = Monitor::new();
+
+        assert!(monitor.execute(&mut sys, "rstep").starts_with("error: "));
+        monitor.execute(&mut sys, "record 100 2");
+        monitor.execute(&mut sys, "step 3");
+        assert_eq!("steps 0 to 3", monitor.execute(&mut sys, "info history"));
+
+        assert_eq!("0000:0103", monitor.execute(&mut sys, "rstep 2"));
+        assert!(monitor.execute(&mut sys, "regs").starts_with("AX=1234 "));
+        assert_eq!("0000:0100", monitor.execute(&mut sys, "rewind 0"));
+    }
+
+    #[test]
+    fn should_use_ports_and_dump_devices() {
+        let mut sys = create_sys();
+        sys.add_device(Cmos::new_current_time());
+        let (mut monitor, _) = Monitor::new();
+
+        assert_eq!("", monitor.execute(&mut sys, "outb 70 0b"));
+        assert!(monitor
+            .execute(&mut sys, "info devices")
+            .starts_with("0: CMOS: selected register 0x0b"));
+        assert_eq!(
+            "0 Cmos: ports 0x0070-0x0071",
+            monitor.execute(&mut sys, "info claims")
+        );
+        assert!(monitor.execute(&mut sys, "inb 1234").starts_with("error: "));
+        assert_eq!("", monitor.execute(&mut sys, "info ports"));
+        assert_eq!("", monitor.execute(&mut sys, "unhandled break"));
+        assert_eq!(UnhandledPortPolicy::Break, sys.unhandled_port_policy);
+        assert!(monitor
+            .execute(&mut sys, "unhandled ignore")
+            .starts_with("error: "));
+    }
+
+    #[test]
+    fn should_save_and_load_snapshots() {
+        let mut sys = create_sys();
+        let (mut monitor, _) = Monitor::new();
+        let path = std::env::temp_dir().join(format!("firn-monitor-{}.snap", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        monitor.execute(&mut sys, &format!("savevm {}", path));
+        monitor.execute(&mut sys, "step 3");
+        assert_eq!("", monitor.execute(&mut sys, &format!("loadvm {}", path)));
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(0x100, sys.cpu.ip);
+    }
+
+    #[test]
+    fn should_run_until_breakpoint() {
+        let (mut monitor, client) = Monitor::new();
+        let handle = thread::spawn(move || {
+            let mut sys = create_sys();
+            monitor.run(&mut sys);
+            sys
+        });
+
+        assert_eq!(
+            Some(String::from("breakpoint 0 at 0x00104")),
+            client.execute("break 104")
+        );
+        client.execute("break 0:105\tif  AX ==\t0x1236");
+        assert_eq!(
+            Some(String::from(
+                "0: 0x00104, hit 0 times\n1: 0000:0105 if AX == 0x1236, hit 0 times"
+            )),
+            client.execute("info breakpoints")
+        );
+
+        for id in 0..2 {
+            client.execute("cont");
+            while client.execute("info status").unwrap() == "running" {
+                thread::yield_now();
+            }
+            assert_eq!(
+                Some(format!("stopped at breakpoint {}", id)),
+                client.execute("info status")
+            );
+        }
+
+        client.execute("quit");
+        let sys = handle.join().unwrap();
+        assert_eq!(0x105, sys.cpu.ip);
+        assert_eq!(None, client.execute("regs"));
+    }
+}
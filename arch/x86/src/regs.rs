@@ -1,6 +1,7 @@
 use num_derive::FromPrimitive;
+use std::fmt::{Display, Formatter};
 
-#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive)]
 pub enum GeneralByteReg {
     Al = 0,
     Cl = 1,
@@ -16,9 +17,22 @@ impl GeneralByteReg {
     pub fn from_u8(reg: u8) -> Option<Self> {
         num_traits::FromPrimitive::from_u8(reg)
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeneralByteReg::Al => "al",
+            GeneralByteReg::Cl => "cl",
+            GeneralByteReg::Dl => "dl",
+            GeneralByteReg::Bl => "bl",
+            GeneralByteReg::Ah => "ah",
+            GeneralByteReg::Ch => "ch",
+            GeneralByteReg::Dh => "dh",
+            GeneralByteReg::Bh => "bh",
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive)]
 pub enum GeneralWordReg {
     Ax = 0,
     Cx = 1,
@@ -34,9 +48,22 @@ impl GeneralWordReg {
     pub fn from_u8(reg: u8) -> Option<Self> {
         num_traits::FromPrimitive::from_u8(reg)
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeneralWordReg::Ax => "ax",
+            GeneralWordReg::Cx => "cx",
+            GeneralWordReg::Dx => "dx",
+            GeneralWordReg::Bx => "bx",
+            GeneralWordReg::Sp => "sp",
+            GeneralWordReg::Bp => "bp",
+            GeneralWordReg::Si => "si",
+            GeneralWordReg::Di => "di",
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, FromPrimitive)]
 pub enum SegmentReg {
     Es = 0,
     Cs = 1,
@@ -48,9 +75,18 @@ impl SegmentReg {
     pub fn from_u8(reg: u8) -> Option<Self> {
         num_traits::FromPrimitive::from_u8(reg)
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegmentReg::Es => "es",
+            SegmentReg::Cs => "cs",
+            SegmentReg::Ss => "ss",
+            SegmentReg::Ds => "ds",
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum WordReg {
     General(GeneralWordReg),
     Segment(SegmentReg),
@@ -68,7 +104,7 @@ impl From<SegmentReg> for WordReg {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum GeneralReg {
     Byte(GeneralByteReg),
     Word(GeneralWordReg),
@@ -86,7 +122,7 @@ impl From<GeneralWordReg> for GeneralReg {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Reg {
     Byte(GeneralByteReg),
     Word(WordReg),
@@ -124,3 +160,51 @@ impl From<GeneralReg> for Reg {
         }
     }
 }
+
+macro_rules! display_name {
+    ($($reg:ident),*) => {
+        $(
+            impl Display for $reg {
+                fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                    f.write_str(self.name())
+                }
+            }
+        )*
+    };
+}
+
+display_name!(
+    GeneralByteReg,
+    GeneralWordReg,
+    SegmentReg,
+    WordReg,
+    GeneralReg,
+    Reg
+);
+
+impl WordReg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WordReg::General(reg) => reg.name(),
+            WordReg::Segment(reg) => reg.name(),
+        }
+    }
+}
+
+impl GeneralReg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeneralReg::Byte(reg) => reg.name(),
+            GeneralReg::Word(reg) => reg.name(),
+        }
+    }
+}
+
+impl Reg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Reg::Byte(reg) => reg.name(),
+            Reg::Word(reg) => reg.name(),
+        }
+    }
+}
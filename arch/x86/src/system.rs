@@ -50,7 +50,7 @@ impl ExtSystem for System {
 
     fn mem_linear_16(&self, address: usize) -> u16 {
         let low = self.mem[address];
-        let high = self.mem[address.wrapping_add(1)];
+        let high = self.mem[address.wrapping_add(1) & self.cpu.address_mask];
 
         u16::from_le_bytes([low, high])
     }
@@ -58,7 +58,7 @@ impl ExtSystem for System {
     fn linear_mem(&self, segment: SegmentReg, offset: u16) -> usize {
         let segment = self.cpu.reg_16(segment.into()) as usize;
 
-        (segment << 4).wrapping_add(offset as usize)
+        (segment << 4).wrapping_add(offset as usize) & self.cpu.address_mask
     }
 
     fn mem_8(&self, segment: SegmentReg, offset: u16) -> u8 {
@@ -93,7 +93,7 @@ impl ExtSystem for System {
 
         let [low, high] = value.to_le_bytes();
         self.mem[linear] = low;
-        self.mem[linear.wrapping_add(1)] = high;
+        self.mem[linear.wrapping_add(1) & self.cpu.address_mask] = high;
     }
 
     fn set_mem_reg_8(&mut self, segment: SegmentReg, offset: GeneralWordReg, value: u8) {
@@ -0,0 +1,647 @@
+//! Execution tracing.
+//!
+//! The CPU can report every instruction that it executes to a [`TraceSink`], along with the state
+//! of its registers right before the instruction is executed. How much is traced is controlled by
+//! the CPU's [`Tracer`], which has a [`TraceLevel`] and a list of [`TraceFilter`]s.
+//!
+//! Traces can be written as text with [`TextSink`], or in a compact binary format with
+//! [`BinarySink`] which can later be read with [`BinaryTraceReader`] or converted to text with
+//! [`convert_to_text`]. The text format is meant to be diffed, either between two runs of Firn or
+//! against a trace from a reference emulator.
+//!
+//! [`TraceSink`]: TraceSink
+//! [`Tracer`]: Tracer
+//! [`TraceLevel`]: TraceLevel
+//! [`TraceFilter`]: TraceFilter
+//! [`TextSink`]: TextSink
+//! [`BinarySink`]: BinarySink
+//! [`BinaryTraceReader`]: BinaryTraceReader
+//! [`convert_to_text`]: convert_to_text
+
+use crate::disasm::MAX_INSTR_LEN;
+use crate::GeneralWordReg::{Ax, Bp, Bx, Cx, Di, Dx, Si, Sp};
+use crate::SegmentReg::{Cs, Ds, Es, Ss};
+use crate::{Cpu, Disassembler, Feature};
+use firn_core::cpu::Restrict;
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+/// The bytes at the start of every binary trace, the last of which is the format version.
+pub const BINARY_MAGIC: [u8; 8] = *b"FIRNTRC\x01";
+
+/// How much the CPU traces.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum TraceLevel {
+    /// Nothing is traced.
+    Off,
+    /// The address and bytes of every instruction are traced.
+    Instrs,
+    /// Instructions are traced along with the state of every register.
+    Regs,
+}
+
+/// A filter that decides which instructions are traced.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TraceFilter {
+    /// Only traces instructions whose first byte has a linear address in the range.
+    Address(RangeInclusive<usize>),
+    /// Only traces instructions with one of these opcodes (ignoring prefixes).
+    Opcodes(Vec<u8>),
+    /// Only traces instructions in this code segment.
+    Segment(u16),
+}
+
+impl TraceFilter {
+    pub fn matches(&self, address: usize, segment: u16, opcode: u8) -> bool {
+        match self {
+            TraceFilter::Address(range) => range.contains(&address),
+            TraceFilter::Opcodes(opcodes) => opcodes.contains(&opcode),
+            TraceFilter::Segment(filter) => *filter == segment,
+        }
+    }
+}
+
+/// The state of the CPU's registers, not including IP (which is part of the [`TraceRecord`]).
+///
+/// [`TraceRecord`]: TraceRecord
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct TraceRegs {
+    pub ax: u16,
+    pub cx: u16,
+    pub dx: u16,
+    pub bx: u16,
+    pub sp: u16,
+    pub bp: u16,
+    pub si: u16,
+    pub di: u16,
+
+    pub es: u16,
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
+
+    pub flags: u16,
+}
+
+impl TraceRegs {
+    const LEN: usize = 13 * 2;
+
+    pub fn from_cpu(cpu: &Cpu) -> Self {
+        Self {
+            ax: cpu.reg_16(Ax.into()),
+            cx: cpu.reg_16(Cx.into()),
+            dx: cpu.reg_16(Dx.into()),
+            bx: cpu.reg_16(Bx.into()),
+            sp: cpu.reg_16(Sp.into()),
+            bp: cpu.reg_16(Bp.into()),
+            si: cpu.reg_16(Si.into()),
+            di: cpu.reg_16(Di.into()),
+
+            es: cpu.reg_16(Es.into()),
+            cs: cpu.reg_16(Cs.into()),
+            ss: cpu.reg_16(Ss.into()),
+            ds: cpu.reg_16(Ds.into()),
+
+            flags: cpu.flags.get_16(),
+        }
+    }
+
+    fn words(&self) -> [u16; 13] {
+        [
+            self.ax, self.cx, self.dx, self.bx, self.sp, self.bp, self.si, self.di, self.es,
+            self.cs, self.ss, self.ds, self.flags,
+        ]
+    }
+
+    fn from_words(words: [u16; 13]) -> Self {
+        let [ax, cx, dx, bx, sp, bp, si, di, es, cs, ss, ds, flags] = words;
+
+        Self {
+            ax,
+            cx,
+            dx,
+            bx,
+            sp,
+            bp,
+            si,
+            di,
+
+            es,
+            cs,
+            ss,
+            ds,
+
+            flags,
+        }
+    }
+}
+
+impl Display for TraceRegs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "AX={:04x} BX={:04x} CX={:04x} DX={:04x} SI={:04x} DI={:04x} BP={:04x} SP={:04x} \
+             CS={:04x} DS={:04x} ES={:04x} SS={:04x} FL={:04x}",
+            self.ax,
+            self.bx,
+            self.cx,
+            self.dx,
+            self.si,
+            self.di,
+            self.bp,
+            self.sp,
+            self.cs,
+            self.ds,
+            self.es,
+            self.ss,
+            self.flags
+        )
+    }
+}
+
+/// A single traced instruction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TraceRecord {
+    /// The number of instructions that the CPU has decoded, including this one.
+    pub count: u64,
+    pub segment: u16,
+    pub ip: u16,
+
+    len: u8,
+    bytes: [u8; MAX_INSTR_LEN],
+
+    /// The registers before the instruction was executed, if the trace level is
+    /// [`TraceLevel::Regs`].
+    ///
+    /// [`TraceLevel::Regs`]: TraceLevel::Regs
+    pub regs: Option<TraceRegs>,
+}
+
+impl TraceRecord {
+    /// Creates a record, ignoring any bytes past [`MAX_INSTR_LEN`].
+    ///
+    /// [`MAX_INSTR_LEN`]: crate::disasm::MAX_INSTR_LEN
+    pub fn new(count: u64, segment: u16, ip: u16, bytes: &[u8], regs: Option<TraceRegs>) -> Self {
+        let len = bytes.len().min(MAX_INSTR_LEN);
+        let mut record = Self {
+            count,
+            segment,
+            ip,
+
+            len: len as u8,
+            bytes: [0; MAX_INSTR_LEN],
+
+            regs,
+        };
+        record.bytes[..len].copy_from_slice(&bytes[..len]);
+
+        record
+    }
+
+    /// The bytes of the instruction.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// Writes the record in the binary trace format.
+    ///
+    /// Every value is little-endian. A record consists of the instruction count (8 bytes), CS and
+    /// IP (2 bytes each), the length of the instruction (1 byte) followed by its bytes, and then a
+    /// byte which is 1 if the registers follow or 0 if they don't. The registers are written in
+    /// the order AX, CX, DX, BX, SP, BP, SI, DI, ES, CS, SS, DS, FLAGS (2 bytes each).
+    pub fn write_binary(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.count.to_le_bytes())?;
+        writer.write_all(&self.segment.to_le_bytes())?;
+        writer.write_all(&self.ip.to_le_bytes())?;
+        writer.write_all(&[self.len])?;
+        writer.write_all(self.bytes())?;
+
+        match &self.regs {
+            Some(regs) => {
+                let mut bytes = [0; 1 + TraceRegs::LEN];
+                bytes[0] = 1;
+                for (index, word) in regs.words().iter().enumerate() {
+                    bytes[1 + index * 2..3 + index * 2].copy_from_slice(&word.to_le_bytes());
+                }
+
+                writer.write_all(&bytes)
+            }
+            None => writer.write_all(&[0]),
+        }
+    }
+
+    /// Reads a record in the binary trace format, returning `None` if the reader is already at the
+    /// end of the trace.
+    ///
+    /// See [`write_binary`] for the format.
+    ///
+    /// [`write_binary`]: TraceRecord::write_binary
+    pub fn read_binary(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut count = [0; 8];
+        match reader.read(&mut count[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut count[1..])?,
+        }
+
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        let segment = u16::from_le_bytes([header[0], header[1]]);
+        let ip = u16::from_le_bytes([header[2], header[3]]);
+        let len = header[4] as usize;
+        if len > MAX_INSTR_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("trace record has an instruction with {} bytes", len),
+            ));
+        }
+
+        let mut bytes = [0; MAX_INSTR_LEN];
+        reader.read_exact(&mut bytes[..len])?;
+
+        let mut has_regs = [0];
+        reader.read_exact(&mut has_regs)?;
+        let regs = match has_regs[0] {
+            0 => None,
+            1 => {
+                let mut bytes = [0; TraceRegs::LEN];
+                reader.read_exact(&mut bytes)?;
+
+                let mut words = [0; 13];
+                for (index, word) in words.iter_mut().enumerate() {
+                    *word = u16::from_le_bytes([bytes[index * 2], bytes[index * 2 + 1]]);
+                }
+                Some(TraceRegs::from_words(words))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "trace record has an invalid register marker",
+                ))
+            }
+        };
+
+        Ok(Some(Self::new(
+            u64::from_le_bytes(count),
+            segment,
+            ip,
+            &bytes[..len],
+            regs,
+        )))
+    }
+}
+
+impl Display for TraceRecord {
+    /// Formats the record as a single line, disassembling the instruction with every feature
+    /// enabled.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bytes = self
+            .bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        let mut disassembler = Disassembler::new();
+        disassembler.add_feature(Feature::InstrCpu1);
+        let instr = match disassembler.decode(self.bytes(), self.ip) {
+            Ok(instr) => instr.to_string(),
+            Err(_) => String::from("(bad)"),
+        };
+
+        write!(
+            f,
+            "{:>10} {:04x}:{:04x} {:<14} ",
+            self.count, self.segment, self.ip, bytes
+        )?;
+        match &self.regs {
+            Some(regs) => write!(f, "{:<28} {}", instr, regs),
+            None => write!(f, "{}", instr),
+        }
+    }
+}
+
+/// A destination for trace records.
+pub trait TraceSink: Send {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Collects trace records in memory.
+impl TraceSink for Vec<TraceRecord> {
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.push(*record);
+
+        Ok(())
+    }
+}
+
+/// Lets a sink be shared with the CPU, for example to read the records that were collected.
+impl<S> TraceSink for Arc<Mutex<S>>
+where
+    S: TraceSink,
+{
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.lock().unwrap().record(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().unwrap().flush()
+    }
+}
+
+/// Writes trace records as lines of text.
+///
+/// Wrap files in a [`BufWriter`] since every record is written separately.
+///
+/// [`BufWriter`]: std::io::BufWriter
+pub struct TextSink<W>
+where
+    W: Write + Send,
+{
+    writer: W,
+}
+
+impl<W> TextSink<W>
+where
+    W: Write + Send,
+{
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> TraceSink for TextSink<W>
+where
+    W: Write + Send,
+{
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        writeln!(self.writer, "{}", record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes trace records in the binary trace format.
+///
+/// The trace starts with [`BINARY_MAGIC`], followed by records in the format described by
+/// [`TraceRecord::write_binary`]. Wrap files in a [`BufWriter`] since every record is written
+/// separately.
+///
+/// [`BINARY_MAGIC`]: BINARY_MAGIC
+/// [`TraceRecord::write_binary`]: TraceRecord::write_binary
+/// [`BufWriter`]: std::io::BufWriter
+pub struct BinarySink<W>
+where
+    W: Write + Send,
+{
+    writer: W,
+}
+
+impl<W> BinarySink<W>
+where
+    W: Write + Send,
+{
+    /// Creates a sink, writing the start of the trace.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&BINARY_MAGIC)?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> TraceSink for BinarySink<W>
+where
+    W: Write + Send,
+{
+    fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        record.write_binary(&mut self.writer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the records of a binary trace that was written by [`BinarySink`].
+///
+/// [`BinarySink`]: BinarySink
+pub struct BinaryTraceReader<R>
+where
+    R: Read,
+{
+    reader: R,
+}
+
+impl<R> BinaryTraceReader<R>
+where
+    R: Read,
+{
+    /// Creates a reader, checking that the trace starts with [`BINARY_MAGIC`].
+    ///
+    /// [`BINARY_MAGIC`]: BINARY_MAGIC
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; BINARY_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != BINARY_MAGIC {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "not a binary trace (or an unsupported version)",
+            ));
+        }
+
+        Ok(Self { reader })
+    }
+}
+
+impl<R> Iterator for BinaryTraceReader<R>
+where
+    R: Read,
+{
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        TraceRecord::read_binary(&mut self.reader).transpose()
+    }
+}
+
+/// Converts a binary trace to the text format that [`TextSink`] writes.
+///
+/// [`TextSink`]: TextSink
+pub fn convert_to_text(reader: impl Read, writer: impl Write + Send) -> io::Result<()> {
+    let mut sink = TextSink::new(writer);
+    for record in BinaryTraceReader::new(reader)? {
+        sink.record(&record?)?;
+    }
+
+    sink.flush()
+}
+
+/// Decides which instructions the CPU traces and where it sends them.
+///
+/// Tracing is off by default. It's enabled once there's a sink and the level isn't
+/// [`TraceLevel::Off`]. An instruction is only traced if it matches every filter.
+///
+/// If the sink returns an error, tracing stops and the error can be retrieved with
+/// [`take_error`].
+///
+/// [`TraceLevel::Off`]: TraceLevel::Off
+/// [`take_error`]: Tracer::take_error
+pub struct Tracer {
+    pub level: TraceLevel,
+    pub filters: Vec<TraceFilter>,
+
+    sink: Option<Box<dyn TraceSink>>,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self {
+            level: TraceLevel::Off,
+            filters: Vec::new(),
+
+            sink: None,
+            error: None,
+        }
+    }
+
+    /// Starts tracing to `sink` at `level`, replacing the old sink.
+    pub fn start(&mut self, level: TraceLevel, sink: impl TraceSink + 'static) {
+        self.level = level;
+        self.sink = Some(Box::new(sink));
+        self.error = None;
+    }
+
+    /// Stops tracing, flushing and returning the sink.
+    pub fn stop(&mut self) -> Option<Box<dyn TraceSink>> {
+        let mut sink = self.sink.take()?;
+        if let Err(err) = sink.flush() {
+            self.error = Some(err);
+        }
+
+        Some(sink)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.level != TraceLevel::Off && self.sink.is_some()
+    }
+
+    /// Whether or not an instruction passes every filter.
+    pub fn matches(&self, address: usize, segment: u16, opcode: u8) -> bool {
+        self.filters
+            .iter()
+            .all(|filter| filter.matches(address, segment, opcode))
+    }
+
+    /// Sends a record to the sink.
+    pub fn record(&mut self, record: &TraceRecord) {
+        if let Some(sink) = &mut self.sink {
+            if let Err(err) = sink.record(record) {
+                self.sink = None;
+                self.error = Some(err);
+            }
+        }
+    }
+
+    /// Takes the error that stopped tracing, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_record(regs: bool) -> TraceRecord {
+        let regs = regs.then(|| TraceRegs {
+            ax: 0x4c00,
+            cs: 0x1234,
+            sp: 0xfffe,
+            flags: 0xf002,
+            ..Default::default()
+        });
+
+        // mov ax, 0x4c00
+        TraceRecord::new(39, 0x1234, 0x100, &[0xb8, 0x00, 0x4c], regs)
+    }
+
+    #[test]
+    fn should_filter_instrs() {
+        let mut tracer = Tracer::new();
+        assert!(tracer.matches(0x500, 0x0, 0x90));
+
+        tracer.filters.push(TraceFilter::Address(0x400..=0x4ff));
+        tracer.filters.push(TraceFilter::Opcodes(vec![0x90, 0xcd]));
+        assert!(tracer.matches(0x450, 0x0, 0xcd));
+        assert!(!tracer.matches(0x500, 0x0, 0x90));
+        assert!(!tracer.matches(0x450, 0x0, 0xb8));
+
+        tracer.filters.push(TraceFilter::Segment(0x40));
+        assert!(tracer.matches(0x450, 0x40, 0x90));
+        assert!(!tracer.matches(0x450, 0x0, 0x90));
+    }
+
+    #[test]
+    fn should_read_binary_trace() {
+        let mut sink = BinarySink::new(Vec::new()).unwrap();
+        sink.record(&create_record(true)).unwrap();
+        sink.record(&create_record(false)).unwrap();
+        let trace = sink.into_inner();
+
+        let records = BinaryTraceReader::new(trace.as_slice())
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(vec![create_record(true), create_record(false)], records);
+    }
+
+    #[test]
+    fn should_reject_invalid_binary_trace() {
+        assert!(BinaryTraceReader::new(&b"FIRNTRC\x00"[..]).is_err());
+
+        let mut trace = BINARY_MAGIC.to_vec();
+        trace.extend([0x1, 0x0, 0x0]);
+        let mut reader = BinaryTraceReader::new(trace.as_slice()).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn should_convert_binary_trace_to_text() {
+        let mut sink = BinarySink::new(Vec::new()).unwrap();
+        sink.record(&create_record(true)).unwrap();
+        sink.record(&create_record(false)).unwrap();
+        let trace = sink.into_inner();
+
+        let mut text = Vec::new();
+        convert_to_text(trace.as_slice(), &mut text).unwrap();
+
+        assert_eq!(
+            "        39 1234:0100 b8004c         mov ax, 0x4c00               AX=4c00 BX=0000 \
+             CX=0000 DX=0000 SI=0000 DI=0000 BP=0000 SP=fffe CS=1234 DS=0000 ES=0000 SS=0000 \
+             FL=f002\n        39 1234:0100 b8004c         mov ax, 0x4c00\n",
+            String::from_utf8(text).unwrap()
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Measures the overhead of devices in the hot path: stepping the system and handling ports with
+//! a number of devices added, none of which are due.
+//!
+//! Run with `cargo bench -p firn-core --bench devices`.
+
+use firn_core::cpu::Cpu;
+use firn_core::device::{Device, PortRequest, PortResponse};
+use firn_core::mem::MemMap;
+use firn_core::System;
+use std::hint::black_box;
+use std::time::Instant;
+
+const DEVICES: u16 = 16;
+const ITERATIONS: u32 = 2_000_000;
+
+/// A CPU that reads a port from the last device every step.
+struct PortCpu;
+
+impl Cpu for PortCpu {
+    fn step(sys: &mut System<Self>) {
+        black_box(sys.port_in_8(DEVICES - 1));
+    }
+}
+
+/// A CPU that does nothing, so that a step only measures the system.
+struct IdleCpu;
+
+impl Cpu for IdleCpu {
+    fn step(_sys: &mut System<Self>) {}
+}
+
+/// A device that handles a single port.
+struct PortDevice {
+    port: u16,
+}
+
+impl<C> Device<C> for PortDevice
+where
+    C: Cpu,
+{
+    fn handle_port(&mut self, _sys: &mut System<C>, request: PortRequest) -> Option<PortResponse> {
+        match request {
+            PortRequest::In8(port) if port == self.port => Some(PortResponse::In8(port as u8)),
+            _ => None,
+        }
+    }
+}
+
+fn create_sys<C>(cpu: C) -> System<C>
+where
+    C: Cpu,
+{
+    let mut sys = System::new(cpu, MemMap::new(0));
+    for port in 0..DEVICES {
+        sys.add_device(PortDevice { port });
+    }
+    sys.init();
+
+    sys
+}
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let nanos = start.elapsed().as_nanos() as f64 / f64::from(ITERATIONS);
+
+    println!("{:<24} {:>8.1} ns", name, nanos);
+}
+
+fn main() {
+    let mut sys = create_sys(IdleCpu);
+    bench("step", || sys.step());
+
+    let mut sys = create_sys(IdleCpu);
+    bench("port_in_8", || {
+        black_box(sys.port_in_8(black_box(DEVICES - 1)));
+    });
+
+    let mut sys = create_sys(PortCpu);
+    bench("step with port_in_8", || sys.step());
+}
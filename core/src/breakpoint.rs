@@ -0,0 +1,784 @@
+//! Execution breakpoints, which stop a running [`System`] before the instruction at their location
+//! is executed.
+//!
+//! Breakpoints are kept in [`System::breakpoints`] and checked before every step of the CPU by
+//! the run API ([`System::start`], [`System::resume`] and [`System::run_for`]), which returns the
+//! breakpoint that stopped it as a [`StopReason`].
+//!
+//! A breakpoint can have a [`Condition`], in which case it only stops the system if the
+//! condition is true:
+//!
+//! ```
+//! use firn_core::breakpoint::{Breakpoint, Condition, Location};
+//!
+//! let location = "f000:e05b".parse::<Location>().unwrap();
+//! let condition = "AX == 0x4c00 && INT 21h".parse::<Condition>().unwrap();
+//! let breakpoint = Breakpoint::conditional(location, condition);
+//! ```
+//!
+//! [`System`]: crate::System
+//! [`System::breakpoints`]: crate::System::breakpoints
+//! [`System::start`]: crate::System::start
+//! [`System::resume`]: crate::System::resume
+//! [`System::run_for`]: crate::System::run_for
+//! [`StopReason`]: crate::system::StopReason
+
+use crate::cpu::Cpu;
+use crate::mem::MemMap;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// A trait for CPUs that can be inspected by breakpoints.
+pub trait Inspect: Cpu {
+    /// The address of the next instruction.
+    fn pc(&self) -> Pc;
+
+    /// The value of a register, or `None` if the CPU doesn't have a register called `name`.
+    ///
+    /// Names are case-insensitive.
+    fn reg(&self, name: &str) -> Option<u64>;
+
+    /// Whether or not the next instruction is `mnemonic` with the given operands, like `INT` with
+    /// `[0x21]`. Mnemonics are case-insensitive.
+    ///
+    /// What the operands are compared with is up to the CPU, but usually they're the values of
+    /// the instruction's immediate operands.
+    fn is_next_instr(&self, mem: &MemMap, mnemonic: &str, operands: &[u64]) -> bool;
+}
+
+/// The address of an instruction.
+///
+/// CPUs without segmented addressing should use 0 as the segment and the linear address as the
+/// offset (truncated if it doesn't fit).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Pc {
+    pub segment: u16,
+    pub offset: u16,
+    pub linear: usize,
+}
+
+/// Where a breakpoint is.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Location {
+    /// An exact `segment:offset` address, which doesn't match other addresses with the same
+    /// linear address.
+    Segmented {
+        segment: u16,
+        offset: u16,
+    },
+    Linear(usize),
+}
+
+impl Location {
+    pub fn matches(&self, pc: Pc) -> bool {
+        match *self {
+            Location::Segmented { segment, offset } => pc.segment == segment && pc.offset == offset,
+            Location::Linear(linear) => pc.linear == linear,
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Segmented { segment, offset } => write!(f, "{:04x}:{:04x}", segment, offset),
+            Location::Linear(linear) => write!(f, "{:#07x}", linear),
+        }
+    }
+}
+
+impl FromStr for Location {
+    type Err = ParseError;
+
+    /// Parses a `segment:offset` or linear address, where every number is hexadecimal (with an
+    /// optional `0x` prefix).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse_hex(hex: &str, position: usize) -> Result<usize, ParseError> {
+            let digits = hex.strip_prefix("0x").unwrap_or(hex);
+            usize::from_str_radix(digits, 16)
+                .map_err(|_| ParseError::new(position, "invalid address"))
+        }
+
+        match s.split_once(':') {
+            Some((segment, offset)) => {
+                let offset = parse_hex(offset, segment.len() + 1)?;
+                let segment = parse_hex(segment, 0)?;
+                match (u16::try_from(segment), u16::try_from(offset)) {
+                    (Ok(segment), Ok(offset)) => Ok(Location::Segmented { segment, offset }),
+                    _ => Err(ParseError::new(0, "segment and offset must be 16 bits")),
+                }
+            }
+            None => Ok(Location::Linear(parse_hex(s, 0)?)),
+        }
+    }
+}
+
+/// An error from parsing a [`Location`] or [`Condition`].
+///
+/// [`Location`]: Location
+/// [`Condition`]: Condition
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    /// The byte offset in the source where the error was found.
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+impl ParseError {
+    fn new(position: usize, reason: &'static str) -> Self {
+        Self { position, reason }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at {})", self.reason, self.position)
+    }
+}
+
+impl Error for ParseError {}
+
+/// A condition over registers, memory and the next instruction.
+///
+/// Conditions are expressions where every value is an unsigned 64-bit integer and true is any
+/// non-zero value. They support:
+///
+/// - numbers, which are decimal unless they start with `0x` or end with `h` like `0x4c00` and
+///   `21h`
+/// - registers like `AX`, which are looked up with [`Inspect::reg`]
+/// - bytes and words of memory at a linear address, like `[0x41a]` (or `byte[0x41a]`) and
+///   `word[0x41a]`
+/// - `+`, `-`, `*`, `&`, `==`, `!=`, `<`, `<=`, `>`, `>=`, `!`, `&&`, `||` and parentheses
+/// - instructions like `INT 21h` or `HLT`, which are true if they're the next instruction (see
+///   [`Inspect::is_next_instr`])
+///
+/// A name that isn't a register is treated as an instruction without operands.
+///
+/// [`Inspect::reg`]: Inspect::reg
+/// [`Inspect::is_next_instr`]: Inspect::is_next_instr
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            index: 0,
+            end: source.len(),
+        };
+
+        let expr = parser.parse_or()?;
+        if parser.index < tokens.len() {
+            return Err(ParseError::new(parser.position(), "unexpected token"));
+        }
+
+        Ok(Self {
+            source: source.trim().to_string(),
+            expr,
+        })
+    }
+
+    pub fn evaluate(&self, cpu: &impl Inspect, mem: &MemMap) -> bool {
+        self.expr.evaluate(cpu, mem) != 0
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Expr {
+    Number(u64),
+    /// A register, or an instruction without operands if the CPU has no register with the name.
+    Name(String),
+    Instr(String, Vec<u64>),
+    Byte(Box<Expr>),
+    Word(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn evaluate(&self, cpu: &impl Inspect, mem: &MemMap) -> u64 {
+        let read = |address: u64| match usize::try_from(address) {
+            Ok(address) if address < mem.addressable => mem[address] as u64,
+            _ => 0,
+        };
+
+        match self {
+            Expr::Number(number) => *number,
+            Expr::Name(name) => match cpu.reg(name) {
+                Some(value) => value,
+                None => cpu.is_next_instr(mem, name, &[]) as u64,
+            },
+            Expr::Instr(mnemonic, operands) => cpu.is_next_instr(mem, mnemonic, operands) as u64,
+            Expr::Byte(address) => read(address.evaluate(cpu, mem)),
+            Expr::Word(address) => {
+                let address = address.evaluate(cpu, mem);
+                read(address) | read(address.wrapping_add(1)) << 8
+            }
+            Expr::Not(expr) => (expr.evaluate(cpu, mem) == 0) as u64,
+            Expr::Binary(BinaryOp::And, left, right) => {
+                (left.evaluate(cpu, mem) != 0 && right.evaluate(cpu, mem) != 0) as u64
+            }
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                (left.evaluate(cpu, mem) != 0 || right.evaluate(cpu, mem) != 0) as u64
+            }
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate(cpu, mem);
+                let right = right.evaluate(cpu, mem);
+                match op {
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Sub => left.wrapping_sub(right),
+                    BinaryOp::Mul => left.wrapping_mul(right),
+                    BinaryOp::BitAnd => left & right,
+                    BinaryOp::Eq => (left == right) as u64,
+                    BinaryOp::Ne => (left != right) as u64,
+                    BinaryOp::Lt => (left < right) as u64,
+                    BinaryOp::Le => (left <= right) as u64,
+                    BinaryOp::Gt => (left > right) as u64,
+                    BinaryOp::Ge => (left >= right) as u64,
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Token {
+    Number(u64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+/// The symbols that can appear in conditions, with longer symbols first so that `&&` isn't read
+/// as two `&`s.
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "+", "-", "*", "&", "(", ")", "[", "]", ",",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+
+    let mut position = 0;
+    while position < source.len() {
+        let rest = &source[position..];
+        let c = rest.chars().next().unwrap();
+
+        if c.is_whitespace() {
+            position += c.len_utf8();
+            continue;
+        }
+
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+
+            let token = if c.is_ascii_digit() {
+                Token::Number(
+                    parse_number(word).ok_or(ParseError::new(position, "invalid number"))?,
+                )
+            } else {
+                Token::Name(word.to_string())
+            };
+            tokens.push((position, token));
+            position += len;
+            continue;
+        }
+
+        match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            Some(symbol) => {
+                tokens.push((position, Token::Symbol(symbol)));
+                position += symbol.len();
+            }
+            None => return Err(ParseError::new(position, "unexpected character")),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(number: &str) -> Option<u64> {
+    if let Some(hex) = number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = number
+        .strip_suffix('h')
+        .or_else(|| number.strip_suffix('H'))
+    {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        number.parse().ok()
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    index: usize,
+    /// The length of the source, which is the position of errors at the end.
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.index).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map_or(self.end, |(position, _)| *position)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.peek();
+        self.index += 1;
+
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(next)) if *next == symbol) {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str, reason: &'static str) -> Result<(), ParseError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(ParseError::new(self.position(), reason))
+        }
+    }
+
+    /// Parses a chain of binary operators, where `ops` maps symbols to operators and `operand`
+    /// parses the operands (which have a higher precedence).
+    fn parse_binary(
+        &mut self,
+        ops: &[(&str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Expr, ParseError>,
+    ) -> Result<Expr, ParseError> {
+        let mut expr = operand(self)?;
+        'chain: loop {
+            for (symbol, op) in ops {
+                if self.eat(symbol) {
+                    expr = Expr::Binary(*op, Box::new(expr), Box::new(operand(self)?));
+                    continue 'chain;
+                }
+            }
+
+            return Ok(expr);
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(&[("||", BinaryOp::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(&[("&&", BinaryOp::And)], Self::parse_comparison)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(
+            &[
+                ("==", BinaryOp::Eq),
+                ("!=", BinaryOp::Ne),
+                ("<=", BinaryOp::Le),
+                (">=", BinaryOp::Ge),
+                ("<", BinaryOp::Lt),
+                (">", BinaryOp::Gt),
+            ],
+            Self::parse_sum,
+        )
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(
+            &[
+                ("+", BinaryOp::Add),
+                ("-", BinaryOp::Sub),
+                ("&", BinaryOp::BitAnd),
+            ],
+            Self::parse_product,
+        )
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(&[("*", BinaryOp::Mul)], Self::parse_unary)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+
+        let position = self.position();
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(*number)),
+            Some(Token::Symbol("(")) => {
+                let expr = self.parse_or()?;
+                self.expect(")", "expected `)`")?;
+                Ok(expr)
+            }
+            Some(Token::Symbol("[")) => self.parse_mem(false),
+            Some(Token::Name(name)) => {
+                let size = name.to_ascii_lowercase();
+                if (size == "byte" || size == "word") && self.eat("[") {
+                    return self.parse_mem(size == "word");
+                }
+
+                match self.peek() {
+                    Some(Token::Number(_)) => self.parse_instr(name),
+                    _ => Ok(Expr::Name(name.clone())),
+                }
+            }
+            _ => Err(ParseError::new(position, "expected a value")),
+        }
+    }
+
+    /// Parses a memory access after its `[`.
+    fn parse_mem(&mut self, word: bool) -> Result<Expr, ParseError> {
+        let address = Box::new(self.parse_or()?);
+        self.expect("]", "expected `]`")?;
+
+        Ok(if word {
+            Expr::Word(address)
+        } else {
+            Expr::Byte(address)
+        })
+    }
+
+    /// Parses the operands of an instruction, which are numbers separated by commas.
+    fn parse_instr(&mut self, mnemonic: &str) -> Result<Expr, ParseError> {
+        let mut operands = Vec::new();
+        loop {
+            let position = self.position();
+            match self.next() {
+                Some(Token::Number(number)) => operands.push(*number),
+                _ => return Err(ParseError::new(position, "expected an operand")),
+            }
+
+            if !self.eat(",") {
+                return Ok(Expr::Instr(mnemonic.to_string(), operands));
+            }
+        }
+    }
+}
+
+pub type BreakpointId = usize;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Breakpoint {
+    pub location: Location,
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+
+    /// How many times the breakpoint has been hit, which is every time the CPU reaches it (while
+    /// it's enabled) and its condition is true, including ignored hits.
+    pub hits: u64,
+    /// How many hits to ignore before the breakpoint stops the system.
+    pub ignore_count: u64,
+}
+
+impl Breakpoint {
+    pub fn new(location: Location) -> Self {
+        Self {
+            location,
+            condition: None,
+            enabled: true,
+
+            hits: 0,
+            ignore_count: 0,
+        }
+    }
+
+    pub fn conditional(location: Location, condition: Condition) -> Self {
+        Self {
+            condition: Some(condition),
+            ..Self::new(location)
+        }
+    }
+}
+
+impl Display for Breakpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.location)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        if !self.enabled {
+            f.write_str(" (disabled)")?;
+        }
+
+        write!(f, ", hit {} times", self.hits)
+    }
+}
+
+/// A registry of breakpoints, which are given increasing IDs as they're added.
+#[derive(Default)]
+pub struct Breakpoints {
+    breakpoints: BTreeMap<BreakpointId, Breakpoint>,
+    next_id: BreakpointId,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+
+        id
+    }
+
+    pub fn remove(&mut self, id: BreakpointId) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn get(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.breakpoints.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: BreakpointId) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// Iterates over the breakpoints in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    /// Checks whether the CPU is at a breakpoint, counting a hit for every enabled breakpoint at
+    /// the next instruction whose condition is true.
+    ///
+    /// Returns the first breakpoint that should stop the system, if there is one.
+    pub fn check(&mut self, cpu: &impl Inspect, mem: &MemMap) -> Option<BreakpointId> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+
+        let pc = cpu.pc();
+        let mut stop = None;
+        for (id, breakpoint) in self.breakpoints.iter_mut() {
+            if !breakpoint.enabled || !breakpoint.location.matches(pc) {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                if !condition.evaluate(cpu, mem) {
+                    continue;
+                }
+            }
+
+            breakpoint.hits += 1;
+            if breakpoint.hits > breakpoint.ignore_count && stop.is_none() {
+                stop = Some(*id);
+            }
+        }
+
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::BasicMem;
+    use crate::system::StopReason;
+    use crate::System;
+
+    struct TestCpu {
+        ip: u16,
+        ax: u16,
+    }
+
+    impl Cpu for TestCpu {
+        fn step(sys: &mut System<Self>) {
+            sys.cpu.ip = sys.cpu.ip.wrapping_add(1);
+        }
+    }
+
+    impl Inspect for TestCpu {
+        fn pc(&self) -> Pc {
+            Pc {
+                segment: 0,
+                offset: self.ip,
+                linear: self.ip as usize,
+            }
+        }
+
+        fn reg(&self, name: &str) -> Option<u64> {
+            match name.to_ascii_lowercase().as_str() {
+                "ax" => Some(self.ax as u64),
+                "ip" => Some(self.ip as u64),
+                _ => None,
+            }
+        }
+
+        fn is_next_instr(&self, mem: &MemMap, mnemonic: &str, operands: &[u64]) -> bool {
+            // Every byte is an `INT` with itself as the operand
+            mnemonic.eq_ignore_ascii_case("int") && operands == [mem[self.ip as usize] as u64]
+        }
+    }
+
+    fn create_mem() -> MemMap {
+        let mut mem = BasicMem::new(0x100);
+        mem[0x10] = 0x21;
+        mem[0x11] = 0x4c;
+        let mut map = MemMap::new(0x100);
+        map.map_full(mem);
+
+        map
+    }
+
+    fn evaluate(condition: &str, cpu: &TestCpu) -> bool {
+        Condition::parse(condition)
+            .unwrap()
+            .evaluate(cpu, &create_mem())
+    }
+
+    #[test]
+    fn should_parse_locations() {
+        assert_eq!(
+            Ok(Location::Segmented {
+                segment: 0xf000,
+                offset: 0xe05b
+            }),
+            "f000:e05b".parse()
+        );
+        assert_eq!(Ok(Location::Linear(0x7c00)), "0x7c00".parse());
+        assert!("10000:0".parse::<Location>().is_err());
+        assert!("xyz".parse::<Location>().is_err());
+    }
+
+    #[test]
+    fn should_evaluate_conditions() {
+        let cpu = TestCpu {
+            ip: 0x10,
+            ax: 0x4c00,
+        };
+
+        assert!(evaluate("AX == 0x4c00 && INT 21h", &cpu));
+        assert!(!evaluate("AX == 0x4c00 && INT 20h", &cpu));
+        assert!(evaluate("ax != 19456 || ip == 16", &cpu));
+        assert!(evaluate("word[ip] == 0x4c21 && [ip + 1] == 0x4c", &cpu));
+        assert!(evaluate("!(ax & 0xff) && 2 * 3 + 1 == 7", &cpu));
+        assert!(evaluate("byte[0x1000] == 0", &cpu));
+    }
+
+    #[test]
+    fn should_reject_invalid_conditions() {
+        assert_eq!(
+            Err(ParseError::new(5, "expected a value")),
+            Condition::parse("AX ==")
+        );
+        assert_eq!(
+            Err(ParseError::new(2, "invalid number")),
+            Condition::parse("1 2x")
+        );
+        assert!(Condition::parse("(AX == 1").is_err());
+        assert!(Condition::parse("AX $ 1").is_err());
+        assert!(Condition::parse("INT 21h,").is_err());
+    }
+
+    #[test]
+    fn should_count_hits() {
+        let mem = create_mem();
+        let mut cpu = TestCpu { ip: 0x10, ax: 0 };
+        let mut breakpoints = Breakpoints::new();
+
+        let mut ignored = Breakpoint::new(Location::Linear(0x10));
+        ignored.ignore_count = 1;
+        let ignored = breakpoints.add(ignored);
+        let conditional = breakpoints.add(Breakpoint::conditional(
+            Location::Segmented {
+                segment: 0,
+                offset: 0x10,
+            },
+            Condition::parse("AX == 1").unwrap(),
+        ));
+
+        assert_eq!(None, breakpoints.check(&cpu, &mem));
+        assert_eq!(Some(ignored), breakpoints.check(&cpu, &mem));
+
+        cpu.ax = 1;
+        breakpoints.get_mut(ignored).unwrap().enabled = false;
+        assert_eq!(Some(conditional), breakpoints.check(&cpu, &mem));
+
+        cpu.ip = 0x11;
+        assert_eq!(None, breakpoints.check(&cpu, &mem));
+        assert_eq!(2, breakpoints.get(ignored).unwrap().hits);
+        assert_eq!(1, breakpoints.get(conditional).unwrap().hits);
+    }
+
+    #[test]
+    fn should_stop_at_breakpoints() {
+        let mut sys = System::new(TestCpu { ip: 0, ax: 0 }, create_mem());
+        let id = sys.breakpoints.add(Breakpoint::new(Location::Linear(0x10)));
+
+        assert_eq!(StopReason::Breakpoint(id), sys.start());
+        assert_eq!(0x10, sys.cpu.ip);
+
+        assert_eq!(StopReason::StepLimit, sys.run_for(3));
+        assert_eq!(0x13, sys.cpu.ip);
+
+        sys.cpu.ip = 0xf;
+        assert_eq!(StopReason::Breakpoint(id), sys.run_for(3));
+        assert_eq!(StopReason::StepLimit, sys.run_for(0));
+        assert_eq!(2, sys.breakpoints.get(id).unwrap().hits);
+    }
+}
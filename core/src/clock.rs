@@ -0,0 +1,523 @@
+//! Virtual time, which is derived from the cycles that the CPU has executed instead of the host's
+//! clock.
+//!
+//! Every [`System`] has a [`Clock`] that counts the cycles executed by the CPU. Devices that need
+//! to do something at a certain time should schedule a timer (see [`System::set_timer`]) or a
+//! deadline (see [`System::wake_at`]) instead of checking the host's clock, so that they run at the
+//! speed of the emulated system no matter how fast the host is, and run the same way every time.
+//!
+//! Timers and deadlines share a single queue that's ordered by the cycle count that they expire
+//! at, and only the ones that have expired are looked at after each step of the CPU, so devices
+//! cost nothing while they're waiting.
+//!
+//! By default, virtual time isn't related to real time at all and the system runs as fast as
+//! possible, which is what tests want. Hosts that run real software usually want
+//! [`Pacing::RealTime`] instead (see [`Clock::set_pacing`]).
+//!
+//! [`System`]: crate::System
+//! [`Clock`]: Clock
+//! [`System::set_timer`]: crate::System::set_timer
+//! [`System::wake_at`]: crate::System::wake_at
+//! [`Pacing::RealTime`]: Pacing::RealTime
+//! [`Clock::set_pacing`]: Clock::set_pacing
+
+use crate::cpu::Cpu;
+use crate::mem::MemRange;
+use crate::System;
+use std::collections::{BTreeMap, HashMap};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often (in virtual time) the clock is compared to the host's clock when it's paced.
+const PACE_INTERVAL: Duration = Duration::from_millis(1);
+/// How far the clock can fall behind the host's clock before it gives up on catching up.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// How virtual time relates to real time.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Pacing {
+    /// The system runs as fast as possible.
+    Virtual,
+    /// The system is slowed down so that virtual time passes at the same speed as real time.
+    RealTime,
+    /// Like `RealTime`, but virtual time passes the given number of times faster.
+    FastForward(u32),
+}
+
+/// The virtual clock of a system.
+pub struct Clock {
+    /// The number of cycles per second of virtual time.
+    frequency: u64,
+    cycles: u64,
+
+    pacing: Pacing,
+    /// The host's time and the cycle count that pacing is measured from.
+    anchor: Option<(Instant, u64)>,
+    /// The cycle count when the clock should be paced next.
+    next_pace: u64,
+}
+
+impl Clock {
+    /// Creates a clock at time zero which runs at `frequency` cycles per second.
+    pub fn new(frequency: u64) -> Self {
+        assert!(frequency > 0, "the clock frequency must be at least 1 Hz");
+
+        Self {
+            frequency,
+            cycles: 0,
+
+            pacing: Pacing::Virtual,
+            anchor: None,
+            next_pace: 0,
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// The number of cycles that have been executed.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Advances the clock by a number of cycles.
+    ///
+    /// CPUs should call this in [`Cpu::step`] with the number of cycles that the step took. If they
+    /// don't, the step is counted as one cycle.
+    ///
+    /// [`Cpu::step`]: crate::cpu::Cpu::step
+    pub fn advance(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    /// The virtual time that has passed since the system was created.
+    pub fn now(&self) -> Duration {
+        self.time_at(self.cycles)
+    }
+
+    /// The virtual time at a cycle count.
+    pub fn time_at(&self, cycles: u64) -> Duration {
+        let nanos = cycles as u128 * 1_000_000_000 / self.frequency as u128;
+
+        Duration::from_nanos(nanos as u64)
+    }
+
+    /// The first cycle count at or after a virtual time.
+    pub fn cycles_at(&self, time: Duration) -> u64 {
+        (time.as_nanos() * self.frequency as u128).div_ceil(1_000_000_000) as u64
+    }
+
+    pub fn pacing(&self) -> Pacing {
+        self.pacing
+    }
+
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+        self.anchor = None;
+    }
+
+    /// Makes pacing start over from the current time, so that the system doesn't try to catch up
+    /// on the time that it was stopped for.
+    pub(crate) fn reanchor(&mut self) {
+        self.anchor = None;
+    }
+
+    pub(crate) fn set_cycles(&mut self, cycles: u64) {
+        self.cycles = cycles;
+        self.anchor = None;
+    }
+
+    /// Sleeps until the host's clock catches up with virtual time, if the clock is paced.
+    pub(crate) fn pace(&mut self) {
+        let factor = match self.pacing {
+            Pacing::Virtual => return,
+            Pacing::RealTime => 1,
+            Pacing::FastForward(factor) => factor.max(1),
+        };
+        if self.cycles < self.next_pace {
+            return;
+        }
+        self.next_pace = self.cycles + self.cycles_at(PACE_INTERVAL);
+
+        let (start, start_cycles) = *self
+            .anchor
+            .get_or_insert_with(|| (Instant::now(), self.cycles));
+        let virtual_elapsed = self.time_at(self.cycles - start_cycles) / factor;
+        let real_elapsed = start.elapsed();
+
+        if virtual_elapsed > real_elapsed {
+            thread::sleep(virtual_elapsed - real_elapsed);
+        } else if real_elapsed - virtual_elapsed > MAX_LAG {
+            // The host is too slow, so running faster to catch up would only make things jumpy
+            self.anchor = None;
+        }
+    }
+}
+
+pub type TimerId = u64;
+
+type Callback<C> = Box<dyn FnOnce(&mut System<C>) + Send>;
+
+enum Timer<C>
+where
+    C: Cpu,
+{
+    Callback(Callback<C>),
+    /// Calls [`Device::timer`] on the device with the index and the token.
+    ///
+    /// [`Device::timer`]: crate::device::Device::timer
+    Device(usize, u64),
+    /// Steps the device with the index.
+    Wake(usize),
+}
+
+/// The timers of a system, ordered by the cycle count that they expire at.
+pub(crate) struct Timers<C>
+where
+    C: Cpu,
+{
+    next_id: TimerId,
+    queue: BTreeMap<(u64, TimerId), Timer<C>>,
+    /// The cycle count that every queued timer expires at.
+    expiries: HashMap<TimerId, u64>,
+    /// The deadline timer of every device that has one, by device index.
+    deadlines: HashMap<usize, TimerId>,
+}
+
+impl<C> Timers<C>
+where
+    C: Cpu,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            next_id: 0,
+            queue: BTreeMap::new(),
+            expiries: HashMap::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, cycles: u64, timer: Timer<C>) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.insert((cycles, id), timer);
+        self.expiries.insert(id, cycles);
+
+        id
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Timer<C>> {
+        let cycles = self.expiries.remove(&id)?;
+        let timer = self.queue.remove(&(cycles, id));
+        if let Some(Timer::Wake(device)) = &timer {
+            self.deadlines.remove(device);
+        }
+
+        timer
+    }
+
+    /// The cycle count that the next timer expires at, if there are any.
+    pub(crate) fn next_expiry(&self) -> Option<u64> {
+        self.queue.keys().next().map(|(cycles, _)| *cycles)
+    }
+
+    /// Removes the deadlines that expire at or before `cycles` and were set before the timer with
+    /// the ID `before`, returning the devices they belong to.
+    fn take_expired_deadlines(&mut self, cycles: u64, before: TimerId) -> Vec<usize> {
+        let expired: Vec<_> = self
+            .queue
+            .range(..=(cycles, TimerId::MAX))
+            .filter(|((_, id), timer)| *id < before && matches!(timer, Timer::Wake(_)))
+            .map(|((_, id), _)| *id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|id| match self.remove(id) {
+                Some(Timer::Wake(device)) => Some(device),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The oldest timer that expires at or before `cycles` and was queued before the timer with
+    /// the ID `before`.
+    fn next_expired(&self, cycles: u64, before: TimerId) -> Option<TimerId> {
+        self.queue
+            .range(..=(cycles, TimerId::MAX))
+            .map(|((_, id), _)| *id)
+            .find(|id| *id < before)
+    }
+}
+
+impl<C> System<C>
+where
+    C: Cpu,
+{
+    /// Calls `callback` once virtual time reaches `at`, after the CPU step that reaches it.
+    pub fn schedule(
+        &mut self,
+        at: Duration,
+        callback: impl FnOnce(&mut System<C>) + Send + 'static,
+    ) -> TimerId {
+        let cycles = self.timer_cycles(at);
+
+        self.timers.add(cycles, Timer::Callback(Box::new(callback)))
+    }
+
+    /// Calls [`Device::timer`] with `token` on the calling device once virtual time reaches `at`,
+    /// after the CPU step that reaches it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this isn't called by a device (from one of the methods of [`Device`]).
+    ///
+    /// [`Device::timer`]: crate::device::Device::timer
+    /// [`Device`]: crate::device::Device
+    pub fn set_timer(&mut self, at: Duration, token: u64) -> TimerId {
+        let device = self.calling_device("timers");
+        let cycles = self.timer_cycles(at);
+
+        self.timers.add(cycles, Timer::Device(device, token))
+    }
+
+    /// Cancels a timer, returning `false` if it already expired or was cancelled.
+    pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+        self.timers.remove(id).is_some()
+    }
+
+    /// Sets the deadline of the calling device, which means it's stepped (see [`Device::step`])
+    /// once virtual time reaches `at`, after the CPU step that reaches it. This replaces the
+    /// device's previous deadline.
+    ///
+    /// Devices are only stepped when their deadline is reached or when memory that they watch is
+    /// written to (see [`wake_on_write`]). Their port handlers are called whenever the CPU accesses
+    /// a port, whether or not they have a deadline.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this isn't called by a device (from one of the methods of [`Device`]).
+    ///
+    /// [`Device::step`]: crate::device::Device::step
+    /// [`wake_on_write`]: System::wake_on_write
+    /// [`Device`]: crate::device::Device
+    pub fn wake_at(&mut self, at: Duration) {
+        let device = self.calling_device("deadlines");
+        let cycles = self.timer_cycles(at);
+
+        self.clear_deadline(device);
+        let id = self.timers.add(cycles, Timer::Wake(device));
+        self.timers.deadlines.insert(device, id);
+    }
+
+    /// Removes the deadline of the calling device, if it has one (see [`wake_at`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if this isn't called by a device (from one of the methods of [`Device`]).
+    ///
+    /// [`wake_at`]: System::wake_at
+    /// [`Device`]: crate::device::Device
+    pub fn sleep(&mut self) {
+        let device = self.calling_device("deadlines");
+
+        self.clear_deadline(device);
+    }
+
+    /// Steps the calling device (see [`Device::step`]) after every CPU step that writes to memory
+    /// in `range`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this isn't called by a device (from one of the methods of [`Device`]).
+    ///
+    /// [`Device::step`]: crate::device::Device::step
+    /// [`Device`]: crate::device::Device
+    pub fn wake_on_write(&mut self, range: MemRange) {
+        let device = self.calling_device("memory watches");
+
+        self.mem.watch_writes(range, device);
+    }
+
+    /// The cycle count that a timer for `at` expires at.
+    ///
+    /// Timers that are already due expire after the next step (or the step that's running), which
+    /// is when the clock first passes the cycle count they were set at. This still holds after the
+    /// system is rewound to before they were set (see [`rewind`]).
+    ///
+    /// [`rewind`]: crate::rewind
+    fn timer_cycles(&self, at: Duration) -> u64 {
+        self.clock.cycles_at(at).max(self.clock.cycles() + 1)
+    }
+
+    fn calling_device(&self, what: &str) -> usize {
+        match self.current_device {
+            Some(device) => device,
+            None => panic!("{} can only be set by devices", what),
+        }
+    }
+
+    fn clear_deadline(&mut self, device: usize) {
+        if let Some(id) = self.timers.deadlines.remove(&device) {
+            self.timers.remove(id);
+        }
+    }
+
+    /// Steps the devices whose deadlines expired or whose watched memory was written to in
+    /// dependency order, and then calls every timer that has expired in the order they expire.
+    ///
+    /// Timers (and deadlines) that are set while this is running don't expire until the next call,
+    /// even if they're already due, so that devices can't keep the system from making progress.
+    pub(crate) fn expire_timers(&mut self) {
+        let before = self.timers.next_id;
+
+        let mut woken = self.mem.take_touched();
+        woken.extend(
+            self.timers
+                .take_expired_deadlines(self.clock.cycles(), before),
+        );
+        woken.sort_by_key(|device| self.device_rank(*device));
+        woken.dedup();
+        for device in woken {
+            self.call_device(device, |device, sys| device.step(sys));
+        }
+
+        while let Some(id) = self.timers.next_expired(self.clock.cycles(), before) {
+            match self.timers.remove(id).unwrap() {
+                Timer::Callback(callback) => callback(self),
+                Timer::Device(device, token) => {
+                    self.call_device(device, |device, sys| device.timer(sys, token));
+                }
+                Timer::Wake(device) => {
+                    self.call_device(device, |device, sys| device.step(sys));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::mem::{BasicMem, MemMap};
+    use std::sync::{Arc, Mutex};
+
+    /// A CPU that takes three cycles every step.
+    struct TestCpu;
+
+    impl Cpu for TestCpu {
+        fn frequency(&self) -> u64 {
+            1000
+        }
+
+        fn step(sys: &mut System<Self>) {
+            sys.clock.advance(3);
+        }
+    }
+
+    /// A device that counts its timers and sets a new one every 10 milliseconds.
+    #[derive(Default)]
+    struct Ticker {
+        ticks: Vec<(u64, u64)>,
+    }
+
+    impl Device<TestCpu> for Ticker {
+        fn init(&mut self, sys: &mut System<TestCpu>) {
+            sys.set_timer(Duration::from_millis(10), 0);
+        }
+
+        fn timer(&mut self, sys: &mut System<TestCpu>, token: u64) {
+            self.ticks.push((token, sys.clock.cycles()));
+            sys.set_timer(sys.clock.now() + Duration::from_millis(10), token + 1);
+        }
+    }
+
+    /// A device that keeps the cycle counts it's stepped at, which wakes up 5 milliseconds after
+    /// it's initialized and whenever the first 16 bytes of memory are written to.
+    #[derive(Default)]
+    struct Sleeper {
+        steps: Vec<u64>,
+    }
+
+    impl Device<TestCpu> for Sleeper {
+        fn init(&mut self, sys: &mut System<TestCpu>) {
+            sys.wake_at(Duration::from_millis(20));
+            sys.wake_at(Duration::from_millis(5));
+            sys.wake_on_write(MemRange::new(0x00, 0x0f));
+        }
+
+        fn step(&mut self, sys: &mut System<TestCpu>) {
+            self.steps.push(sys.clock.cycles());
+        }
+    }
+
+    #[test]
+    fn should_convert_between_cycles_and_time() {
+        let mut clock = Clock::new(3);
+        clock.advance(4);
+
+        assert_eq!(Duration::from_nanos(1_333_333_333), clock.now());
+        assert_eq!(4, clock.cycles_at(Duration::from_nanos(1_333_333_333)));
+        assert_eq!(3, clock.cycles_at(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn should_expire_timers_in_virtual_time() {
+        let mut sys = System::new(TestCpu, MemMap::new(0));
+        let ticker = sys.add_device(Ticker::default());
+        sys.init();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for millis in [15, 5] {
+            let order = Arc::clone(&order);
+            sys.schedule(Duration::from_millis(millis), move |sys| {
+                order.lock().unwrap().push(sys.clock.cycles());
+            });
+        }
+        let cancelled = sys.schedule(Duration::from_millis(6), |_| panic!("timer was cancelled"));
+        assert!(sys.cancel_timer(cancelled));
+        assert!(!sys.cancel_timer(cancelled));
+
+        for _ in 0..8 {
+            sys.step();
+        }
+
+        assert_eq!(vec![6, 15], *order.lock().unwrap());
+        assert_eq!(vec![(0, 12), (1, 24)], sys.get_device(ticker).ticks);
+    }
+
+    #[test]
+    fn should_only_wake_devices_when_due() {
+        let mut map = MemMap::new(0x20);
+        map.map_full(BasicMem::new(0x20));
+        let mut sys = System::new(TestCpu, map);
+        let sleeper = sys.add_device(Sleeper::default());
+        sys.init();
+
+        for step in 0..8 {
+            match step {
+                3 => sys.mem[0x04] = 1,
+                5 => sys.mem[0x10] = 1,
+                _ => {}
+            }
+            sys.step();
+        }
+
+        assert_eq!(vec![6, 12], sys.get_device(sleeper).steps);
+    }
+
+    #[test]
+    fn should_pace_to_real_time() {
+        let mut sys = System::new(TestCpu, MemMap::new(0));
+        sys.clock.set_pacing(Pacing::FastForward(2));
+
+        let start = Instant::now();
+        while sys.clock.now() < Duration::from_millis(100) {
+            sys.step();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(45));
+    }
+}
@@ -11,7 +11,7 @@ use crate::System;
 ///
 /// [`System`]: crate::System
 /// [`Device`]: crate::device::Device
-pub trait Cpu: Sized {
+pub trait Cpu: Sized + 'static {
     /// Initializes the CPU.
     ///
     /// This is called in [`System::init`] (and therefore [`System::run`]) after all [`Device`]s are
@@ -28,24 +28,38 @@ pub trait Cpu: Sized {
 
     /// Resets the CPU.
     ///
-    /// This is called in [`System::start`] (and therefore [`System::run`]) right before the
-    /// execution loop begins. The CPU will always be initialized before it's reset. For tasks that
-    /// need to be run only once, implement [`init`] instead.
+    /// This is called in [`System::reset`] (and therefore [`System::start`] and [`System::run`])
+    /// after all [`Device`]s are reset. The CPU will always be initialized before it's reset. For
+    /// tasks that need to be run only once, implement [`init`] instead.
     ///
     /// If this method isn't implemented, the `Cpu` will do nothing when it's reset.
     ///
+    /// [`System::reset`]: crate::System::reset
     /// [`System::start`]: crate::System::start
     /// [`System::run`]: crate::System::run
+    /// [`Device`]: crate::device::Device
     /// [`init`]: Cpu::init
     fn reset(&mut self) {}
 
+    /// The number of cycles that the CPU executes per second, which determines how fast virtual
+    /// time passes (see [`Clock`]).
+    ///
+    /// If this method isn't implemented, the CPU runs at 1 MHz.
+    ///
+    /// [`Clock`]: crate::clock::Clock
+    fn frequency(&self) -> u64 {
+        1_000_000
+    }
+
     /// Executes the next iteration of the CPU.
     ///
     /// This is called constantly while the [`System`] is running, after all [`Device`]s are
-    /// stepped. You probably want to decode and execute a single instruction in this method.
+    /// stepped. You probably want to decode and execute a single instruction in this method, and
+    /// then advance the [`Clock`] by the number of cycles it took.
     ///
     /// [`System`]: crate::System
     /// [`Device`]: crate::device::Device
+    /// [`Clock`]: crate::clock::Clock
     fn step(sys: &mut System<Self>);
 }
 
@@ -1,6 +1,40 @@
 use crate::cpu::Cpu;
+use crate::mem::MemRange;
 use crate::System;
-use std::sync::{Arc, Mutex};
+use std::any::{Any, TypeId};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt::{Debug, Display, Formatter};
+use std::marker::PhantomData;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// The index of a device in a [`System`], which is the order it was added in.
+///
+/// [`System`]: crate::System
+pub type DeviceId = usize;
+
+/// The ports and memory that a device handles, which is used by introspection tools (see
+/// [`Device::claims`]).
+///
+/// [`Device::claims`]: Device::claims
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Claims {
+    pub ports: Vec<RangeInclusive<u16>>,
+    pub mem: Vec<MemRange>,
+}
+
+/// A description of a device in a system (see [`System::device_infos`]).
+///
+/// [`System::device_infos`]: crate::System::device_infos
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub name: String,
+    pub claims: Claims,
+}
 
 /// A port request that devices can choose to handle.
 ///
@@ -8,7 +42,7 @@ use std::sync::{Arc, Mutex};
 /// handling, see there.
 ///
 /// [`Device::handle_port`]: Device::handle_port
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum PortRequest {
     /// An input port request which requests an 8-bit value: `In8(port)`.
     In8(u16),
@@ -20,6 +54,36 @@ pub enum PortRequest {
     Out16(u16, u16),
 }
 
+impl PortRequest {
+    pub fn port(self) -> u16 {
+        match self {
+            PortRequest::In8(port)
+            | PortRequest::In16(port)
+            | PortRequest::Out8(port, _)
+            | PortRequest::Out16(port, _) => port,
+        }
+    }
+
+    pub fn is_read(self) -> bool {
+        matches!(self, PortRequest::In8(_) | PortRequest::In16(_))
+    }
+}
+
+impl Display for PortRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            PortRequest::In8(port) => write!(f, "8-bit read from port {port:#06x}"),
+            PortRequest::In16(port) => write!(f, "16-bit read from port {port:#06x}"),
+            PortRequest::Out8(port, value) => {
+                write!(f, "8-bit write of {value:#04x} to port {port:#06x}")
+            }
+            PortRequest::Out16(port, value) => {
+                write!(f, "16-bit write of {value:#06x} to port {port:#06x}")
+            }
+        }
+    }
+}
+
 /// A port response that's returned when a device chooses to handle a port.
 ///
 /// A `PortResponse` responds to a [`PortRequest`] which is sent to devices in
@@ -43,16 +107,52 @@ pub enum PortResponse {
     Out,
 }
 
+/// What happens when the CPU accesses a port that no device handles (see
+/// [`System::unhandled_port`]).
+///
+/// Reads get all ones (0xFF or 0xFFFF) and writes are ignored unless the policy panics, which is
+/// what an open bus does on a PC. This is how software detects that hardware is absent.
+///
+/// [`System::unhandled_port`]: crate::System::unhandled_port
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum UnhandledPortPolicy {
+    /// Accesses act like an open bus without anything else happening.
+    #[default]
+    OpenBus,
+    /// Accesses act like an open bus and are logged to stderr.
+    Log,
+    /// Accesses act like an open bus, and the run API stops after the step that made them (see
+    /// [`StopReason::UnhandledPort`]).
+    ///
+    /// [`StopReason::UnhandledPort`]: crate::system::StopReason::UnhandledPort
+    Break,
+    /// Accesses panic, which is useful for tests that should only touch emulated hardware.
+    Panic,
+}
+
+/// The accesses to a port that no device handled (see [`System::unhandled_ports`]).
+///
+/// [`System::unhandled_ports`]: crate::System::unhandled_ports
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UnhandledPort {
+    pub reads: u64,
+    pub writes: u64,
+    /// The step that the port was first accessed in.
+    pub first_step: u64,
+}
+
 /// A (technically) optional device that connects to a system.
 ///
 /// A "device" here simply refers to any component of the system besides the motherboard (see
 /// [`System`]), CPU (see [`Cpu`]), or memory (see [`Mem`] and [`MemMap`]). Everything besides those
 /// three components is considered "optional" and should be represented as a `Device`.
 ///
-/// Devices can access other devices by creating a constructor that takes in an
-/// `Arc<Mutex<OtherDevice>>` as a parameter, which a user can obtain from [`System::add_device`].
-/// These parameters should come before any other parameters in a device constructor for consistency
-/// with other devices.
+/// Devices are owned by the [`System`] they're added to. They can access other devices through the
+/// system they're given, by creating a constructor that takes in a `DeviceRef<OtherDevice>` as a
+/// parameter, which a user can obtain from [`System::add_device`]. These parameters should come
+/// before any other parameters in a device constructor for consistency with other devices. A device
+/// can't access itself or any device whose method is calling it (like a device that's writing to a
+/// port it handles), since those devices are busy.
 ///
 /// You must implement `Device<C>` for every `Cpu` that your device can be used with, where `C` is
 /// the `Cpu` type. If a real-world device only supports a couple of CPUs, you should probably only
@@ -64,45 +164,78 @@ pub enum PortResponse {
 /// [`Mem`]: crate::mem::Mem
 /// [`MemMap`]: crate::mem::MemMap
 /// [`System::add_device`]: crate::System::add_device
-pub trait Device<C>: Send + Sync
+pub trait Device<C>: Any + Send
 where
     C: Cpu,
 {
     /// Initializes the device.
     ///
-    /// This is called in [`System::init`] (and therefore [`System::run`]) before the [`Cpu`] is
-    /// initialized. Devices are initialized in an undefined order so you should not rely on any
-    /// other devices being initialized when initializing your own device. In the future, there will
-    /// be a way for devices to have more control over when they're initialized in relation to other
-    /// devices.
+    /// This is called once in [`System::init`] (and therefore [`System::run`]) before the [`Cpu`]
+    /// is initialized. Devices are initialized in dependency order (see
+    /// [`System::add_dependency`]), so the devices that your device depends on are initialized
+    /// before it, but you should not rely on any other devices being initialized.
     ///
     /// If this method isn't implemented, the `Device` will do nothing during initialization.
     ///
     /// [`System::init`]: crate::System::init
     /// [`System::run`]: crate::System::run
     /// [`Cpu`]: crate::cpu::Cpu
+    /// [`System::add_dependency`]: crate::System::add_dependency
     fn init(&mut self, sys: &mut System<C>) {
         let _ = sys;
     }
 
-    /// Executes the next iteration of the device.
+    /// Resets the device, like when the machine's reset line is asserted.
     ///
-    /// This is called constantly while the [`System`] is running. All devices will be stepped
-    /// before the [`Cpu`] is stepped. Devices are stepped in an undefined order. In the future,
-    /// there will be a way for devices to have more control over when they're stepped in relation
-    /// to other devices.
+    /// This is called in [`System::reset`] (and therefore [`System::start`]) before the [`Cpu`] is
+    /// reset, in the same order as [`init`]. Unlike `init`, it can be called any number of times,
+    /// and the device should go back to the state it's in when the machine is powered on (except
+    /// for anything that survives a reset on the real device, like a battery-backed clock).
     ///
-    /// Devices have no control over how often this method is called; instead it's determined by
-    /// [`System::start`]. If you need to loop at a certain frequency, use [`std::thread`] instead
-    /// of (or in addition to) implementing `step`.
+    /// If this method isn't implemented, the `Device` will do nothing when it's reset.
     ///
-    /// If this method isn't implemented, the `Device` will do nothing during each iteration of the
-    /// execution loop.
+    /// [`System::reset`]: crate::System::reset
+    /// [`System::start`]: crate::System::start
+    /// [`Cpu`]: crate::cpu::Cpu
+    /// [`init`]: Device::init
+    fn reset(&mut self, sys: &mut System<C>) {
+        let _ = sys;
+    }
+
+    /// Shuts the device down, which is where it should save anything that's persisted (like disk
+    /// images or CMOS contents).
+    ///
+    /// This is called in [`System::shutdown`], in the reverse order of [`init`] so that devices
+    /// are shut down before the devices they depend on. The device won't be used again afterwards.
+    ///
+    /// If this method isn't implemented, the `Device` will do nothing when it's shut down.
+    ///
+    /// [`System::shutdown`]: crate::System::shutdown
+    /// [`init`]: Device::init
+    fn shutdown(&mut self, sys: &mut System<C>) {
+        let _ = sys;
+    }
+
+    /// Wakes the device up to do its work.
+    ///
+    /// Devices aren't stepped constantly. Instead, this is called after the step of the [`Cpu`]
+    /// that reaches the deadline set with [`System::wake_at`], or that writes to memory watched
+    /// with [`System::wake_on_write`]. Deadlines only expire once, so a device that needs to be
+    /// stepped regularly should set its next deadline every time it's stepped. Devices that are
+    /// woken by the same step are stepped in dependency order (see [`System::add_dependency`]),
+    /// before any timers are called.
+    ///
+    /// Devices don't need to be stepped to handle ports, since [`handle_port`] is always called
+    /// when the CPU accesses a port. Devices with nothing to do until then shouldn't set a
+    /// deadline at all.
+    ///
+    /// If this method isn't implemented, the `Device` will do nothing when it's woken up.
     ///
-    /// [`System`]: crate::System
     /// [`Cpu`]: crate::cpu::Cpu
-    /// [`System::start`]: crate::System::start
-    /// [`std::thread`]: std::thread
+    /// [`System::wake_at`]: crate::System::wake_at
+    /// [`System::wake_on_write`]: crate::System::wake_on_write
+    /// [`System::add_dependency`]: crate::System::add_dependency
+    /// [`handle_port`]: Device::handle_port
     fn step(&mut self, sys: &mut System<C>) {
         let _ = sys;
     }
@@ -119,10 +252,10 @@ where
     /// if the device did handle the request. If the device did handle the request, the
     /// `PortResponse` contains nothing if it's an output port, or a value if it's an input port.
     ///
-    /// When the CPU wants a port to be handled, it calls the correct method in [`System`] which
-    /// calls the correct method in [`Devices`]. `Devices` will loop through all devices in an
-    /// undefined order and find the first one that handles the port. Once it finds one device that
-    /// handles the port, it doesn't send the port request to any more devices.
+    /// When the CPU wants a port to be handled, it calls the correct method in [`System`], which
+    /// will loop through all devices that aren't busy in an undefined order and find the first one
+    /// that handles the port. Once it finds one device that handles the port, it doesn't send the
+    /// port request to any more devices.
     ///
     /// | If the `PortRequest` is... | And you...      | You return...       |
     /// | -------------------------- | --------------- | ------------------- |
@@ -138,20 +271,177 @@ where
     /// [`PortRequest`]: PortRequest
     /// [`PortResponse`]: PortResponse
     /// [`System`]: crate::System
-    /// [`Devices`]: Devices
     fn handle_port(&mut self, sys: &mut System<C>, request: PortRequest) -> Option<PortResponse> {
         let _ = (sys, request);
 
         None
     }
+
+    /// Handles a timer that the device set with [`System::set_timer`], which has expired.
+    ///
+    /// `token` is the token that the timer was set with, which can be used to tell timers apart.
+    ///
+    /// If this method isn't implemented, the `Device` will do nothing when its timers expire.
+    ///
+    /// [`System::set_timer`]: crate::System::set_timer
+    fn timer(&mut self, sys: &mut System<C>, token: u64) {
+        let _ = (sys, token);
+    }
+
+    /// The ports and memory that the device handles.
+    ///
+    /// This is only used to describe the device to introspection tools, so it doesn't change which
+    /// port requests are sent to the device (see [`handle_port`]). It should include every port the
+    /// device could handle, even if it's disabled.
+    ///
+    /// If this method isn't implemented, the `Device` claims nothing.
+    ///
+    /// [`handle_port`]: Device::handle_port
+    fn claims(&self) -> Claims {
+        Claims::default()
+    }
+
+    /// Describes the state of the device for debuggers, or returns `None` if it has nothing to
+    /// show.
+    ///
+    /// The description should be human-readable and can span multiple lines.
+    ///
+    /// If this method isn't implemented, the `Device` will return `None`.
+    fn dump(&self) -> Option<String> {
+        None
+    }
+}
+
+/// A typed reference to a device in a [`System`], which is returned by [`System::add_device`].
+///
+/// It's used to get the device from the system (see [`System::get_device`] and
+/// [`System::with_device`]), or to create a [`DeviceHandle`] that can be sent to other threads.
+///
+/// [`System`]: crate::System
+/// [`System::add_device`]: crate::System::add_device
+/// [`System::get_device`]: crate::System::get_device
+/// [`System::with_device`]: crate::System::with_device
+/// [`DeviceHandle`]: DeviceHandle
+pub struct DeviceRef<D> {
+    id: DeviceId,
+    device: PhantomData<fn() -> D>,
+}
+
+impl<D> DeviceRef<D> {
+    pub fn id(&self) -> DeviceId {
+        self.id
+    }
+}
+
+impl<D> Copy for DeviceRef<D> {}
+
+impl<D> Clone for DeviceRef<D> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<D> Debug for DeviceRef<D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DeviceRef").field(&self.id).finish()
+    }
+}
+
+impl<D> PartialEq for DeviceRef<D> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<D> Eq for DeviceRef<D> {}
+
+/// A closure that's posted to a system from another thread.
+pub(crate) type Posted<C> = Box<dyn FnOnce(&mut System<C>) + Send>;
+
+/// A handle to a device that other threads can use, like a frontend thread that sends key presses
+/// to a keyboard while the system runs on its own thread.
+///
+/// The system owns its devices, so other threads can't access them directly. Instead, they post
+/// closures which the system calls on the device before its next step (see
+/// [`System::process_posted`]).
+///
+/// [`System::process_posted`]: crate::System::process_posted
+pub struct DeviceHandle<C, D>
+where
+    C: Cpu,
+{
+    device: DeviceRef<D>,
+    sender: Sender<Posted<C>>,
+    pending: Arc<AtomicBool>,
 }
 
-/// A collection of devices.
+impl<C, D> DeviceHandle<C, D>
+where
+    C: Cpu,
+    D: Device<C>,
+{
+    pub(crate) fn new(
+        device: DeviceRef<D>,
+        sender: Sender<Posted<C>>,
+        pending: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            device,
+            sender,
+            pending,
+        }
+    }
+
+    /// Posts a closure that the system calls on the device before its next step, returning
+    /// `false` if the system has been dropped.
+    pub fn post(&self, f: impl FnOnce(&mut D, &mut System<C>) + Send + 'static) -> bool {
+        let device = self.device;
+        let sent = self
+            .sender
+            .send(Box::new(move |sys: &mut System<C>| {
+                sys.with_device(device, f)
+            }))
+            .is_ok();
+        self.pending.store(true, Ordering::Release);
+
+        sent
+    }
+}
+
+impl<C, D> Clone for DeviceHandle<C, D>
+where
+    C: Cpu,
+{
+    fn clone(&self) -> Self {
+        Self {
+            device: self.device,
+            sender: self.sender.clone(),
+            pending: Arc::clone(&self.pending),
+        }
+    }
+}
+
+/// A collection of devices, which owns them.
+///
+/// While a device's method is being called, it's taken out of the collection (so that the method
+/// can be given the [`System`] that the collection is in), which makes it "busy".
+///
+/// [`System`]: crate::System
 pub struct Devices<C>
 where
     C: Cpu,
 {
-    devices: Vec<Arc<Mutex<dyn Device<C>>>>,
+    /// The devices, which are `None` while they're busy.
+    slots: Vec<Option<Box<dyn Device<C>>>>,
+    types: Vec<TypeId>,
+    names: Vec<String>,
+    /// The indices of the devices that each device depends on.
+    dependencies: Vec<Vec<usize>>,
+    /// The indices of the devices in dependency order, which puts dependencies first and is
+    /// otherwise the order they were pushed in.
+    order: Vec<usize>,
+    /// The position of each device in `order`.
+    ranks: Vec<usize>,
 }
 
 impl<C> Devices<C>
@@ -163,117 +453,270 @@ where
     /// [`Device`]: Device
     pub fn new() -> Self {
         Self {
-            devices: Vec::new(),
+            slots: Vec::new(),
+            types: Vec::new(),
+            names: Vec::new(),
+            dependencies: Vec::new(),
+            order: Vec::new(),
+            ranks: Vec::new(),
         }
     }
 
-    /// Pushes a device to the end of the collection.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Pushes a device to the end of the collection, named after its type.
+    ///
+    /// This returns a [`DeviceRef`] which can be given to other devices that need to access this
+    /// device. See [`Device`] for more information.
     ///
-    /// This returns an `Arc<Mutex<D>>` (where `D` is the type of device passed in) which can be
-    /// given to other devices that need to access this device. See [`Device`] for more information.
+    /// The name is the name of the type without its path (like `Cmos`), followed by `#2`, `#3` and
+    /// so on if there's already a device with that name.
     ///
+    /// [`DeviceRef`]: DeviceRef
     /// [`Device`]: Device
-    pub fn push<D>(&mut self, device: D) -> Arc<Mutex<D>>
+    pub fn push<D>(&mut self, device: D) -> DeviceRef<D>
     where
-        D: Device<C> + 'static,
+        D: Device<C>,
     {
-        let arc = Arc::new(Mutex::new(device));
-        let clone = Arc::clone(&arc);
-        self.devices.push(arc);
+        let type_name = std::any::type_name::<D>();
+        let type_name = type_name
+            .split('<')
+            .next()
+            .and_then(|path| path.rsplit("::").next())
+            .unwrap_or(type_name);
 
-        clone
+        let mut name = type_name.to_string();
+        let mut number = 1;
+        while self.find_by_name(&name).is_some() {
+            number += 1;
+            name = format!("{}#{}", type_name, number);
+        }
+
+        self.push_named(&name, device)
     }
 
-    /// Initializes all devices in the collection.
-    pub fn init_all(&self, sys: &mut System<C>) {
-        for device in &self.devices {
-            device.lock().unwrap().init(sys);
+    /// Pushes a device to the end of the collection with a name, like `com1`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there's already a device with the name.
+    pub fn push_named<D>(&mut self, name: &str, device: D) -> DeviceRef<D>
+    where
+        D: Device<C>,
+    {
+        assert!(
+            self.find_by_name(name).is_none(),
+            "there's already a device named {}",
+            name
+        );
+
+        let id = self.slots.len();
+        self.slots.push(Some(Box::new(device)));
+        self.types.push(TypeId::of::<D>());
+        self.names.push(name.to_string());
+        self.dependencies.push(Vec::new());
+        self.order.push(id);
+        self.ranks.push(id);
+
+        DeviceRef {
+            id,
+            device: PhantomData,
         }
     }
 
-    /// Steps all devices in the collection.
-    pub fn step_all(&self, sys: &mut System<C>) {
-        for device in &self.devices {
-            device.lock().unwrap().step(sys);
-        }
+    /// Gets a device.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device is busy.
+    pub fn get<D>(&self, device: DeviceRef<D>) -> &D
+    where
+        D: Device<C>,
+    {
+        let any: &dyn Any = self.get_dyn(device.id).expect("device is busy");
+
+        any.downcast_ref().unwrap()
     }
 
-    /// Handles an input port request which expects an 8-bit response.
+    /// Gets a device mutably.
     ///
-    /// See [`Device::handle_port`] for more information.
+    /// # Panics
     ///
-    /// [`Device::handle_port`]: Device::handle_port
-    pub fn port_in_8(&self, sys: &mut System<C>, port: u16) -> Option<u8> {
-        let request = PortRequest::In8(port);
-        for device in &self.devices {
-            let value = device.lock().unwrap().handle_port(sys, request);
-            if let Some(PortResponse::In8(value)) = value {
-                return Some(value);
-            }
-        }
+    /// Panics if the device is busy.
+    pub fn get_mut<D>(&mut self, device: DeviceRef<D>) -> &mut D
+    where
+        D: Device<C>,
+    {
+        let any: &mut dyn Any = self.slots[device.id]
+            .as_deref_mut()
+            .expect("device is busy");
 
-        None
+        any.downcast_mut().unwrap()
     }
 
-    /// Handles an input port request which expects a 16-bit response.
+    /// Gets a device without knowing its type, or `None` if it's busy.
     ///
-    /// See [`Device::handle_port`] for more information.
+    /// # Panics
     ///
-    /// [`Device::handle_port`]: Device::handle_port
-    pub fn port_in_16(&self, sys: &mut System<C>, port: u16) -> Option<u16> {
-        let request = PortRequest::In16(port);
-        for device in &self.devices {
-            let value = device.lock().unwrap().handle_port(sys, request);
-            if let Some(PortResponse::In16(value)) = value {
-                return Some(value);
-            }
+    /// Panics if there's no device with the ID.
+    pub fn get_dyn(&self, id: DeviceId) -> Option<&dyn Device<C>> {
+        self.slots[id].as_deref()
+    }
+
+    /// The first device of a type, in the order they were pushed in.
+    pub fn find<D>(&self) -> Option<DeviceRef<D>>
+    where
+        D: Device<C>,
+    {
+        let id = self
+            .types
+            .iter()
+            .position(|other| *other == TypeId::of::<D>())?;
+
+        Some(DeviceRef {
+            id,
+            device: PhantomData,
+        })
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<DeviceId> {
+        self.names.iter().position(|other| other == name)
+    }
+
+    /// The device with a name, if it has the type `D`.
+    pub fn find_by_name_as<D>(&self, name: &str) -> Option<DeviceRef<D>>
+    where
+        D: Device<C>,
+    {
+        let id = self.find_by_name(name)?;
+        if self.types[id] != TypeId::of::<D>() {
+            return None;
         }
 
-        None
+        Some(DeviceRef {
+            id,
+            device: PhantomData,
+        })
     }
 
-    /// Handles an output port request..
+    /// The name of a device.
     ///
-    /// See [`Device::handle_port`] for more information.
+    /// # Panics
     ///
-    /// [`Device::handle_port`]: Device::handle_port
-    pub fn port_out_8(&self, sys: &mut System<C>, port: u16, value: u8) -> Option<()> {
-        let request = PortRequest::Out8(port, value);
+    /// Panics if there's no device with the ID.
+    pub fn name(&self, id: DeviceId) -> &str {
+        &self.names[id]
+    }
 
-        self.port_out(sys, request)
+    /// Describes every device in the collection, in the order they were pushed in. Busy devices
+    /// don't have any claims.
+    pub fn infos(&self) -> Vec<DeviceInfo> {
+        self.slots
+            .iter()
+            .zip(&self.names)
+            .enumerate()
+            .map(|(id, (device, name))| DeviceInfo {
+                id,
+                name: name.clone(),
+                claims: device
+                    .as_ref()
+                    .map(|device| device.claims())
+                    .unwrap_or_default(),
+            })
+            .collect()
     }
 
-    /// Handles an output port request..
+    /// Makes `dependent` depend on `dependency`, so that it's initialized, reset and stepped after
+    /// `dependency` and shut down before it.
     ///
-    /// See [`Device::handle_port`] for more information.
+    /// # Panics
     ///
-    /// [`Device::handle_port`]: Device::handle_port
-    pub fn port_out_16(&self, sys: &mut System<C>, port: u16, value: u16) -> Option<()> {
-        let request = PortRequest::Out16(port, value);
-
-        self.port_out(sys, request)
+    /// Panics if `dependency` already depends on `dependent` (directly or not).
+    pub fn add_dependency<D, E>(&mut self, dependent: DeviceRef<D>, dependency: DeviceRef<E>) {
+        self.dependencies[dependent.id].push(dependency.id);
+        if !self.sort() {
+            self.dependencies[dependent.id].pop();
+            panic!("device dependencies can't be circular");
+        }
     }
 
-    fn port_out(&self, sys: &mut System<C>, request: PortRequest) -> Option<()> {
-        for device in &self.devices {
-            let value = device.lock().unwrap().handle_port(sys, request);
-            if let Some(PortResponse::Out) = value {
-                return Some(());
+    /// Sorts the devices in dependency order, returning `false` if the dependencies are circular
+    /// (which leaves the order unchanged).
+    fn sort(&mut self) -> bool {
+        let mut dependents = vec![Vec::new(); self.slots.len()];
+        let mut remaining = vec![0; self.slots.len()];
+        for (index, dependencies) in self.dependencies.iter().enumerate() {
+            for dependency in dependencies {
+                dependents[*dependency].push(index);
+                remaining[index] += 1;
             }
         }
 
-        None
-    }
-}
+        // Devices that are ready go in the order they were pushed in
+        let mut ready: BinaryHeap<_> = (0..self.slots.len())
+            .filter(|index| remaining[*index] == 0)
+            .map(Reverse)
+            .collect();
+        let mut order = Vec::with_capacity(self.slots.len());
+        while let Some(Reverse(index)) = ready.pop() {
+            order.push(index);
+            for dependent in &dependents[index] {
+                remaining[*dependent] -= 1;
+                if remaining[*dependent] == 0 {
+                    ready.push(Reverse(*dependent));
+                }
+            }
+        }
 
-impl<C> Clone for Devices<C>
-where
-    C: Cpu,
-{
-    fn clone(&self) -> Self {
-        Self {
-            devices: self.devices.clone(),
+        if order.len() < self.slots.len() {
+            return false;
+        }
+        for (rank, index) in order.iter().enumerate() {
+            self.ranks[*index] = rank;
         }
+        self.order = order;
+
+        true
+    }
+
+    /// The IDs of the devices in dependency order.
+    pub fn order(&self) -> &[DeviceId] {
+        &self.order
+    }
+
+    /// The position of a device in dependency order.
+    pub(crate) fn rank(&self, id: DeviceId) -> usize {
+        self.ranks[id]
+    }
+
+    /// Takes a device out of the collection so that it's busy, or returns `None` if it's already
+    /// busy.
+    pub(crate) fn take(&mut self, id: DeviceId) -> Option<Box<dyn Device<C>>> {
+        self.slots[id].take()
+    }
+
+    /// Puts a device that was taken back into the collection.
+    pub(crate) fn put_back(&mut self, id: DeviceId, device: Box<dyn Device<C>>) {
+        self.slots[id] = Some(device);
+    }
+
+    /// Dumps the state of every device in the collection, in the order they were pushed. Busy
+    /// devices can't be dumped.
+    ///
+    /// See [`Device::dump`] for more information.
+    ///
+    /// [`Device::dump`]: Device::dump
+    pub fn dump_all(&self) -> Vec<Option<String>> {
+        self.slots
+            .iter()
+            .map(|device| device.as_ref().and_then(|device| device.dump()))
+            .collect()
     }
 }
 
@@ -285,3 +728,232 @@ where
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    struct TestCpu;
+
+    impl Cpu for TestCpu {
+        fn step(_sys: &mut System<Self>) {}
+    }
+
+    /// A device that logs its name and every lifecycle method that's called on it, and counts its
+    /// steps.
+    struct Logger {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        steps: u32,
+    }
+
+    impl Logger {
+        fn log(&self, method: &str) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", method, self.name));
+        }
+    }
+
+    impl Device<TestCpu> for Logger {
+        fn init(&mut self, _sys: &mut System<TestCpu>) {
+            self.log("init");
+        }
+
+        fn reset(&mut self, _sys: &mut System<TestCpu>) {
+            self.log("reset");
+        }
+
+        fn shutdown(&mut self, _sys: &mut System<TestCpu>) {
+            self.log("shutdown");
+        }
+
+        fn step(&mut self, _sys: &mut System<TestCpu>) {
+            self.steps += 1;
+        }
+    }
+
+    /// A device that claims some ports and watches some memory.
+    struct Counter;
+
+    impl Device<TestCpu> for Counter {
+        fn init(&mut self, sys: &mut System<TestCpu>) {
+            sys.wake_on_write(MemRange::new(0x00, 0x0f));
+        }
+
+        fn claims(&self) -> Claims {
+            Claims {
+                ports: vec![0x10..=0x11],
+                mem: Vec::new(),
+            }
+        }
+    }
+
+    /// A device with an 8-bit register at each of its two ports.
+    struct Registers {
+        values: [u8; 2],
+    }
+
+    impl Device<TestCpu> for Registers {
+        fn handle_port(
+            &mut self,
+            _sys: &mut System<TestCpu>,
+            request: PortRequest,
+        ) -> Option<PortResponse> {
+            match request {
+                PortRequest::In8(port @ 0x10..=0x11) => {
+                    Some(PortResponse::In8(self.values[usize::from(port - 0x10)]))
+                }
+                PortRequest::Out8(port @ 0x10..=0x11, value) => {
+                    self.values[usize::from(port - 0x10)] = value;
+                    Some(PortResponse::Out)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn create_sys() -> (System<TestCpu>, Arc<Mutex<Vec<String>>>) {
+        (System::new(TestCpu, MemMap::new(0)), Arc::default())
+    }
+
+    fn add_logger(
+        sys: &mut System<TestCpu>,
+        log: &Arc<Mutex<Vec<String>>>,
+        name: &'static str,
+    ) -> DeviceRef<Logger> {
+        sys.add_device(Logger {
+            name,
+            log: Arc::clone(log),
+            steps: 0,
+        })
+    }
+
+    #[test]
+    fn should_call_lifecycle_methods_in_dependency_order() {
+        let (mut sys, log) = create_sys();
+        let keyboard = add_logger(&mut sys, &log, "keyboard");
+        let timer = add_logger(&mut sys, &log, "timer");
+        let pic = add_logger(&mut sys, &log, "pic");
+        sys.add_dependency(keyboard, pic);
+        sys.add_dependency(timer, pic);
+
+        sys.init();
+        sys.reset();
+        sys.shutdown();
+
+        assert_eq!(
+            vec![
+                "init pic",
+                "init keyboard",
+                "init timer",
+                "reset pic",
+                "reset keyboard",
+                "reset timer",
+                "shutdown timer",
+                "shutdown keyboard",
+                "shutdown pic",
+            ],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn should_find_devices_by_type_and_name() {
+        let (mut sys, log) = create_sys();
+        let first = add_logger(&mut sys, &log, "first");
+        add_logger(&mut sys, &log, "second");
+        sys.add_named_device("counter", Counter);
+
+        assert_eq!(Some(first), sys.device::<Logger>());
+        assert_eq!(Some(1), sys.device_by_name("Logger#2"));
+        assert!(sys.device_by_name_as::<Counter>("counter").is_some());
+        assert!(sys.device_by_name_as::<Logger>("counter").is_none());
+        assert!(sys.device_by_name("Logger#3").is_none());
+
+        sys.init();
+        let infos = sys.device_infos();
+        assert_eq!(
+            vec!["Logger", "Logger#2", "counter"],
+            infos.iter().map(|info| &info.name[..]).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Claims {
+                ports: vec![0x10..=0x11],
+                mem: vec![MemRange::new(0x00, 0x0f)],
+            },
+            infos[2].claims
+        );
+    }
+
+    #[test]
+    fn should_call_posted_closures_before_next_step() {
+        let (mut sys, log) = create_sys();
+        let logger = add_logger(&mut sys, &log, "logger");
+        let handle = sys.handle(logger);
+
+        std::thread::spawn(move || {
+            handle.post(|logger, sys| {
+                logger.log("posted");
+                sys.wake_at(Duration::ZERO);
+            })
+        })
+        .join()
+        .unwrap();
+        assert!(log.lock().unwrap().is_empty());
+
+        sys.step();
+        assert_eq!(vec!["posted logger"], *log.lock().unwrap());
+        assert_eq!(1, sys.get_device(logger).steps);
+    }
+
+    #[test]
+    fn should_split_unhandled_16_bit_port_accesses() {
+        let (mut sys, _) = create_sys();
+        let registers = sys.add_device(Registers { values: [0; 2] });
+
+        assert_eq!(Some(()), sys.port_out_16(0x10, 0x1234));
+        assert_eq!([0x34, 0x12], sys.get_device(registers).values);
+        assert_eq!(Some(0x1234), sys.port_in_16(0x10));
+        assert_eq!(None, sys.port_in_16(0x20));
+        assert_eq!(None, sys.port_out_16(0x20, 0));
+        assert!(sys.unhandled_ports().is_empty());
+
+        // Only the low byte is handled, so the high byte is unhandled and reads as all ones
+        assert_eq!(Some(0xff12), sys.port_in_16(0x11));
+        assert_eq!(Some(()), sys.port_out_16(0x0f, 0x5678));
+        assert_eq!([0x56, 0x12], sys.get_device(registers).values);
+        let ports: Vec<_> = sys.unhandled_ports().keys().copied().collect();
+        assert_eq!(vec![0x0f, 0x12], ports);
+        assert_eq!(1, sys.unhandled_ports()[&0x12].reads);
+        assert_eq!(1, sys.unhandled_ports()[&0x0f].writes);
+    }
+
+    #[test]
+    #[should_panic(expected = "device is busy")]
+    fn should_reject_port_accesses_while_devices_are_busy() {
+        let (mut sys, log) = create_sys();
+        let logger = add_logger(&mut sys, &log, "logger");
+
+        sys.handle(logger).post(|_, sys| {
+            sys.port_in_8(0x10);
+        });
+        sys.step();
+    }
+
+    #[test]
+    #[should_panic(expected = "device dependencies can't be circular")]
+    fn should_reject_circular_dependencies() {
+        let (mut sys, log) = create_sys();
+        let first = add_logger(&mut sys, &log, "first");
+        let second = add_logger(&mut sys, &log, "second");
+        let third = add_logger(&mut sys, &log, "third");
+        sys.add_dependency(first, second);
+        sys.add_dependency(second, third);
+        sys.add_dependency(third, first);
+    }
+}
@@ -1,6 +1,11 @@
+pub mod breakpoint;
+pub mod clock;
 pub mod cpu;
 pub mod device;
 pub mod mem;
+pub mod replay;
+pub mod rewind;
+pub mod snapshot;
 pub mod system;
 
 pub use system::System;
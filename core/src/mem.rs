@@ -9,7 +9,7 @@ pub mod range;
 
 pub use basic::BasicMem;
 pub use eeprom::Eeprom;
-pub use map::MemMap;
+pub use map::{MemMap, PAGE_SIZE};
 pub use range::MemRange;
 
 #[derive(Copy, Clone)]
@@ -7,8 +7,7 @@ pub struct BasicMem {
 
 impl BasicMem {
     pub fn new(size: usize) -> Self {
-        let mut memory = Vec::new();
-        memory.resize(size, 0);
+        let memory = vec![0; size];
 
         Self { memory }
     }
@@ -1,13 +1,31 @@
 use crate::mem;
 use crate::mem::{DumpRadix, Mem, MemRange};
 use linked_hash_map::LinkedHashMap;
+use std::collections::VecDeque;
 use std::fs;
 use std::ops::{Index, IndexMut};
 use std::path::Path;
 
+/// The size of the pages that [`MemMap::watch_page`] works with.
+///
+/// [`MemMap::watch_page`]: MemMap::watch_page
+pub const PAGE_SIZE: usize = 0x1000;
+
 pub struct MemMap {
     pub addressable: usize,
     mappings: LinkedHashMap<MemRange, Box<dyn Mem>>,
+
+    watched_pages: Vec<bool>,
+    written_pages: Vec<usize>,
+
+    /// The ranges that devices are woken up by when they're written to, with the device's index.
+    write_watches: Vec<(MemRange, usize)>,
+    touched: Vec<usize>,
+
+    /// The address and previous value of every write, or `None` if writes aren't journaled.
+    journal: Option<VecDeque<(usize, u8)>>,
+    /// The position of the first entry in the journal.
+    journal_start: usize,
 }
 
 impl MemMap {
@@ -15,6 +33,167 @@ impl MemMap {
         Self {
             addressable,
             mappings: LinkedHashMap::new(),
+
+            watched_pages: vec![false; addressable / PAGE_SIZE + 1],
+            written_pages: Vec::new(),
+
+            write_watches: Vec::new(),
+            touched: Vec::new(),
+
+            journal: None,
+            journal_start: 0,
+        }
+    }
+
+    /// The page that an address is in.
+    pub fn page(address: usize) -> usize {
+        address / PAGE_SIZE
+    }
+
+    /// Starts watching a page for writes.
+    ///
+    /// The next write to a watched page stops it from being watched and adds it to the list
+    /// returned by [`take_written_pages`]. This lets anything that caches memory contents (like
+    /// decoded instructions) find out when its cache is stale without checking every write.
+    ///
+    /// [`take_written_pages`]: MemMap::take_written_pages
+    pub fn watch_page(&mut self, page: usize) {
+        if let Some(watched) = self.watched_pages.get_mut(page) {
+            *watched = true;
+        }
+    }
+
+    /// Whether or not any watched pages have been written to since the last call to
+    /// [`take_written_pages`].
+    ///
+    /// [`take_written_pages`]: MemMap::take_written_pages
+    pub fn has_written_pages(&self) -> bool {
+        !self.written_pages.is_empty()
+    }
+
+    /// Returns the watched pages that have been written to, clearing the list.
+    pub fn take_written_pages(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.written_pages)
+    }
+
+    /// Starts watching a range for writes on behalf of a device.
+    ///
+    /// Writes to the range add `device` to the list returned by [`take_touched`]. This is used by
+    /// [`System::wake_on_write`].
+    ///
+    /// [`take_touched`]: MemMap::take_touched
+    /// [`System::wake_on_write`]: crate::System::wake_on_write
+    pub fn watch_writes(&mut self, range: MemRange, device: usize) {
+        self.write_watches.push((range, device));
+    }
+
+    /// Stops watching every range that's watched on behalf of a device.
+    pub fn unwatch_writes(&mut self, device: usize) {
+        self.write_watches.retain(|(_, other)| *other != device);
+    }
+
+    /// The ranges that are watched on behalf of a device.
+    pub fn watched_writes(&self, device: usize) -> Vec<MemRange> {
+        self.write_watches
+            .iter()
+            .filter(|(_, other)| *other == device)
+            .map(|(range, _)| *range)
+            .collect()
+    }
+
+    /// Whether or not any ranges watched on behalf of devices have been written to since the last
+    /// call to [`take_touched`].
+    ///
+    /// [`take_touched`]: MemMap::take_touched
+    pub fn has_touched(&self) -> bool {
+        !self.touched.is_empty()
+    }
+
+    /// Returns the devices whose watched ranges have been written to, clearing the list.
+    pub fn take_touched(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.touched)
+    }
+
+    fn mark_touched(&mut self, address: usize) {
+        for (range, device) in &self.write_watches {
+            if range.contains(address) && !self.touched.contains(device) {
+                self.touched.push(*device);
+            }
+        }
+    }
+
+    fn mark_written(&mut self, page: usize) {
+        if let Some(watched) = self.watched_pages.get_mut(page) {
+            if *watched {
+                *watched = false;
+                self.written_pages.push(page);
+            }
+        }
+    }
+
+    /// Starts journaling writes, so that they can be undone with [`undo_journal`].
+    ///
+    /// Each write adds an entry to the journal which has a position, starting at 0 for the first
+    /// journal. Positions keep increasing even if the journal is stopped and started again.
+    ///
+    /// [`undo_journal`]: MemMap::undo_journal
+    pub fn start_journal(&mut self) {
+        if self.journal.is_none() {
+            self.journal = Some(VecDeque::new());
+        }
+    }
+
+    /// Stops journaling writes and clears the journal.
+    pub fn stop_journal(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            self.journal_start += journal.len();
+        }
+    }
+
+    pub fn is_journaling(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// The position that the next journaled write will have.
+    pub fn journal_position(&self) -> usize {
+        self.journal_start + self.journal.as_ref().map_or(0, VecDeque::len)
+    }
+
+    /// Undoes every journaled write at or after `position`, newest first, and removes them from
+    /// the journal.
+    ///
+    /// Undoing writes isn't tracked like writing: pages aren't marked as written (see
+    /// [`watch_page`]) and devices aren't woken up (see [`watch_writes`]). Writes that were trimmed
+    /// with [`trim_journal`] can't be undone.
+    ///
+    /// [`watch_page`]: MemMap::watch_page
+    /// [`watch_writes`]: MemMap::watch_writes
+    /// [`trim_journal`]: MemMap::trim_journal
+    pub fn undo_journal(&mut self, position: usize) {
+        let mut journal = match self.journal.take() {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        let keep = position.saturating_sub(self.journal_start);
+        while journal.len() > keep {
+            let (address, value) = journal.pop_back().unwrap();
+            if let Some((key, mapped_index)) = self.map_index(address) {
+                self.mappings.get_mut(&key).unwrap()[mapped_index] = value;
+            }
+        }
+
+        self.journal = Some(journal);
+    }
+
+    /// Removes the journaled writes before `position`, which means they can't be undone anymore.
+    pub fn trim_journal(&mut self, position: usize) {
+        if let Some(journal) = &mut self.journal {
+            let count = position
+                .saturating_sub(self.journal_start)
+                .min(journal.len());
+            journal.drain(..count);
+            self.journal_start += count;
         }
     }
 
@@ -23,6 +202,9 @@ impl MemMap {
             panic!("range count must be the same as the memory size");
         }
 
+        for page in Self::page(range.start())..=Self::page(range.end()) {
+            self.mark_written(page);
+        }
         self.mappings.insert(range, Box::new(memory));
     }
 
@@ -36,6 +218,11 @@ impl MemMap {
         self.map(range, memory);
     }
 
+    /// Whether or not an address has memory mapped to it, which is required to write to it.
+    pub fn is_mapped(&self, address: usize) -> bool {
+        address < self.addressable && self.map_index(address).is_some()
+    }
+
     fn map_index(&self, index: usize) -> Option<(MemRange, usize)> {
         if index > self.addressable {
             panic!(
@@ -96,8 +283,15 @@ impl IndexMut<usize> for MemMap {
             Some(mapped) => mapped,
             None => panic!("cannot mutably index a memory address with no mapping"),
         };
+        self.mark_written(Self::page(index));
+        if !self.write_watches.is_empty() {
+            self.mark_touched(index);
+        }
 
         let mapping = &mut self.mappings[&key];
+        if let Some(journal) = &mut self.journal {
+            journal.push_back((index, mapping[mapped_index]));
+        }
         &mut mapping[mapped_index]
     }
 }
@@ -151,6 +345,15 @@ mod tests {
         assert_eq!(0, map[48]);
     }
 
+    #[test]
+    fn should_check_if_mapped() {
+        let map = create_test_map();
+        assert!(map.is_mapped(6));
+        assert!(map.is_mapped(39));
+        assert!(!map.is_mapped(48));
+        assert!(!map.is_mapped(64));
+    }
+
     #[test]
     fn should_write_physical_values() {
         let mut map = create_test_map();
@@ -164,4 +367,56 @@ mod tests {
         map[26] = 71;
         assert_eq!(71, map[26]);
     }
+
+    #[test]
+    fn should_undo_journaled_writes() {
+        let mut map = create_test_map();
+        map[13] = 1;
+        map.start_journal();
+
+        map[13] = 2;
+        let position = map.journal_position();
+        map[13] = 3;
+        map[26] = 4;
+        map.undo_journal(position);
+        assert_eq!((2, 0), (map[13], map[26]));
+
+        map.trim_journal(position);
+        map.undo_journal(0);
+        assert_eq!(2, map[13]);
+
+        map.stop_journal();
+        map[13] = 5;
+        assert_eq!(position, map.journal_position());
+    }
+
+    #[test]
+    fn should_report_writes_to_watched_pages() {
+        let mut map = MemMap::new(0x4000);
+        map.map_full(BasicMem::new(0x4000));
+        map.watch_page(1);
+        map.watch_page(2);
+
+        map[0x0fff] = 1;
+        assert!(!map.has_written_pages());
+
+        map[0x1000] = 2;
+        map[0x1001] = 3;
+        assert!(map.has_written_pages());
+        assert_eq!(vec![1], map.take_written_pages());
+        assert!(!map.has_written_pages());
+
+        map[0x1002] = 4;
+        assert!(map.take_written_pages().is_empty());
+    }
+
+    #[test]
+    fn should_report_remapped_watched_pages() {
+        let mut map = MemMap::new(0x4000);
+        map.map_full(BasicMem::new(0x4000));
+        map.watch_page(3);
+
+        map.map_from(0x3000, 0x3fff, BasicMem::new(0x1000));
+        assert_eq!(vec![3], map.take_written_pages());
+    }
 }
@@ -0,0 +1,494 @@
+//! Deterministic record and replay of the nondeterministic inputs that devices get from the host,
+//! like the current time or key presses.
+//!
+//! Devices get these inputs through [`System::input`] (for discrete events) and [`System::sample`]
+//! (for values that change over time). While the system is recording, every input is logged with
+//! the step it arrived at. While it's replaying a log, devices get the logged inputs at the same
+//! steps instead of the host's inputs, so the system does exactly what it did while it was
+//! recording, as long as it starts in the same state (usually by recording and replaying from
+//! before [`System::init`]) and uses the same CPU backend.
+//!
+//! [`System::input`]: crate::System::input
+//! [`System::sample`]: crate::System::sample
+//! [`System::init`]: crate::System::init
+
+use crate::cpu::Cpu;
+use crate::System;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::{fs, io};
+
+/// The bytes at the start of a serialized [`InputLog`], the last of which is the format version.
+///
+/// [`InputLog`]: InputLog
+pub const INPUT_LOG_MAGIC: [u8; 8] = *b"FIRNINP\x01";
+
+#[derive(Debug)]
+pub enum InputLogError {
+    Io(io::Error),
+    /// The log is corrupted or was created by something else.
+    Invalid(&'static str),
+}
+
+impl Display for InputLogError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputLogError::Io(err) => write!(f, "input log I/O failed: {}", err),
+            InputLogError::Invalid(reason) => write!(f, "invalid input log: {}", reason),
+        }
+    }
+}
+
+impl Error for InputLogError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InputLogError::Io(err) => Some(err),
+            InputLogError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for InputLogError {
+    fn from(err: io::Error) -> Self {
+        InputLogError::Io(err)
+    }
+}
+
+/// An input that a device got from the host.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InputEvent {
+    /// The step that the input arrived at (see [`System::steps`]).
+    ///
+    /// [`System::steps`]: crate::System::steps
+    pub step: u64,
+    /// The name of the input, like `cmos.time`.
+    pub channel: String,
+    pub data: Vec<u8>,
+}
+
+/// Every input that a system got while it was recording, in the order they arrived.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct InputLog {
+    pub events: Vec<InputEvent>,
+}
+
+impl InputLog {
+    /// Serializes the log.
+    ///
+    /// The format is [`INPUT_LOG_MAGIC`] followed by the events. Each event is its step (8 bytes,
+    /// little-endian), the length of its channel (2 bytes), the channel, the length of its data
+    /// (4 bytes) and then the data.
+    ///
+    /// [`INPUT_LOG_MAGIC`]: INPUT_LOG_MAGIC
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = INPUT_LOG_MAGIC.to_vec();
+        for event in &self.events {
+            bytes.extend(event.step.to_le_bytes());
+            bytes.extend((event.channel.len() as u16).to_le_bytes());
+            bytes.extend(event.channel.as_bytes());
+            bytes.extend((event.data.len() as u32).to_le_bytes());
+            bytes.extend(&event.data);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, InputLogError> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], InputLogError> {
+            if bytes.len() < len {
+                return Err(InputLogError::Invalid("input log is truncated"));
+            }
+
+            let (taken, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Ok(taken)
+        }
+
+        let mut rest = bytes
+            .strip_prefix(&INPUT_LOG_MAGIC)
+            .ok_or(InputLogError::Invalid(
+                "not an input log (or an unsupported version)",
+            ))?;
+
+        let mut events = Vec::new();
+        while !rest.is_empty() {
+            let step = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+            let len = u16::from_le_bytes(take(&mut rest, 2)?.try_into().unwrap());
+            let channel = String::from_utf8(take(&mut rest, len as usize)?.to_vec())
+                .map_err(|_| InputLogError::Invalid("channel isn't UTF-8"))?;
+            let len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap());
+            let data = take(&mut rest, len as usize)?.to_vec();
+
+            events.push(InputEvent {
+                step,
+                channel,
+                data,
+            });
+        }
+
+        Ok(Self { events })
+    }
+
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), InputLogError> {
+        fs::write(path, self.to_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, InputLogError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InputMode {
+    /// Devices get the host's inputs.
+    Live,
+    Recording,
+    Replaying,
+}
+
+/// Where a replay stopped matching the log.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Divergence {
+    pub step: u64,
+    pub channel: String,
+}
+
+pub(crate) enum Inputs {
+    Live,
+    Recording {
+        log: InputLog,
+        /// The last value of every sampled channel.
+        samples: HashMap<String, Vec<u8>>,
+    },
+    Replaying {
+        events: HashMap<String, VecDeque<InputEvent>>,
+        samples: HashMap<String, Vec<u8>>,
+        divergence: Option<Divergence>,
+    },
+}
+
+impl Inputs {
+    /// Records the first divergence from the log.
+    fn diverge(divergence: &mut Option<Divergence>, step: u64, channel: &str) {
+        if divergence.is_none() {
+            *divergence = Some(Divergence {
+                step,
+                channel: channel.to_string(),
+            });
+        }
+    }
+}
+
+impl<C> System<C>
+where
+    C: Cpu,
+{
+    /// Starts recording inputs, throwing away any log that's being recorded or replayed.
+    pub fn record_inputs(&mut self) {
+        self.inputs = Inputs::Recording {
+            log: InputLog::default(),
+            samples: HashMap::new(),
+        };
+    }
+
+    /// Starts replaying a log.
+    pub fn replay_inputs(&mut self, log: InputLog) {
+        let mut events: HashMap<String, VecDeque<InputEvent>> = HashMap::new();
+        for event in log.events {
+            events
+                .entry(event.channel.clone())
+                .or_default()
+                .push_back(event);
+        }
+
+        self.inputs = Inputs::Replaying {
+            events,
+            samples: HashMap::new(),
+            divergence: None,
+        };
+    }
+
+    /// Goes back to live inputs, returning the log if the system was recording.
+    pub fn stop_inputs(&mut self) -> Option<InputLog> {
+        match std::mem::replace(&mut self.inputs, Inputs::Live) {
+            Inputs::Recording { log, .. } => Some(log),
+            _ => None,
+        }
+    }
+
+    pub fn input_mode(&self) -> InputMode {
+        match self.inputs {
+            Inputs::Live => InputMode::Live,
+            Inputs::Recording { .. } => InputMode::Recording,
+            Inputs::Replaying { .. } => InputMode::Replaying,
+        }
+    }
+
+    /// Where the replay first stopped matching the log, which means a device asked for an input
+    /// that wasn't logged or didn't ask for one that was. The replay keeps going after diverging,
+    /// but it can't be trusted anymore.
+    pub fn divergence(&self) -> Option<&Divergence> {
+        match &self.inputs {
+            Inputs::Replaying { divergence, .. } => divergence.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Gets a discrete input for a device, like a key press, or `None` if there isn't one.
+    ///
+    /// `host` gets the input from the host, which is always called so that host inputs don't pile
+    /// up while replaying (but its result is ignored).
+    pub fn input(
+        &mut self,
+        channel: &str,
+        host: impl FnOnce() -> Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let step = self.steps();
+        let data = host();
+
+        match &mut self.inputs {
+            Inputs::Live => data,
+            Inputs::Recording { log, .. } => {
+                if let Some(data) = &data {
+                    log.events.push(InputEvent {
+                        step,
+                        channel: channel.to_string(),
+                        data: data.clone(),
+                    });
+                }
+
+                data
+            }
+            Inputs::Replaying {
+                events, divergence, ..
+            } => {
+                let queue = events.get_mut(channel)?;
+                while queue.front().is_some_and(|event| event.step < step) {
+                    queue.pop_front();
+                    Inputs::diverge(divergence, step, channel);
+                }
+
+                match queue.front() {
+                    Some(event) if event.step == step => queue.pop_front().map(|event| event.data),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Samples a value for a device that changes over time, like the current time.
+    ///
+    /// Values are only logged when they change, so devices should sample values at the coarsest
+    /// resolution they need (like seconds instead of microseconds) to keep logs small.
+    pub fn sample(&mut self, channel: &str, host: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        let step = self.steps();
+
+        match &mut self.inputs {
+            Inputs::Live => host(),
+            Inputs::Recording { log, samples } => {
+                let data = host();
+                if samples.get(channel) != Some(&data) {
+                    samples.insert(channel.to_string(), data.clone());
+                    log.events.push(InputEvent {
+                        step,
+                        channel: channel.to_string(),
+                        data: data.clone(),
+                    });
+                }
+
+                data
+            }
+            Inputs::Replaying {
+                events,
+                samples,
+                divergence,
+            } => {
+                if let Some(queue) = events.get_mut(channel) {
+                    while queue.front().is_some_and(|event| event.step <= step) {
+                        let event = queue.pop_front().unwrap();
+                        samples.insert(event.channel, event.data);
+                    }
+                }
+
+                match samples.get(channel) {
+                    Some(data) => data.clone(),
+                    None => {
+                        Inputs::diverge(divergence, step, channel);
+                        host()
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like [`sample`], but for values with a fixed size.
+    ///
+    /// A replayed value with the wrong size (which only comes from a corrupted log or a different
+    /// version of the device) is a divergence, and the host's value is used instead.
+    ///
+    /// [`sample`]: System::sample
+    pub fn sample_array<const N: usize>(
+        &mut self,
+        channel: &str,
+        host: impl FnOnce() -> [u8; N],
+    ) -> [u8; N] {
+        let mut host = Some(host);
+        let data = self.sample(channel, || host.take().unwrap()().to_vec());
+
+        data.try_into().unwrap_or_else(|_| {
+            let step = self.steps();
+            if let Inputs::Replaying { divergence, .. } = &mut self.inputs {
+                Inputs::diverge(divergence, step, channel);
+            }
+
+            host.take().unwrap()()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Device, DeviceRef};
+    use crate::mem::MemMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct TestCpu;
+
+    impl Cpu for TestCpu {
+        fn step(_sys: &mut System<Self>) {}
+    }
+
+    /// The host's key press (if any) and time.
+    type Host = (Option<u8>, u8);
+
+    /// A device that keeps every input and sample it gets after every step of the CPU, where the
+    /// host's inputs come from `host`.
+    struct TestDevice {
+        host: Arc<Mutex<Host>>,
+        seen: Vec<(Option<Vec<u8>>, Vec<u8>)>,
+    }
+
+    impl Device<TestCpu> for TestDevice {
+        fn init(&mut self, sys: &mut System<TestCpu>) {
+            sys.wake_at(Duration::ZERO);
+        }
+
+        fn step(&mut self, sys: &mut System<TestCpu>) {
+            sys.wake_at(sys.clock.now());
+
+            let (key, time) = *self.host.lock().unwrap();
+            let input = sys.input("test.key", || key.map(|key| vec![key]));
+            let sample = sys.sample("test.time", || vec![time]);
+
+            self.seen.push((input, sample));
+        }
+    }
+
+    fn run(sys: &mut System<TestCpu>, host: &Mutex<Host>) {
+        for step in 0..4 {
+            *host.lock().unwrap() = match step {
+                1 => (Some(7), 0),
+                2 | 3 => (None, 1),
+                _ => (None, 0),
+            };
+            sys.step();
+        }
+    }
+
+    fn create_sys() -> (System<TestCpu>, DeviceRef<TestDevice>, Arc<Mutex<Host>>) {
+        let mut sys = System::new(TestCpu, MemMap::new(0));
+        let host = Arc::new(Mutex::new((None, 0)));
+        let device = sys.add_device(TestDevice {
+            host: Arc::clone(&host),
+            seen: Vec::new(),
+        });
+        sys.init();
+
+        (sys, device, host)
+    }
+
+    #[test]
+    fn should_replay_recorded_inputs() {
+        let (mut sys, device, host) = create_sys();
+        sys.record_inputs();
+        run(&mut sys, &host);
+        let log = sys.stop_inputs().unwrap();
+        assert_eq!(3, log.events.len());
+
+        let (mut replay_sys, replay_device, _) = create_sys();
+        replay_sys.replay_inputs(InputLog::from_bytes(&log.to_bytes()).unwrap());
+        for _ in 0..4 {
+            replay_sys.step();
+        }
+
+        assert_eq!(None, replay_sys.divergence());
+        assert_eq!(
+            sys.get_device(device).seen,
+            replay_sys.get_device(replay_device).seen
+        );
+    }
+
+    #[test]
+    fn should_detect_divergence() {
+        let (mut sys, _, _) = create_sys();
+        sys.replay_inputs(InputLog {
+            events: vec![InputEvent {
+                step: 1,
+                channel: String::from("test.key"),
+                data: vec![1],
+            }],
+        });
+
+        sys.step();
+        assert_eq!(
+            Some(&Divergence {
+                step: 1,
+                channel: String::from("test.time"),
+            }),
+            sys.divergence()
+        );
+    }
+
+    #[test]
+    fn should_diverge_on_samples_with_the_wrong_size() {
+        let (mut sys, _, _) = create_sys();
+        sys.replay_inputs(InputLog {
+            events: vec![InputEvent {
+                step: 0,
+                channel: String::from("test.start"),
+                data: vec![1, 2],
+            }],
+        });
+
+        assert_eq!([3; 4], sys.sample_array("test.start", || [3; 4]));
+        assert_eq!(
+            Some(&Divergence {
+                step: 0,
+                channel: String::from("test.start"),
+            }),
+            sys.divergence()
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_logs() {
+        let log = InputLog {
+            events: vec![InputEvent {
+                step: 3,
+                channel: String::from("test.key"),
+                data: vec![1, 2],
+            }],
+        };
+        let bytes = log.to_bytes();
+
+        assert_eq!(log, InputLog::from_bytes(&bytes).unwrap());
+        assert!(InputLog::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(InputLog::from_bytes(b"FIRNSNP\x01").is_err());
+    }
+}
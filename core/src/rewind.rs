@@ -0,0 +1,501 @@
+//! Reverse execution, which lets a [`System`] be rewound to an earlier step.
+//!
+//! While a system is recording, it takes a checkpoint of the CPU every few steps and journals every
+//! memory write (see [`MemMap::start_journal`]) and port access. Rewinding restores the newest
+//! checkpoint before the target step, undoes the memory writes that came after it, and then
+//! executes forward to the target step.
+//!
+//! Steps that have already been executed once are replayed: devices aren't stepped, and port
+//! accesses get the responses from the journal instead of going to the devices. This keeps
+//! replayed execution the same as the original, and means that the system can be rewound and then
+//! run forward again any number of times. Once execution passes the newest step that was recorded
+//! (or the CPU does something different from the journal, like after its registers are changed),
+//! the system runs normally again.
+//!
+//! Devices aren't rewound, so after rewinding they're in the state they were in at the newest
+//! step, and the closures posted by device handles aren't called while replaying. Timers (see
+//! [`clock`]) still expire on time while replaying, but the only ones left are the ones that
+//! weren't due yet at the newest step and the ones set after rewinding. Changes that devices make
+//! to memory while they're stepped are undone when rewinding but aren't replayed.
+//!
+//! Undoing memory writes doesn't mark pages as written (see [`MemMap::undo_journal`]), so CPUs that
+//! cache decoded instructions should clear their caches when their state is loaded.
+//!
+//! [`System`]: crate::System
+//! [`MemMap::start_journal`]: crate::mem::MemMap::start_journal
+//! [`MemMap::undo_journal`]: crate::mem::MemMap::undo_journal
+//! [`clock`]: crate::clock
+
+use crate::cpu::Cpu;
+use crate::device::PortRequest;
+use crate::snapshot::{SaveState, SnapshotError};
+use crate::System;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+
+/// A journaled port access.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PortAccess {
+    /// The step that the access happened in.
+    pub step: u64,
+    pub request: PortRequest,
+    /// The value that was read, 0 for handled writes, or `None` if no device handled the access.
+    pub response: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum RewindError {
+    NotRecording,
+    /// The step isn't in the recorded history.
+    OutOfHistory(RangeInclusive<u64>),
+    /// The CPU rejected the state from a checkpoint.
+    Snapshot(SnapshotError),
+}
+
+impl Display for RewindError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RewindError::NotRecording => f.write_str("the system isn't recording"),
+            RewindError::OutOfHistory(history) => write!(
+                f,
+                "step is outside of the recorded history (steps {} to {})",
+                history.start(),
+                history.end()
+            ),
+            RewindError::Snapshot(err) => write!(f, "couldn't restore checkpoint: {}", err),
+        }
+    }
+}
+
+impl Error for RewindError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RewindError::Snapshot(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+struct Checkpoint {
+    step: u64,
+    cycles: u64,
+    cpu: Vec<u8>,
+    /// The positions of the memory and port journals when the checkpoint was taken.
+    mem_position: usize,
+    port_position: usize,
+}
+
+struct Recording<C> {
+    window: u64,
+    interval: u64,
+    save: fn(&C) -> Vec<u8>,
+
+    checkpoints: VecDeque<Checkpoint>,
+    ports: VecDeque<PortAccess>,
+    /// The position of the first port access in the journal.
+    ports_start: usize,
+}
+
+/// The rewind state of a system.
+pub(crate) struct Rewind<C> {
+    recording: Option<Recording<C>>,
+    /// The port accesses that are being replayed, oldest first.
+    replay: VecDeque<PortAccess>,
+    /// The step where replaying ends, which is the newest step that was executed.
+    replay_until: u64,
+}
+
+impl<C> Rewind<C> {
+    pub(crate) fn new() -> Self {
+        Self {
+            recording: None,
+            replay: VecDeque::new(),
+            replay_until: 0,
+        }
+    }
+
+    pub(crate) fn is_replaying(&self, step: u64) -> bool {
+        step < self.replay_until
+    }
+
+    /// Takes the response for a port access from the replay journal, if the access is being
+    /// replayed.
+    fn replay_port(&mut self, step: u64, request: PortRequest) -> Option<Option<u16>> {
+        if !self.is_replaying(step) {
+            self.replay.clear();
+            return None;
+        }
+
+        match self.replay.front() {
+            Some(access) if access.step == step && access.request == request => {
+                self.replay.pop_front().map(|access| access.response)
+            }
+            // The CPU isn't doing what it did before, so the rest of the journal is useless
+            _ => {
+                self.replay.clear();
+                self.replay_until = 0;
+                None
+            }
+        }
+    }
+}
+
+impl<C> System<C>
+where
+    C: Cpu,
+{
+    pub fn is_recording(&self) -> bool {
+        self.rewind.recording.is_some()
+    }
+
+    /// The steps that the system can be rewound to, or `None` if it isn't recording.
+    pub fn history(&self) -> Option<RangeInclusive<u64>> {
+        let first = self.rewind.recording.as_ref()?.checkpoints.front()?;
+
+        Some(first.step..=self.steps())
+    }
+
+    /// The journaled port accesses, oldest first.
+    pub fn port_journal(&self) -> impl Iterator<Item = &PortAccess> {
+        self.rewind
+            .recording
+            .iter()
+            .flat_map(|recording| recording.ports.iter())
+    }
+
+    /// Stops recording and forgets the history.
+    pub fn stop_recording(&mut self) {
+        self.rewind = Rewind::new();
+        self.mem.stop_journal();
+    }
+
+    /// Takes a checkpoint if it's time for one, forgetting history that's outside of the window.
+    pub(crate) fn checkpoint_if_due(&mut self) {
+        let step = self.steps();
+        let recording = match &mut self.rewind.recording {
+            Some(recording) => recording,
+            None => return,
+        };
+        if let Some(last) = recording.checkpoints.back() {
+            if step < last.step + recording.interval {
+                return;
+            }
+        }
+
+        recording.checkpoints.push_back(Checkpoint {
+            step,
+            cycles: self.clock.cycles(),
+            cpu: (recording.save)(&self.cpu),
+            mem_position: self.mem.journal_position(),
+            port_position: recording.ports_start + recording.ports.len(),
+        });
+
+        // Keep the newest checkpoint that's at or before the start of the window
+        let window_start = step.saturating_sub(recording.window);
+        while recording.checkpoints.len() > 1 && recording.checkpoints[1].step <= window_start {
+            recording.checkpoints.pop_front();
+        }
+
+        let oldest = &recording.checkpoints[0];
+        self.mem.trim_journal(oldest.mem_position);
+        let trimmed = oldest.port_position - recording.ports_start;
+        recording.ports.drain(..trimmed);
+        recording.ports_start = oldest.port_position;
+    }
+
+    /// Handles a port access, replaying it or journaling it if needed.
+    pub(crate) fn port_access(
+        &mut self,
+        request: PortRequest,
+        access: impl FnOnce(&mut Self) -> Option<u16>,
+    ) -> Option<u16> {
+        let step = self.steps();
+        let response = match self.rewind.replay_port(step, request) {
+            Some(response) => response,
+            None => access(self),
+        };
+
+        if let Some(recording) = &mut self.rewind.recording {
+            recording.ports.push_back(PortAccess {
+                step,
+                request,
+                response,
+            });
+        }
+
+        response
+    }
+}
+
+impl<C> System<C>
+where
+    C: Cpu + SaveState,
+{
+    /// Starts recording, so that the system can be rewound to any of the last `window` steps.
+    ///
+    /// A checkpoint of the CPU is taken every `interval` steps. Smaller intervals use more memory
+    /// but make rewinding faster, since fewer steps have to be executed after restoring a
+    /// checkpoint.
+    ///
+    /// The history starts at the current step. If the system is already recording, only the
+    /// window and interval are changed.
+    pub fn start_recording(&mut self, window: u64, interval: u64) {
+        assert!(interval > 0, "the checkpoint interval must be at least 1");
+
+        if let Some(recording) = &mut self.rewind.recording {
+            recording.window = window;
+            recording.interval = interval;
+            return;
+        }
+
+        self.rewind.recording = Some(Recording {
+            window,
+            interval,
+            save: C::save_state,
+
+            checkpoints: VecDeque::new(),
+            ports: VecDeque::new(),
+            ports_start: 0,
+        });
+        self.mem.start_journal();
+        self.checkpoint_if_due();
+    }
+
+    /// Rewinds the system to an earlier step in its history (see [`history`]).
+    ///
+    /// The system can be run forward again afterwards, which replays the steps that were rewound.
+    ///
+    /// [`history`]: System::history
+    pub fn rewind_to(&mut self, step: u64) -> Result<(), RewindError> {
+        let history = self.history().ok_or(RewindError::NotRecording)?;
+        if !history.contains(&step) {
+            return Err(RewindError::OutOfHistory(history));
+        }
+
+        let recording = self.rewind.recording.as_mut().unwrap();
+        let index = recording
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| checkpoint.step <= step)
+            .unwrap();
+        recording.checkpoints.truncate(index + 1);
+
+        let checkpoint = &recording.checkpoints[index];
+        self.cpu
+            .load_state(&checkpoint.cpu)
+            .map_err(RewindError::Snapshot)?;
+        self.mem.undo_journal(checkpoint.mem_position);
+
+        // The port accesses after the checkpoint are replayed before any that were already
+        // going to be replayed
+        let mut replay = recording
+            .ports
+            .split_off(checkpoint.port_position - recording.ports_start);
+        replay.append(&mut self.rewind.replay);
+        self.rewind.replay = replay;
+        self.rewind.replay_until = self.rewind.replay_until.max(self.steps);
+        self.steps = checkpoint.step;
+        self.clock.set_cycles(checkpoint.cycles);
+
+        while self.steps() < step {
+            self.step();
+        }
+
+        Ok(())
+    }
+
+    /// Rewinds the system by one step.
+    pub fn step_back(&mut self) -> Result<(), RewindError> {
+        let step = self
+            .steps()
+            .checked_sub(1)
+            .ok_or(RewindError::OutOfHistory(
+                self.history().ok_or(RewindError::NotRecording)?,
+            ))?;
+
+        self.rewind_to(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Device, DeviceRef, PortResponse};
+    use crate::mem::{BasicMem, Mem, MemMap};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A CPU that reads a byte from port 0 and writes it to the address in `ptr` every step.
+    struct TestCpu {
+        ptr: u16,
+    }
+
+    impl Cpu for TestCpu {
+        fn step(sys: &mut System<Self>) {
+            let value = sys.port_in_8(0).unwrap();
+            let ptr = sys.cpu.ptr as usize;
+            sys.mem[ptr] = value;
+            sys.cpu.ptr += 1;
+        }
+    }
+
+    impl SaveState for TestCpu {
+        fn save_state(&self) -> Vec<u8> {
+            self.ptr.to_le_bytes().to_vec()
+        }
+
+        fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+            self.ptr = u16::from_le_bytes([state[0], state[1]]);
+
+            Ok(())
+        }
+    }
+
+    /// A device that counts the steps and port reads it gets, which is stepped after every step of
+    /// the CPU.
+    #[derive(Default)]
+    struct Counter {
+        steps: u8,
+        reads: u8,
+    }
+
+    impl Device<TestCpu> for Counter {
+        fn init(&mut self, sys: &mut System<TestCpu>) {
+            sys.wake_at(Duration::ZERO);
+        }
+
+        fn step(&mut self, sys: &mut System<TestCpu>) {
+            self.steps += 1;
+            sys.wake_at(sys.clock.now());
+        }
+
+        fn handle_port(
+            &mut self,
+            _sys: &mut System<TestCpu>,
+            request: PortRequest,
+        ) -> Option<PortResponse> {
+            match request {
+                PortRequest::In8(0) => {
+                    self.reads += 1;
+                    Some(PortResponse::In8(self.reads * 10))
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn create_sys() -> (System<TestCpu>, DeviceRef<Counter>) {
+        let mut map = MemMap::new(0x100);
+        map.map_full(BasicMem::new(0x100));
+
+        let mut sys = System::new(TestCpu { ptr: 0 }, map);
+        let counter = sys.add_device(Counter::default());
+        sys.init();
+
+        (sys, counter)
+    }
+
+    #[test]
+    fn should_rewind_and_replay() {
+        let (mut sys, counter) = create_sys();
+        sys.start_recording(100, 3);
+        for _ in 0..8 {
+            sys.step();
+        }
+        assert_eq!(Some(0..=8), sys.history());
+
+        sys.rewind_to(4).unwrap();
+        assert_eq!(4, sys.steps());
+        assert_eq!(4, sys.cpu.ptr);
+        assert_eq!([10, 20, 30, 40, 0], sys.mem.dump()[..5]);
+
+        sys.step_back().unwrap();
+        assert_eq!(3, sys.cpu.ptr);
+        for _ in 0..6 {
+            sys.step();
+        }
+
+        // Replayed steps use the journal, and then the devices are used again
+        assert_eq!([10, 20, 30, 40, 50, 60, 70, 80, 90], sys.mem.dump()[..9]);
+        let counter = sys.get_device(counter);
+        assert_eq!((9, 9), (counter.steps, counter.reads));
+        assert_eq!(9, sys.port_journal().count());
+    }
+
+    #[test]
+    fn should_forget_steps_outside_of_window() {
+        let (mut sys, _) = create_sys();
+        sys.start_recording(4, 2);
+        for _ in 0..9 {
+            sys.step();
+        }
+
+        assert_eq!(Some(4..=9), sys.history());
+        assert!(matches!(
+            sys.rewind_to(3),
+            Err(RewindError::OutOfHistory(_))
+        ));
+        sys.rewind_to(5).unwrap();
+        assert_eq!([10, 20, 30, 40, 50, 0], sys.mem.dump()[..6]);
+
+        sys.stop_recording();
+        assert!(matches!(sys.step_back(), Err(RewindError::NotRecording)));
+    }
+
+    #[test]
+    fn should_expire_timers_while_replaying() {
+        let (mut sys, _) = create_sys();
+        sys.start_recording(100, 2);
+        for _ in 0..8 {
+            sys.step();
+        }
+
+        sys.rewind_to(2).unwrap();
+        let expired = Arc::new(AtomicBool::new(false));
+        let timer_expired = Arc::clone(&expired);
+        sys.schedule(sys.clock.time_at(3), move |_| {
+            timer_expired.store(true, Ordering::Relaxed);
+        });
+        sys.step();
+        assert!(expired.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn should_not_track_undone_writes() {
+        let (mut sys, _) = create_sys();
+        sys.start_recording(100, 2);
+        sys.mem.watch_page(0);
+        for _ in 0..4 {
+            sys.step();
+        }
+        assert_eq!(vec![0], sys.mem.take_written_pages());
+
+        sys.mem.watch_page(0);
+        sys.rewind_to(0).unwrap();
+        assert_eq!([0, 0, 0, 0], sys.mem.dump()[..4]);
+        assert!(!sys.mem.has_written_pages());
+    }
+
+    #[test]
+    fn should_not_process_posted_closures_while_replaying() {
+        let (mut sys, counter) = create_sys();
+        sys.start_recording(100, 2);
+        for _ in 0..4 {
+            sys.step();
+        }
+
+        sys.rewind_to(1).unwrap();
+        sys.handle(counter).post(|counter, _| counter.reads = 20);
+        sys.step();
+        assert_eq!(4, sys.get_device(counter).reads);
+
+        while sys.steps() < 4 {
+            sys.step();
+        }
+        sys.step();
+        assert_eq!(21, sys.get_device(counter).reads);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::cpu::Cpu;
+use crate::mem::Mem;
+use crate::System;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::{fs, io};
+
+/// The bytes at the start of a serialized [`Snapshot`], the last of which is the format version.
+///
+/// [`Snapshot`]: Snapshot
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"FIRNSNP\x01";
+
+/// A trait for components whose state can be saved and loaded.
+///
+/// The format of the state is up to the implementation, but it should reject states that it
+/// didn't create instead of panicking.
+pub trait SaveState {
+    fn save_state(&self) -> Vec<u8>;
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError>;
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The snapshot (or part of it) is corrupted or was created by something else.
+    Invalid(&'static str),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot I/O failed: {}", err),
+            SnapshotError::Invalid(reason) => write!(f, "invalid snapshot: {}", reason),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(err) => Some(err),
+            SnapshotError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/// The state of a system's CPU and memory at some point in time.
+///
+/// Devices aren't part of snapshots yet, so restoring a snapshot leaves them as they are.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    pub cpu: Vec<u8>,
+    /// Every addressable byte of memory, including unmapped bytes (which are 0).
+    pub mem: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Serializes the snapshot.
+    ///
+    /// The format is [`SNAPSHOT_MAGIC`] followed by the length of the CPU state (8 bytes,
+    /// little-endian), the CPU state, the length of memory and then memory.
+    ///
+    /// [`SNAPSHOT_MAGIC`]: SNAPSHOT_MAGIC
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(SNAPSHOT_MAGIC.len() + 16 + self.cpu.len() + self.mem.len());
+        bytes.extend(SNAPSHOT_MAGIC);
+        for part in [&self.cpu, &self.mem] {
+            bytes.extend((part.len() as u64).to_le_bytes());
+            bytes.extend(part);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut rest = bytes
+            .strip_prefix(&SNAPSHOT_MAGIC)
+            .ok_or(SnapshotError::Invalid(
+                "not a snapshot (or an unsupported version)",
+            ))?;
+
+        let mut parts = Vec::new();
+        for _ in 0..2 {
+            if rest.len() < 8 {
+                return Err(SnapshotError::Invalid("snapshot is truncated"));
+            }
+            let (len, remaining) = rest.split_at(8);
+            let len = u64::from_le_bytes(len.try_into().unwrap()) as usize;
+            if remaining.len() < len {
+                return Err(SnapshotError::Invalid("snapshot is truncated"));
+            }
+
+            let (part, remaining) = remaining.split_at(len);
+            parts.push(part.to_vec());
+            rest = remaining;
+        }
+
+        let mem = parts.pop().unwrap();
+        let cpu = parts.pop().unwrap();
+
+        Ok(Self { cpu, mem })
+    }
+
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+impl<C> System<C>
+where
+    C: Cpu + SaveState,
+{
+    /// Takes a snapshot of the CPU and memory.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cpu: self.cpu.save_state(),
+            mem: self.mem.dump(),
+        }
+    }
+
+    /// Restores the CPU and memory from a snapshot.
+    ///
+    /// Only mapped memory is restored, so memory that was mapped after the snapshot was taken keeps
+    /// its current contents.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        if snapshot.mem.len() != self.mem.addressable {
+            return Err(SnapshotError::Invalid(
+                "snapshot has a different amount of memory",
+            ));
+        }
+
+        self.cpu.load_state(&snapshot.cpu)?;
+        for (address, byte) in snapshot.mem.iter().enumerate() {
+            if self.mem.is_mapped(address) && self.mem[address] != *byte {
+                self.mem[address] = *byte;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_serialize_snapshots() {
+        let snapshot = Snapshot {
+            cpu: vec![1, 2, 3],
+            mem: vec![4, 5, 6, 7],
+        };
+
+        let bytes = snapshot.to_bytes();
+        assert_eq!(snapshot, Snapshot::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn should_reject_invalid_snapshots() {
+        let snapshot = Snapshot {
+            cpu: vec![1, 2, 3],
+            mem: vec![4, 5, 6, 7],
+        };
+        let bytes = snapshot.to_bytes();
+
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Snapshot::from_bytes(b"FIRNSNP\x00").is_err());
+    }
+}
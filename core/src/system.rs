@@ -1,7 +1,37 @@
+use crate::breakpoint::{BreakpointId, Breakpoints, Inspect};
+use crate::clock::{Clock, Timers};
 use crate::cpu::Cpu;
-use crate::device::{Device, Devices};
+use crate::device::{
+    Device, DeviceHandle, DeviceId, DeviceInfo, DeviceRef, Devices, PortRequest, PortResponse,
+    Posted, UnhandledPort, UnhandledPortPolicy,
+};
 use crate::mem::MemMap;
-use std::sync::{Arc, Mutex};
+use crate::replay::Inputs;
+use crate::rewind::Rewind;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+/// Why the run API ([`System::start`], [`System::resume`] or [`System::run_for`]) stopped
+/// executing.
+///
+/// [`System::start`]: System::start
+/// [`System::resume`]: System::resume
+/// [`System::run_for`]: System::run_for
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StopReason {
+    /// The CPU reached a breakpoint, which hasn't been executed yet.
+    Breakpoint(BreakpointId),
+    /// The CPU executed as many steps as it was asked to.
+    StepLimit,
+    /// The CPU accessed a port that no device handled while the unhandled port policy is
+    /// [`UnhandledPortPolicy::Break`]. The step that accessed it has been executed.
+    ///
+    /// [`UnhandledPortPolicy::Break`]: UnhandledPortPolicy::Break
+    UnhandledPort(PortRequest),
+}
 
 pub struct System<C>
 where
@@ -9,7 +39,34 @@ where
 {
     pub cpu: Box<C>,
     pub mem: MemMap,
+    /// The breakpoints that are checked before every step of the CPU by the run API.
+    pub breakpoints: Breakpoints,
+    pub clock: Clock,
+    /// What happens when the CPU accesses a port that no device handles (see
+    /// [`unhandled_port`]).
+    ///
+    /// [`unhandled_port`]: System::unhandled_port
+    pub unhandled_port_policy: UnhandledPortPolicy,
     devices: Devices<C>,
+    pub(crate) timers: Timers<C>,
+    /// The index of the device whose method is being called, which is who timers are set for.
+    pub(crate) current_device: Option<usize>,
+    /// The closures that were posted by device handles on other threads (see [`DeviceHandle`]).
+    posted: Receiver<Posted<C>>,
+    poster: Sender<Posted<C>>,
+    /// Whether anything might have been posted, which is checked before every step so that the
+    /// channel is only polled when it's needed.
+    pending: Arc<AtomicBool>,
+    unhandled_ports: BTreeMap<u16, UnhandledPort>,
+    /// The unhandled port access that the run API should stop for.
+    unhandled_break: Option<PortRequest>,
+    /// The step that the run API stops at, if it was asked to stop after a number of steps.
+    step_limit: Option<u64>,
+
+    /// The number of steps that have been executed (or replayed up to, after rewinding).
+    pub(crate) steps: u64,
+    pub(crate) rewind: Rewind<C>,
+    pub(crate) inputs: Inputs,
 }
 
 impl<C> System<C>
@@ -17,63 +74,590 @@ where
     C: Cpu,
 {
     pub fn new(cpu: C, mem: MemMap) -> Self {
+        let (poster, posted) = mpsc::channel();
+
         Self {
+            clock: Clock::new(cpu.frequency()),
             cpu: Box::new(cpu),
             mem,
+            breakpoints: Breakpoints::new(),
+            unhandled_port_policy: UnhandledPortPolicy::default(),
             devices: Devices::new(),
+            timers: Timers::new(),
+            current_device: None,
+            posted,
+            poster,
+            pending: Arc::default(),
+            unhandled_ports: BTreeMap::new(),
+            unhandled_break: None,
+            step_limit: None,
+
+            steps: 0,
+            rewind: Rewind::new(),
+            inputs: Inputs::Live,
         }
     }
 
+    /// Initializes every device (see [`Device::init`]) and then the CPU.
+    ///
+    /// [`Device::init`]: crate::device::Device::init
     pub fn init(&mut self) {
-        let devices = Devices::clone(&self.devices);
-        devices.init_all(self);
+        for index in 0..self.devices.len() {
+            let id = self.devices.order()[index];
+            self.call_device(id, |device, sys| device.init(sys));
+        }
 
         self.cpu.init();
     }
 
-    pub fn start(&mut self) {
+    /// Resets every device (see [`Device::reset`]) and then the CPU.
+    ///
+    /// [`Device::reset`]: crate::device::Device::reset
+    pub fn reset(&mut self) {
+        for index in 0..self.devices.len() {
+            let id = self.devices.order()[index];
+            self.call_device(id, |device, sys| device.reset(sys));
+        }
+
         self.cpu.reset();
+    }
 
-        let devices = Devices::clone(&self.devices);
-        loop {
-            devices.step_all(self);
-            C::step(self);
+    /// Shuts down every device (see [`Device::shutdown`]), which should be done before the system
+    /// is dropped so that devices can save their state.
+    ///
+    /// [`Device::shutdown`]: crate::device::Device::shutdown
+    pub fn shutdown(&mut self) {
+        for index in (0..self.devices.len()).rev() {
+            let id = self.devices.order()[index];
+            self.call_device(id, |device, sys| device.shutdown(sys));
         }
     }
 
-    pub fn run(&mut self) {
-        self.init();
-        self.start();
+    /// Steps the CPU once and then the devices that are due, like a single iteration of [`start`].
+    ///
+    /// This is useful for debuggers that need to control execution. The CPU isn't reset first and
+    /// breakpoints aren't checked.
+    ///
+    /// The closures posted by device handles are called first (see [`process_posted`]), unless the
+    /// step is being replayed, and then a checkpoint is taken if the system is recording (see
+    /// [`rewind`]). After the CPU is stepped, the devices whose deadlines were reached or whose
+    /// watched memory was written to (unless the step is being replayed) are stepped and the
+    /// timers that expired are called (see [`clock`]), and then the clock is paced.
+    ///
+    /// [`start`]: System::start
+    /// [`process_posted`]: System::process_posted
+    /// [`rewind`]: crate::rewind
+    /// [`clock`]: crate::clock
+    pub fn step(&mut self) {
+        let replaying = self.rewind.is_replaying(self.steps);
+        if !replaying {
+            self.process_posted();
+        }
+        self.checkpoint_if_due();
+
+        let cycles = self.clock.cycles();
+        C::step(self);
+        if self.clock.cycles() == cycles {
+            self.clock.advance(1);
+        }
+        self.steps += 1;
+
+        if replaying {
+            self.mem.take_touched();
+        }
+        self.expire_timers();
+        self.clock.pace();
     }
 
-    pub fn add_device<D>(&mut self, device: D) -> Arc<Mutex<D>>
+    /// Counts instructions that the CPU executed in the step that's running as extra steps, for
+    /// CPUs that execute more than one instruction per step.
+    ///
+    /// Every instruction should count as a step so that the run API, rewinding and replays see
+    /// the same steps no matter how many instructions the CPU executes at a time. The step itself
+    /// is counted once [`C::step`] returns, so a CPU that executes `n` instructions counts `n - 1`
+    /// of them (and it counts the ones before an instruction that accesses devices before
+    /// executing it).
+    ///
+    /// [`C::step`]: Cpu::step
+    pub fn count_steps(&mut self, steps: u64) {
+        self.steps += steps;
+    }
+
+    /// The number of instructions that the CPU can execute (including the one that it's about to
+    /// execute) before something has to happen between instructions, for CPUs that execute more
+    /// than one instruction per step (see [`count_steps`]).
+    ///
+    /// That's until the next timer expires (assuming that every instruction takes at least a
+    /// cycle) or the run API reaches its step limit, or 0 if a device has to be woken up for a
+    /// write to memory that it's watching or the run API has to stop for an unhandled port.
+    ///
+    /// [`count_steps`]: System::count_steps
+    pub fn step_budget(&self) -> u64 {
+        if self.mem.has_touched() || self.unhandled_break.is_some() {
+            return 0;
+        }
+
+        let timer = self.timers.next_expiry().map_or(u64::MAX, |cycles| {
+            cycles.saturating_sub(self.clock.cycles())
+        });
+        let limit = self
+            .step_limit
+            .map_or(u64::MAX, |limit| limit.saturating_sub(self.steps));
+
+        timer.min(limit)
+    }
+
+    /// The number of steps that the CPU has executed, which goes back down when the system is
+    /// rewound (see [`rewind`]).
+    ///
+    /// [`rewind`]: crate::rewind
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Adds a device, which the system owns from now on (see [`Devices::push`]).
+    ///
+    /// [`Devices::push`]: Devices::push
+    pub fn add_device<D>(&mut self, device: D) -> DeviceRef<D>
     where
-        D: Device<C> + 'static,
+        D: Device<C>,
     {
         self.devices.push(device)
     }
 
-    pub fn port_in_8(&mut self, port: u16) -> Option<u8> {
-        let devices = Devices::clone(&self.devices);
+    /// Adds a device with a name, like `com1` (see [`Devices::push_named`]).
+    ///
+    /// [`Devices::push_named`]: Devices::push_named
+    pub fn add_named_device<D>(&mut self, name: &str, device: D) -> DeviceRef<D>
+    where
+        D: Device<C>,
+    {
+        self.devices.push_named(name, device)
+    }
+
+    /// Gets a device.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device is busy, like when it's the device whose method is being called.
+    pub fn get_device<D>(&self, device: DeviceRef<D>) -> &D
+    where
+        D: Device<C>,
+    {
+        self.devices.get(device)
+    }
+
+    /// Gets a device mutably.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device is busy, like when it's the device whose method is being called.
+    pub fn get_device_mut<D>(&mut self, device: DeviceRef<D>) -> &mut D
+    where
+        D: Device<C>,
+    {
+        self.devices.get_mut(device)
+    }
+
+    /// Calls a closure with a device and the system, which is how devices call each other's
+    /// methods that need the system (like raising an interrupt).
+    ///
+    /// The device is the calling device while the closure runs, so any timers and deadlines that
+    /// are set are set for it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device is busy, like when it's the device whose method is being called.
+    pub fn with_device<D, R>(
+        &mut self,
+        device: DeviceRef<D>,
+        f: impl FnOnce(&mut D, &mut Self) -> R,
+    ) -> R
+    where
+        D: Device<C>,
+    {
+        self.call_device(device.id(), |dyn_device, sys| {
+            let any: &mut dyn std::any::Any = dyn_device;
+
+            f(any.downcast_mut().unwrap(), sys)
+        })
+        .expect("device is busy")
+    }
+
+    /// Creates a handle to a device that other threads can use (see [`DeviceHandle`]).
+    ///
+    /// [`DeviceHandle`]: DeviceHandle
+    pub fn handle<D>(&self, device: DeviceRef<D>) -> DeviceHandle<C, D>
+    where
+        D: Device<C>,
+    {
+        DeviceHandle::new(device, self.poster.clone(), Arc::clone(&self.pending))
+    }
+
+    /// Calls the closures that were posted by device handles, in the order they were posted.
+    ///
+    /// This is done before every step, but it can be called while the system isn't running too.
+    pub fn process_posted(&mut self) {
+        if !self.pending.load(Ordering::Relaxed) || !self.pending.swap(false, Ordering::Acquire) {
+            return;
+        }
+
+        while let Ok(posted) = self.posted.try_recv() {
+            posted(self);
+        }
+    }
+
+    /// The first device of a type, in the order they were added.
+    pub fn device<D>(&self) -> Option<DeviceRef<D>>
+    where
+        D: Device<C>,
+    {
+        self.devices.find()
+    }
+
+    pub fn device_by_name(&self, name: &str) -> Option<DeviceId> {
+        self.devices.find_by_name(name)
+    }
+
+    /// The device with a name, if it has the type `D`.
+    pub fn device_by_name_as<D>(&self, name: &str) -> Option<DeviceRef<D>>
+    where
+        D: Device<C>,
+    {
+        self.devices.find_by_name_as(name)
+    }
+
+    /// Describes every device, in the order they were added.
+    ///
+    /// The memory claims of each device include the memory that it watches (see
+    /// [`wake_on_write`]).
+    ///
+    /// [`wake_on_write`]: System::wake_on_write
+    pub fn device_infos(&self) -> Vec<DeviceInfo> {
+        let mut infos = self.devices.infos();
+        for info in &mut infos {
+            for range in self.mem.watched_writes(info.id) {
+                if !info.claims.mem.contains(&range) {
+                    info.claims.mem.push(range);
+                }
+            }
+        }
+
+        infos
+    }
+
+    /// Makes one device depend on another (see [`Devices::add_dependency`]).
+    ///
+    /// [`Devices::add_dependency`]: Devices::add_dependency
+    pub fn add_dependency<D, E>(&mut self, dependent: DeviceRef<D>, dependency: DeviceRef<E>) {
+        self.devices.add_dependency(dependent, dependency);
+    }
+
+    /// The position of a device in dependency order.
+    pub(crate) fn device_rank(&self, index: usize) -> usize {
+        self.devices.rank(index)
+    }
+
+    /// Calls a method of the device with the index, which is the calling device while it's called
+    /// (so that it can set timers and deadlines). Returns `None` without calling it if the device
+    /// is busy.
+    pub(crate) fn call_device<R>(
+        &mut self,
+        index: usize,
+        method: impl FnOnce(&mut dyn Device<C>, &mut Self) -> R,
+    ) -> Option<R> {
+        let mut device = self.devices.take(index)?;
+        let previous = self.current_device.replace(index);
+        let result = method(&mut *device, self);
+        self.current_device = previous;
+        self.devices.put_back(index, device);
 
-        devices.port_in_8(self, port)
+        Some(result)
     }
 
+    /// Dumps the state of every device, in the order they were added.
+    ///
+    /// See [`Device::dump`] for more information.
+    ///
+    /// [`Device::dump`]: crate::device::Device::dump
+    pub fn dump_devices(&self) -> Vec<Option<String>> {
+        self.devices.dump_all()
+    }
+
+    /// Handles an input port request which expects an 8-bit response.
+    ///
+    /// See [`Device::handle_port`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a device is busy, like when a device accesses a port from one of its methods.
+    ///
+    /// [`Device::handle_port`]: crate::device::Device::handle_port
+    pub fn port_in_8(&mut self, port: u16) -> Option<u8> {
+        self.port_access(PortRequest::In8(port), |sys| {
+            sys.handle_port(PortRequest::In8(port), in_8_value)
+        })
+        .map(|value| value as u8)
+    }
+
+    /// Handles an input port request which expects a 16-bit response.
+    ///
+    /// If no device handles it, it's split into 8-bit requests for `port` and `port + 1` like on
+    /// the ISA bus. If only one of them is handled, the other one is passed to [`unhandled_port`]
+    /// (which it gets its value from), and if neither is handled, the whole request is unhandled.
+    ///
+    /// See [`Device::handle_port`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a device is busy, like when a device accesses a port from one of its methods.
+    ///
+    /// [`Device::handle_port`]: crate::device::Device::handle_port
+    /// [`unhandled_port`]: System::unhandled_port
     pub fn port_in_16(&mut self, port: u16) -> Option<u16> {
-        let devices = Devices::clone(&self.devices);
+        self.port_access(PortRequest::In16(port), |sys| {
+            sys.handle_port(PortRequest::In16(port), |response| match response {
+                PortResponse::In16(value) => Some(value),
+                _ => None,
+            })
+            .or_else(|| {
+                let high_port = port.wrapping_add(1);
+                let low = sys.handle_port(PortRequest::In8(port), in_8_value);
+                let high = sys.handle_port(PortRequest::In8(high_port), in_8_value);
+                if low.is_none() && high.is_none() {
+                    return None;
+                }
 
-        devices.port_in_16(self, port)
+                let mut unhandled = |port| sys.unhandled_port(PortRequest::In8(port)) & 0xff;
+                let low = low.unwrap_or_else(|| unhandled(port));
+                let high = high.unwrap_or_else(|| unhandled(high_port));
+                Some(low | (high << 8))
+            })
+        })
     }
 
+    /// Handles an output port request.
+    ///
+    /// See [`Device::handle_port`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a device is busy, like when a device accesses a port from one of its methods.
+    ///
+    /// [`Device::handle_port`]: crate::device::Device::handle_port
     pub fn port_out_8(&mut self, port: u16, value: u8) -> Option<()> {
-        let devices = Devices::clone(&self.devices);
-
-        devices.port_out_8(self, port, value)
+        let request = PortRequest::Out8(port, value);
+        self.port_access(request, |sys| sys.handle_port(request, out_value))
+            .map(|_| ())
     }
 
+    /// Handles an output port request.
+    ///
+    /// If no device handles it, it's split into 8-bit requests for `port` and `port + 1` like on
+    /// the ISA bus. If only one of them is handled, the other one is passed to [`unhandled_port`],
+    /// and if neither is handled, the whole request is unhandled.
+    ///
+    /// See [`Device::handle_port`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a device is busy, like when a device accesses a port from one of its methods.
+    ///
+    /// [`Device::handle_port`]: crate::device::Device::handle_port
+    /// [`unhandled_port`]: System::unhandled_port
     pub fn port_out_16(&mut self, port: u16, value: u16) -> Option<()> {
-        let devices = Devices::clone(&self.devices);
+        let [low, high] = value.to_le_bytes();
+        self.port_access(PortRequest::Out16(port, value), |sys| {
+            sys.handle_port(PortRequest::Out16(port, value), out_value)
+                .or_else(|| {
+                    let requests = [
+                        PortRequest::Out8(port, low),
+                        PortRequest::Out8(port.wrapping_add(1), high),
+                    ];
+                    let handled = requests.map(|request| sys.handle_port(request, out_value));
+                    if handled == [None, None] {
+                        return None;
+                    }
+
+                    for (request, handled) in requests.into_iter().zip(handled) {
+                        if handled.is_none() {
+                            sys.unhandled_port(request);
+                        }
+                    }
+                    Some(0)
+                })
+        })
+        .map(|_| ())
+    }
+
+    /// Handles a port access by the CPU that no device handled, according to the
+    /// [`unhandled_port_policy`], and returns the value that a read gets, which is all ones (only
+    /// the low byte is used by 8-bit reads).
+    ///
+    /// The access is added to the report of unhandled ports (see [`unhandled_ports`]), unless
+    /// it's being replayed (see [`rewind`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the policy is [`UnhandledPortPolicy::Panic`].
+    ///
+    /// [`unhandled_port_policy`]: System::unhandled_port_policy
+    /// [`unhandled_ports`]: System::unhandled_ports
+    /// [`rewind`]: crate::rewind
+    /// [`UnhandledPortPolicy::Panic`]: UnhandledPortPolicy::Panic
+    pub fn unhandled_port(&mut self, request: PortRequest) -> u16 {
+        let step = self.steps;
+        if self.rewind.is_replaying(step) {
+            return 0xffff;
+        }
+
+        let port = self
+            .unhandled_ports
+            .entry(request.port())
+            .or_insert(UnhandledPort {
+                reads: 0,
+                writes: 0,
+                first_step: step,
+            });
+        if request.is_read() {
+            port.reads += 1;
+        } else {
+            port.writes += 1;
+        }
+
+        match self.unhandled_port_policy {
+            UnhandledPortPolicy::OpenBus => {}
+            UnhandledPortPolicy::Log => eprintln!("unhandled {request} at step {step}"),
+            UnhandledPortPolicy::Break => self.unhandled_break = Some(request),
+            UnhandledPortPolicy::Panic => panic!("unhandled {request} at step {step}"),
+        }
+
+        0xffff
+    }
+
+    /// The ports that the CPU accessed without a device handling them, sorted by port.
+    pub fn unhandled_ports(&self) -> &BTreeMap<u16, UnhandledPort> {
+        &self.unhandled_ports
+    }
+
+    /// Describes the unhandled ports with a line for each, like
+    /// `0x0201: 3 reads and 0 writes, first at step 1042`.
+    pub fn unhandled_port_report(&self) -> String {
+        let mut report = String::new();
+        for (port, accesses) in &self.unhandled_ports {
+            let _ = writeln!(
+                report,
+                "{port:#06x}: {} reads and {} writes, first at step {}",
+                accesses.reads, accesses.writes, accesses.first_step
+            );
+        }
+
+        report
+    }
+
+    pub fn clear_unhandled_ports(&mut self) {
+        self.unhandled_ports.clear();
+    }
+
+    /// Sends a port request to every device until one of them handles it with a response that
+    /// `accept` accepts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a device is busy, since it would miss the request.
+    fn handle_port(
+        &mut self,
+        request: PortRequest,
+        accept: impl Fn(PortResponse) -> Option<u16>,
+    ) -> Option<u16> {
+        for index in 0..self.devices.len() {
+            let response = self
+                .call_device(index, |device, sys| device.handle_port(sys, request))
+                .unwrap_or_else(|| panic!("device is busy, so it can't handle {}", request));
+            if let Some(value) = response.and_then(&accept) {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+}
+
+/// The value of a response to an 8-bit input port request.
+fn in_8_value(response: PortResponse) -> Option<u16> {
+    match response {
+        PortResponse::In8(value) => Some(u16::from(value)),
+        _ => None,
+    }
+}
+
+/// Accepts the response to an output port request.
+fn out_value(response: PortResponse) -> Option<u16> {
+    match response {
+        PortResponse::Out => Some(0),
+        _ => None,
+    }
+}
+
+impl<C> System<C>
+where
+    C: Cpu + Inspect,
+{
+    /// Resets the system and executes until a breakpoint is reached.
+    ///
+    /// Breakpoints are checked before every step of the CPU, including the first.
+    pub fn start(&mut self) -> StopReason {
+        self.reset();
+
+        self.execute(None, true)
+    }
+
+    /// Continues executing after the system has stopped, until a breakpoint is reached.
+    ///
+    /// The breakpoints at the next instruction aren't checked, so resuming after a breakpoint
+    /// executes it instead of stopping again immediately.
+    pub fn resume(&mut self) -> StopReason {
+        self.execute(None, false)
+    }
+
+    /// Like [`resume`], but stops after `steps` steps if no breakpoint is reached first.
+    ///
+    /// [`resume`]: System::resume
+    pub fn run_for(&mut self, steps: u64) -> StopReason {
+        self.execute(Some(steps), false)
+    }
+
+    pub fn run(&mut self) -> StopReason {
+        self.init();
+        self.start()
+    }
+
+    fn execute(&mut self, steps: Option<u64>, check_first: bool) -> StopReason {
+        self.clock.reanchor();
+
+        let mut check = check_first;
+        self.unhandled_break = None;
+        self.step_limit = steps.map(|steps| self.steps + steps);
+        let reason = loop {
+            if check {
+                if let Some(id) = self.breakpoints.check(&*self.cpu, &self.mem) {
+                    break StopReason::Breakpoint(id);
+                }
+            }
+            check = true;
+
+            if self.step_limit.is_some_and(|limit| self.steps >= limit) {
+                break StopReason::StepLimit;
+            }
+
+            self.step();
+
+            if let Some(request) = self.unhandled_break.take() {
+                break StopReason::UnhandledPort(request);
+            }
+        };
+        self.step_limit = None;
 
-        devices.port_out_16(self, port, value)
+        reason
     }
 }
@@ -1,8 +1,9 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use firn::arch::x86;
-use firn::arch::x86::device::{Cmos, DualPic};
+use firn::arch::x86::device::{Cmos, Dma, DualPic, KeyboardController, Pit, Ppi};
 use firn::arch::x86::{Cpu, Feature};
+use firn::clock::Pacing;
 use firn::cpu::Restrict;
 use firn::mem::{BasicMem, Eeprom, MemMap};
 use firn::System;
@@ -52,11 +53,18 @@ fn create_sys() -> System<Cpu> {
     let mut cpu = Cpu::new();
     cpu.add_feature(Feature::InstrCpu1);
 
-    let pic = DualPic::new();
     let cmos = Cmos::new_current_time();
 
     let mut sys = System::new(cpu, map);
-    sys.add_device(pic);
+    sys.clock.set_pacing(Pacing::RealTime);
+    let pic = sys.add_device(DualPic::new());
+    let pit = sys.add_device(Pit::new(pic));
+    sys.add_dependency(pit, pic);
+    let ppi = sys.add_device(Ppi::new_at(pit, pic));
+    sys.add_dependency(ppi, pit);
+    let kbc = sys.add_device(KeyboardController::new(pic));
+    sys.add_dependency(kbc, pic);
+    sys.add_device(Dma::new());
     sys.add_device(cmos);
 
     sys